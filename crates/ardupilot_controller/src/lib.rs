@@ -0,0 +1,457 @@
+//! Flies the drone with ArduPilot's copter SITL through its JSON physics interface (`--model
+//! JSON`). ArduPilot sends a binary servo packet for every frame and waits for the physics state
+//! of the next frame as a line of json, so the two run in lock-step: every flight controller
+//! update answers the last frame and waits for the next one.
+//!
+//! ArduPilot is NED/FRD while the simulator is y up, see `flight_controller::frames`. The motor
+//! outputs are reordered from the ArduPilot quad X numbering to the Betaflight order.
+
+pub mod mock;
+
+use flight_controller::{
+    FlightController, FlightControllerUpdate, InitError, MotorInput,
+    frames::{specific_force_frd, to_frd, to_ned_quaternion},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+    time::Duration,
+};
+use tempfile::TempDir;
+
+// the servo packet with 16 and 32 channels
+pub const SERVO_MAGIC_16: u16 = 18458;
+pub const SERVO_MAGIC_32: u16 = 29569;
+// ArduPilot motor n drives this Betaflight motor (quad X: front right, rear left, front left,
+// rear right)
+const BF_MOTOR_OF_AP_MOTOR: [usize; 4] = [1, 2, 3, 0];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServoPacket {
+    pub frame_rate: u16,
+    pub frame_count: u32,
+    pub pwm: Vec<u16>,
+}
+
+impl ServoPacket {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        if bytes.len() < 8 {
+            return None;
+        }
+        let channels = match u16_at(0) {
+            SERVO_MAGIC_16 => 16,
+            SERVO_MAGIC_32 => 32,
+            _ => return None,
+        };
+        if bytes.len() < 8 + 2 * channels {
+            return None;
+        }
+        Some(Self {
+            frame_rate: u16_at(2),
+            frame_count: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            pwm: (0..channels).map(|i| u16_at(8 + 2 * i)).collect(),
+        })
+    }
+
+    /// Always the 16 channel layout, missing channels are 0
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(40);
+        bytes.extend(SERVO_MAGIC_16.to_le_bytes());
+        bytes.extend(self.frame_rate.to_le_bytes());
+        bytes.extend(self.frame_count.to_le_bytes());
+        for i in 0..16 {
+            bytes.extend(self.pwm.get(i).copied().unwrap_or(0).to_le_bytes());
+        }
+        bytes
+    }
+
+    /// The first four outputs between 0 and 1, in Betaflight motor order
+    pub fn motor_input(&self) -> MotorInput {
+        let mut input = [0.; 4];
+        for (ap_motor, bf_motor) in BF_MOTOR_OF_AP_MOTOR.iter().enumerate() {
+            let pwm = self.pwm.get(ap_motor).copied().unwrap_or(1000) as f64;
+            input[*bf_motor] = ((pwm - 1000.) / 1000.).clamp(0., 1.);
+        }
+        MotorInput { input }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Imu {
+    pub gyro: [f64; 3],       // FRD, rad/s
+    pub accel_body: [f64; 3], // FRD specific force, m/s^2
+}
+
+/// The physics state ArduPilot expects for every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PhysicsState {
+    pub timestamp: f64, // s
+    pub imu: Imu,
+    pub position: [f64; 3],   // NED, m
+    pub quaternion: [f64; 4], // w, x, y, z
+    pub velocity: [f64; 3],   // NED, m/s
+}
+
+impl PhysicsState {
+    pub fn new(timestamp: f64, update: &FlightControllerUpdate, velocity: [f64; 3]) -> Self {
+        let gyro = &update.gyro_update;
+        Self {
+            timestamp,
+            imu: Imu {
+                gyro: to_frd(gyro.angular_velocity),
+                accel_body: specific_force_frd(gyro),
+            },
+            position: to_frd(update.position),
+            quaternion: to_ned_quaternion(gyro.rotation),
+            velocity: to_frd(velocity),
+        }
+    }
+
+    /// ArduPilot wants the json between two newlines
+    pub fn to_message(&self) -> String {
+        format!("\n{}\n", serde_json::to_string(self).unwrap())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ArduPilotConfig {
+    // None if the SITL is started by hand
+    pub binary: Option<PathBuf>,
+    // parameter file handed to --defaults
+    pub defaults: Option<PathBuf>,
+    pub args: Vec<String>,
+    // ArduPilot sends the servo packets to port 9002
+    pub bind: String,
+    // should match the loop rate of ArduPilot (SCHED_LOOP_RATE)
+    pub scheduler_delta: Duration,
+    // how long to wait for the next frame, the first one can take much longer, neither can be zero
+    pub frame_timeout: Duration,
+    pub startup_timeout: Duration,
+}
+
+impl Default for ArduPilotConfig {
+    fn default() -> Self {
+        Self {
+            binary: None,
+            defaults: None,
+            args: vec![],
+            bind: "127.0.0.1:9002".into(),
+            scheduler_delta: Duration::from_micros(2500),
+            frame_timeout: Duration::from_secs(1),
+            startup_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ArduPilotConfig {
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: Some(binary.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_defaults(self, defaults: impl Into<PathBuf>) -> Self {
+        Self {
+            defaults: Some(defaults.into()),
+            ..self
+        }
+    }
+}
+
+/// A running ArduPilot SITL, it is killed when this is dropped.
+#[derive(Debug)]
+pub struct ArduPilotProcess {
+    child: Child,
+    // ArduPilot writes its eeprom and logs into the working directory
+    _workdir: TempDir,
+}
+
+impl ArduPilotProcess {
+    pub fn spawn(binary: &Path, config: &ArduPilotConfig) -> std::io::Result<Self> {
+        let workdir = TempDir::new()?;
+        let host = config.bind.split(':').next().unwrap_or("127.0.0.1");
+        let mut command = Command::new(binary);
+        command
+            .arg("--model")
+            .arg(format!("JSON:{host}"))
+            .arg("-I0")
+            .args(&config.args)
+            .current_dir(workdir.path())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if let Some(defaults) = &config.defaults {
+            command.arg("--defaults").arg(defaults);
+        }
+        let child = command.spawn()?;
+        log::info!("Started ArduPilot SITL with pid {}", child.id());
+        Ok(Self {
+            child,
+            _workdir: workdir,
+        })
+    }
+}
+
+impl Drop for ArduPilotProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[derive(Debug, Default)]
+struct ArduPilotState {
+    process: Option<ArduPilotProcess>,
+    socket: Option<UdpSocket>,
+    // where the servo packets come from, the physics state is sent back there
+    peer: Option<SocketAddr>,
+    frame_count: Option<u32>,
+    frame_rate: u16,
+    time: f64,
+    last_position: Option<[f64; 3]>,
+    motor_input: MotorInput,
+    timeouts: usize,
+}
+
+#[derive(Debug)]
+pub struct ArduPilotController {
+    pub config: ArduPilotConfig,
+    state: Mutex<ArduPilotState>,
+}
+
+impl ArduPilotController {
+    pub fn new(config: ArduPilotConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(ArduPilotState::default()),
+        }
+    }
+
+    /// The address the servo packets are received on, None before init
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        let state = self.state.lock().unwrap();
+        state
+            .socket
+            .as_ref()
+            .and_then(|socket| socket.local_addr().ok())
+    }
+
+    /// The loop rate ArduPilot reported in the last frame
+    pub fn frame_rate(&self) -> u16 {
+        self.state.lock().unwrap().frame_rate
+    }
+
+    /// How often no frame arrived in time
+    pub fn timeouts(&self) -> usize {
+        self.state.lock().unwrap().timeouts
+    }
+}
+
+impl FlightController for ArduPilotController {
+    fn init(&self) -> Result<(), InitError> {
+        // a socket can not wait for zero time
+        if self.config.frame_timeout.is_zero() || self.config.startup_timeout.is_zero() {
+            return Err("the frame and startup timeouts of ArduPilot can not be zero".into());
+        }
+        let mut state = self.state.lock().unwrap();
+        // the old process has to release the port first
+        *state = ArduPilotState::default();
+        let socket = UdpSocket::bind(&self.config.bind)
+            .map_err(|err| format!("could not bind the servo port {}: {err}", self.config.bind))?;
+        if let Some(binary) = &self.config.binary {
+            let process = ArduPilotProcess::spawn(binary, &self.config).map_err(|err| {
+                format!(
+                    "could not start the ArduPilot SITL {}: {err}",
+                    binary.display()
+                )
+            })?;
+            state.process = Some(process);
+        }
+        // only a started controller waits for frames
+        state.socket = Some(socket);
+        Ok(())
+    }
+
+    fn update(&self, delta_time: f64, update: FlightControllerUpdate) -> MotorInput {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let Some(socket) = state.socket.as_ref() else {
+            // the motors stay off until init succeeds
+            log::warn!("The ArduPilot controller was not initialized");
+            return MotorInput::default();
+        };
+        state.time += delta_time;
+        let velocity = match state.last_position {
+            Some(last) if delta_time > 0. => {
+                std::array::from_fn(|i| (update.position[i] - last[i]) / delta_time)
+            }
+            _ => [0.; 3],
+        };
+        state.last_position = Some(update.position);
+
+        // answer the last frame, there is nothing to answer before the first one
+        let message = PhysicsState::new(state.time, &update, velocity).to_message();
+        if let Some(peer) = state.peer
+            && let Err(err) = socket.send_to(message.as_bytes(), peer)
+        {
+            log::warn!("Could not send the physics state: {err}");
+        }
+
+        let timeout = if state.peer.is_some() {
+            self.config.frame_timeout
+        } else {
+            self.config.startup_timeout
+        };
+        if let Err(err) = socket.set_read_timeout(Some(timeout)) {
+            log::warn!("Could not wait {timeout:?} for ArduPilot: {err}");
+            return state.motor_input;
+        }
+        let mut buf = [0; 128];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    log::warn!("No frame from ArduPilot within {timeout:?}");
+                    state.timeouts += 1;
+                    break;
+                }
+                Err(err) => {
+                    log::warn!("ArduPilot socket error: {err}");
+                    break;
+                }
+            };
+            let Some(packet) = ServoPacket::parse(&buf[..len]) else {
+                continue;
+            };
+            // a repeated frame means ArduPilot did not get the answer
+            if state.frame_count == Some(packet.frame_count) {
+                let _ = socket.send_to(message.as_bytes(), peer);
+                continue;
+            }
+            if state
+                .frame_count
+                .is_some_and(|frame_count| packet.frame_count < frame_count)
+            {
+                log::info!("ArduPilot restarted");
+            }
+            state.peer = Some(peer);
+            state.frame_count = Some(packet.frame_count);
+            state.frame_rate = packet.frame_rate;
+            state.motor_input = packet.motor_input();
+            break;
+        }
+        state.motor_input
+    }
+
+    fn scheduler_delta(&self) -> Duration {
+        self.config.scheduler_delta
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{ArduPilotConfig, ArduPilotController, ServoPacket, mock::MockArduPilot};
+    use flight_controller::{FlightController, FlightControllerUpdate};
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn servo_packet() {
+        let packet = ServoPacket {
+            frame_rate: 400,
+            frame_count: 7,
+            pwm: vec![1100, 1200, 1300, 1400],
+        };
+        let parsed = ServoPacket::parse(&packet.to_bytes()).unwrap();
+        assert_eq!(parsed.frame_count, 7);
+        assert_eq!(parsed.pwm[..4], [1100, 1200, 1300, 1400]);
+        // rear right, front right, rear left, front left
+        let input = parsed.motor_input().input;
+        let expected = [0.4, 0.1, 0.2, 0.3];
+        assert!(
+            input
+                .iter()
+                .zip(expected)
+                .all(|(a, b)| (a - b).abs() < 1e-9)
+        );
+        assert!(ServoPacket::parse(&[0; 40]).is_none());
+    }
+
+    #[test]
+    fn missing_binary() {
+        let config = ArduPilotConfig {
+            bind: "127.0.0.1:0".into(),
+            ..ArduPilotConfig::new("/nonexistent/arducopter")
+        };
+        let controller = ArduPilotController::new(config);
+        let err = controller.init().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("could not start the ArduPilot SITL")
+        );
+        // without a SITL the motors stay off
+        let mut update = FlightControllerUpdate::default();
+        update.gyro_update.rotation = [0., 0., 0., 1.];
+        assert_eq!(controller.update(0.0025, update).input, [0.; 4]);
+    }
+
+    #[test]
+    fn zero_timeout() {
+        let config = ArduPilotConfig {
+            bind: "127.0.0.1:0".into(),
+            frame_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        let err = ArduPilotController::new(config).init().unwrap_err();
+        assert!(err.to_string().contains("can not be zero"));
+    }
+
+    #[test]
+    fn lock_step_with_mock() {
+        let config = ArduPilotConfig {
+            bind: "127.0.0.1:0".into(),
+            frame_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let controller = ArduPilotController::new(config);
+        controller.init().unwrap();
+        let address = controller.local_addr().unwrap();
+
+        let mock = thread::spawn(move || {
+            let mut mock = MockArduPilot::new(address);
+            mock.send_servos([1100, 1200, 1300, 1400]);
+            let first = mock.receive_state().unwrap();
+            // the answer got lost, the same frame is sent again
+            mock.resend();
+            let repeated = mock.receive_state().unwrap();
+            mock.send_servos([1000; 4]);
+            (first, repeated)
+        });
+
+        let mut update = FlightControllerUpdate::default();
+        update.gyro_update.rotation = [0., 0., 0., 1.];
+        update.position = [0., 2., 0.];
+        let input = controller.update(0.0025, update);
+        assert!((input[0] - 0.4).abs() < 1e-9);
+        let input = controller.update(0.0025, update);
+        assert_eq!(input.input, [0.; 4]);
+        assert_eq!(controller.frame_rate(), 400);
+
+        let (first, repeated) = mock.join().unwrap();
+        assert_eq!(first, repeated);
+        assert_eq!(first.timestamp, 0.005);
+        assert_eq!(first.position, [-0., 0., -2.]);
+        assert_eq!(first.imu.accel_body, [0., 0., -9.81]);
+        assert_eq!(first.quaternion, [1., 0., 0., 0.]);
+
+        // nothing comes anymore, the last outputs are held
+        let input = controller.update(0.0025, update);
+        assert_eq!(input.input, [0.; 4]);
+        assert_eq!(controller.timeouts(), 1);
+    }
+}
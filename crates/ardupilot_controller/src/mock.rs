@@ -0,0 +1,63 @@
+use crate::{PhysicsState, ServoPacket};
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+/// Plays the ArduPilot side of the JSON interface, so the controller can be tested without an
+/// ArduPilot build.
+pub struct MockArduPilot {
+    pub socket: UdpSocket,
+    pub simulator: SocketAddr,
+    pub frame_rate: u16,
+    last_packet: Option<ServoPacket>,
+}
+
+impl MockArduPilot {
+    pub fn new(simulator: SocketAddr) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        Self {
+            socket,
+            simulator,
+            frame_rate: 400,
+            last_packet: None,
+        }
+    }
+
+    /// Sends the next frame
+    pub fn send_servos(&mut self, pwm: [u16; 4]) {
+        let frame_count = self
+            .last_packet
+            .as_ref()
+            .map_or(0, |packet| packet.frame_count + 1);
+        let packet = ServoPacket {
+            frame_rate: self.frame_rate,
+            frame_count,
+            pwm: pwm.to_vec(),
+        };
+        self.socket
+            .send_to(&packet.to_bytes(), self.simulator)
+            .unwrap();
+        self.last_packet = Some(packet);
+    }
+
+    /// Sends the last frame again, like ArduPilot does when the physics state does not arrive
+    pub fn resend(&self) {
+        if let Some(packet) = &self.last_packet {
+            self.socket
+                .send_to(&packet.to_bytes(), self.simulator)
+                .unwrap();
+        }
+    }
+
+    /// Waits for the physics state, None if it does not arrive or can not be parsed
+    pub fn receive_state(&self) -> Option<PhysicsState> {
+        let mut buf = [0; 1024];
+        let len = self.socket.recv(&mut buf).ok()?;
+        let message = std::str::from_utf8(&buf[..len]).ok()?;
+        serde_json::from_str(message.trim()).ok()
+    }
+}
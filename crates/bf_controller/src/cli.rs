@@ -0,0 +1,268 @@
+//! Applies Betaflight CLI scripts (the output of `diff`, `diff all` or `dump`) to a running instance
+//! through the CLI on its first UART, so the tunes of real quads can be flown as they are.
+//!
+//! Lines that only make sense on real hardware (pin mappings, board ids, serial port setup) are
+//! dropped, everything else is sent as it is. The CLI keeps going after a rejected command, the
+//! rejections are returned so they can be reported.
+
+use crate::msp::WsStream;
+use std::{
+    io::{self, Read, Write},
+    net::ToSocketAddrs,
+    time::{Duration, Instant},
+};
+
+const PROMPT: &[u8] = b"\r\n# ";
+
+// the hardware specific commands of a dump, the serial setup could also cut the CLI connection
+const SKIPPED_COMMANDS: [&str; 13] = [
+    "batch",
+    "board_name",
+    "manufacturer_id",
+    "mcu_id",
+    "signature",
+    "resource",
+    "timer",
+    "dma",
+    "serial",
+    "defaults",
+    "save",
+    "exit",
+    "bl",
+];
+
+/// The commands of a CLI script that should be sent to a simulated Betaflight
+pub fn parse_script(script: &str) -> Vec<String> {
+    script
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter(|line| {
+            let command = line.split_whitespace().next().unwrap_or_default();
+            !SKIPPED_COMMANDS.contains(&command)
+        })
+        .map(String::from)
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CliRejection {
+    pub command: String,
+    pub message: String,
+}
+
+/// An open CLI, Betaflight leaves MSP mode as soon as it reads a `#`
+#[derive(Debug)]
+pub struct CliSession<S: Read + Write = WsStream> {
+    stream: S,
+}
+
+impl<S: Read + Write> CliSession<S> {
+    pub fn enter(stream: S) -> io::Result<Self> {
+        let mut session = Self { stream };
+        session.stream.write_all(b"#")?;
+        session.read_until_prompt()?;
+        Ok(session)
+    }
+
+    fn read_until_prompt(&mut self) -> io::Result<String> {
+        let mut output = vec![];
+        let mut byte = [0];
+        while !output.ends_with(PROMPT) {
+            self.stream.read_exact(&mut byte)?;
+            output.push(byte[0]);
+        }
+        output.truncate(output.len() - PROMPT.len());
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    /// Runs a command and returns its output, without the echoed command
+    pub fn command(&mut self, command: &str) -> io::Result<String> {
+        self.stream.write_all(format!("{command}\n").as_bytes())?;
+        let output = self.read_until_prompt()?;
+        let output = output.trim_start_matches(command).trim();
+        Ok(output.to_string())
+    }
+
+    /// Writes the configuration to the eeprom, Betaflight resets itself afterwards so the session
+    /// ends here
+    pub fn save(mut self) -> io::Result<()> {
+        self.stream.write_all(b"save\n")?;
+        let mut output = vec![];
+        let mut chunk = [0; 256];
+        while !String::from_utf8_lossy(&output).contains("Rebooting") {
+            match self.stream.read(&mut chunk) {
+                // the connection closes with the reset
+                Ok(0) => break,
+                Ok(len) => output.extend(&chunk[..len]),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends the commands to the CLI and saves them, returns the commands Betaflight rejected
+pub fn run_commands<S: Read + Write>(
+    stream: S,
+    commands: &[String],
+) -> io::Result<Vec<CliRejection>> {
+    let mut session = CliSession::enter(stream)?;
+    let mut rejections = vec![];
+    for command in commands {
+        let output = session.command(command)?;
+        if let Some(error) = output.lines().find(|line| line.contains("###ERROR")) {
+            rejections.push(CliRejection {
+                command: command.clone(),
+                message: error.trim_matches('#').trim().to_string(),
+            });
+        }
+    }
+    session.save()?;
+    Ok(rejections)
+}
+
+/// Connects to the serial port of an instance, which might still be starting up, and applies the
+/// commands
+pub fn apply(
+    address: impl ToSocketAddrs + Copy,
+    commands: &[String],
+    timeout: Duration,
+) -> io::Result<Vec<CliRejection>> {
+    let start = Instant::now();
+    let stream = loop {
+        match WsStream::connect(address, timeout) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < timeout => {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(err) => return Err(err),
+        }
+    };
+    run_commands(stream, commands)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cli::{CliRejection, parse_script, run_commands};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    const DIFF: &str = "
+# version
+# Betaflight / STM32F405 (S405) 4.4.2 Jun  9 2023 / 02:53:44 (4a4f2d5) MSP API: 1.45
+
+# start the command batch
+batch start
+
+board_name MATEKF405
+manufacturer_id MTKS
+
+# resources
+resource BEEPER 1 C13
+
+# feature
+feature -AIRMODE
+
+# serial
+serial 1 64 115200 57600 0 115200
+
+# master
+set gyro_lpf2_static_hz = 375
+set motor_pwm_protocol = DSHOT600
+
+profile 0
+
+# profile 0
+set p_pitch = 52
+
+# end the command batch
+batch end
+
+# save configuration
+save
+";
+
+    #[test]
+    fn script() {
+        let commands = parse_script(DIFF);
+        assert_eq!(
+            commands,
+            [
+                "feature -AIRMODE",
+                "set gyro_lpf2_static_hz = 375",
+                "set motor_pwm_protocol = DSHOT600",
+                "profile 0",
+                "set p_pitch = 52",
+            ]
+        );
+    }
+
+    // answers like the Betaflight CLI, set only knows p_pitch
+    fn fake_cli(listener: TcpListener) -> Vec<String> {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut hash = [0];
+        stream.read_exact(&mut hash).unwrap();
+        assert_eq!(&hash, b"#");
+        stream
+            .write_all(b"\r\nEntering CLI Mode, type 'exit' to return, or 'help'\r\n\r\n# ")
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut received = vec![];
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 {
+            let command = line.trim().to_string();
+            line.clear();
+            // the CLI echoes what it reads
+            stream.write_all(command.as_bytes()).unwrap();
+            if command == "save" {
+                stream.write_all(b"\r\nSaving\r\nRebooting\r\n").unwrap();
+                received.push(command);
+                break;
+            }
+            if command.starts_with("set") && !command.contains("p_pitch") {
+                stream
+                    .write_all(b"\r\n###ERROR IN set: INVALID NAME###")
+                    .unwrap();
+            }
+            stream.write_all(b"\r\n# ").unwrap();
+            received.push(command);
+        }
+        received
+    }
+
+    #[test]
+    fn apply_to_fake_cli() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let cli = thread::spawn(move || fake_cli(listener));
+
+        let stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        let commands = parse_script(DIFF);
+        let rejections = run_commands(stream, &commands).unwrap();
+        assert_eq!(
+            rejections,
+            [
+                CliRejection {
+                    command: "set gyro_lpf2_static_hz = 375".into(),
+                    message: "ERROR IN set: INVALID NAME".into(),
+                },
+                CliRejection {
+                    command: "set motor_pwm_protocol = DSHOT600".into(),
+                    message: "ERROR IN set: INVALID NAME".into(),
+                },
+            ]
+        );
+        let received = cli.join().unwrap();
+        assert_eq!(received.len(), commands.len() + 1);
+        assert_eq!(received.last().unwrap(), "save");
+    }
+}
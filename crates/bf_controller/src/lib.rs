@@ -1,29 +1,98 @@
-use core::panic;
-use flight_controller::{FlightController, FlightControllerUpdate, MotorInput};
+pub mod cli;
+pub mod msp;
+pub mod sitl;
+
+use cli::CliRejection;
+use flight_controller::{
+    ChannelMap, FlightController, FlightControllerUpdate, GyroUpdate, InitError, MotorInput,
+};
 use libc::{LM_ID_NEWLM, Lmid_t, RTLD_DI_LMID, dlclose, dlerror, dlinfo, dlmopen, dlsym};
 use once_cell::sync::Lazy;
 use std::{
-    collections::{HashMap, hash_map::Entry},
+    collections::{BTreeSet, HashMap},
     ffi::{CStr, CString},
+    fmt, fs,
     io::Write,
+    net::{Ipv4Addr, SocketAddr, TcpStream},
     os::raw::{self, c_void},
-    sync::{Arc, Mutex},
-    time::Duration,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use tempfile::NamedTempFile;
 use uuid::Uuid;
 
 const RTLD_FLAGS: i32 = 0x0002; // Resolves all symbols and do not use them for further resolutions
 
-// const LIB_PATH: &str =
-//     "/home/gabor/ascent/virtual-betaflight-dynlib/src/libvirtual-betaflight.so\0";
+/// Every instance is loaded into its own link-map namespace so the globals of betaflight are not
+/// shared. glibc has 16 namespaces and the program itself uses the first one, so at most this many
+/// instances can be alive at the same time.
+pub const MAX_INSTANCES: usize = 15;
+
+// how long the serial port of a new instance gets to come up and to apply the cli script
+const CLI_TIMEOUT: Duration = Duration::from_secs(5);
+// the time betaflight runs for on every update while the cli script is applied
+const CLI_STEP: Duration = Duration::from_millis(1);
+
+// the library and the eeprom are looked up next to the rest of the data by default, without a
+// home directory loading the library fails instead
+fn data_path() -> PathBuf {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    home.unwrap_or_default().join(".local/share/quad")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BFConfig {
+    // the virtual betaflight dynamic library
+    pub library: PathBuf,
+    // copied for every instance, betaflight starts with its defaults without one
+    pub eeprom: Option<PathBuf>,
+    // a cli diff or dump applied on top of the eeprom at init
+    pub cli: Option<PathBuf>,
+}
+
+impl Default for BFConfig {
+    fn default() -> Self {
+        Self {
+            library: data_path().join("libvirtual_betaflight.so"),
+            eeprom: Some(data_path().join("eeprom.bin")),
+            cli: None,
+        }
+    }
+}
 
-const LIB_PATH: &str = "/home/gabor/projects/quad/libvirtual_betaflight.so\0";
+impl BFConfig {
+    pub fn with_library(self, library: impl Into<PathBuf>) -> Self {
+        Self {
+            library: library.into(),
+            ..self
+        }
+    }
+
+    pub fn with_eeprom(self, eeprom: Option<PathBuf>) -> Self {
+        Self { eeprom, ..self }
+    }
+
+    pub fn with_cli(self, cli: impl Into<PathBuf>) -> Self {
+        Self {
+            cli: Some(cli.into()),
+            ..self
+        }
+    }
+}
 
 type VBFInit = unsafe extern "C" fn(file_name: *const std::os::raw::c_char);
 type VBFUpdate = unsafe extern "C" fn(time_passed: f64);
 type VBFArm = unsafe extern "C" fn();
+type VBFDisarm = unsafe extern "C" fn();
+type VBFGetIsArmed = unsafe extern "C" fn() -> bool;
+type VBFGetArmingDisableFlags = unsafe extern "C" fn() -> u32;
 type VBFStartSerialWsThread = unsafe extern "C" fn();
+type VBFStopSerialWsThread = unsafe extern "C" fn();
 type VBFGetMotorSignals = unsafe extern "C" fn(*mut f64);
 type VBFSetRcData = unsafe extern "C" fn(*const f64);
 type VBFSetGyroData = unsafe extern "C" fn(*const f64);
@@ -39,6 +108,9 @@ pub struct VirtualBF {
     pub vbf_init: VBFInit,
     pub vbf_update: VBFUpdate,
     pub vbf_arm: VBFArm,
+    pub vbf_disarm: VBFDisarm,
+    pub vbf_get_is_armed: VBFGetIsArmed,
+    pub vbf_get_arming_disable_flags: VBFGetArmingDisableFlags,
     pub vbf_get_motor_signals: VBFGetMotorSignals,
     pub vbf_set_rc_data: VBFSetRcData,
     pub vbf_set_gyro_data: VBFSetGyroData,
@@ -46,32 +118,70 @@ pub struct VirtualBF {
     pub vbf_set_attitude: VBFSetAttitude,
     pub vbf_set_battery_data: VBFSetBattery,
     pub vbf_start_serial_ws_thread: VBFStartSerialWsThread,
+    pub vbf_stop_serial_ws_thread: VBFStopSerialWsThread,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BFError {
+    // dlmopen failed, the library is missing or glibc ran out of namespaces
+    Load(String),
+    // the library is not a virtual betaflight
+    Symbol(String),
+    // MAX_INSTANCES instances are alive
+    PoolExhausted,
+    UnknownInstance(String),
+    // a call into the instance panicked, its state can not be trusted anymore
+    Crashed(String),
+    // a call into the instance has not returned in time
+    Hung(String),
+    // the instance is not initialized or could not open its serial port, the port is fixed in
+    // the library so only one instance of a process can have one
+    NoSerialPort(String),
+    // the cli script could not be read or sent to the instance
+    Cli(String),
+}
+
+impl fmt::Display for BFError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BFError::Load(err) => write!(f, "could not load the library: {err}"),
+            BFError::Symbol(err) => write!(f, "missing symbol: {err}"),
+            BFError::PoolExhausted => write!(f, "all {MAX_INSTANCES} instances are in use"),
+            BFError::UnknownInstance(id) => write!(f, "no instance {id}"),
+            BFError::Crashed(id) => write!(f, "instance {id} crashed"),
+            BFError::Hung(id) => write!(f, "instance {id} does not respond"),
+            BFError::NoSerialPort(id) => write!(f, "instance {id} has no serial port"),
+            BFError::Cli(err) => write!(f, "could not apply the cli script: {err}"),
+        }
+    }
 }
 
-unsafe fn check_dl_error(dl_sym: *mut raw::c_void) -> Result<(), CString> {
+impl std::error::Error for BFError {}
+
+unsafe fn check_dl_error(dl_sym: *mut raw::c_void) -> Result<(), String> {
     if dl_sym.is_null() {
-        let dlerror_str = dlerror();
+        let dlerror_str = unsafe { dlerror() };
         if dlerror_str.is_null() {
-            panic!("Unknown error when loading a dl");
-        } else {
-            let error_str = CStr::from_ptr(dlerror_str);
-            return Err(error_str.into());
+            return Err("unknown error".into());
         }
+        let error_str = unsafe { CStr::from_ptr(dlerror_str) };
+        return Err(error_str.to_string_lossy().into_owned());
     }
 
     Ok(())
 }
-unsafe fn get_lm_id(dl_handle: *mut raw::c_void) -> i64 {
+
+unsafe fn get_lm_id(dl_handle: *mut raw::c_void) -> Result<i64, String> {
     let mut lmid: Lmid_t = 0;
-    let result = dlinfo(dl_handle, RTLD_DI_LMID, &mut lmid as *mut _ as *mut c_void);
+    let result = unsafe { dlinfo(dl_handle, RTLD_DI_LMID, &mut lmid as *mut _ as *mut c_void) };
     if result != 0 {
-        panic!("Could not get dl info");
+        return Err("could not get the namespace of the library".into());
     }
-    lmid as Lmid_t
+    Ok(lmid as Lmid_t)
 }
 
 impl VirtualBF {
-    unsafe fn new(lib_handle: *mut c_void, lmid: i64) -> Result<Self, CString> {
+    unsafe fn new(lib_handle: *mut c_void, lmid: i64) -> Result<Self, String> {
         macro_rules! get_vb_method {
             ($fn:ident, $fn_type:ty) => {
                 let function_name = format!("{}\0", stringify!($fn));
@@ -84,6 +194,9 @@ impl VirtualBF {
         get_vb_method!(vbf_init, VBFInit);
         get_vb_method!(vbf_update, VBFUpdate);
         get_vb_method!(vbf_arm, VBFArm);
+        get_vb_method!(vbf_disarm, VBFDisarm);
+        get_vb_method!(vbf_get_is_armed, VBFGetIsArmed);
+        get_vb_method!(vbf_get_arming_disable_flags, VBFGetArmingDisableFlags);
         get_vb_method!(vbf_get_motor_signals, VBFGetMotorSignals);
         get_vb_method!(vbf_set_rc_data, VBFSetRcData);
         get_vb_method!(vbf_set_gyro_data, VBFSetGyroData);
@@ -91,12 +204,16 @@ impl VirtualBF {
         get_vb_method!(vbf_set_attitude, VBFSetAttitude);
         get_vb_method!(vbf_set_battery_data, VBFSetBattery);
         get_vb_method!(vbf_start_serial_ws_thread, VBFStartSerialWsThread);
+        get_vb_method!(vbf_stop_serial_ws_thread, VBFStopSerialWsThread);
 
         Ok(Self {
             lib_handle,
             lmid,
             vbf_init,
             vbf_arm,
+            vbf_disarm,
+            vbf_get_is_armed,
+            vbf_get_arming_disable_flags,
             vbf_get_motor_signals,
             vbf_set_accel_data,
             vbf_set_attitude,
@@ -105,6 +222,7 @@ impl VirtualBF {
             vbf_set_gyro_data,
             vbf_set_battery_data,
             vbf_start_serial_ws_thread,
+            vbf_stop_serial_ws_thread,
         })
     }
 }
@@ -114,6 +232,8 @@ impl Drop for VirtualBF {
         let code = unsafe { dlclose(self.lib_handle) };
         if code == 0 {
             log::info!("Vbf instance with handle {:?} dropped", self.lib_handle);
+        } else {
+            log::warn!("Could not close vbf handle {:?}", self.lib_handle);
         }
     }
 }
@@ -121,10 +241,24 @@ impl Drop for VirtualBF {
 unsafe impl Send for VirtualBF {}
 unsafe impl Sync for VirtualBF {}
 
+// a loaded library and the bookkeeping for the health check
+#[derive(Debug)]
+struct Instance {
+    virtual_bf: Mutex<VirtualBF>,
+    // when the call in progress started
+    busy_since: Mutex<Option<Instant>>,
+}
+
+/// Keeps the loaded instances, each one has its own lock so N controllers can run on N threads. A
+/// segfault in the library takes the whole process down, panics and hangs are caught by the health
+/// check.
 #[derive(Debug)]
 pub struct BFManager {
-    instances: Mutex<HashMap<String, Arc<VirtualBF>>>,
+    instances: Mutex<HashMap<String, Arc<Instance>>>,
+    // the namespaces of closed instances, reused before new ones are created
     available_workspace_ids: Mutex<Vec<i64>>,
+    // held while an instance is initialized, so the serial port that shows up is its own
+    opening_serial_port: Mutex<()>,
 }
 
 impl BFManager {
@@ -132,73 +266,146 @@ impl BFManager {
         Self {
             instances: Mutex::new(HashMap::new()),
             available_workspace_ids: Mutex::new(vec![]),
+            opening_serial_port: Mutex::new(()),
         }
     }
 
-    unsafe fn load_library(&self) -> (*mut c_void, i64) {
-        let mut available_workspace_ids = self.available_workspace_ids.lock().unwrap();
-        let lib_handle = if let Some(workspace_id) = available_workspace_ids.pop() {
-            dlmopen(workspace_id, LIB_PATH.as_ptr().cast(), RTLD_FLAGS)
-        } else {
-            dlmopen(LM_ID_NEWLM, LIB_PATH.as_ptr().cast(), RTLD_FLAGS)
-        };
-        check_dl_error(lib_handle).unwrap();
-
-        let lmid = get_lm_id(lib_handle);
-        (lib_handle, lmid)
+    unsafe fn open(&self, library: &CStr) -> Result<*mut c_void, BFError> {
+        let workspace_id = self.available_workspace_ids.lock().unwrap().pop();
+        if let Some(workspace_id) = workspace_id {
+            let lib_handle = unsafe { dlmopen(workspace_id, library.as_ptr(), RTLD_FLAGS) };
+            if unsafe { check_dl_error(lib_handle) }.is_ok() {
+                return Ok(lib_handle);
+            }
+            // glibc drops namespaces once everything in them is unloaded
+            log::info!("Namespace {workspace_id} can not be reused");
+        }
+        let lib_handle = unsafe { dlmopen(LM_ID_NEWLM, library.as_ptr(), RTLD_FLAGS) };
+        unsafe { check_dl_error(lib_handle) }.map_err(BFError::Load)?;
+        Ok(lib_handle)
     }
 
-    unsafe fn register_new2(&self) -> String {
-        let (lib_handle, lmid) = self.load_library();
-
-        let new_id = Uuid::new_v4().to_string();
-        let mut guard = self.instances.lock().unwrap();
-        let entry = guard.entry(new_id.clone());
-
-        match entry {
-            Entry::Vacant(vacant) => {
-                let controller = unsafe { VirtualBF::new(lib_handle, lmid).unwrap() };
-                vacant.insert(Arc::new(controller));
-            }
-            Entry::Occupied(_) => {
-                todo!("Hanle collisions");
+    unsafe fn load_library(&self, library: &Path) -> Result<VirtualBF, BFError> {
+        let library = library
+            .to_str()
+            .and_then(|library| CString::new(library).ok())
+            .ok_or_else(|| BFError::Load(format!("invalid path {library:?}")))?;
+        let lib_handle = unsafe { self.open(&library) }?;
+        let lmid = match unsafe { get_lm_id(lib_handle) } {
+            Ok(lmid) => lmid,
+            Err(err) => {
+                unsafe { dlclose(lib_handle) };
+                return Err(BFError::Load(err));
             }
+        };
+        // closed again if it is not a virtual betaflight
+        unsafe { VirtualBF::new(lib_handle, lmid) }.map_err(|err| {
+            self.available_workspace_ids.lock().unwrap().push(lmid);
+            unsafe { dlclose(lib_handle) };
+            BFError::Symbol(err)
+        })
+    }
+
+    unsafe fn register(&self, library: &Path) -> Result<String, BFError> {
+        // held while loading, so the pool can not grow past its size
+        let mut instances = self.instances.lock().unwrap();
+        if instances.len() >= MAX_INSTANCES {
+            return Err(BFError::PoolExhausted);
         }
-        new_id
+        let virtual_bf = unsafe { self.load_library(library) }?;
+        let new_id = loop {
+            let new_id = Uuid::new_v4().to_string();
+            if !instances.contains_key(&new_id) {
+                break new_id;
+            }
+        };
+        let instance = Instance {
+            virtual_bf: Mutex::new(virtual_bf),
+            busy_since: Mutex::new(None),
+        };
+        instances.insert(new_id.clone(), Arc::new(instance));
+        Ok(new_id)
+    }
+
+    fn instance(&self, instance_id: &str) -> Result<Arc<Instance>, BFError> {
+        let instances = self.instances.lock().unwrap();
+        instances
+            .get(instance_id)
+            .cloned()
+            .ok_or_else(|| BFError::UnknownInstance(instance_id.to_string()))
     }
 
-    pub fn access<F, R>(&self, instance_id: &str, f: F) -> R
+    /// Runs `f` on the instance, waits if another thread is using the same instance
+    pub fn access<F, R>(&self, instance_id: &str, f: F) -> Result<R, BFError>
     where
         F: FnOnce(&VirtualBF) -> R,
     {
-        // What we actually want is to prevent access only to the locked instance
-        let instance;
-        {
-            let guard = self.instances.lock().unwrap();
-            instance = guard.get(instance_id).unwrap().clone();
+        let instance = self.instance(instance_id)?;
+        let virtual_bf = instance
+            .virtual_bf
+            .lock()
+            .map_err(|_| BFError::Crashed(instance_id.to_string()))?;
+        *instance.busy_since.lock().unwrap() = Some(Instant::now());
+        let result = f(&virtual_bf);
+        *instance.busy_since.lock().unwrap() = None;
+        Ok(result)
+    }
+
+    /// Crashed if a call into the instance panicked, Hung if a call has been running for longer
+    /// than the timeout
+    pub fn check_health(&self, instance_id: &str, timeout: Duration) -> Result<(), BFError> {
+        let instance = self.instance(instance_id)?;
+        if instance.virtual_bf.is_poisoned() {
+            return Err(BFError::Crashed(instance_id.to_string()));
         }
-        if Arc::strong_count(&instance) > 2 {
-            panic!("Tried to access the same library multiple times");
+        match *instance.busy_since.lock().unwrap() {
+            Some(busy_since) if busy_since.elapsed() > timeout => {
+                Err(BFError::Hung(instance_id.to_string()))
+            }
+            _ => Ok(()),
         }
-        f(&instance)
+    }
+
+    /// The number of instances alive, at most `MAX_INSTANCES`
+    pub fn instance_count(&self) -> usize {
+        self.instances.lock().unwrap().len()
     }
 
     fn close(&self, instance_id: &str) {
-        let mut instances_guard = self.instances.lock().unwrap();
-        let mut workspace_guard = self.available_workspace_ids.lock().unwrap();
-        let vbf = instances_guard.remove(instance_id).unwrap();
-        workspace_guard.push(vbf.lmid);
+        let Some(instance) = self.instances.lock().unwrap().remove(instance_id) else {
+            return;
+        };
+        // a call in progress still holds the instance, its namespace is not reused then
+        let Ok(instance) = Arc::try_unwrap(instance) else {
+            log::warn!("Instance {instance_id} closed while in use");
+            return;
+        };
+        let virtual_bf = instance
+            .virtual_bf
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner);
+        let lmid = virtual_bf.lmid;
+        drop(virtual_bf);
+        self.available_workspace_ids.lock().unwrap().push(lmid);
     }
 
     // only static managers can register new controllers
-    pub fn request_new_controller(&'static self) -> BFController {
-        let instance_id = unsafe { self.register_new2() };
+    pub fn request_new_controller(&'static self) -> Result<BFController, BFError> {
+        self.request_controller(BFConfig::default())
+    }
+
+    pub fn request_controller(&'static self, config: BFConfig) -> Result<BFController, BFError> {
+        let instance_id = unsafe { self.register(&config.library) }?;
         let scheduler_delta = Duration::from_micros(50);
-        BFController {
+        Ok(BFController {
             manager: self,
             instance_id,
             scheduler_delta,
-        }
+            channel_map: ChannelMap::default(),
+            config,
+            eeprom: Mutex::new(None),
+            serial_port: Mutex::new(None),
+        })
     }
 }
 
@@ -208,46 +415,200 @@ pub static VIRTUAL_BF_MANAGER: Lazy<BFManager> = Lazy::new(|| BFManager::new());
 pub struct BFController {
     pub instance_id: String,
     pub scheduler_delta: Duration,
+    // how the channels are handed to betaflight, the aux roles should match the eeprom modes
+    pub channel_map: ChannelMap,
+    pub config: BFConfig,
     manager: &'static BFManager,
+    // the instance reads and saves its configuration here
+    eeprom: Mutex<Option<NamedTempFile>>,
+    // the port the instance serves its first UART on, known after init
+    serial_port: Mutex<Option<u16>>,
 }
 
-impl BFController {}
+impl BFController {
+    pub fn new(config: BFConfig) -> Result<Self, BFError> {
+        VIRTUAL_BF_MANAGER.request_controller(config)
+    }
 
-impl Default for BFController {
-    fn default() -> Self {
-        // default manager
-        VIRTUAL_BF_MANAGER.request_new_controller()
+    pub fn with_channel_map(mut self, channel_map: ChannelMap) -> Self {
+        self.channel_map = channel_map;
+        self
+    }
+
+    pub fn is_armed(&self) -> Result<bool, BFError> {
+        self.manager.access(&self.instance_id, |virtual_bf| unsafe {
+            (virtual_bf.vbf_get_is_armed)()
+        })
+    }
+
+    /// The raw betaflight arming disable flags, useful to figure out why the arm switch is ignored
+    pub fn arming_disable_flags(&self) -> Result<u32, BFError> {
+        self.manager.access(&self.instance_id, |virtual_bf| unsafe {
+            (virtual_bf.vbf_get_arming_disable_flags)()
+        })
+    }
+
+    /// The WebSocket server of the first UART, for `msp::MspClient` and `cli::apply`. The
+    /// simulation has to keep running, betaflight answers from its scheduler.
+    pub fn serial_address(&self) -> Result<SocketAddr, BFError> {
+        let port = self.serial_port.lock().unwrap();
+        let port = port.ok_or_else(|| BFError::NoSerialPort(self.instance_id.clone()))?;
+        Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))
+    }
+
+    /// Meant for a watchdog thread, see `BFManager::check_health`
+    pub fn check_health(&self, timeout: Duration) -> Result<(), BFError> {
+        self.manager.check_health(&self.instance_id, timeout)
+    }
+
+    // betaflight only answers on the serial port from its scheduler, so it keeps running level
+    // and disarmed until the cli script is saved
+    fn run_cli(&self, cli: &Path) -> Result<Vec<CliRejection>, BFError> {
+        let serial_address = self.serial_address()?;
+        let level = FlightControllerUpdate {
+            gyro_update: GyroUpdate {
+                rotation: [0., 0., 0., 1.],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let applying = AtomicBool::new(true);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while applying.load(Ordering::Relaxed) {
+                    self.update(CLI_STEP.as_secs_f64(), level);
+                    thread::sleep(CLI_STEP / 2);
+                }
+            });
+            let rejections = apply_cli(cli, serial_address);
+            applying.store(false, Ordering::Relaxed);
+            rejections
+        })
     }
 }
 
 impl Drop for BFController {
     fn drop(&mut self) {
+        // the library stays mapped after it is closed, a running serial thread would abort the
+        // process when it exits
+        if let Ok(address) = self.serial_address() {
+            let stopped = AtomicBool::new(false);
+            thread::scope(|scope| {
+                // the serial thread waits for the next event, a connection wakes it up
+                scope.spawn(|| {
+                    while !stopped.load(Ordering::Relaxed) {
+                        drop(TcpStream::connect(address));
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                });
+                let _ = self.manager.access(&self.instance_id, |virtual_bf| unsafe {
+                    (virtual_bf.vbf_stop_serial_ws_thread)();
+                });
+                stopped.store(true, Ordering::Relaxed);
+            });
+        }
         self.manager.close(&self.instance_id);
     }
 }
 
-fn tmp_eeprom() -> NamedTempFile {
-    let mut temp_eeprom = NamedTempFile::new().expect("Could not create named temp file");
-    let eeprom = std::fs::read("/home/gabor/projects/quad/eeprom.bin").expect("No file");
-    temp_eeprom
-        .write(&eeprom)
-        .expect("Could not write temp file");
-    temp_eeprom
+// the ports of the tcp sockets this process listens on
+fn listening_ports() -> BTreeSet<u16> {
+    let sockets: BTreeSet<_> = fs::read_dir("/proc/self/fd")
+        .into_iter()
+        .flatten()
+        .filter_map(|fd| fs::read_link(fd.ok()?.path()).ok())
+        .filter_map(|link| {
+            let inode = link.to_str()?.strip_prefix("socket:[")?.strip_suffix(']')?;
+            Some(inode.to_string())
+        })
+        .collect();
+    let tables = ["/proc/self/net/tcp", "/proc/self/net/tcp6"];
+    tables
+        .iter()
+        .filter_map(|table| fs::read_to_string(table).ok())
+        .flat_map(|table| {
+            table
+                .lines()
+                .skip(1)
+                .filter_map(|line| {
+                    let fields: Vec<_> = line.split_whitespace().collect();
+                    // 0A is LISTEN, the socket has to be one of ours
+                    if fields.get(3) != Some(&"0A") || !sockets.contains(*fields.get(9)?) {
+                        return None;
+                    }
+                    let (_, port) = fields[1].rsplit_once(':')?;
+                    u16::from_str_radix(port, 16).ok()
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+fn tmp_eeprom(eeprom: Option<&Path>) -> std::io::Result<NamedTempFile> {
+    let mut temp_eeprom = NamedTempFile::new()?;
+    if let Some(eeprom) = eeprom {
+        temp_eeprom.write_all(&std::fs::read(eeprom)?)?;
+    }
+    Ok(temp_eeprom)
+}
+
+// sends the cli script to the running instance, which saves it to its eeprom
+fn apply_cli(cli: &Path, serial_address: SocketAddr) -> Result<Vec<CliRejection>, BFError> {
+    let script = std::fs::read_to_string(cli)
+        .map_err(|err| BFError::Cli(format!("could not read {cli:?}: {err}")))?;
+    let commands = cli::parse_script(&script);
+    cli::apply(serial_address, &commands, CLI_TIMEOUT)
+        .map_err(|err| BFError::Cli(format!("{cli:?}: {err}")))
 }
 
 impl FlightController for BFController {
-    fn init(&self) {
-        VIRTUAL_BF_MANAGER.access(&self.instance_id, |virtual_bf| unsafe {
-            let tmp_eeprom = tmp_eeprom();
-            let c_path = std::ffi::CString::new(tmp_eeprom.path().to_str().unwrap()).unwrap();
+    fn init(&self) -> Result<(), InitError> {
+        let eeprom = self.config.eeprom.as_deref();
+        let tmp_eeprom = tmp_eeprom(eeprom)
+            .map_err(|err| format!("could not copy the eeprom {eeprom:?}: {err}"))?;
+        let c_path = tmp_eeprom
+            .path()
+            .to_str()
+            .and_then(|path| CString::new(path).ok())
+            .ok_or_else(|| format!("invalid eeprom path {:?}", tmp_eeprom.path()))?;
+        // the library opens the serial port on the first init, the port is fixed in the library
+        // and could be taken, so the new socket is looked up
+        let opening_serial_port = self.manager.opening_serial_port.lock().unwrap();
+        let ports = listening_ports();
+        let started = self.manager.access(&self.instance_id, |virtual_bf| unsafe {
             (virtual_bf.vbf_init)(c_path.as_ptr());
+            // serves MSP and the cli on the first UART, see `msp` and `cli`
             (virtual_bf.vbf_start_serial_ws_thread)();
-            (virtual_bf.vbf_arm)();
+            // no force arming, the arm switch on the aux channels needs to be flipped
         });
+        let serial_port = listening_ports().difference(&ports).next().copied();
+        drop(opening_serial_port);
+        started?;
+        // a restarted instance keeps its port
+        if serial_port.is_some() {
+            *self.serial_port.lock().unwrap() = serial_port;
+        }
+        if let Some(cli) = &self.config.cli {
+            // without the cli the instance would fly with a different configuration
+            for rejection in self.run_cli(cli)? {
+                log::warn!(
+                    "Betaflight rejected `{}`: {}",
+                    rejection.command,
+                    rejection.message
+                );
+            }
+            // the saved configuration only takes effect after a restart
+            self.manager
+                .access(&self.instance_id, |virtual_bf| unsafe {
+                    (virtual_bf.vbf_init)(c_path.as_ptr());
+                })?;
+        }
+        *self.eeprom.lock().unwrap() = Some(tmp_eeprom);
+        Ok(())
     }
 
     fn update(&self, delta_time: f64, update: FlightControllerUpdate) -> crate::MotorInput {
-        VIRTUAL_BF_MANAGER.access(&self.instance_id, |virtual_bf| unsafe {
+        let motor_input = self.manager.access(&self.instance_id, |virtual_bf| unsafe {
             (virtual_bf.vbf_set_battery_data)(
                 update.battery_update.cell_count,
                 update.battery_update.bat_voltage,
@@ -263,15 +624,22 @@ impl FlightController for BFController {
             let gyro_update = update.gyro_update.angular_velocity;
             (virtual_bf.vbf_set_gyro_data)(gyro_update.as_ptr());
 
-            let rc_data = update.channels.to_bf_channels();
+            // NOTE: the virtual betaflight only reads the first 8 channels
+            let rc_data = update.channels.to_bf_channels_with_map(&self.channel_map);
             (virtual_bf.vbf_set_rc_data)(rc_data.as_ptr());
             (virtual_bf.vbf_update)(delta_time);
 
+            // Betaflight motor order, the drone's motor map decides which rotor each one drives
             let mut motors_signal = [0.; 4];
             (virtual_bf.vbf_get_motor_signals)(motors_signal.as_mut_ptr());
             MotorInput {
                 input: motors_signal.map(|x| x as f64),
             }
+        });
+        motor_input.unwrap_or_else(|err| {
+            // the motors stop, the health check tells why
+            log::warn!("Virtual betaflight update failed: {err}");
+            MotorInput::default()
         })
     }
 
@@ -279,3 +647,109 @@ impl FlightController for BFController {
         self.scheduler_delta
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        BFConfig, BFController, BFError, VIRTUAL_BF_MANAGER, apply_cli,
+        msp::{MspClient, MspError, PID_PITCH},
+        tmp_eeprom,
+    };
+    use flight_controller::{FlightController, FlightControllerUpdate, GyroUpdate};
+    use std::{
+        io::Write,
+        path::Path,
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+    use tempfile::NamedTempFile;
+
+    // betaflight answers from its scheduler, so it has to keep running while `f` talks to it
+    fn running<R>(controller: &BFController, f: impl FnOnce() -> R) -> R {
+        let update = FlightControllerUpdate {
+            gyro_update: GyroUpdate {
+                rotation: [0., 0., 0., 1.],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let running = AtomicBool::new(true);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while running.load(Ordering::Relaxed) {
+                    controller.update(0.001, update);
+                    thread::sleep(Duration::from_micros(500));
+                }
+            });
+            let result = f();
+            running.store(false, Ordering::Relaxed);
+            result
+        })
+    }
+
+    #[test]
+    fn load_errors() {
+        let missing = BFConfig::default().with_library("/nonexistent/libvirtual_betaflight.so");
+        assert!(matches!(
+            VIRTUAL_BF_MANAGER.request_controller(missing),
+            Err(BFError::Load(_))
+        ));
+        // loads, but it is not a virtual betaflight
+        let libm = BFConfig::default().with_library("libm.so.6");
+        match VIRTUAL_BF_MANAGER.request_controller(libm) {
+            Err(BFError::Symbol(err)) => assert!(err.contains("vbf_init")),
+            other => panic!("expected a missing symbol, got {other:?}"),
+        }
+        assert_eq!(VIRTUAL_BF_MANAGER.instance_count(), 0);
+    }
+
+    #[test]
+    fn missing_files() {
+        assert!(tmp_eeprom(Some(Path::new("/nonexistent/eeprom.bin"))).is_err());
+        assert!(tmp_eeprom(None).is_ok());
+        let address = "127.0.0.1:1".parse().unwrap();
+        assert!(matches!(
+            apply_cli(Path::new("/nonexistent/diff.txt"), address),
+            Err(BFError::Cli(_))
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs libvirtual_betaflight.so and eeprom.bin in the data path"]
+    fn msp_on_the_serial_port() {
+        let controller = BFController::new(BFConfig::default()).unwrap();
+        assert!(matches!(
+            controller.serial_address(),
+            Err(BFError::NoSerialPort(_))
+        ));
+        controller.init().unwrap();
+        let address = controller.serial_address().unwrap();
+        let configured = running(&controller, || {
+            let mut client = MspClient::connect(address, Duration::from_secs(5))?;
+            let version = client.api_version()?;
+            let mut pids = client.pids()?;
+            pids[PID_PITCH].p += 1;
+            client.set_pids(&pids)?;
+            Ok::<_, MspError>((version, pids, client.pids()?))
+        });
+        let (version, pids, configured_pids) = configured.unwrap();
+        assert_eq!(version.major, 1);
+        assert_eq!(configured_pids, pids);
+    }
+
+    #[test]
+    #[ignore = "needs libvirtual_betaflight.so and eeprom.bin in the data path"]
+    fn cli_diff_at_init() {
+        let mut diff = NamedTempFile::new().unwrap();
+        writeln!(diff, "# master\nprofile 0\nset p_pitch = 52\nsave").unwrap();
+        let config = BFConfig::default().with_cli(diff.path());
+        let controller = BFController::new(config).unwrap();
+        controller.init().unwrap();
+        let address = controller.serial_address().unwrap();
+        let pids = running(&controller, || {
+            MspClient::connect(address, Duration::from_secs(5))?.pids()
+        });
+        assert_eq!(pids.unwrap()[PID_PITCH].p, 52);
+    }
+}
@@ -0,0 +1,822 @@
+//! Client for the MSP (MultiWii Serial Protocol) the Betaflight Configurator talks. The serial
+//! thread started by `BFController::init` serves the first UART of the virtual Betaflight as a
+//! WebSocket server, the bytes of the UART travel in binary messages. The port is the one the
+//! instance opened, see `BFController::serial_address`. PIDs, rates and filters can be read and
+//! changed while the simulation runs.
+//!
+//! Only MSP v1 frames are used, all the commands below fit in a single byte. The payload layouts
+//! are the ones of Betaflight 4.1 and later (MSP API 1.43), older versions are reported as
+//! malformed responses.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+pub const MSP_API_VERSION: u8 = 1;
+pub const MSP_FILTER_CONFIG: u8 = 92;
+pub const MSP_STATUS: u8 = 101;
+pub const MSP_MOTOR: u8 = 104;
+pub const MSP_ATTITUDE: u8 = 108;
+pub const MSP_RC_TUNING: u8 = 111;
+pub const MSP_PID: u8 = 112;
+pub const MSP_SET_FILTER_CONFIG: u8 = 93;
+pub const MSP_SET_PID: u8 = 202;
+pub const MSP_SET_RC_TUNING: u8 = 204;
+pub const MSP_EEPROM_WRITE: u8 = 250;
+
+// the rows of the PID table
+pub const PID_ROLL: usize = 0;
+pub const PID_PITCH: usize = 1;
+pub const PID_YAW: usize = 2;
+pub const PID_LEVEL: usize = 3;
+pub const PID_MAG: usize = 4;
+
+#[derive(Debug)]
+pub enum MspError {
+    Io(io::Error),
+    Checksum,
+    // the flight controller answered with an error frame, the command is unknown or was rejected
+    Rejected(u8),
+    // the payload is shorter than expected for the command
+    Malformed(u8),
+}
+
+impl fmt::Display for MspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MspError::Io(err) => write!(f, "io error: {err}"),
+            MspError::Checksum => write!(f, "checksum mismatch"),
+            MspError::Rejected(command) => write!(f, "command {command} was rejected"),
+            MspError::Malformed(command) => write!(f, "malformed response to command {command}"),
+        }
+    }
+}
+
+impl std::error::Error for MspError {}
+
+impl From<io::Error> for MspError {
+    fn from(err: io::Error) -> Self {
+        MspError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToFlightController,   // <
+    FromFlightController, // >
+    Error,                // !
+}
+
+impl Direction {
+    fn byte(self) -> u8 {
+        match self {
+            Direction::ToFlightController => b'<',
+            Direction::FromFlightController => b'>',
+            Direction::Error => b'!',
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MspFrame {
+    pub direction: Direction,
+    pub command: u8,
+    pub payload: Vec<u8>,
+}
+
+impl MspFrame {
+    pub fn new(direction: Direction, command: u8, payload: Vec<u8>) -> Self {
+        Self {
+            direction,
+            command,
+            payload,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        assert!(
+            self.payload.len() < 256,
+            "MSP v1 payloads are at most 255 bytes"
+        );
+        let mut bytes = vec![b'$', b'M', self.direction.byte(), self.payload.len() as u8];
+        bytes.push(self.command);
+        bytes.extend(&self.payload);
+        bytes.push(checksum(&bytes[3..]));
+        bytes
+    }
+
+    /// Reads the next frame, bytes before the `$M` header are skipped
+    pub fn read(reader: &mut impl BufRead) -> Result<Self, MspError> {
+        let mut header = [0; 2];
+        loop {
+            reader.read_exact(&mut header[1..])?;
+            if header == *b"$M" {
+                break;
+            }
+            header[0] = header[1];
+        }
+        let mut bytes = [0; 3];
+        reader.read_exact(&mut bytes)?;
+        let [direction, len, command] = bytes;
+        let direction = match direction {
+            b'<' => Direction::ToFlightController,
+            b'>' => Direction::FromFlightController,
+            b'!' => Direction::Error,
+            _ => return Err(MspError::Malformed(command)),
+        };
+        let mut payload = vec![0; len as usize + 1];
+        reader.read_exact(&mut payload)?;
+        let crc = payload.pop().unwrap();
+        if checksum(&[&[len, command], payload.as_slice()].concat()) != crc {
+            return Err(MspError::Checksum);
+        }
+        Ok(Self::new(direction, command, payload))
+    }
+}
+
+// xor of the size, the command and the payload
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| crc ^ byte)
+}
+
+// reads the little endian fields of a payload in order
+struct PayloadReader<'a> {
+    command: u8,
+    payload: &'a [u8],
+}
+
+impl PayloadReader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], MspError> {
+        if self.payload.len() < N {
+            return Err(MspError::Malformed(self.command));
+        }
+        let (bytes, rest) = self.payload.split_at(N);
+        self.payload = rest;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, MspError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MspError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn i16(&mut self) -> Result<i16, MspError> {
+        Ok(i16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, MspError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApiVersion {
+    pub protocol: u8,
+    pub major: u8,
+    pub minor: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub cycle_time: u16, // us
+    pub i2c_errors: u16,
+    pub sensors: u16,
+    // a bit for each active box (mode), bit 0 is ARM
+    pub flight_mode_flags: u32,
+    pub pid_profile: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attitude {
+    // deg
+    pub roll: f64,
+    pub pitch: f64,
+    pub yaw: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Pid {
+    pub p: u8,
+    pub i: u8,
+    pub d: u8,
+}
+
+/// The active rate profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RcTuning {
+    // roll, pitch, yaw
+    pub rc_rates: [u8; 3],
+    pub rc_expo: [u8; 3],
+    pub rates: [u8; 3],
+    pub throttle_mid: u8,
+    pub throttle_expo: u8,
+    // MSP API 1.43 still sets TPA here, later versions ignore it, written back as it was read
+    pub tpa_rate: u8,
+    pub tpa_breakpoint: u16,
+    pub throttle_limit_type: u8,
+    pub throttle_limit_percent: u8,
+    pub rate_limit: [u16; 3], // deg/s
+    // 0 Betaflight, 1 Raceflight, 2 Kiss, 3 Actual, 4 Quick
+    pub rates_type: u8,
+}
+
+impl RcTuning {
+    fn decode(payload: &mut PayloadReader) -> Result<Self, MspError> {
+        let mut tuning = Self::default();
+        tuning.rc_rates[0] = payload.u8()?;
+        tuning.rc_expo[0] = payload.u8()?;
+        for rate in &mut tuning.rates {
+            *rate = payload.u8()?;
+        }
+        tuning.tpa_rate = payload.u8()?;
+        tuning.throttle_mid = payload.u8()?;
+        tuning.throttle_expo = payload.u8()?;
+        tuning.tpa_breakpoint = payload.u16()?;
+        tuning.rc_expo[2] = payload.u8()?;
+        tuning.rc_rates[2] = payload.u8()?;
+        tuning.rc_rates[1] = payload.u8()?;
+        tuning.rc_expo[1] = payload.u8()?;
+        tuning.throttle_limit_type = payload.u8()?;
+        tuning.throttle_limit_percent = payload.u8()?;
+        for limit in &mut tuning.rate_limit {
+            *limit = payload.u16()?;
+        }
+        tuning.rates_type = payload.u8()?;
+        Ok(tuning)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![self.rc_rates[0], self.rc_expo[0]];
+        payload.extend(self.rates);
+        payload.extend([self.tpa_rate, self.throttle_mid, self.throttle_expo]);
+        payload.extend(self.tpa_breakpoint.to_le_bytes());
+        payload.extend([
+            self.rc_expo[2],
+            self.rc_rates[2],
+            self.rc_rates[1],
+            self.rc_expo[1],
+        ]);
+        payload.extend([self.throttle_limit_type, self.throttle_limit_percent]);
+        for limit in self.rate_limit {
+            payload.extend(limit.to_le_bytes());
+        }
+        payload.push(self.rates_type);
+        payload
+    }
+}
+
+/// The static filters of the gyro and the dterm, cut offs in Hz (0 is off). The dynamic filters
+/// that newer versions append are left untouched when this is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FilterConfig {
+    pub dterm_lpf1_hz: u16,
+    pub yaw_lowpass_hz: u16,
+    pub gyro_notch1_hz: u16,
+    pub gyro_notch1_cutoff: u16,
+    pub dterm_notch_hz: u16,
+    pub dterm_notch_cutoff: u16,
+    pub gyro_notch2_hz: u16,
+    pub gyro_notch2_cutoff: u16,
+    pub dterm_lpf1_type: u8,
+    pub gyro_hardware_lpf: u8,
+    pub gyro_lpf1_hz: u16,
+    pub gyro_lpf2_hz: u16,
+    pub gyro_lpf1_type: u8,
+    pub gyro_lpf2_type: u8,
+    pub dterm_lpf2_hz: u16,
+    pub dterm_lpf2_type: u8,
+}
+
+impl FilterConfig {
+    fn decode(payload: &mut PayloadReader) -> Result<Self, MspError> {
+        payload.u8()?; // the low byte of the gyro lpf1 for old configurators
+        Ok(Self {
+            dterm_lpf1_hz: payload.u16()?,
+            yaw_lowpass_hz: payload.u16()?,
+            gyro_notch1_hz: payload.u16()?,
+            gyro_notch1_cutoff: payload.u16()?,
+            dterm_notch_hz: payload.u16()?,
+            dterm_notch_cutoff: payload.u16()?,
+            gyro_notch2_hz: payload.u16()?,
+            gyro_notch2_cutoff: payload.u16()?,
+            dterm_lpf1_type: payload.u8()?,
+            gyro_hardware_lpf: payload.u8()?,
+            gyro_lpf1_hz: {
+                payload.u8()?; // was the 32kHz hardware lpf
+                payload.u16()?
+            },
+            gyro_lpf2_hz: payload.u16()?,
+            gyro_lpf1_type: payload.u8()?,
+            gyro_lpf2_type: payload.u8()?,
+            dterm_lpf2_hz: payload.u16()?,
+            dterm_lpf2_type: payload.u8()?,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![self.gyro_lpf1_hz as u8];
+        for value in [
+            self.dterm_lpf1_hz,
+            self.yaw_lowpass_hz,
+            self.gyro_notch1_hz,
+            self.gyro_notch1_cutoff,
+            self.dterm_notch_hz,
+            self.dterm_notch_cutoff,
+            self.gyro_notch2_hz,
+            self.gyro_notch2_cutoff,
+        ] {
+            payload.extend(value.to_le_bytes());
+        }
+        payload.extend([self.dterm_lpf1_type, self.gyro_hardware_lpf, 0]);
+        payload.extend(self.gyro_lpf1_hz.to_le_bytes());
+        payload.extend(self.gyro_lpf2_hz.to_le_bytes());
+        payload.extend([self.gyro_lpf1_type, self.gyro_lpf2_type]);
+        payload.extend(self.dterm_lpf2_hz.to_le_bytes());
+        payload.push(self.dterm_lpf2_type);
+        payload
+    }
+}
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+// the serial thread only sends what betaflight wrote after it received something itself
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// a single final frame, clients have to mask what they send
+fn ws_frame(opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..126 => frame.push(0x80 | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(0x80 | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(mask);
+    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+    frame
+}
+
+// the opcode and the payload of the first frame and its length, None if it is incomplete
+fn parse_ws_frame(bytes: &[u8]) -> Option<(u8, Vec<u8>, usize)> {
+    let opcode = bytes.first()? & 0x0f;
+    let second = *bytes.get(1)?;
+    let (len, mut start) = match second & 0x7f {
+        126 => (
+            u16::from_be_bytes(bytes.get(2..4)?.try_into().ok()?) as usize,
+            4,
+        ),
+        127 => (
+            u64::from_be_bytes(bytes.get(2..10)?.try_into().ok()?) as usize,
+            10,
+        ),
+        len => (len as usize, 2),
+    };
+    let mut mask = [0; 4];
+    if second & 0x80 != 0 {
+        mask = bytes.get(start..start + 4)?.try_into().ok()?;
+        start += 4;
+    }
+    let payload = bytes.get(start..start + len)?;
+    let payload = payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m);
+    Some((opcode, payload.collect(), start + len))
+}
+
+/// A serial port of the virtual Betaflight. Whatever is written is sent as a binary WebSocket
+/// message, reads return the payload of the messages that came back. A read gives up after the
+/// timeout.
+#[derive(Debug)]
+pub struct WsStream {
+    stream: TcpStream,
+    timeout: Duration,
+    // bytes from the socket that are not a whole frame yet
+    incoming: Vec<u8>,
+    // the payload of the received messages that was not read yet
+    received: VecDeque<u8>,
+    closed: bool,
+}
+
+impl WsStream {
+    /// Connects and upgrades the connection to a WebSocket
+    pub fn connect(address: impl ToSocketAddrs, timeout: Duration) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        let host = stream.peer_addr()?;
+        let key = base64(Uuid::new_v4().as_bytes());
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        )?;
+        // read byte by byte, the first frame could follow right after the header
+        let mut response = vec![];
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte)?;
+            response.push(byte[0]);
+        }
+        if !response.starts_with(b"HTTP/1.1 101") {
+            let status = String::from_utf8_lossy(&response);
+            let status = status.lines().next().unwrap_or_default();
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("no WebSocket upgrade: {status}"),
+            ));
+        }
+        stream.set_read_timeout(Some(POLL_INTERVAL.min(timeout)))?;
+        Ok(Self {
+            stream,
+            timeout,
+            incoming: vec![],
+            received: VecDeque::new(),
+            closed: false,
+        })
+    }
+
+    fn send(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mask = Uuid::new_v4().as_bytes()[..4].try_into().unwrap();
+        self.stream.write_all(&ws_frame(opcode, payload, mask))
+    }
+
+    // moves the payload of the complete frames to `received`, answers pings
+    fn process_frames(&mut self) -> io::Result<()> {
+        while let Some((opcode, payload, len)) = parse_ws_frame(&self.incoming) {
+            self.incoming.drain(..len);
+            match opcode {
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => self.received.extend(payload),
+                OPCODE_CLOSE => self.closed = true,
+                OPCODE_PING => self.send(OPCODE_PONG, &payload)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = Instant::now();
+        let mut chunk = [0; 1024];
+        while self.received.is_empty() && !self.closed {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.closed = true,
+                Ok(len) => {
+                    self.incoming.extend(&chunk[..len]);
+                    self.process_frames()?;
+                }
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    if start.elapsed() >= self.timeout {
+                        return Err(err);
+                    }
+                    // an empty message makes the serial thread send what is waiting
+                    self.send(OPCODE_BINARY, &[])?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let len = buf.len().min(self.received.len());
+        for (byte, received) in buf.iter_mut().zip(self.received.drain(..len)) {
+            *byte = received;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(OPCODE_BINARY, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A blocking MSP connection, every request waits for its response.
+#[derive(Debug)]
+pub struct MspClient<S: Read + Write = WsStream> {
+    stream: BufReader<S>,
+}
+
+impl MspClient<WsStream> {
+    /// Connects to a serial port of the virtual Betaflight
+    pub fn connect(address: impl ToSocketAddrs, timeout: Duration) -> Result<Self, MspError> {
+        Ok(Self::new(WsStream::connect(address, timeout)?))
+    }
+}
+
+impl<S: Read + Write> MspClient<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    /// Sends a command and returns the payload of the response, responses to other commands
+    /// (left over from timed out requests) are dropped.
+    pub fn request(&mut self, command: u8, payload: &[u8]) -> Result<Vec<u8>, MspError> {
+        let frame = MspFrame::new(Direction::ToFlightController, command, payload.to_vec());
+        let stream = self.stream.get_mut();
+        stream.write_all(&frame.to_bytes())?;
+        stream.flush()?;
+        loop {
+            let response = MspFrame::read(&mut self.stream)?;
+            if response.command != command {
+                continue;
+            }
+            return match response.direction {
+                Direction::FromFlightController => Ok(response.payload),
+                Direction::Error => Err(MspError::Rejected(command)),
+                // our own request echoed back
+                Direction::ToFlightController => continue,
+            };
+        }
+    }
+
+    fn read<R>(
+        &mut self,
+        command: u8,
+        decode: impl FnOnce(&mut PayloadReader) -> Result<R, MspError>,
+    ) -> Result<R, MspError> {
+        let payload = self.request(command, &[])?;
+        decode(&mut PayloadReader {
+            command,
+            payload: &payload,
+        })
+    }
+
+    pub fn api_version(&mut self) -> Result<ApiVersion, MspError> {
+        self.read(MSP_API_VERSION, |payload| {
+            Ok(ApiVersion {
+                protocol: payload.u8()?,
+                major: payload.u8()?,
+                minor: payload.u8()?,
+            })
+        })
+    }
+
+    pub fn status(&mut self) -> Result<Status, MspError> {
+        self.read(MSP_STATUS, |payload| {
+            Ok(Status {
+                cycle_time: payload.u16()?,
+                i2c_errors: payload.u16()?,
+                sensors: payload.u16()?,
+                flight_mode_flags: payload.u32()?,
+                pid_profile: payload.u8()?,
+            })
+        })
+    }
+
+    /// The motor outputs in Betaflight order, in the units of the motor protocol
+    pub fn motors(&mut self) -> Result<Vec<u16>, MspError> {
+        self.read(MSP_MOTOR, |payload| {
+            (0..payload.payload.len() / 2)
+                .map(|_| payload.u16())
+                .collect()
+        })
+    }
+
+    pub fn attitude(&mut self) -> Result<Attitude, MspError> {
+        self.read(MSP_ATTITUDE, |payload| {
+            Ok(Attitude {
+                roll: payload.i16()? as f64 / 10.,
+                pitch: payload.i16()? as f64 / 10.,
+                yaw: payload.i16()? as f64,
+            })
+        })
+    }
+
+    /// The PIDs of the active profile, indexed by `PID_ROLL`, `PID_PITCH`, ...
+    pub fn pids(&mut self) -> Result<Vec<Pid>, MspError> {
+        self.read(MSP_PID, |payload| {
+            (0..payload.payload.len() / 3)
+                .map(|_| {
+                    let [p, i, d] = payload.take()?;
+                    Ok(Pid { p, i, d })
+                })
+                .collect()
+        })
+    }
+
+    pub fn set_pids(&mut self, pids: &[Pid]) -> Result<(), MspError> {
+        let payload: Vec<_> = pids.iter().flat_map(|pid| [pid.p, pid.i, pid.d]).collect();
+        self.request(MSP_SET_PID, &payload).map(|_| ())
+    }
+
+    pub fn rc_tuning(&mut self) -> Result<RcTuning, MspError> {
+        self.read(MSP_RC_TUNING, RcTuning::decode)
+    }
+
+    pub fn set_rc_tuning(&mut self, tuning: &RcTuning) -> Result<(), MspError> {
+        self.request(MSP_SET_RC_TUNING, &tuning.encode())
+            .map(|_| ())
+    }
+
+    pub fn filter_config(&mut self) -> Result<FilterConfig, MspError> {
+        self.read(MSP_FILTER_CONFIG, FilterConfig::decode)
+    }
+
+    pub fn set_filter_config(&mut self, filters: &FilterConfig) -> Result<(), MspError> {
+        self.request(MSP_SET_FILTER_CONFIG, &filters.encode())
+            .map(|_| ())
+    }
+
+    /// Writes the current configuration into the eeprom file of the instance
+    pub fn save(&mut self) -> Result<(), MspError> {
+        self.request(MSP_EEPROM_WRITE, &[]).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::msp::{
+        Direction, FilterConfig, MSP_API_VERSION, MSP_ATTITUDE, MSP_EEPROM_WRITE,
+        MSP_FILTER_CONFIG, MSP_PID, MSP_RC_TUNING, MSP_SET_FILTER_CONFIG, MSP_SET_PID,
+        MSP_SET_RC_TUNING, MspClient, MspError, MspFrame, OPCODE_BINARY, PID_PITCH, RcTuning,
+        base64, parse_ws_frame, ws_frame,
+    };
+    use std::{
+        io::{BufReader, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn frames() {
+        let request = MspFrame::new(Direction::ToFlightController, MSP_API_VERSION, vec![]);
+        assert_eq!(request.to_bytes(), b"$M<\x00\x01\x01");
+        let response = MspFrame::new(Direction::FromFlightController, MSP_PID, vec![45, 80, 30]);
+        // garbage in front of the frame is skipped
+        let bytes = [b"M$".as_slice(), &response.to_bytes()].concat();
+        assert_eq!(MspFrame::read(&mut bytes.as_slice()).unwrap(), response);
+
+        let mut corrupted = response.to_bytes();
+        corrupted[6] += 1;
+        assert!(matches!(
+            MspFrame::read(&mut corrupted.as_slice()),
+            Err(MspError::Checksum)
+        ));
+    }
+
+    #[test]
+    fn ws_frames() {
+        assert_eq!(base64(b"quad"), "cXVhZA==");
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8=");
+        let request = MspFrame::new(Direction::ToFlightController, MSP_PID, vec![]).to_bytes();
+        let frame = ws_frame(OPCODE_BINARY, &request, [1, 2, 3, 4]);
+        assert_eq!(frame[..2], [0x82, 0x80 | request.len() as u8]);
+        assert_ne!(frame[6..], request);
+        assert_eq!(
+            parse_ws_frame(&frame),
+            Some((OPCODE_BINARY, request.clone(), frame.len()))
+        );
+        assert_eq!(parse_ws_frame(&frame[..frame.len() - 1]), None);
+        // the server does not mask
+        let long = vec![7; 300];
+        let mut frame = vec![0x82, 126, 1, 44];
+        frame.extend(&long);
+        assert_eq!(parse_ws_frame(&frame), Some((OPCODE_BINARY, long, 304)));
+    }
+
+    // a flight controller that stores whatever is set and returns it
+    fn serve(listener: TcpListener) -> usize {
+        let (stream, _) = listener.accept().unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut pids = vec![45, 80, 30, 47, 84, 32, 45, 80, 0, 50, 50, 75, 40, 0, 0];
+        let mut rc_tuning = vec![0; 23];
+        let mut filters = vec![0; 29];
+        let mut saves = 0;
+        while let Ok(request) = MspFrame::read(&mut reader) {
+            let payload = match request.command {
+                MSP_PID => Some(pids.clone()),
+                MSP_SET_PID => {
+                    pids = request.payload;
+                    Some(vec![])
+                }
+                MSP_RC_TUNING => Some(rc_tuning.clone()),
+                MSP_SET_RC_TUNING => {
+                    rc_tuning = request.payload;
+                    Some(vec![])
+                }
+                MSP_FILTER_CONFIG => Some(filters.clone()),
+                MSP_SET_FILTER_CONFIG => {
+                    filters = request.payload;
+                    Some(vec![])
+                }
+                MSP_EEPROM_WRITE => {
+                    saves += 1;
+                    Some(vec![])
+                }
+                _ => None,
+            };
+            let response = match payload {
+                Some(payload) => {
+                    MspFrame::new(Direction::FromFlightController, request.command, payload)
+                }
+                None => MspFrame::new(Direction::Error, request.command, vec![]),
+            };
+            writer.write_all(&response.to_bytes()).unwrap();
+        }
+        saves
+    }
+
+    #[test]
+    fn configuration_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || serve(listener));
+        let stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut client = MspClient::new(stream);
+
+        let mut pids = client.pids().unwrap();
+        assert_eq!(pids.len(), 5);
+        pids[PID_PITCH].d = 40;
+        client.set_pids(&pids).unwrap();
+        assert_eq!(client.pids().unwrap(), pids);
+
+        let tuning = RcTuning {
+            rc_rates: [100, 110, 120],
+            rc_expo: [1, 2, 3],
+            rates: [70, 71, 72],
+            throttle_mid: 50,
+            throttle_expo: 10,
+            tpa_rate: 65,
+            tpa_breakpoint: 1350,
+            throttle_limit_type: 1,
+            throttle_limit_percent: 90,
+            rate_limit: [1998, 1999, 2000],
+            rates_type: 3,
+        };
+        client.set_rc_tuning(&tuning).unwrap();
+        assert_eq!(client.rc_tuning().unwrap(), tuning);
+
+        let filters = FilterConfig {
+            dterm_lpf1_hz: 100,
+            gyro_notch1_hz: 300,
+            gyro_notch1_cutoff: 200,
+            gyro_lpf1_hz: 250,
+            gyro_lpf2_hz: 500,
+            dterm_lpf2_hz: 150,
+            dterm_lpf2_type: 1,
+            ..Default::default()
+        };
+        client.set_filter_config(&filters).unwrap();
+        assert_eq!(client.filter_config().unwrap(), filters);
+
+        // the fake does not know the attitude
+        assert!(matches!(
+            client.attitude(),
+            Err(MspError::Rejected(MSP_ATTITUDE))
+        ));
+        client.save().unwrap();
+        drop(client);
+        assert_eq!(server.join().unwrap(), 1);
+    }
+}
@@ -0,0 +1,404 @@
+//! Backend for the stock Betaflight SITL executable (`make TARGET=SITL`). The SITL binary listens
+//! for flight dynamics (FDM) and RC packets over UDP and sends the motor outputs back, so any
+//! upstream Betaflight version can be flown without the patched dynamic library.
+//!
+//! The SITL runs on its own (wall) clock, the motor outputs are whatever it sent last when the
+//! simulator asks for them. Simulations with this backend should run close to real time.
+//!
+//! The ports are fixed in the SITL target, so only a single instance can run on a host.
+
+use flight_controller::{
+    ChannelMap, FlightController, FlightControllerUpdate, GyroUpdate, InitError, MotorInput,
+    frames::{pressure, specific_force_frd, to_frd, to_ned_quaternion},
+};
+use std::{
+    io::ErrorKind,
+    net::UdpSocket,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+    time::Duration,
+};
+use tempfile::TempDir;
+
+// the RC packet always carries this many channels
+const SITL_RC_CHANNELS: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct SitlConfig {
+    pub binary: PathBuf,
+    // copied into the working directory of the SITL, which reads and writes eeprom.bin there
+    pub eeprom: Option<PathBuf>,
+    pub host: String,
+    // the SITL receives the FDM and RC packets on these ports
+    pub state_port: u16,
+    pub rc_port: u16,
+    // and sends the motor outputs to this one
+    pub pwm_port: u16,
+    // how long to wait for the first motor outputs after a start
+    pub startup_timeout: Duration,
+    pub scheduler_delta: Duration,
+}
+
+impl SitlConfig {
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: binary.into(),
+            eeprom: None,
+            host: "127.0.0.1".into(),
+            state_port: 9003,
+            rc_port: 9004,
+            pwm_port: 9002,
+            startup_timeout: Duration::from_secs(5),
+            scheduler_delta: Duration::from_millis(1),
+        }
+    }
+
+    pub fn with_eeprom(self, eeprom: impl Into<PathBuf>) -> Self {
+        Self {
+            eeprom: Some(eeprom.into()),
+            ..self
+        }
+    }
+}
+
+// sent until the SITL answers, a zero rotation would turn into a NaN attitude
+fn level_at_rest() -> FlightControllerUpdate {
+    FlightControllerUpdate {
+        gyro_update: GyroUpdate {
+            rotation: [0., 0., 0., 1.],
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// The FDM packet of the SITL, all vectors are in NED (world) or FRD (body) coordinates.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FdmPacket {
+    pub timestamp: f64, // s
+    pub imu_angular_velocity_rpy: [f64; 3],
+    // specific force, -9.81 on z when level at rest
+    pub imu_linear_acceleration_xyz: [f64; 3],
+    pub imu_orientation_quat: [f64; 4], // w, x, y, z
+    pub velocity_xyz: [f64; 3],
+    pub position_xyz: [f64; 3],
+    pub pressure: f64,
+}
+
+impl FdmPacket {
+    pub const SIZE: usize = 18 * 8;
+
+    pub fn new(timestamp: f64, update: &FlightControllerUpdate, velocity: [f64; 3]) -> Self {
+        let gyro = &update.gyro_update;
+        Self {
+            timestamp,
+            imu_angular_velocity_rpy: to_frd(gyro.angular_velocity),
+            imu_linear_acceleration_xyz: specific_force_frd(gyro),
+            imu_orientation_quat: to_ned_quaternion(gyro.rotation),
+            velocity_xyz: to_frd(velocity),
+            position_xyz: to_frd(update.position),
+            pressure: pressure(update.position[1]),
+        }
+    }
+
+    /// Little endian, laid out like the C struct
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let values = std::iter::once(self.timestamp)
+            .chain(self.imu_angular_velocity_rpy)
+            .chain(self.imu_linear_acceleration_xyz)
+            .chain(self.imu_orientation_quat)
+            .chain(self.velocity_xyz)
+            .chain(self.position_xyz)
+            .chain(std::iter::once(self.pressure));
+        let mut bytes = [0; Self::SIZE];
+        for (chunk, value) in bytes.chunks_exact_mut(8).zip(values) {
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RcPacket {
+    pub timestamp: f64,
+    // pwm values between 1000 and 2000
+    pub channels: [u16; SITL_RC_CHANNELS],
+}
+
+impl RcPacket {
+    pub const SIZE: usize = 8 + 2 * SITL_RC_CHANNELS;
+
+    pub fn new(timestamp: f64, update: &FlightControllerUpdate, channel_map: &ChannelMap) -> Self {
+        let channels = update.channels.to_bf_channels_with_map(channel_map);
+        Self {
+            timestamp,
+            channels: std::array::from_fn(|i| {
+                let value = channels.get(i).copied().unwrap_or(-1.);
+                (1500. + 500. * value.clamp(-1., 1.)).round() as u16
+            }),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        for (chunk, channel) in bytes[8..].chunks_exact_mut(2).zip(self.channels) {
+            chunk.copy_from_slice(&channel.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// The normalized motor outputs in Betaflight motor order.
+pub fn parse_servo_packet(bytes: &[u8]) -> Option<MotorInput> {
+    if bytes.len() < 16 {
+        return None;
+    }
+    let mut input = [0.; 4];
+    for (motor, chunk) in input.iter_mut().zip(bytes.chunks_exact(4)) {
+        *motor = f32::from_le_bytes(chunk.try_into().unwrap()) as f64;
+    }
+    Some(MotorInput { input })
+}
+
+/// A running SITL executable, it is killed when this is dropped.
+#[derive(Debug)]
+pub struct SitlProcess {
+    child: Child,
+    // holds the eeprom, removed after the process is gone
+    _workdir: TempDir,
+}
+
+impl SitlProcess {
+    pub fn spawn(config: &SitlConfig) -> std::io::Result<Self> {
+        let workdir = TempDir::new()?;
+        if let Some(eeprom) = &config.eeprom {
+            std::fs::copy(eeprom, workdir.path().join("eeprom.bin"))?;
+        }
+        let child = Command::new(&config.binary)
+            .arg(&config.host)
+            .current_dir(workdir.path())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        log::info!("Started Betaflight SITL with pid {}", child.id());
+        Ok(Self {
+            child,
+            _workdir: workdir,
+        })
+    }
+
+    pub fn is_running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+}
+
+impl Drop for SitlProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[derive(Debug, Default)]
+struct SitlState {
+    process: Option<SitlProcess>,
+    socket: Option<UdpSocket>,
+    time: f64,
+    last_position: Option<[f64; 3]>,
+    motor_input: MotorInput,
+}
+
+/// Flies the drone with a Betaflight SITL child process, which is (re)started by `init`.
+#[derive(Debug)]
+pub struct SitlController {
+    pub config: SitlConfig,
+    // how the channels are handed to betaflight, the aux roles should match the eeprom modes
+    pub channel_map: ChannelMap,
+    state: Mutex<SitlState>,
+}
+
+impl SitlController {
+    pub fn new(config: SitlConfig) -> Self {
+        Self {
+            config,
+            channel_map: ChannelMap::default(),
+            state: Mutex::new(SitlState::default()),
+        }
+    }
+
+    pub fn with_channel_map(mut self, channel_map: ChannelMap) -> Self {
+        self.channel_map = channel_map;
+        self
+    }
+
+    pub fn is_running(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state
+            .process
+            .as_mut()
+            .is_some_and(|process| process.is_running())
+    }
+
+    /// Blocks until the SITL sends its first motor outputs, returns false if it does not within
+    /// the startup timeout.
+    pub fn wait_until_ready(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        let Some(socket) = state.socket.as_ref() else {
+            return false;
+        };
+        let start = std::time::Instant::now();
+        let mut buf = [0; 64];
+        // the SITL only starts its loop once it receives state packets
+        let fdm = FdmPacket::new(0., &level_at_rest(), [0.; 3]);
+        while start.elapsed() < self.config.startup_timeout {
+            self.send(socket, self.config.state_port, &fdm.to_bytes());
+            match socket.recv(&mut buf) {
+                Ok(len) if parse_servo_packet(&buf[..len]).is_some() => return true,
+                _ => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        false
+    }
+
+    fn send(&self, socket: &UdpSocket, port: u16, bytes: &[u8]) {
+        // the SITL might not listen yet while it boots
+        if let Err(err) = socket.send_to(bytes, (self.config.host.as_str(), port)) {
+            log::debug!("Could not send to the SITL: {err}");
+        }
+    }
+}
+
+impl FlightController for SitlController {
+    fn init(&self) -> Result<(), InitError> {
+        let mut state = self.state.lock().unwrap();
+        // the old process has to release the ports first
+        *state = SitlState::default();
+        let socket = UdpSocket::bind((self.config.host.as_str(), self.config.pwm_port))
+            .map_err(|err| format!("could not bind the SITL pwm port: {err}"))?;
+        socket.set_nonblocking(true)?;
+        let process = SitlProcess::spawn(&self.config).map_err(|err| {
+            format!(
+                "could not start the SITL {}: {err}",
+                self.config.binary.display()
+            )
+        })?;
+        state.process = Some(process);
+        state.socket = Some(socket);
+        Ok(())
+    }
+
+    fn update(&self, delta_time: f64, update: FlightControllerUpdate) -> MotorInput {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let Some(socket) = state.socket.as_ref() else {
+            // the motors stay off until init succeeds
+            log::warn!("The SITL controller was not initialized");
+            return MotorInput::default();
+        };
+        state.time += delta_time;
+        let velocity = match state.last_position {
+            Some(last) if delta_time > 0. => {
+                std::array::from_fn(|i| (update.position[i] - last[i]) / delta_time)
+            }
+            _ => [0.; 3],
+        };
+        state.last_position = Some(update.position);
+
+        let fdm = FdmPacket::new(state.time, &update, velocity);
+        self.send(socket, self.config.state_port, &fdm.to_bytes());
+        let rc = RcPacket::new(state.time, &update, &self.channel_map);
+        self.send(socket, self.config.rc_port, &rc.to_bytes());
+
+        // only the newest motor outputs matter
+        let mut buf = [0; 64];
+        loop {
+            match socket.recv(&mut buf) {
+                Ok(len) => {
+                    if let Some(motor_input) = parse_servo_packet(&buf[..len]) {
+                        state.motor_input = motor_input;
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::warn!("SITL socket error: {err}");
+                    break;
+                }
+            }
+        }
+        state.motor_input
+    }
+
+    fn scheduler_delta(&self) -> Duration {
+        self.config.scheduler_delta
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sitl::{
+        FdmPacket, RcPacket, SitlConfig, SitlController, level_at_rest, parse_servo_packet,
+    };
+    use flight_controller::{ChannelMap, FlightController, FlightControllerUpdate};
+
+    fn update() -> FlightControllerUpdate {
+        level_at_rest()
+    }
+
+    #[test]
+    fn fdm_frames() {
+        // level and at rest, the accelerometer measures 1g up
+        let fdm = FdmPacket::new(0.1, &update(), [0.; 3]);
+        assert_eq!(fdm.imu_linear_acceleration_xyz, [0., 0., -9.81]);
+        assert_eq!(fdm.imu_orientation_quat, [1., 0., 0., 0.]);
+        assert!((fdm.pressure - 101325.).abs() < 1e-6);
+
+        // rolling right (around the forward axis, which is -z) and climbing
+        let mut update = update();
+        update.gyro_update.angular_velocity = [0., 0., -1.];
+        update.position = [0., 10., 0.];
+        let fdm = FdmPacket::new(0.1, &update, [0., 2., 0.]);
+        assert_eq!(fdm.imu_angular_velocity_rpy, [1., 0., 0.]);
+        assert_eq!(fdm.position_xyz, [0., 0., -10.]);
+        assert_eq!(fdm.velocity_xyz, [0., 0., -2.]);
+        assert!(fdm.pressure < 101325.);
+
+        let bytes = fdm.to_bytes();
+        assert_eq!(bytes[..8], 0.1f64.to_le_bytes());
+        assert_eq!(bytes[8..16], 1f64.to_le_bytes());
+    }
+
+    #[test]
+    fn rc_and_servo_packets() {
+        let mut update = update();
+        update.channels.throttle = 1.;
+        let rc = RcPacket::new(0., &update, &ChannelMap::default());
+        assert!(rc.channels.contains(&2000));
+        assert!(rc.channels.iter().all(|c| (1000..=2000).contains(c)));
+        assert_eq!(rc.to_bytes().len(), RcPacket::SIZE);
+
+        let bytes: Vec<u8> = [0.1f32, 0.2, 0.3, 0.4]
+            .iter()
+            .flat_map(|motor| motor.to_le_bytes())
+            .collect();
+        let motor_input = parse_servo_packet(&bytes).unwrap();
+        assert!((motor_input[3] - 0.4).abs() < 1e-6);
+        assert!(parse_servo_packet(&bytes[..8]).is_none());
+    }
+
+    #[test]
+    fn missing_binary() {
+        let config = SitlConfig {
+            pwm_port: 0,
+            ..SitlConfig::new("/nonexistent/betaflight_SITL.elf")
+        };
+        let controller = SitlController::new(config);
+        let err = controller.init().unwrap_err();
+        assert!(err.to_string().contains("could not start the SITL"));
+        // without a SITL the motors stay off
+        let motor_input = controller.update(0.001, update());
+        assert_eq!(motor_input.input, [0.; 4]);
+    }
+}
@@ -149,6 +149,10 @@ pub struct DBFlightLog {
     pub roll: f64,
     pub pitch: f64,
     pub yaw: f64,
+    pub aux_channels: Option<String>, // json encoded aux channels
+    pub shadow_motor_inputs: Option<String>, // json encoded shadow controller outputs
+    pub arming_state: Option<String>, // json encoded arming state
+    pub intervention: Option<String>, // json encoded breach of the safety envelope
 }
 
 pub struct DBNewFlightLog {
@@ -178,6 +182,20 @@ pub struct DBNewFlightLog {
     pub roll: f64,
     pub pitch: f64,
     pub yaw: f64,
+    pub aux_channels: Option<String>, // json encoded aux channels
+    pub shadow_motor_inputs: Option<String>, // json encoded shadow controller outputs
+    pub arming_state: Option<String>, // json encoded arming state
+    pub intervention: Option<String>, // json encoded breach of the safety envelope
+}
+
+// one row per flight log
+#[derive(Debug, Clone)]
+pub struct DBFlightLogEvents {
+    pub simulation_id: String,
+    pub collisions: Option<String>, // json encoded collisions with the scene
+    pub gate_passes: Option<String>, // json encoded gate passes
+    pub laps: Option<String>,       // json encoded lap times
+    pub parameters: Option<String>, // json encoded parameters of the drone
 }
 
 pub struct NewDBRcModel {
@@ -190,3 +208,22 @@ pub struct NewDBRcModel {
     pub readout_coeff: Option<String>,
     pub readout_intercept: Option<String>,
 }
+
+pub struct DBRateProfile {
+    pub id: String,
+    pub rates_type: String,
+    pub roll_rc_rate: f64,
+    pub pitch_rc_rate: f64,
+    pub yaw_rc_rate: f64,
+    pub roll_expo: f64,
+    pub pitch_expo: f64,
+    pub yaw_expo: f64,
+    pub roll_srate: f64,
+    pub pitch_srate: f64,
+    pub yaw_srate: f64,
+    pub roll_rate_limit: f64,
+    pub pitch_rate_limit: f64,
+    pub yaw_rate_limit: f64,
+    pub thr_mid: f64,
+    pub thr_expo: f64,
+}
@@ -1,8 +1,8 @@
 use sqlx::{Connection, SqliteConnection, query, query_as};
 
 use crate::{
-    DBDroneModel, DBFlightLog, DBLowPassFilter, DBNewFlightLog, DBRcModel, DBRotorState,
-    DBSamplePoint, DBSimulationFrame, NewDBRcModel,
+    DBDroneModel, DBFlightLog, DBFlightLogEvents, DBLowPassFilter, DBNewFlightLog, DBRateProfile,
+    DBRcModel, DBRotorState, DBSamplePoint, DBSimulationFrame, NewDBRcModel,
 };
 
 #[derive(Debug)]
@@ -107,6 +107,19 @@ impl TestingDB {
         smol::block_on(async { self.fetch_rotor_state_async(id).await })
     }
 
+    async fn fetch_rate_profile_async(&mut self, profile_id: &str) -> DBRateProfile {
+        let query = query_as!(
+            DBRateProfile,
+            r#"SELECT * FROM rate_profile WHERE rate_profile.id = ?"#,
+            profile_id
+        );
+        query.fetch_one(&mut self.conn).await.unwrap()
+    }
+
+    pub fn fetch_rate_profile(&mut self, profile_id: &str) -> DBRateProfile {
+        smol::block_on(async { self.fetch_rate_profile_async(profile_id).await })
+    }
+
     async fn load_replay_ids_async(&mut self) -> Vec<String> {
         struct SimulationId {
             simulation_id: String, // TODO: this should be string
@@ -149,6 +162,20 @@ impl TestingDB {
         smol::block_on(async { self.fetch_flight_logs_async(sim_id).await })
     }
 
+    async fn fetch_flight_log_events_async(&mut self, sim_id: &str) -> Option<DBFlightLogEvents> {
+        let query = query_as!(
+            DBFlightLogEvents,
+            r#"SELECT * from flight_log_events WHERE simulation_id = ?"#,
+            sim_id
+        );
+        query.fetch_optional(&mut self.conn).await.unwrap()
+    }
+
+    /// None for the flight logs that were written before the events were stored
+    pub fn fetch_flight_log_events(&mut self, sim_id: &str) -> Option<DBFlightLogEvents> {
+        smol::block_on(async { self.fetch_flight_log_events_async(sim_id).await })
+    }
+
     async fn insert_reservoir_async(&mut self, res: NewDBRcModel) {
         let q = query!(
             r#"
@@ -194,9 +221,10 @@ impl TestingDB {
                         motor_input_3, motor_input_4, battery_voltage_sag, battery_voltage, amperage,
                         mah_drawn, cell_count, rot_quat_x, rot_quat_y, rot_quat_z, rot_quat_w,
                         linear_acceleration_x, linear_acceleration_y, linear_acceleration_z,
-                        angular_velocity_x, angular_velocity_y, angular_velocity_z, throttle, roll, pitch, yaw
+                        angular_velocity_x, angular_velocity_y, angular_velocity_z, throttle, roll, pitch, yaw,
+                        aux_channels, shadow_motor_inputs, arming_state, intervention
                     ) VALUES (
-                        ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?
+                        ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?
                     )"#,
                 simulation_id,
                 flight_log.start_seconds,
@@ -224,6 +252,10 @@ impl TestingDB {
                 flight_log.roll,
                 flight_log.pitch,
                 flight_log.yaw,
+                flight_log.aux_channels,
+                flight_log.shadow_motor_inputs,
+                flight_log.arming_state,
+                flight_log.intervention,
             );
             query.execute(&mut *trx).await.unwrap();
         }
@@ -1,10 +1,15 @@
+use flight_controller::MotorMap;
 use nalgebra::{Matrix3, Rotation3, UnitQuaternion, Vector3};
 
 use crate::{
     BatteryModel, BatteryState, Drone, DroneFrameState, DroneModel, GyroModel, GyroState,
-    LowPassFilter, RotorModel, RotorState, RotorsState, SampleCurve, SamplePoint, SimulationFrame,
+    LowPassFilter, Noise, RotorModel, RotorState, RotorsState, SampleCurve, SamplePoint,
+    SimulationFrame, SimulationRates,
 };
 
+// The rotors are in Betaflight quad X order (rear right, front right, rear left, front left) with
+// props in, `rotor_dir` is the sign of the yaw factor in the mixer. `Drone::set_rotor_dirs` with
+// the directions of a props out mixer reverses them
 pub const PROP_BLADE_MESH_NAMES: [(&str, f64, Vector3<f64>); 4] = [
     (
         "prop_blade.001",
@@ -73,6 +78,8 @@ fn initial_simulation_frame() -> SimulationFrame {
         linear_velocity: Vector3::zeros(),
         angular_velocity: Vector3::new(0., 0., 0.),
         acceleration: Vector3::zeros(),
+        external_force: Vector3::zeros(),
+        wind: Vector3::zeros(),
     };
 
     let gyro_state = GyroState {
@@ -141,7 +148,7 @@ pub fn default_7in_4s_drone() -> Drone {
         inv_tensor: Matrix3::from_diagonal(&Vector3::new(750., 5150.0, 750.0)),
     };
 
-    let gyro_model = GyroModel {};
+    let gyro_model = GyroModel::default();
     let initial_frame = initial_simulation_frame();
 
     Drone {
@@ -151,5 +158,8 @@ pub fn default_7in_4s_drone() -> Drone {
         rotor_model,
         drone_model,
         gyro_model,
+        motor_map: MotorMap::default(),
+        rates: SimulationRates::default(),
+        noise: Noise::default(),
     }
 }
@@ -1,22 +1,55 @@
 pub mod default_drone;
+pub mod randomization;
 
 use derive_more::derive::{Deref, DerefMut};
-use flight_controller::{BatteryUpdate, GyroUpdate, MotorInput};
+use flight_controller::{BatteryUpdate, GyroUpdate, MotorInput, MotorMap};
 use nalgebra::{Matrix3, Rotation3, UnitQuaternion, Vector3};
 use rand::{Rng, SeedableRng, rngs::StdRng};
 use serde::{Deserialize, Serialize};
-use std::{cell::RefCell, f64::consts::PI, ops::Range};
+use std::{f64::consts::PI, ops::Range};
 
 pub const MAX_EFFECT_SPEED: f64 = 18.0;
 pub const AIR_RHO: f64 = 1.225;
 pub const GRAVITY: f64 = 9.81;
 
-thread_local! {
-    static RNG: RefCell<StdRng> = RefCell::new(StdRng::seed_from_u64(0));
+/// The random number generator of the simulated noise. Every drone and simulator has its own, so
+/// the same seed gives the same noise on whatever thread the simulation runs.
+#[derive(Debug, Clone)]
+pub struct Noise(StdRng);
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        Self(StdRng::seed_from_u64(seed))
+    }
+
+    pub fn gen_range(&mut self, range: Range<f64>) -> f64 {
+        self.0.gen_range(range)
+    }
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self::new(0)
+    }
 }
 
-pub fn rng_gen_range(range: Range<f64>) -> f64 {
-    RNG.with(|rng| rng.borrow_mut().gen_range(range))
+/// Everything that draws random numbers during an episode, each one gets its own stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeedStream {
+    SensorNoise,
+    LoopJitter,
+    InitialState,
+    Randomization,
+    Sticks,
+}
+
+/// Derives the seed of a stream from the seed of an episode with SplitMix64, so the streams of
+/// an episode do not draw the same numbers.
+pub fn sub_seed(seed: u64, stream: SeedStream) -> u64 {
+    let mut z = seed.wrapping_add((stream as u64 + 1).wrapping_mul(0x9e3779b97f4a7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
 }
 
 fn interpolate(a: f64, b: f64, i: f64) -> f64 {
@@ -88,6 +121,7 @@ trait DroneComponent {
         &self,
         current_frame: &SimulationFrame,
         next_frame: &mut SimulationFrame,
+        noise: &mut Noise,
         dt: f64,
     );
 }
@@ -141,6 +175,12 @@ pub struct DroneFrameState {
     pub linear_velocity: Vector3<f64>,
     pub angular_velocity: Vector3<f64>,
     pub acceleration: Vector3<f64>,
+    // a force from outside the drone in the world frame, e.g. the prop wash of another drone
+    #[serde(default)]
+    pub external_force: Vector3<f64>,
+    // the velocity of the air in the world frame, the drag and the props work against the air
+    #[serde(default)]
+    pub wind: Vector3<f64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -188,6 +228,7 @@ impl DroneComponent for BatteryModel {
         &self,
         current_frame: &SimulationFrame,
         next_frame: &mut SimulationFrame,
+        noise: &mut Noise,
         dt: f64,
     ) {
         let state = &current_frame.battery_state;
@@ -203,9 +244,13 @@ impl DroneComponent for BatteryModel {
 
         let v_sag = self.max_voltage_sag * power_factor_squared
             + (self.max_voltage_sag * charge_factor_inv * charge_factor_inv * power_factor_squared);
-        let bat_voltage_sag =
-            f64::clamp(bat_voltage - v_sag - rng_gen_range(-0.01..0.01), 0.0, 100.);
-        let m_a_min = f64::min(0.2, rng_gen_range(-0.125..0.375)) / f64::max(bat_voltage_sag, 0.01);
+        let bat_voltage_sag = f64::clamp(
+            bat_voltage - v_sag - noise.gen_range(-0.01..0.01),
+            0.0,
+            100.,
+        );
+        let m_a_min =
+            f64::min(0.2, noise.gen_range(-0.125..0.375)) / f64::max(bat_voltage_sag, 0.01);
         let current_sum: f64 = current_frame.rotors_state.iter().map(|s| s.current).sum();
         let currentm_as = f64::max(current_sum / 3.6, m_a_min);
         let capacity = state.capacity - currentm_as * dt;
@@ -267,13 +312,15 @@ impl DroneComponent for RotorModel {
         &self,
         current_frame: &SimulationFrame,
         next_frame: &mut SimulationFrame,
+        _noise: &mut Noise,
         dt: f64,
     ) {
+        let state = &current_frame.drone_frame_state;
         let vel_up = f64::max(
             0.,
             Vector3::dot(
-                &current_frame.drone_frame_state.linear_velocity,
-                &current_frame.drone_frame_state.rotation.matrix().column(0),
+                &(state.linear_velocity - state.wind),
+                &state.rotation.matrix().column(0),
             ),
         );
 
@@ -327,21 +374,21 @@ impl DroneComponent for DroneModel {
         &self,
         current_frame: &SimulationFrame,
         next_frame: &mut SimulationFrame,
+        _noise: &mut Noise,
         dt: f64,
     ) {
-        let mut sum_force = Vector3::new(0., -GRAVITY * self.mass, 0.);
+        let mut sum_force = Vector3::new(0., -GRAVITY * self.mass, 0.)
+            + current_frame.drone_frame_state.external_force;
         let mut sum_torque = Vector3::zeros();
 
         let rotation = current_frame.drone_frame_state.rotation;
-        let (linear_velocity_dir, speed) =
-            if current_frame.drone_frame_state.linear_velocity.lp_norm(1) > 0. {
-                let linear_velocity_dir =
-                    current_frame.drone_frame_state.linear_velocity.normalize();
-                let speed = current_frame.drone_frame_state.linear_velocity.norm();
-                (linear_velocity_dir, speed)
-            } else {
-                (Vector3::zeros(), 0.)
-            };
+        let air_velocity =
+            current_frame.drone_frame_state.linear_velocity - current_frame.drone_frame_state.wind;
+        let (linear_velocity_dir, speed) = if air_velocity.lp_norm(1) > 0. {
+            (air_velocity.normalize(), air_velocity.norm())
+        } else {
+            (Vector3::zeros(), 0.)
+        };
         let drag_dir =
             speed.powi(2) * linear_velocity_dir * 0.5 * AIR_RHO * self.frame_drag_constant;
 
@@ -397,6 +444,8 @@ impl DroneComponent for DroneModel {
             linear_velocity,
             angular_velocity,
             acceleration,
+            external_force: current_frame.drone_frame_state.external_force,
+            wind: current_frame.drone_frame_state.wind,
         };
     }
 }
@@ -414,9 +463,23 @@ impl DroneModel {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct GyroModel {
     // pub low_pass_filter: [LowPassFilter; 3],
+    // the readings are off by up to this much, in rad/s and m/s²
+    #[serde(default)]
+    pub gyro_noise: f64,
+    #[serde(default)]
+    pub accel_noise: f64,
+}
+
+// uniform noise on every axis, the noise generator is left alone without noise
+fn sensor_noise(noise: &mut Noise, amplitude: f64) -> Vector3<f64> {
+    if amplitude > 0. {
+        Vector3::from_fn(|_, _| noise.gen_range(-amplitude..amplitude))
+    } else {
+        Vector3::zeros()
+    }
 }
 
 impl DroneComponent for GyroModel {
@@ -424,6 +487,7 @@ impl DroneComponent for GyroModel {
         &self,
         current_frame: &SimulationFrame,
         next_frame: &mut SimulationFrame,
+        noise: &mut Noise,
         dt: f64,
     ) {
         let rotation = next_frame.drone_frame_state.rotation;
@@ -444,9 +508,11 @@ impl DroneComponent for GyroModel {
             dt,
             cutoff_freq,
         );
-        let angular_velocity =
-            rotation.transpose() * Vector3::new(gyro_vel_x, gyro_vel_y, gyro_vel_z);
-        let acceleration = rotation.transpose() * next_frame.drone_frame_state.acceleration;
+        let angular_velocity = rotation.transpose()
+            * Vector3::new(gyro_vel_x, gyro_vel_y, gyro_vel_z)
+            + sensor_noise(noise, self.gyro_noise);
+        let acceleration = rotation.transpose() * next_frame.drone_frame_state.acceleration
+            + sensor_noise(noise, self.accel_noise);
         next_frame.gyro_state = GyroState {
             rotation: UnitQuaternion::from(rotation),
             acceleration,
@@ -460,6 +526,35 @@ impl DroneComponent for GyroModel {
     }
 }
 
+/// How often the parts of the simulation run, in Hz. A physics step is the smallest unit of time,
+/// the other rates are rounded to a whole number of physics steps. The sensors are sampled every
+/// physics step unless their rate is set. The flight controller runs at its own scheduler rate
+/// unless `controller` is set, the flight log and the RC link are updated with every controller
+/// call unless their rate is set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimulationRates {
+    pub physics: u64,
+    pub gyro: Option<u64>,
+    pub accel: Option<u64>,
+    pub controller: Option<u64>,
+    pub logging: Option<u64>,
+    pub rc: Option<u64>,
+}
+
+impl Default for SimulationRates {
+    fn default() -> Self {
+        Self {
+            physics: 200_000,
+            gyro: None,
+            accel: None,
+            controller: None,
+            logging: None,
+            rc: None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Drone {
     // data
@@ -471,6 +566,18 @@ pub struct Drone {
     pub rotor_model: RotorModel,
     pub drone_model: DroneModel,
     pub gyro_model: GyroModel,
+
+    // which rotor each flight controller output drives
+    #[serde(default)]
+    pub motor_map: MotorMap,
+
+    // the simulation rates this drone is flown with, an experiment can override them
+    #[serde(default)]
+    pub rates: SimulationRates,
+
+    // the battery and sensor noise, it is not part of the config
+    #[serde(skip)]
+    pub noise: Noise,
 }
 
 impl Drone {
@@ -479,22 +586,37 @@ impl Drone {
         self.next_frame = initial_frame.clone();
     }
 
+    /// Sets the motor pwms from the flight controller outputs, the outputs are remapped to the
+    /// rotors with the motor map.
     pub fn set_motor_pwms(&mut self, pwms: MotorInput) {
+        let pwms = self.motor_map.to_rotors(pwms);
         let rotor_state = &mut self.current_frame.rotors_state;
         for i in 0..4 {
             rotor_state.0[i].pwm = pwms[i];
         }
     }
 
+    /// Sets the spin direction of the rotors, the directions are in flight controller output
+    /// order like `Mixer::quad_rotor_dirs` and are remapped to the rotors with the motor map.
+    pub fn set_rotor_dirs(&mut self, rotor_dirs: [f64; 4]) {
+        let rotor_dirs = self.motor_map.to_rotors(MotorInput { input: rotor_dirs });
+        for frame in [&mut self.current_frame, &mut self.next_frame] {
+            for (rotor, rotor_dir) in frame.rotors_state.0.iter_mut().zip(rotor_dirs.input) {
+                rotor.rotor_dir = rotor_dir;
+            }
+        }
+    }
+
     pub fn update(&mut self, dt: f64) {
+        let noise = &mut self.noise;
         self.battery_model
-            .set_new_state(&self.current_frame, &mut self.next_frame, dt);
+            .set_new_state(&self.current_frame, &mut self.next_frame, noise, dt);
         self.rotor_model
-            .set_new_state(&self.current_frame, &mut self.next_frame, dt);
+            .set_new_state(&self.current_frame, &mut self.next_frame, noise, dt);
         self.drone_model
-            .set_new_state(&self.current_frame, &mut self.next_frame, dt);
+            .set_new_state(&self.current_frame, &mut self.next_frame, noise, dt);
         self.gyro_model
-            .set_new_state(&self.current_frame, &mut self.next_frame, dt);
+            .set_new_state(&self.current_frame, &mut self.next_frame, noise, dt);
 
         std::mem::swap(&mut self.current_frame, &mut self.next_frame);
     }
@@ -511,19 +633,66 @@ impl Drone {
         }
     }
 
+    /// The motor pwms in flight controller output order
     pub fn motor_input(&self) -> MotorInput {
         let rotor_state = &self.current_frame.rotors_state;
-        MotorInput {
+        let rotor_pwms = MotorInput {
             input: [
                 rotor_state.0[0].pwm,
                 rotor_state.0[1].pwm,
                 rotor_state.0[2].pwm,
                 rotor_state.0[3].pwm,
             ],
-        }
+        };
+        self.motor_map.to_outputs(rotor_pwms)
     }
 
     pub fn position(&self) -> Vector3<f64> {
         self.current_frame.drone_frame_state.position
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{Noise, SeedStream, default_drone::default_7in_4s_drone, sub_seed};
+    use flight_controller::{Mixer, MixerType, PropDirection};
+
+    #[test]
+    fn seed_streams() {
+        let streams = [
+            SeedStream::SensorNoise,
+            SeedStream::LoopJitter,
+            SeedStream::InitialState,
+            SeedStream::Randomization,
+            SeedStream::Sticks,
+        ];
+        let seeds: Vec<_> = streams.iter().map(|stream| sub_seed(7, *stream)).collect();
+        for (i, seed) in seeds.iter().enumerate() {
+            assert!(!seeds[..i].contains(seed));
+            assert_ne!(*seed, 7);
+            assert_ne!(*seed, sub_seed(8, streams[i]));
+        }
+        assert_eq!(seeds[0], sub_seed(7, SeedStream::SensorNoise));
+    }
+
+    #[test]
+    fn props_out_yaw() {
+        let yaw_rate = |props| {
+            let mixer = Mixer::new(MixerType::QuadX, props);
+            let mut drone = default_7in_4s_drone();
+            drone.noise = Noise::new(0);
+            drone.set_rotor_dirs(mixer.quad_rotor_dirs().unwrap());
+            for _ in 0..200 {
+                drone.set_motor_pwms(mixer.mix_quad(0.3, 0., 0., 0.2).unwrap());
+                drone.update(0.001);
+            }
+            drone.current_frame.drone_frame_state.angular_velocity
+        };
+        let props_in = yaw_rate(PropDirection::PropsIn);
+        let props_out = yaw_rate(PropDirection::PropsOut);
+        // the drone yaws around its up axis
+        assert!(props_in[1].abs() > 0.1);
+        assert!((props_in[1] - props_out[1]).abs() < 1e-9);
+        assert!((props_in - props_out).norm() < 1e-4);
+    }
+}
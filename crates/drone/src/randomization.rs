@@ -0,0 +1,186 @@
+//! Domain randomisation. A `RandomizationSpec` perturbs the physical parameters of a drone, so
+//! that every episode is flown with a slightly different drone, battery, sensors and weather.
+
+use crate::Drone;
+use nalgebra::Vector3;
+use rand::{Rng, rngs::StdRng};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, f64::consts::PI};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "distribution", rename_all = "snake_case")]
+pub enum ParameterDistribution {
+    Fixed { value: f64 },
+    Uniform { min: f64, max: f64 },
+    Normal { mean: f64, std_dev: f64 },
+}
+
+impl ParameterDistribution {
+    pub fn sample(&self, rng: &mut StdRng) -> f64 {
+        match *self {
+            ParameterDistribution::Fixed { value } => value,
+            ParameterDistribution::Uniform { min, max } if min < max => rng.gen_range(min..max),
+            ParameterDistribution::Uniform { min, .. } => min,
+            ParameterDistribution::Normal { mean, std_dev } => {
+                // Box-Muller, 1 - u keeps the logarithm finite
+                let u: f64 = 1. - rng.r#gen::<f64>();
+                let v: f64 = rng.r#gen();
+                mean + std_dev * (-2. * u.ln()).sqrt() * (2. * PI * v).cos()
+            }
+        }
+    }
+}
+
+/// Which parameters are randomized and how. The physical parameters are factors on the values of
+/// the drone config, the sensor noise and the wind are absolute. Parameters without a
+/// distribution are left as configured.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RandomizationSpec {
+    pub mass: Option<ParameterDistribution>,
+    pub inertia: Option<ParameterDistribution>,
+    pub motor_kv: Option<ParameterDistribution>,
+    pub motor_resistance: Option<ParameterDistribution>,
+    pub thrust: Option<ParameterDistribution>,
+    pub battery_capacity: Option<ParameterDistribution>,
+    pub gyro_noise: Option<ParameterDistribution>, // rad/s
+    pub accel_noise: Option<ParameterDistribution>, // m/s²
+    // the wind blows horizontally into a random direction
+    pub wind_speed: Option<ParameterDistribution>, // m/s
+}
+
+impl RandomizationSpec {
+    /// Perturbs the drone and returns its parameters as they were sampled. The same seeded rng
+    /// samples the same drone.
+    pub fn apply(&self, rng: &mut StdRng, drone: &mut Drone) -> BTreeMap<String, f64> {
+        // none of the parameters make sense below zero
+        let mut sample = |distribution: Option<ParameterDistribution>| {
+            distribution.map(|distribution| distribution.sample(rng).max(0.))
+        };
+
+        if let Some(factor) = sample(self.mass) {
+            drone.drone_model.mass *= factor;
+        }
+        if let Some(factor) = sample(self.inertia) {
+            drone.drone_model.inv_tensor /= factor.max(f64::EPSILON);
+        }
+        let rotor_model = &mut drone.rotor_model;
+        if let Some(factor) = sample(self.motor_kv) {
+            rotor_model.motor_kv *= factor.max(f64::EPSILON);
+        }
+        if let Some(factor) = sample(self.motor_resistance) {
+            rotor_model.motor_r *= factor.max(f64::EPSILON);
+        }
+        if let Some(factor) = sample(self.thrust) {
+            rotor_model.prop_thrust_factor *= factor;
+            rotor_model.prop_a_factor *= factor;
+        }
+        if let Some(factor) = sample(self.battery_capacity) {
+            let battery_model = &mut drone.battery_model;
+            battery_model.quad_bat_capacity *= factor;
+            battery_model.quad_bat_capacity_charged *= factor;
+            drone.current_frame.battery_state.capacity *= factor;
+            drone.next_frame.battery_state.capacity *= factor;
+        }
+        if let Some(noise) = sample(self.gyro_noise) {
+            drone.gyro_model.gyro_noise = noise;
+        }
+        if let Some(noise) = sample(self.accel_noise) {
+            drone.gyro_model.accel_noise = noise;
+        }
+        if let Some(speed) = sample(self.wind_speed) {
+            let direction = rng.gen_range(0. ..2. * PI);
+            let wind = Vector3::new(direction.cos(), 0., direction.sin()) * speed;
+            drone.current_frame.drone_frame_state.wind = wind;
+            drone.next_frame.drone_frame_state.wind = wind;
+        }
+        parameters(drone)
+    }
+}
+
+/// The parameters a `RandomizationSpec` can change, as the drone has them
+pub fn parameters(drone: &Drone) -> BTreeMap<String, f64> {
+    let inertia = drone
+        .drone_model
+        .inv_tensor
+        .try_inverse()
+        .map(|tensor| tensor.diagonal())
+        .unwrap_or_default();
+    let wind = drone.current_frame.drone_frame_state.wind;
+    [
+        ("mass", drone.drone_model.mass),
+        ("inertia_x", inertia.x),
+        ("inertia_y", inertia.y),
+        ("inertia_z", inertia.z),
+        ("motor_kv", drone.rotor_model.motor_kv),
+        ("motor_r", drone.rotor_model.motor_r),
+        ("prop_a_factor", drone.rotor_model.prop_a_factor),
+        ("battery_capacity", drone.battery_model.quad_bat_capacity),
+        ("gyro_noise", drone.gyro_model.gyro_noise),
+        ("accel_noise", drone.gyro_model.accel_noise),
+        ("wind_x", wind.x),
+        ("wind_z", wind.z),
+        ("wind_speed", wind.norm()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        default_drone::default_7in_4s_drone,
+        randomization::{ParameterDistribution, RandomizationSpec, parameters},
+    };
+    use rand::{SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn sampled_drones() {
+        let spec = RandomizationSpec {
+            mass: Some(ParameterDistribution::Uniform { min: 0.9, max: 1.1 }),
+            thrust: Some(ParameterDistribution::Normal {
+                mean: 1.,
+                std_dev: 0.05,
+            }),
+            wind_speed: Some(ParameterDistribution::Fixed { value: 3. }),
+            ..Default::default()
+        };
+        let drone = default_7in_4s_drone();
+        let sample = |seed| {
+            let mut drone = drone.clone();
+            let parameters = spec.apply(&mut StdRng::seed_from_u64(seed), &mut drone);
+            (drone, parameters)
+        };
+
+        let (randomized, sampled) = sample(1);
+        assert_eq!(sampled, sample(1).1);
+        assert_ne!(sampled, sample(2).1);
+        let mass = randomized.drone_model.mass / drone.drone_model.mass;
+        assert!((0.9..1.1).contains(&mass));
+        assert!((sampled["wind_speed"] - 3.).abs() < 1e-9);
+        // untouched parameters stay as configured
+        assert_eq!(sampled["motor_kv"], parameters(&drone)["motor_kv"]);
+        assert_eq!(
+            RandomizationSpec::default().apply(&mut StdRng::seed_from_u64(1), &mut drone.clone()),
+            parameters(&drone)
+        );
+    }
+
+    #[test]
+    fn wind_drift() {
+        let mut drone = default_7in_4s_drone();
+        let spec = RandomizationSpec {
+            wind_speed: Some(ParameterDistribution::Fixed { value: 10. }),
+            ..Default::default()
+        };
+        spec.apply(&mut StdRng::seed_from_u64(0), &mut drone);
+        let wind = drone.current_frame.drone_frame_state.wind;
+        for _ in 0..1000 {
+            drone.update(0.001);
+        }
+        // the drag pulls the falling drone along with the wind
+        let velocity = drone.current_frame.drone_frame_state.linear_velocity;
+        assert!(velocity.dot(&wind) > 0.);
+    }
+}
@@ -0,0 +1,347 @@
+//! Arming and RC link failsafe. Loosely follows Betaflight: the drone only arms when the arm switch
+//! is flipped with the throttle low and the drone level, and when the RC link is lost the drone
+//! first holds the last sticks, then either lands or drops (see `failsafe.c` in Betaflight).
+
+use crate::{frames::tilt, AuxRole, ChannelMap, Channels};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FailsafeStage {
+    // the last received sticks are held, the link can still recover
+    Hold,
+    // sticks centered with the landing throttle in angle mode
+    Land,
+    // motors off, the arm switch has to be cycled once the link is back
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArmingState {
+    #[default]
+    Disarmed,
+    Armed,
+    Failsafe(FailsafeStage),
+}
+
+impl ArmingState {
+    /// Whether the motors are allowed to spin
+    pub fn motors_enabled(&self) -> bool {
+        match self {
+            ArmingState::Armed
+            | ArmingState::Failsafe(FailsafeStage::Hold)
+            | ArmingState::Failsafe(FailsafeStage::Land) => true,
+            ArmingState::Disarmed | ArmingState::Failsafe(FailsafeStage::Drop) => false,
+        }
+    }
+}
+
+/// The reason the last arming attempt was refused, like the arming disable flags in Betaflight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ArmingDisabled {
+    ThrottleNotLow,
+    NotLevel,
+    NoRcLink,
+    // the arm switch was already on, it has to be switched off and on again
+    ArmSwitch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FailsafeProcedure {
+    Land,
+    Drop,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FailsafeConfig {
+    // the link is lost if no frame arrived for this long
+    pub rc_timeout: Duration,
+    // `failsafe_delay`, how long the sticks are held before the procedure starts
+    pub hold_time: Duration,
+    // `failsafe_procedure`
+    pub procedure: FailsafeProcedure,
+    // `failsafe_throttle` as a stick position
+    pub landing_throttle: f64,
+    // `failsafe_off_delay`, the motors are stopped after landing for this long
+    pub landing_time: Duration,
+}
+
+impl Default for FailsafeConfig {
+    // Betaflight 4.x defaults, except the landing throttle which is 1300us instead of 1000us
+    fn default() -> Self {
+        Self {
+            rc_timeout: Duration::from_millis(100),
+            hold_time: Duration::from_millis(1500),
+            procedure: FailsafeProcedure::Drop,
+            landing_throttle: -0.4,
+            landing_time: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArmingConfig {
+    pub channel_map: ChannelMap,
+    // the throttle stick has to be below this to arm
+    pub max_arm_throttle: f64,
+    // `small_angle`, in rad
+    pub max_arm_angle: f64,
+    pub failsafe: FailsafeConfig,
+}
+
+impl Default for ArmingConfig {
+    fn default() -> Self {
+        Self {
+            channel_map: ChannelMap::default(),
+            max_arm_throttle: -0.9,
+            max_arm_angle: 25f64.to_radians(),
+            failsafe: FailsafeConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Arming {
+    pub config: ArmingConfig,
+    state: ArmingState,
+    // time and value of the last received RC frame
+    last_frame: Option<(Duration, Channels)>,
+    // when the current failsafe stage started
+    stage_start: Duration,
+    // the arm switch has been off since the last arming attempt
+    arm_switch_cycled: bool,
+    disabled: Option<ArmingDisabled>,
+}
+
+impl Default for Arming {
+    fn default() -> Self {
+        Self::new(ArmingConfig::default())
+    }
+}
+
+impl Arming {
+    pub fn new(config: ArmingConfig) -> Self {
+        Self {
+            config,
+            state: ArmingState::Disarmed,
+            last_frame: None,
+            stage_start: Duration::ZERO,
+            arm_switch_cycled: true,
+            disabled: None,
+        }
+    }
+
+    pub fn state(&self) -> ArmingState {
+        self.state
+    }
+
+    /// Why the last arming attempt failed, None if it did not
+    pub fn arming_disabled(&self) -> Option<ArmingDisabled> {
+        self.disabled
+    }
+
+    pub fn rc_link_ok(&self, time: Duration) -> bool {
+        self.last_frame
+            .is_some_and(|(frame_time, _)| time <= frame_time + self.config.failsafe.rc_timeout)
+    }
+
+    fn check_arming(&self, channels: &Channels, rotation: [f64; 4]) -> Option<ArmingDisabled> {
+        if !self.arm_switch_cycled {
+            Some(ArmingDisabled::ArmSwitch)
+        } else if channels.throttle > self.config.max_arm_throttle {
+            Some(ArmingDisabled::ThrottleNotLow)
+        } else if tilt(rotation) > self.config.max_arm_angle {
+            Some(ArmingDisabled::NotLevel)
+        } else {
+            None
+        }
+    }
+
+    fn enter(&mut self, state: ArmingState, time: Duration) {
+        self.state = state;
+        self.stage_start = time;
+    }
+
+    /// Advances the state machine. `frame` is None if no RC frame arrived since the last update.
+    /// Returns the channels that should be handed to the flight controller.
+    pub fn update(
+        &mut self,
+        time: Duration,
+        frame: Option<Channels>,
+        rotation: [f64; 4],
+    ) -> Channels {
+        if let Some(channels) = frame {
+            self.last_frame = Some((time, channels));
+        }
+        let link_ok = self.rc_link_ok(time);
+        let map = &self.config.channel_map;
+        let failsafe = &self.config.failsafe;
+        let last_channels = self.last_frame.map(|(_, channels)| channels);
+        let arm_switch = link_ok && last_channels.is_some_and(|c| c.switch_on(map, AuxRole::Arm));
+        let in_stage = time.saturating_sub(self.stage_start);
+
+        match self.state {
+            ArmingState::Disarmed => {
+                if !arm_switch {
+                    self.arm_switch_cycled = link_ok;
+                    if !link_ok {
+                        self.disabled = Some(ArmingDisabled::NoRcLink);
+                    }
+                } else if let Some(channels) = last_channels {
+                    self.disabled = self.check_arming(&channels, rotation);
+                    self.arm_switch_cycled = false;
+                    if self.disabled.is_none() {
+                        self.enter(ArmingState::Armed, time);
+                    }
+                }
+            }
+            ArmingState::Armed => {
+                if !link_ok {
+                    self.enter(ArmingState::Failsafe(FailsafeStage::Hold), time);
+                } else if !arm_switch {
+                    self.arm_switch_cycled = true;
+                    self.enter(ArmingState::Disarmed, time);
+                }
+            }
+            ArmingState::Failsafe(FailsafeStage::Hold) => {
+                if link_ok {
+                    let state = if arm_switch {
+                        ArmingState::Armed
+                    } else {
+                        ArmingState::Disarmed
+                    };
+                    self.enter(state, time);
+                } else if in_stage > failsafe.hold_time {
+                    let stage = match failsafe.procedure {
+                        FailsafeProcedure::Land => FailsafeStage::Land,
+                        FailsafeProcedure::Drop => FailsafeStage::Drop,
+                    };
+                    self.enter(ArmingState::Failsafe(stage), time);
+                }
+            }
+            ArmingState::Failsafe(FailsafeStage::Land) => {
+                if link_ok && arm_switch {
+                    self.enter(ArmingState::Armed, time);
+                } else if in_stage > failsafe.landing_time {
+                    self.enter(ArmingState::Failsafe(FailsafeStage::Drop), time);
+                }
+            }
+            ArmingState::Failsafe(FailsafeStage::Drop) => {
+                if link_ok {
+                    self.arm_switch_cycled = !arm_switch;
+                    self.enter(ArmingState::Disarmed, time);
+                }
+            }
+        }
+
+        self.flight_controller_channels(last_channels)
+    }
+
+    fn flight_controller_channels(&self, last_channels: Option<Channels>) -> Channels {
+        let map = &self.config.channel_map;
+        let channels = match self.state {
+            ArmingState::Failsafe(FailsafeStage::Land) => Channels {
+                throttle: self.config.failsafe.landing_throttle,
+                roll: 0.,
+                pitch: 0.,
+                yaw: 0.,
+                ..last_channels.unwrap_or_default()
+            }
+            .with_switch(map, AuxRole::Angle, true),
+            ArmingState::Failsafe(FailsafeStage::Drop) => Channels::default(),
+            _ => last_channels.unwrap_or_default(),
+        };
+        // we decide whether the flight controller is armed
+        channels.with_switch(map, AuxRole::Arm, self.state.motors_enabled())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        arming::{
+            Arming, ArmingConfig, ArmingDisabled, ArmingState, FailsafeConfig, FailsafeProcedure,
+            FailsafeStage,
+        },
+        AuxRole, ChannelMap, Channels,
+    };
+    use std::time::Duration;
+
+    const LEVEL: [f64; 4] = [0., 0., 0., 1.];
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn armed_channels(throttle: f64) -> Channels {
+        Channels {
+            throttle,
+            ..Default::default()
+        }
+        .with_switch(&ChannelMap::default(), AuxRole::Arm, true)
+    }
+
+    #[test]
+    fn pre_arm_checks() {
+        let mut arming = Arming::default();
+        arming.update(ms(0), Some(armed_channels(0.)), LEVEL);
+        assert_eq!(arming.state(), ArmingState::Disarmed);
+        assert_eq!(
+            arming.arming_disabled(),
+            Some(ArmingDisabled::ThrottleNotLow)
+        );
+
+        // lowering the throttle is not enough, the switch has to be cycled
+        arming.update(ms(10), Some(armed_channels(-1.)), LEVEL);
+        assert_eq!(arming.arming_disabled(), Some(ArmingDisabled::ArmSwitch));
+        arming.update(ms(20), Some(Channels::default()), LEVEL);
+        let channels = arming.update(ms(30), Some(armed_channels(-1.)), LEVEL);
+        assert_eq!(arming.state(), ArmingState::Armed);
+        assert!(channels.switch_on(&ChannelMap::default(), AuxRole::Arm));
+
+        let mut upside_down = Arming::default();
+        upside_down.update(ms(0), Some(armed_channels(-1.)), [1., 0., 0., 0.]);
+        assert_eq!(
+            upside_down.arming_disabled(),
+            Some(ArmingDisabled::NotLevel)
+        );
+    }
+
+    #[test]
+    fn failsafe_stages() {
+        let config = ArmingConfig {
+            failsafe: FailsafeConfig {
+                procedure: FailsafeProcedure::Land,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut arming = Arming::new(config);
+        arming.update(ms(0), Some(armed_channels(-1.)), LEVEL);
+        arming.update(ms(10), Some(armed_channels(0.5)), LEVEL);
+        assert_eq!(arming.state(), ArmingState::Armed);
+
+        // the sticks are held while the link might still come back
+        let held = arming.update(ms(200), None, LEVEL);
+        assert_eq!(arming.state(), ArmingState::Failsafe(FailsafeStage::Hold));
+        assert_eq!(held.throttle, 0.5);
+        arming.update(ms(250), Some(armed_channels(0.5)), LEVEL);
+        assert_eq!(arming.state(), ArmingState::Armed);
+
+        arming.update(ms(400), None, LEVEL);
+        let landing = arming.update(ms(2000), None, LEVEL);
+        assert_eq!(arming.state(), ArmingState::Failsafe(FailsafeStage::Land));
+        assert_eq!(landing.throttle, -0.4);
+        assert!(landing.switch_on(&ChannelMap::default(), AuxRole::Angle));
+
+        let dropped = arming.update(ms(3100), None, LEVEL);
+        assert_eq!(arming.state(), ArmingState::Failsafe(FailsafeStage::Drop));
+        assert!(!dropped.switch_on(&ChannelMap::default(), AuxRole::Arm));
+
+        // once the link is back the arm switch still has to be cycled
+        arming.update(ms(3200), Some(armed_channels(-1.)), LEVEL);
+        arming.update(ms(3210), Some(armed_channels(-1.)), LEVEL);
+        assert_eq!(arming.state(), ArmingState::Disarmed);
+        assert_eq!(arming.arming_disabled(), Some(ArmingDisabled::ArmSwitch));
+    }
+}
@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The maximum number of RC channels we carry around. The first four are the sticks, the rest are
+/// AUX channels (AUX1 - AUX12).
+pub const MAX_CHANNELS: usize = 16;
+pub const AUX_CHANNELS: usize = MAX_CHANNELS - 4;
+
+/// A switch is considered to be on if the channel is above this value. Betaflight mode ranges are
+/// usually set up as 1700-2100us, which is 0.4 and up in our [-1, 1] channel range.
+pub const SWITCH_ON_THRESHOLD: f64 = 0.4;
+
+fn default_aux() -> [f64; AUX_CHANNELS] {
+    [-1.; AUX_CHANNELS]
+}
+
+// each channel between -1 and 1
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Channels {
+    pub throttle: f64,
+    pub roll: f64,
+    pub pitch: f64,
+    pub yaw: f64,
+    // older logs do not have aux channels, those are treated as all switches low
+    #[serde(default = "default_aux")]
+    pub aux: [f64; AUX_CHANNELS],
+}
+
+impl Default for Channels {
+    fn default() -> Self {
+        Self {
+            throttle: -1.,
+            roll: 0.,
+            pitch: 0.,
+            yaw: 0.,
+            aux: default_aux(),
+        }
+    }
+}
+
+/// The roles that an AUX channel can have. These should match the modes that are set up in the
+/// Betaflight configuration, otherwise flipping the switch will do nothing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AuxRole {
+    Arm,
+    Angle,
+    Horizon,
+    Turtle,
+    Beeper,
+}
+
+/// A single RC channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RcChannel {
+    Throttle,
+    Roll,
+    Pitch,
+    Yaw,
+    Aux(usize), // 0 is AUX1
+}
+
+/// Describes how our channels are handed to Betaflight and which AUX channel carries which switch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelMap {
+    // the channel at position i is sent as the i-th Betaflight channel
+    pub order: [RcChannel; MAX_CHANNELS],
+    pub aux_roles: HashMap<AuxRole, usize>,
+}
+
+impl Default for ChannelMap {
+    // AETR1234 with the arm switch on AUX1, angle on AUX2, beeper on AUX3 and turtle on AUX4
+    fn default() -> Self {
+        let aux_roles = HashMap::from([
+            (AuxRole::Arm, 0),
+            (AuxRole::Angle, 1),
+            (AuxRole::Beeper, 2),
+            (AuxRole::Turtle, 3),
+        ]);
+        Self::from_bf_map("AETR").unwrap().with_aux_roles(aux_roles)
+    }
+}
+
+impl ChannelMap {
+    /// Parses a Betaflight style channel map (the argument of the `map` CLI command), for example
+    /// `AETR1234` or `TAER1234`. Only the stick letters are significant, the AUX channels always
+    /// follow the sticks in order.
+    pub fn from_bf_map(map: &str) -> Option<Self> {
+        let sticks = map
+            .chars()
+            .take(4)
+            .map(|c| match c.to_ascii_uppercase() {
+                'A' => Some(RcChannel::Roll),
+                'E' => Some(RcChannel::Pitch),
+                'T' => Some(RcChannel::Throttle),
+                'R' => Some(RcChannel::Yaw),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if sticks.len() != 4 || (1..4).any(|i| sticks[..i].contains(&sticks[i])) {
+            return None;
+        }
+        let order = std::array::from_fn(|i| {
+            if i < 4 {
+                sticks[i]
+            } else {
+                RcChannel::Aux(i - 4)
+            }
+        });
+        Some(Self {
+            order,
+            aux_roles: HashMap::new(),
+        })
+    }
+
+    pub fn with_aux_roles(self, aux_roles: HashMap<AuxRole, usize>) -> Self {
+        Self { aux_roles, ..self }
+    }
+
+    /// The AUX channel index that is assigned to a role, if any
+    pub fn aux_index(&self, role: AuxRole) -> Option<usize> {
+        self.aux_roles
+            .get(&role)
+            .copied()
+            .filter(|idx| *idx < AUX_CHANNELS)
+    }
+}
+
+impl Channels {
+    pub fn channel(&self, channel: RcChannel) -> f64 {
+        match channel {
+            RcChannel::Throttle => self.throttle,
+            RcChannel::Roll => self.roll,
+            RcChannel::Pitch => self.pitch,
+            RcChannel::Yaw => self.yaw,
+            RcChannel::Aux(idx) => self.aux.get(idx).copied().unwrap_or(-1.),
+        }
+    }
+
+    /// Returns the value of the AUX channel that the role is assigned to.
+    pub fn aux_value(&self, map: &ChannelMap, role: AuxRole) -> Option<f64> {
+        map.aux_index(role).map(|idx| self.aux[idx])
+    }
+
+    pub fn switch_on(&self, map: &ChannelMap, role: AuxRole) -> bool {
+        self.aux_value(map, role)
+            .is_some_and(|value| value > SWITCH_ON_THRESHOLD)
+    }
+
+    /// Flips the switch assigned to the role. Does nothing if the role is not mapped.
+    pub fn set_switch(&mut self, map: &ChannelMap, role: AuxRole, on: bool) {
+        if let Some(idx) = map.aux_index(role) {
+            self.aux[idx] = if on { 1. } else { -1. };
+        }
+    }
+
+    pub fn with_switch(mut self, map: &ChannelMap, role: AuxRole, on: bool) -> Self {
+        self.set_switch(map, role, on);
+        self
+    }
+
+    /// Orders the channels the way Betaflight expects them using the default channel map.
+    pub fn to_bf_channels(&self) -> [f64; MAX_CHANNELS] {
+        self.to_bf_channels_with_map(&ChannelMap::default())
+    }
+
+    pub fn to_bf_channels_with_map(&self, map: &ChannelMap) -> [f64; MAX_CHANNELS] {
+        map.order.map(|channel| self.channel(channel))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::channels::{AuxRole, ChannelMap, Channels, RcChannel};
+
+    #[test]
+    fn bf_map_ordering() {
+        let channels = Channels {
+            throttle: -0.5,
+            roll: 0.1,
+            pitch: 0.2,
+            yaw: 0.3,
+            ..Default::default()
+        }
+        .with_switch(&ChannelMap::default(), AuxRole::Arm, true);
+
+        let aetr = channels.to_bf_channels();
+        assert_eq!(aetr[..5], [0.1, 0.2, -0.5, 0.3, 1.]);
+
+        let taer = channels.to_bf_channels_with_map(&ChannelMap::from_bf_map("TAER1234").unwrap());
+        assert_eq!(taer[..5], [-0.5, 0.1, 0.2, 0.3, 1.]);
+
+        assert!(ChannelMap::from_bf_map("AATR").is_none());
+        assert!(ChannelMap::from_bf_map("AET").is_none());
+        assert_eq!(ChannelMap::default().order[15], RcChannel::Aux(11));
+    }
+}
@@ -1,3 +1,6 @@
 // pub mod bf_controller;
 pub mod null_controller;
+pub mod pid_controller;
+pub mod shadow;
+pub mod supervisor;
 // pub mod res_controller;
@@ -1,20 +1,32 @@
 use std::time::Duration;
 
-use crate::{FlightController, MotorInput};
+use crate::{ControllerState, FlightController, InitError, MotorInput};
 
 #[derive(Debug)]
 pub struct NullController {
     scheduler_delta: Duration,
 }
 
+// the null controller has no state, the marker only tells its snapshots apart from the others
+#[derive(Debug, Clone, Copy)]
+struct NullState;
+
 impl FlightController for NullController {
-    fn init(&self) {}
+    fn init(&self) -> Result<(), InitError> {
+        Ok(())
+    }
     fn update(&self, _: f64, _: crate::FlightControllerUpdate) -> crate::MotorInput {
         MotorInput::default()
     }
     fn scheduler_delta(&self) -> std::time::Duration {
         self.scheduler_delta
     }
+    fn snapshot(&self) -> Option<ControllerState> {
+        Some(Box::new(NullState))
+    }
+    fn restore(&self, state: &ControllerState) -> bool {
+        state.is::<NullState>()
+    }
 }
 
 impl Default for NullController {
@@ -24,3 +36,21 @@ impl Default for NullController {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        controllers::{null_controller::NullController, pid_controller::PidController},
+        ControllerState, FlightController,
+    };
+
+    #[test]
+    fn snapshot_kind() {
+        let controller = NullController::default();
+        let state = controller.snapshot().unwrap();
+        assert!(controller.restore(&state));
+        assert!(!controller.restore(&(Box::new(()) as ControllerState)));
+        let pid_state = PidController::default().snapshot().unwrap();
+        assert!(!controller.restore(&pid_state));
+    }
+}
@@ -0,0 +1,212 @@
+use std::{sync::Mutex, time::Duration};
+
+use crate::{
+    mixer::{Mixer, MixerError},
+    rates::RateProfile,
+    ControllerState, FlightController, FlightControllerUpdate, InitError, MotorInput,
+};
+
+// keeps the integral from winding up while the drone is on the ground or stuck
+const I_TERM_LIMIT: f64 = 0.5;
+
+/// Per axis gains in roll, pitch, yaw order. The output of the controller is a mixer command per
+/// rad/s of rate error.
+#[derive(Debug, Clone, Copy)]
+pub struct PidGains {
+    pub p: [f64; 3],
+    pub i: [f64; 3],
+    pub d: [f64; 3],
+}
+
+impl Default for PidGains {
+    // tuned for the default 7in drone
+    fn default() -> Self {
+        Self {
+            p: [0.04, 0.04, 0.1],
+            i: [0.2, 0.2, 0.3],
+            d: [0.0004, 0.0004, 0.],
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct PidState {
+    integral: [f64; 3],
+    prev_rates: [f64; 3],
+}
+
+/// The gyro measures the body rates with y pointing up. This converts them into the roll, pitch
+/// and yaw rates in the same sense as the mixer commands, so a positive roll command increases the
+/// roll rate.
+pub fn body_rates(angular_velocity: [f64; 3]) -> [f64; 3] {
+    [
+        -angular_velocity[2],
+        -angular_velocity[0],
+        angular_velocity[1],
+    ]
+}
+
+/// A simple native rate controller (acro mode). The sticks are converted into desired rates with
+/// the rate profile and the PID outputs are mixed into motor outputs.
+#[derive(Debug)]
+pub struct PidController {
+    pub rate_profile: RateProfile,
+    pub mixer: Mixer,
+    pub gains: PidGains,
+    // the lowest motor output while running, same as `dshot_idle_value` in Betaflight
+    pub motor_idle: f64,
+    scheduler_delta: Duration,
+    state: Mutex<PidState>,
+}
+
+impl Default for PidController {
+    fn default() -> Self {
+        Self {
+            rate_profile: RateProfile::default(),
+            mixer: Mixer::default(),
+            gains: PidGains::default(),
+            motor_idle: 0.055,
+            scheduler_delta: Duration::from_micros(250),
+            state: Mutex::new(PidState::default()),
+        }
+    }
+}
+
+impl PidController {
+    /// Fails if the mixer can not drive a quad
+    pub fn new(
+        rate_profile: RateProfile,
+        mixer: Mixer,
+        gains: PidGains,
+    ) -> Result<Self, MixerError> {
+        mixer.quad_rotor_dirs()?;
+        Ok(Self {
+            rate_profile,
+            mixer,
+            gains,
+            ..Default::default()
+        })
+    }
+}
+
+impl FlightController for PidController {
+    fn init(&self) -> Result<(), InitError> {
+        *self.state.lock().unwrap() = PidState::default();
+        Ok(())
+    }
+
+    fn update(&self, delta_time: f64, update: FlightControllerUpdate) -> MotorInput {
+        let rates = body_rates(update.gyro_update.angular_velocity);
+        let setpoints = self.rate_profile.desired_rates(&update.channels);
+        let throttle = self.rate_profile.throttle(update.channels.throttle);
+
+        let mut state = self.state.lock().unwrap();
+        let mut commands = [0.; 3];
+        for axis in 0..3 {
+            let error = setpoints[axis] - rates[axis];
+            // D on the measurement, so stick movements do not kick the motors
+            let derivative = if delta_time > 0. {
+                state.integral[axis] = f64::clamp(
+                    state.integral[axis] + error * delta_time,
+                    -I_TERM_LIMIT,
+                    I_TERM_LIMIT,
+                );
+                -(rates[axis] - state.prev_rates[axis]) / delta_time
+            } else {
+                0.
+            };
+            state.prev_rates[axis] = rates[axis];
+            commands[axis] = self.gains.p[axis] * error
+                + self.gains.i[axis] * state.integral[axis]
+                + self.gains.d[axis] * derivative;
+        }
+
+        // `new` only takes quad mixers
+        let motors = self
+            .mixer
+            .mix_quad(throttle, commands[0], commands[1], commands[2])
+            .unwrap_or(MotorInput { input: [0.; 4] });
+        MotorInput {
+            input: motors
+                .input
+                .map(|motor| self.motor_idle + motor * (1. - self.motor_idle)),
+        }
+    }
+
+    fn scheduler_delta(&self) -> Duration {
+        self.scheduler_delta
+    }
+
+    fn snapshot(&self) -> Option<ControllerState> {
+        Some(Box::new(self.state.lock().unwrap().clone()))
+    }
+
+    fn restore(&self, state: &ControllerState) -> bool {
+        let Some(state) = state.downcast_ref::<PidState>() else {
+            return false;
+        };
+        *self.state.lock().unwrap() = state.clone();
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        controllers::pid_controller::{body_rates, PidController},
+        Channels, FlightController, FlightControllerUpdate, GyroUpdate,
+    };
+
+    fn update(roll: f64, angular_velocity: [f64; 3]) -> FlightControllerUpdate {
+        FlightControllerUpdate {
+            channels: Channels {
+                roll,
+                ..Default::default()
+            },
+            gyro_update: GyroUpdate {
+                angular_velocity,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rate_control() {
+        let controller = PidController::default();
+        controller.init().unwrap();
+        // centered sticks and no rotation, all motors get the same output
+        let level = controller.update(0.001, update(0., [0.; 3]));
+        assert!(level.input.iter().all(|motor| *motor == level[0]));
+        assert!(level[0] >= controller.motor_idle);
+
+        // rolling right speeds up the left motors
+        let roll = controller.update(0.001, update(0.5, [0.; 3]));
+        assert!(roll[2] > roll[0] && roll[3] > roll[1]);
+        // already rolling faster than asked, the controller brakes
+        controller.init().unwrap();
+        let [roll_rate, ..] = controller
+            .rate_profile
+            .desired_rates(&update(0.5, [0.; 3]).channels);
+        let braking = controller.update(0.001, update(0.5, [0., 0., -2. * roll_rate]));
+        assert_eq!(body_rates([0., 0., -2. * roll_rate])[0], 2. * roll_rate);
+        assert!(braking[2] < braking[0]);
+
+        // the integral is bounded and a snapshot restores it
+        for _ in 0..10_000 {
+            controller.update(0.001, update(1., [0.; 3]));
+        }
+        let snapshot = controller.snapshot().unwrap();
+        let saturated = controller.update(0.001, update(1., [0.; 3]));
+        assert!(saturated
+            .input
+            .iter()
+            .all(|motor| (0. ..=1.).contains(motor)));
+        controller.init().unwrap();
+        assert!(controller.restore(&snapshot));
+        assert_eq!(
+            controller.update(0.001, update(1., [0.; 3])).input,
+            saturated.input
+        );
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    controllers::supervisor::Breach, ControllerState, FlightController, FlightControllerUpdate,
+    InitError, MotorInput, ShadowOutput,
+};
+
+/// Flies the drone with the primary controller, while the shadow controllers get the exact same
+/// updates. The outputs of the shadows are only recorded, so a controller can be evaluated on the
+/// closed loop data of another one without the risk of crashing. The shadows run at the rate of
+/// the primary controller.
+pub struct ShadowController {
+    pub primary: Arc<dyn FlightController>,
+    pub shadows: Vec<(String, Arc<dyn FlightController>)>,
+    last_outputs: Mutex<Vec<ShadowOutput>>,
+}
+
+// the shadows in the order they were added
+struct ShadowSnapshot {
+    primary: ControllerState,
+    shadows: Vec<ControllerState>,
+    last_outputs: Vec<ShadowOutput>,
+}
+
+impl ShadowController {
+    pub fn new(primary: Arc<dyn FlightController>) -> Self {
+        Self {
+            primary,
+            shadows: vec![],
+            last_outputs: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn with_shadow(
+        mut self,
+        controller_id: impl Into<String>,
+        controller: Arc<dyn FlightController>,
+    ) -> Self {
+        self.shadows.push((controller_id.into(), controller));
+        self
+    }
+}
+
+impl FlightController for ShadowController {
+    fn init(&self) -> Result<(), InitError> {
+        self.primary.init()?;
+        for (_, shadow) in self.shadows.iter() {
+            shadow.init()?;
+        }
+        Ok(())
+    }
+
+    fn update(&self, delta_time: f64, update: FlightControllerUpdate) -> MotorInput {
+        let motor_input = self.primary.update(delta_time, update);
+        // nested shadow controllers are recorded as well
+        let mut outputs = self.primary.shadow_outputs();
+        for (controller_id, shadow) in self.shadows.iter() {
+            outputs.push(ShadowOutput {
+                controller_id: controller_id.clone(),
+                motor_input: shadow.update(delta_time, update),
+            });
+        }
+        *self.last_outputs.lock().unwrap() = outputs;
+        motor_input
+    }
+
+    fn scheduler_delta(&self) -> Duration {
+        self.primary.scheduler_delta()
+    }
+
+    fn shadow_outputs(&self) -> Vec<ShadowOutput> {
+        self.last_outputs.lock().unwrap().clone()
+    }
+
+    fn intervention(&self) -> Option<Breach> {
+        self.primary.intervention()
+    }
+
+    fn snapshot(&self) -> Option<ControllerState> {
+        let shadows = self
+            .shadows
+            .iter()
+            .map(|(_, shadow)| shadow.snapshot())
+            .collect::<Option<Vec<_>>>()?;
+        Some(Box::new(ShadowSnapshot {
+            primary: self.primary.snapshot()?,
+            shadows,
+            last_outputs: self.last_outputs.lock().unwrap().clone(),
+        }))
+    }
+
+    fn restore(&self, state: &ControllerState) -> bool {
+        let Some(snapshot) = state.downcast_ref::<ShadowSnapshot>() else {
+            return false;
+        };
+        if snapshot.shadows.len() != self.shadows.len() || !self.primary.restore(&snapshot.primary)
+        {
+            return false;
+        }
+        let restored = self
+            .shadows
+            .iter()
+            .zip(&snapshot.shadows)
+            .all(|((_, shadow), state)| shadow.restore(state));
+        if restored {
+            *self.last_outputs.lock().unwrap() = snapshot.last_outputs.clone();
+        }
+        restored
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::{
+        controllers::{null_controller::NullController, shadow::ShadowController},
+        FlightController, FlightControllerUpdate,
+    };
+
+    #[test]
+    fn records_shadow_outputs() {
+        let nested = ShadowController::new(Arc::new(NullController::default()))
+            .with_shadow("inner", Arc::new(NullController::default()));
+        let controller = ShadowController::new(Arc::new(nested))
+            .with_shadow("outer", Arc::new(NullController::default()));
+        assert!(controller.shadow_outputs().is_empty());
+
+        let motor_input = controller.update(0.005, FlightControllerUpdate::default());
+        let shadows = controller.shadow_outputs();
+        let ids: Vec<_> = shadows.iter().map(|s| s.controller_id.as_str()).collect();
+        assert_eq!(ids, ["inner", "outer"]);
+        assert_eq!(shadows[1].motor_input.input, motor_input.input);
+    }
+}
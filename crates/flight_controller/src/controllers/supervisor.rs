@@ -0,0 +1,400 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    frames::tilt, ControllerState, FlightController, FlightControllerUpdate, InitError, MotorInput,
+    ShadowOutput,
+};
+
+// motor outputs at or above this count as saturated
+const SATURATION_LEVEL: f64 = 0.99;
+
+/// The limits the supervised controller has to stay within. Angles are in rad, rates in rad/s and
+/// altitudes in m.
+#[derive(Debug, Clone)]
+pub struct SafetyEnvelope {
+    // angle between the drone's up axis and the world's up axis
+    pub max_tilt: f64,
+    pub max_rate: f64,
+    pub min_altitude: f64,
+    pub max_altitude: f64,
+    // how long a motor can stay saturated before the fallback takes over
+    pub max_saturation: Duration,
+    // how long the drone has to stay within the envelope before control is handed back
+    pub recovery_time: Duration,
+}
+
+impl Default for SafetyEnvelope {
+    fn default() -> Self {
+        Self {
+            max_tilt: 60f64.to_radians(),
+            max_rate: 1500f64.to_radians(),
+            min_altitude: -1.,
+            max_altitude: 100.,
+            max_saturation: Duration::from_millis(200),
+            recovery_time: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SupervisorMode {
+    // hand all control over to the fallback immediately
+    Switch,
+    // fade between the two controllers over the blend time
+    Blend(Duration),
+}
+
+/// The reason for an intervention, holds the measured value that broke the envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Breach {
+    NonFinite,
+    Tilt(f64),
+    Rate(f64),
+    Altitude(f64),
+    Saturation(Duration),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Intervention {
+    pub start: Duration,
+    // None while the fallback is still in control
+    pub end: Option<Duration>,
+    pub breach: Breach,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SupervisorStats {
+    pub interventions: usize,
+    pub total_time: Duration,
+    pub supervised_time: Duration,
+}
+
+impl SupervisorStats {
+    /// The fraction of the flight in which the fallback was in control.
+    pub fn supervised_fraction(&self) -> f64 {
+        if self.total_time.is_zero() {
+            return 0.;
+        }
+        self.supervised_time.as_secs_f64() / self.total_time.as_secs_f64()
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct SupervisorState {
+    time: Duration,
+    saturated_for: Duration,
+    healthy_for: Duration,
+    // 0 is the primary controller, 1 the fallback
+    fallback_weight: f64,
+    interventions: Vec<Intervention>,
+    stats: SupervisorStats,
+}
+
+impl SupervisorState {
+    fn intervening(&self) -> bool {
+        self.interventions
+            .last()
+            .is_some_and(|intervention| intervention.end.is_none())
+    }
+}
+
+// the supervisor can only be restored if both controllers can
+struct SupervisorSnapshot {
+    state: SupervisorState,
+    primary: ControllerState,
+    fallback: ControllerState,
+}
+
+/// Wraps a controller (typically a learned one) and watches the drone. When the drone leaves the
+/// safety envelope the fallback controller takes over until the drone is back within the envelope.
+/// The fallback is updated every step, even while it is not in control, so it is ready to take
+/// over. Both controllers run at the rate of the primary controller.
+pub struct SafetySupervisor {
+    pub primary: Arc<dyn FlightController>,
+    pub fallback: Arc<dyn FlightController>,
+    pub envelope: SafetyEnvelope,
+    pub mode: SupervisorMode,
+    state: Mutex<SupervisorState>,
+}
+
+fn is_finite(update: &FlightControllerUpdate, motor_input: &MotorInput) -> bool {
+    let gyro = &update.gyro_update;
+    gyro.rotation
+        .iter()
+        .chain(gyro.angular_velocity.iter())
+        .chain(gyro.linear_acc.iter())
+        .chain(update.position.iter())
+        .chain(motor_input.input.iter())
+        .all(|value| value.is_finite())
+}
+
+impl SafetySupervisor {
+    pub fn new(primary: Arc<dyn FlightController>, fallback: Arc<dyn FlightController>) -> Self {
+        Self {
+            primary,
+            fallback,
+            envelope: SafetyEnvelope::default(),
+            mode: SupervisorMode::Switch,
+            state: Mutex::new(SupervisorState::default()),
+        }
+    }
+
+    pub fn with_envelope(self, envelope: SafetyEnvelope) -> Self {
+        Self { envelope, ..self }
+    }
+
+    pub fn with_mode(self, mode: SupervisorMode) -> Self {
+        Self { mode, ..self }
+    }
+
+    /// All interventions so far, the last one might still be ongoing
+    pub fn interventions(&self) -> Vec<Intervention> {
+        self.state.lock().unwrap().interventions.clone()
+    }
+
+    pub fn stats(&self) -> SupervisorStats {
+        self.state.lock().unwrap().stats
+    }
+
+    pub fn is_intervening(&self) -> bool {
+        self.state.lock().unwrap().intervening()
+    }
+
+    fn check_envelope(
+        &self,
+        state: &SupervisorState,
+        update: &FlightControllerUpdate,
+        motor_input: &MotorInput,
+    ) -> Option<Breach> {
+        let envelope = &self.envelope;
+        if !is_finite(update, motor_input) {
+            return Some(Breach::NonFinite);
+        }
+        let tilt = tilt(update.gyro_update.rotation);
+        if tilt > envelope.max_tilt {
+            return Some(Breach::Tilt(tilt));
+        }
+        let [x, y, z] = update.gyro_update.angular_velocity;
+        let rate = f64::sqrt(x * x + y * y + z * z);
+        if rate > envelope.max_rate {
+            return Some(Breach::Rate(rate));
+        }
+        let altitude = update.position[1];
+        if altitude < envelope.min_altitude || altitude > envelope.max_altitude {
+            return Some(Breach::Altitude(altitude));
+        }
+        if state.saturated_for > envelope.max_saturation {
+            return Some(Breach::Saturation(state.saturated_for));
+        }
+        None
+    }
+}
+
+impl FlightController for SafetySupervisor {
+    fn init(&self) -> Result<(), InitError> {
+        self.primary.init()?;
+        self.fallback.init()
+    }
+
+    fn update(&self, delta_time: f64, update: FlightControllerUpdate) -> MotorInput {
+        let primary_input = self.primary.update(delta_time, update);
+        let fallback_input = self.fallback.update(delta_time, update);
+
+        let mut state = self.state.lock().unwrap();
+        let dt = Duration::from_secs_f64(delta_time.max(0.));
+        state.time += dt;
+        state.stats.total_time += dt;
+        if primary_input
+            .input
+            .iter()
+            .any(|motor| *motor >= SATURATION_LEVEL)
+        {
+            state.saturated_for += dt;
+        } else {
+            state.saturated_for = Duration::ZERO;
+        }
+
+        let breach = self.check_envelope(&state, &update, &primary_input);
+        let time = state.time;
+        match (breach, state.intervening()) {
+            (Some(breach), false) => {
+                state.interventions.push(Intervention {
+                    start: time,
+                    end: None,
+                    breach,
+                });
+                state.stats.interventions += 1;
+                state.healthy_for = Duration::ZERO;
+            }
+            (Some(_), true) => state.healthy_for = Duration::ZERO,
+            (None, true) => {
+                state.healthy_for += dt;
+                if state.healthy_for >= self.envelope.recovery_time {
+                    if let Some(intervention) = state.interventions.last_mut() {
+                        intervention.end = Some(time);
+                    }
+                }
+            }
+            (None, false) => {}
+        }
+
+        let intervening = state.intervening();
+        if intervening {
+            state.stats.supervised_time += dt;
+        }
+        let target = if intervening { 1. } else { 0. };
+        state.fallback_weight = match self.mode {
+            SupervisorMode::Switch => target,
+            SupervisorMode::Blend(blend_time) if !blend_time.is_zero() => {
+                let step = dt.as_secs_f64() / blend_time.as_secs_f64();
+                let weight = state.fallback_weight;
+                weight + f64::clamp(target - weight, -step, step)
+            }
+            SupervisorMode::Blend(_) => target,
+        };
+
+        // never pass on a broken output, even while blending
+        if breach == Some(Breach::NonFinite) {
+            return fallback_input;
+        }
+        let weight = state.fallback_weight;
+        MotorInput {
+            input: std::array::from_fn(|i| {
+                (1. - weight) * primary_input[i] + weight * fallback_input[i]
+            }),
+        }
+    }
+
+    fn scheduler_delta(&self) -> Duration {
+        self.primary.scheduler_delta()
+    }
+
+    fn shadow_outputs(&self) -> Vec<ShadowOutput> {
+        self.primary.shadow_outputs()
+    }
+
+    fn intervention(&self) -> Option<Breach> {
+        let state = self.state.lock().unwrap();
+        state
+            .interventions
+            .last()
+            .filter(|intervention| intervention.end.is_none())
+            .map(|intervention| intervention.breach)
+    }
+
+    fn snapshot(&self) -> Option<ControllerState> {
+        Some(Box::new(SupervisorSnapshot {
+            state: self.state.lock().unwrap().clone(),
+            primary: self.primary.snapshot()?,
+            fallback: self.fallback.snapshot()?,
+        }))
+    }
+
+    fn restore(&self, state: &ControllerState) -> bool {
+        let Some(snapshot) = state.downcast_ref::<SupervisorSnapshot>() else {
+            return false;
+        };
+        if !self.primary.restore(&snapshot.primary) || !self.fallback.restore(&snapshot.fallback) {
+            return false;
+        }
+        *self.state.lock().unwrap() = snapshot.state.clone();
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        controllers::{
+            null_controller::NullController,
+            shadow::ShadowController,
+            supervisor::{Breach, SafetySupervisor, SupervisorMode},
+        },
+        FlightController, FlightControllerUpdate, InitError, MotorInput,
+    };
+
+    struct ConstController(f64);
+
+    impl FlightController for ConstController {
+        fn init(&self) -> Result<(), InitError> {
+            Ok(())
+        }
+        fn update(&self, _: f64, _: FlightControllerUpdate) -> MotorInput {
+            MotorInput { input: [self.0; 4] }
+        }
+        fn scheduler_delta(&self) -> Duration {
+            Duration::from_millis(5)
+        }
+    }
+
+    fn update_with_tilt(tilted: bool) -> FlightControllerUpdate {
+        let mut update = FlightControllerUpdate::default();
+        update.position[1] = 1.;
+        // 90 degrees around x, the default is the identity rotation
+        update.gyro_update.rotation = if tilted {
+            [f64::sqrt(0.5), 0., 0., f64::sqrt(0.5)]
+        } else {
+            [0., 0., 0., 1.]
+        };
+        update
+    }
+
+    #[test]
+    fn switches_to_fallback() {
+        let supervisor = Arc::new(SafetySupervisor::new(
+            Arc::new(ConstController(0.5)),
+            Arc::new(NullController::default()),
+        ));
+        let out = supervisor.update(0.005, update_with_tilt(false));
+        assert_eq!(out.input, [0.5; 4]);
+        let out = supervisor.update(0.005, update_with_tilt(true));
+        assert_eq!(out.input, [0.; 4]);
+        assert!(supervisor.is_intervening());
+        assert!(matches!(
+            supervisor.interventions()[0].breach,
+            Breach::Tilt(_)
+        ));
+        // passed on through a shadow controller, so the simulator can log it
+        let shadowed = ShadowController::new(supervisor.clone());
+        assert!(matches!(shadowed.intervention(), Some(Breach::Tilt(_))));
+
+        // stays with the fallback until the recovery time has passed
+        for _ in 0..100 {
+            supervisor.update(0.005, update_with_tilt(false));
+        }
+        assert!(!supervisor.is_intervening());
+        assert_eq!(shadowed.intervention(), None);
+        let out = supervisor.update(0.005, update_with_tilt(false));
+        assert_eq!(out.input, [0.5; 4]);
+        let stats = supervisor.stats();
+        assert_eq!(stats.interventions, 1);
+        assert!(stats.supervised_fraction() > 0.9);
+    }
+
+    #[test]
+    fn blends_and_rejects_nan() {
+        let supervisor = SafetySupervisor::new(
+            Arc::new(ConstController(1.)),
+            Arc::new(NullController::default()),
+        )
+        .with_mode(SupervisorMode::Blend(Duration::from_millis(20)));
+        let out = supervisor.update(0.005, update_with_tilt(true));
+        assert!((out.input[0] - 0.75).abs() < 1e-9);
+
+        let nan = SafetySupervisor::new(
+            Arc::new(ConstController(f64::NAN)),
+            Arc::new(ConstController(0.2)),
+        )
+        .with_mode(SupervisorMode::Blend(Duration::from_millis(20)));
+        let out = nan.update(0.005, update_with_tilt(false));
+        assert_eq!(out.input, [0.2; 4]);
+        assert_eq!(nan.interventions()[0].breach, Breach::NonFinite);
+    }
+}
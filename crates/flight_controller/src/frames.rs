@@ -0,0 +1,96 @@
+//! Conversions to the frames the external simulators (Betaflight and ArduPilot SITL) expect. The
+//! body frame of the simulator is x right, y up, z back and the world frame has the same axes, the
+//! SITLs use forward, right, down (FRD) in the body and north, east, down (NED) in the world. The
+//! forward direction of the drone is taken as north.
+
+use crate::GyroUpdate;
+use nalgebra::{Quaternion, UnitQuaternion, Vector3};
+
+pub const GRAVITY: f64 = 9.81;
+
+/// Converts a body frame vector to FRD or a world frame vector to NED
+pub fn to_frd(v: [f64; 3]) -> [f64; 3] {
+    [-v[2], v[0], -v[1]]
+}
+
+// the rotation is i, j, k, w
+fn to_unit_quaternion(rotation: [f64; 4]) -> UnitQuaternion<f64> {
+    let [i, j, k, w] = rotation;
+    UnitQuaternion::from_quaternion(Quaternion::new(w, i, j, k))
+}
+
+/// The angle (in rad) between the up axis of the drone and the up axis of the world, the rotation
+/// is i, j, k, w
+pub fn tilt(rotation: [f64; 4]) -> f64 {
+    let [i, _, k, _] = rotation;
+    f64::acos(f64::clamp(1. - 2. * (i * i + k * k), -1., 1.))
+}
+
+/// The attitude (i, j, k, w) as the NED to FRD quaternion w, x, y, z
+pub fn to_ned_quaternion(rotation: [f64; 4]) -> [f64; 4] {
+    let rotation = to_unit_quaternion(rotation);
+    // both frames are converted the same way, so the rotation axis is converted like any vector
+    let [x, y, z] = to_frd([rotation.i, rotation.j, rotation.k]);
+    [rotation.w, x, y, z]
+}
+
+/// Rotates a NED vector into the FRD body frame
+pub fn ned_to_frd(rotation: [f64; 4], v: [f64; 3]) -> [f64; 3] {
+    let [w, x, y, z] = to_ned_quaternion(rotation);
+    let rotation = UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z));
+    (rotation.inverse() * Vector3::from(v)).into()
+}
+
+/// Euler angles roll, pitch and yaw (in rad) of the NED to FRD rotation
+pub fn to_ned_euler(rotation: [f64; 4]) -> [f64; 3] {
+    let [w, x, y, z] = to_ned_quaternion(rotation);
+    let (roll, pitch, yaw) =
+        UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z)).euler_angles();
+    [roll, pitch, yaw]
+}
+
+/// What an accelerometer measures in FRD, the acceleration of the drone minus gravity. Level and at
+/// rest this is -9.81 on z.
+pub fn specific_force_frd(gyro_update: &GyroUpdate) -> [f64; 3] {
+    let rotation = to_unit_quaternion(gyro_update.rotation);
+    // at rest the accelerometer measures the normal force, which points up
+    let up = rotation.inverse() * Vector3::new(0., GRAVITY, 0.);
+    let specific_force = Vector3::from(gyro_update.linear_acc) + up;
+    to_frd(specific_force.into())
+}
+
+/// The pressure of the international standard atmosphere, altitude in m and pressure in Pa
+pub fn pressure(altitude: f64) -> f64 {
+    101325. * f64::powf(1. - 2.25577e-5 * altitude, 5.25588)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        frames::{ned_to_frd, specific_force_frd, tilt, to_frd, to_ned_euler},
+        GyroUpdate,
+    };
+
+    #[test]
+    fn ned_conversion() {
+        let level = GyroUpdate {
+            rotation: [0., 0., 0., 1.],
+            ..Default::default()
+        };
+        assert_eq!(specific_force_frd(&level), [0., 0., -9.81]);
+        // rolling right is a negative rotation around the back axis
+        assert_eq!(to_frd([0., 0., -1.]), [1., 0., 0.]);
+
+        // 90 degrees around the up axis turns the nose to the left, which is a negative yaw
+        let half = f64::sqrt(0.5);
+        let [roll, pitch, yaw] = to_ned_euler([0., half, 0., half]);
+        assert!(roll.abs() < 1e-9 && pitch.abs() < 1e-9);
+        assert!((yaw + std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+        // north is on the right of the drone now
+        let [f, r, d] = ned_to_frd([0., half, 0., half], [1., 0., 0.]);
+        assert!(f.abs() < 1e-9 && (r - 1.).abs() < 1e-9 && d.abs() < 1e-9);
+        // yawing does not tilt the drone, rolling does
+        assert!(tilt([0., half, 0., half]).abs() < 1e-9);
+        assert!((tilt([0., 0., half, half]) - std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+    }
+}
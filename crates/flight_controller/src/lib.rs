@@ -1,7 +1,17 @@
 use serde::{Deserialize, Serialize};
-use std::{ops::Index, time::Duration};
+use std::{any::Any, ops::Index, time::Duration};
 
+pub mod arming;
+pub mod channels;
 pub mod controllers;
+pub mod frames;
+pub mod mixer;
+pub mod rates;
+
+pub use arming::{Arming, ArmingConfig, ArmingState, FailsafeStage};
+pub use channels::{AuxRole, ChannelMap, Channels, RcChannel, AUX_CHANNELS, MAX_CHANNELS};
+pub use controllers::supervisor::Breach;
+pub use mixer::{Mixer, MixerError, MixerType, MotorMap, PropDirection};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct MotorInput {
@@ -32,26 +42,52 @@ pub struct GyroUpdate {
     pub angular_velocity: [f64; 3],
 }
 
-// each channel between -1 and 1
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub struct Channels {
-    pub throttle: f64,
-    pub roll: f64,
-    pub pitch: f64,
-    pub yaw: f64,
-}
-
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub struct FlightControllerUpdate {
     pub battery_update: BatteryUpdate,
     pub gyro_update: GyroUpdate,
     pub channels: Channels,
+    // ground truth position (y is up), a real flight controller does not know this
+    pub position: [f64; 3],
 }
 
+/// The output of a controller that was evaluated but did not fly the drone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShadowOutput {
+    pub controller_id: String,
+    pub motor_input: MotorInput,
+}
+
+/// The internal state of a controller, only the kind of controller that took it can restore it.
+pub type ControllerState = Box<dyn Any + Send + Sync>;
+
+/// Why a controller could not be started, e.g. its SITL could not be spawned
+pub type InitError = Box<dyn std::error::Error + Send + Sync>;
+
 pub trait FlightController: Send + Sync + 'static {
-    fn init(&self);
+    /// Starts the controller, or restarts it for a new episode
+    fn init(&self) -> Result<(), InitError>;
     fn update(&self, delta_time: f64, update: FlightControllerUpdate) -> MotorInput;
     fn scheduler_delta(&self) -> Duration;
+    /// The outputs of the shadow controllers from the last update, if there are any
+    fn shadow_outputs(&self) -> Vec<ShadowOutput> {
+        vec![]
+    }
+    /// Why a safety supervisor has handed control to its fallback, None while the controller flies
+    /// the drone itself
+    fn intervention(&self) -> Option<Breach> {
+        None
+    }
+    /// Captures everything the next updates depend on, None if the controller does not support
+    /// snapshots (e.g. it runs in another process)
+    fn snapshot(&self) -> Option<ControllerState> {
+        None
+    }
+    /// Puts the controller back into a state taken with `snapshot`, returns false if the state
+    /// was not taken from this kind of controller
+    fn restore(&self, _state: &ControllerState) -> bool {
+        false
+    }
 }
 
 impl Default for MotorInput {
@@ -59,30 +95,3 @@ impl Default for MotorInput {
         Self { input: [0.; 4] }
     }
 }
-
-impl Default for Channels {
-    fn default() -> Self {
-        Self {
-            throttle: -1.,
-            roll: 0.,
-            pitch: 0.,
-            yaw: 0.,
-        }
-    }
-}
-
-impl Channels {
-    // TODO: verify this!
-    pub fn to_bf_channels(&self) -> [f64; 8] {
-        [
-            self.roll,
-            self.pitch,
-            self.throttle,
-            self.yaw,
-            0.,
-            0.,
-            0.,
-            0.,
-        ]
-    }
-}
@@ -0,0 +1,315 @@
+//! Motor mixing and motor ordering. The mixer tables and the motor order follow Betaflight
+//! (see `mixer.c` in the Betaflight source), so motor 1 of a quad X is the rear right motor. With
+//! the default drone the Betaflight motor order matches the rotor order one to one.
+
+use crate::MotorInput;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const SIN_60: f64 = 0.866025;
+
+/// The spin direction of the props. With props in the front props spin towards the frame, this is
+/// the Betaflight default. Props out is `yaw_motors_reversed = ON` in the Betaflight CLI. Only the
+/// mixer keeps the direction, the drone gets the matching spin directions from `quad_rotor_dirs`
+/// through `Drone::set_rotor_dirs`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PropDirection {
+    #[default]
+    PropsIn,
+    PropsOut,
+}
+
+impl PropDirection {
+    pub fn yaw_sign(&self) -> f64 {
+        match self {
+            PropDirection::PropsIn => 1.,
+            PropDirection::PropsOut => -1.,
+        }
+    }
+}
+
+/// A single row of the mixer table, the same as the arguments of the `mmix` CLI command.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MotorMixRule {
+    pub throttle: f64,
+    pub roll: f64,
+    pub pitch: f64,
+    pub yaw: f64,
+}
+
+impl MotorMixRule {
+    pub const fn new(throttle: f64, roll: f64, pitch: f64, yaw: f64) -> Self {
+        Self {
+            throttle,
+            roll,
+            pitch,
+            yaw,
+        }
+    }
+}
+
+const QUAD_X: [MotorMixRule; 4] = [
+    MotorMixRule::new(1., -1., 1., -1.), // rear right
+    MotorMixRule::new(1., -1., -1., 1.), // front right
+    MotorMixRule::new(1., 1., 1., 1.),   // rear left
+    MotorMixRule::new(1., 1., -1., -1.), // front left
+];
+
+const QUAD_PLUS: [MotorMixRule; 4] = [
+    MotorMixRule::new(1., 0., 1., -1.),  // rear
+    MotorMixRule::new(1., -1., 0., 1.),  // right
+    MotorMixRule::new(1., 1., 0., 1.),   // left
+    MotorMixRule::new(1., 0., -1., -1.), // front
+];
+
+const HEX_6X: [MotorMixRule; 6] = [
+    MotorMixRule::new(1., -0.5, SIN_60, 1.),  // rear right
+    MotorMixRule::new(1., -0.5, -SIN_60, 1.), // front right
+    MotorMixRule::new(1., 0.5, SIN_60, -1.),  // rear left
+    MotorMixRule::new(1., 0.5, -SIN_60, -1.), // front left
+    MotorMixRule::new(1., -1., 0., -1.),      // right
+    MotorMixRule::new(1., 1., 0., 1.),        // left
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MixerType {
+    QuadX,
+    QuadPlus,
+    // Betaflight flies H frames with the quad X table, the motor order is the same
+    HFrame,
+    Hex6X,
+    Custom(Vec<MotorMixRule>),
+}
+
+impl MixerType {
+    pub fn rules(&self) -> Vec<MotorMixRule> {
+        match self {
+            MixerType::QuadX | MixerType::HFrame => QUAD_X.to_vec(),
+            MixerType::QuadPlus => QUAD_PLUS.to_vec(),
+            MixerType::Hex6X => HEX_6X.to_vec(),
+            MixerType::Custom(rules) => rules.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MixerError {
+    // only a mixer with four motors can drive the simulated drone
+    MotorCount(usize),
+    // the motor map is not a permutation of the four rotors
+    MotorMap([usize; 4]),
+}
+
+impl fmt::Display for MixerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MixerError::MotorCount(count) => write!(f, "can not drive a quad with {count} motors"),
+            MixerError::MotorMap(rotor_index) => {
+                write!(f, "{rotor_index:?} is not an order of the four rotors")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MixerError {}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mixer {
+    pub rules: Vec<MotorMixRule>,
+    pub props: PropDirection,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new(MixerType::QuadX, PropDirection::PropsIn)
+    }
+}
+
+impl Mixer {
+    pub fn new(mixer_type: MixerType, props: PropDirection) -> Self {
+        Self {
+            rules: mixer_type.rules(),
+            props,
+        }
+    }
+
+    pub fn motor_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Mixes the throttle (between 0 and 1) and the roll, pitch and yaw commands (between -1
+    /// and 1) into motor outputs between 0 and 1, in mixer order. Like Betaflight's airmode,
+    /// roll, pitch and yaw are scaled down if they do not fit and the throttle is moved so that
+    /// the motors do not saturate.
+    pub fn mix(&self, throttle: f64, roll: f64, pitch: f64, yaw: f64) -> Vec<f64> {
+        let yaw = yaw * self.props.yaw_sign();
+        let mut rpy: Vec<f64> = self
+            .rules
+            .iter()
+            .map(|rule| rule.roll * roll + rule.pitch * pitch + rule.yaw * yaw)
+            .collect();
+        let mut min = rpy.iter().copied().fold(f64::INFINITY, f64::min);
+        let mut max = rpy.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let range = max - min;
+        if range > 1. {
+            rpy.iter_mut().for_each(|mix| *mix /= range);
+            min /= range;
+            max /= range;
+        }
+        // after scaling the range can be a rounding error above 1, so no clamp
+        let throttle = throttle.clamp(0., 1.).max(-min).min(1. - max);
+        self.rules
+            .iter()
+            .zip(rpy)
+            .map(|(rule, mix)| (rule.throttle * throttle + mix).clamp(0., 1.))
+            .collect()
+    }
+
+    /// Same as `mix`, but for the quad mixers that can drive the simulated drone.
+    pub fn mix_quad(
+        &self,
+        throttle: f64,
+        roll: f64,
+        pitch: f64,
+        yaw: f64,
+    ) -> Result<MotorInput, MixerError> {
+        let outputs = self.mix(throttle, roll, pitch, yaw);
+        let input = outputs
+            .try_into()
+            .map_err(|_| MixerError::MotorCount(self.motor_count()))?;
+        Ok(MotorInput { input })
+    }
+
+    /// The spin direction of every motor as used by the drone model (`rotor_dir`), this is the
+    /// sign of the yaw factor.
+    pub fn rotor_dirs(&self) -> Vec<f64> {
+        self.rules
+            .iter()
+            .map(|rule| rule.yaw.signum() * self.props.yaw_sign())
+            .collect()
+    }
+
+    /// Same as `rotor_dirs`, but for the quad mixers that can drive the simulated drone.
+    pub fn quad_rotor_dirs(&self) -> Result<[f64; 4], MixerError> {
+        self.rotor_dirs()
+            .try_into()
+            .map_err(|_| MixerError::MotorCount(self.motor_count()))
+    }
+}
+
+/// Maps the motor outputs of a flight controller to the rotors of the drone. This is the
+/// equivalent of the `resource MOTOR` remapping in Betaflight: output i drives the rotor at
+/// `rotor_index[i]`. It is stored as the `rotor_index` table and checked when it is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "[usize; 4]", into = "[usize; 4]")]
+pub struct MotorMap {
+    pub rotor_index: [usize; 4],
+}
+
+impl Default for MotorMap {
+    // the default drone has its rotors in Betaflight quad X order
+    fn default() -> Self {
+        Self {
+            rotor_index: [0, 1, 2, 3],
+        }
+    }
+}
+
+impl MotorMap {
+    /// Returns `None` if the table is not a permutation of the four rotors.
+    pub fn new(rotor_index: [usize; 4]) -> Option<Self> {
+        let mut seen = [false; 4];
+        for idx in rotor_index {
+            if idx >= 4 || seen[idx] {
+                return None;
+            }
+            seen[idx] = true;
+        }
+        Some(Self { rotor_index })
+    }
+
+    /// Reorders the controller outputs into rotor order
+    pub fn to_rotors(&self, outputs: MotorInput) -> MotorInput {
+        let mut input = [0.; 4];
+        for (output, rotor) in self.rotor_index.iter().enumerate() {
+            input[*rotor] = outputs[output];
+        }
+        MotorInput { input }
+    }
+
+    /// Reorders the rotor values back into controller output order
+    pub fn to_outputs(&self, rotors: MotorInput) -> MotorInput {
+        MotorInput {
+            input: self.rotor_index.map(|rotor| rotors[rotor]),
+        }
+    }
+}
+
+impl TryFrom<[usize; 4]> for MotorMap {
+    type Error = MixerError;
+
+    fn try_from(rotor_index: [usize; 4]) -> Result<Self, Self::Error> {
+        Self::new(rotor_index).ok_or(MixerError::MotorMap(rotor_index))
+    }
+}
+
+impl From<MotorMap> for [usize; 4] {
+    fn from(motor_map: MotorMap) -> Self {
+        motor_map.rotor_index
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mixer::{Mixer, MixerError, MixerType, MotorMap, PropDirection};
+    use crate::MotorInput;
+
+    #[test]
+    fn quad_x_mixing() {
+        let mixer = Mixer::default();
+        assert_eq!(mixer.mix(0.5, 0., 0., 0.), vec![0.5; 4]);
+        // rolling right speeds up the left motors
+        assert_eq!(mixer.mix(0.5, 0.2, 0., 0.), vec![0.3, 0.3, 0.7, 0.7]);
+        // full stick does not saturate, the throttle is moved instead
+        let saturated = mixer.mix(0.9, 1., 0., 0.);
+        assert_eq!(saturated, vec![0., 0., 1., 1.]);
+        // the scaled down range can round to slightly more than 1
+        for i in 0..1000 {
+            let outputs = mixer.mix(0.5, 0.7, i as f64 / 500. - 1., -0.9);
+            assert!(outputs.iter().all(|output| (0. ..=1.).contains(output)));
+        }
+        assert_eq!(mixer.rotor_dirs(), vec![-1., 1., 1., -1.]);
+
+        let props_out = Mixer::new(MixerType::QuadX, PropDirection::PropsOut);
+        let yaw = mixer.mix(0.5, 0., 0., 0.2);
+        let yaw_out = props_out.mix(0.5, 0., 0., 0.2);
+        assert_eq!(yaw, vec![0.3, 0.7, 0.7, 0.3]);
+        assert_eq!(yaw_out, vec![0.7, 0.3, 0.3, 0.7]);
+        let hex = Mixer::new(MixerType::Hex6X, PropDirection::PropsIn);
+        assert_eq!(hex.motor_count(), 6);
+        assert_eq!(
+            hex.mix_quad(0.5, 0., 0., 0.).unwrap_err(),
+            MixerError::MotorCount(6)
+        );
+        assert_eq!(hex.quad_rotor_dirs(), Err(MixerError::MotorCount(6)));
+    }
+
+    #[test]
+    fn motor_remap() {
+        assert!(MotorMap::new([0, 1, 1, 3]).is_none());
+        assert!(MotorMap::new([0, 1, 2, 4]).is_none());
+        let map = MotorMap::new([3, 0, 1, 2]).unwrap();
+        let outputs = MotorInput {
+            input: [0.1, 0.2, 0.3, 0.4],
+        };
+        assert_eq!(map.to_rotors(outputs).input, [0.2, 0.3, 0.4, 0.1]);
+        assert_eq!(map.to_outputs(map.to_rotors(outputs)).input, outputs.input);
+
+        // configs go through the same check
+        let config = serde_json::to_string(&map).unwrap();
+        assert_eq!(config, "[3,0,1,2]");
+        assert_eq!(serde_json::from_str::<MotorMap>(&config).unwrap(), map);
+        assert!(serde_json::from_str::<MotorMap>("[0,1,1,3]").is_err());
+        assert!(serde_json::from_str::<MotorMap>("[0,1,2,4]").is_err());
+    }
+}
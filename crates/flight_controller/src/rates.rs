@@ -0,0 +1,194 @@
+//! Stick input shaping. Converts the raw [-1, 1] stick positions into desired body rates using the
+//! same formulas as Betaflight (see `rc.c` in the Betaflight source). The rate profile uses the
+//! same units as the Betaflight CLI, so a profile can be copied over from a real quad.
+
+use crate::Channels;
+use serde::{Deserialize, Serialize};
+
+// Betaflight bumps the rc rate above 2.0 by this factor
+const RC_RATE_INCREMENTAL: f64 = 14.54;
+// Maximum rate Betaflight allows, in deg/s
+pub const SETPOINT_RATE_LIMIT: f64 = 1998.;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RatesType {
+    Betaflight,
+    #[default]
+    Actual,
+    Kiss,
+}
+
+impl RatesType {
+    /// Parses the value of the `rates_type` CLI setting, e.g. `ACTUAL`
+    pub fn from_bf_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "BETAFLIGHT" => Some(Self::Betaflight),
+            "ACTUAL" => Some(Self::Actual),
+            "KISS" => Some(Self::Kiss),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateAxis {
+    Roll = 0,
+    Pitch = 1,
+    Yaw = 2,
+}
+
+/// Mirrors the Betaflight control rate profile. All the per axis values are in roll, pitch, yaw
+/// order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateProfile {
+    pub rates_type: RatesType,
+    // `roll_rc_rate`, in Actual rates this is the center sensitivity / 10
+    pub rc_rates: [f64; 3],
+    // `roll_expo`, for KISS rates this is the rc curve
+    pub rc_expo: [f64; 3],
+    // `roll_srate`, in Actual rates this is the max rate / 10
+    pub rates: [f64; 3],
+    // `roll_rate_limit`, deg/s
+    pub rate_limit: [f64; 3],
+    // `thr_mid` and `thr_expo`
+    pub thr_mid: f64,
+    pub thr_expo: f64,
+}
+
+impl Default for RateProfile {
+    // Betaflight 4.x defaults
+    fn default() -> Self {
+        Self {
+            rates_type: RatesType::Actual,
+            rc_rates: [7.; 3],
+            rc_expo: [0.; 3],
+            rates: [67.; 3],
+            rate_limit: [SETPOINT_RATE_LIMIT; 3],
+            thr_mid: 50.,
+            thr_expo: 0.,
+        }
+    }
+}
+
+fn betaflight_rates(rc_rate: f64, rate: f64, expo: f64, stick: f64) -> f64 {
+    let stick_abs = stick.abs();
+    let expo = expo / 100.;
+    let stick = stick * stick_abs.powi(3) * expo + stick * (1. - expo);
+    let mut rc_rate = rc_rate / 100.;
+    if rc_rate > 2. {
+        rc_rate += RC_RATE_INCREMENTAL * (rc_rate - 2.);
+    }
+    let super_factor = 1. / f64::clamp(1. - stick_abs * (rate / 100.), 0.01, 1.);
+    200. * rc_rate * stick * super_factor
+}
+
+fn actual_rates(rc_rate: f64, rate: f64, expo: f64, stick: f64) -> f64 {
+    let stick_abs = stick.abs();
+    let expo = expo / 100.;
+    let expo = stick_abs * (stick.powi(5) * expo + stick * (1. - expo));
+    let center_sensitivity = rc_rate * 10.;
+    let stick_movement = f64::max(0., rate * 10. - center_sensitivity);
+    stick * center_sensitivity + stick_movement * expo
+}
+
+fn kiss_rates(rc_rate: f64, rate: f64, rc_curve: f64, stick: f64) -> f64 {
+    let rc_curve = rc_curve / 100.;
+    let use_rates = 1. / f64::clamp(1. - stick.abs() * (rate / 100.), 0.01, 1.);
+    let stick = (stick.powi(3) * rc_curve + stick * (1. - rc_curve)) * (rc_rate / 1000.);
+    f64::clamp(
+        2000. * use_rates * stick,
+        -SETPOINT_RATE_LIMIT,
+        SETPOINT_RATE_LIMIT,
+    )
+}
+
+impl RateProfile {
+    /// The desired rate of an axis in deg/s given a stick position between -1 and 1.
+    pub fn setpoint_rate(&self, axis: RateAxis, stick: f64) -> f64 {
+        let axis = axis as usize;
+        let stick = stick.clamp(-1., 1.);
+        let (rc_rate, rate, expo) = (self.rc_rates[axis], self.rates[axis], self.rc_expo[axis]);
+        let angle_rate = match self.rates_type {
+            RatesType::Betaflight => betaflight_rates(rc_rate, rate, expo, stick),
+            RatesType::Actual => actual_rates(rc_rate, rate, expo, stick),
+            RatesType::Kiss => kiss_rates(rc_rate, rate, expo, stick),
+        };
+        angle_rate.clamp(-self.rate_limit[axis], self.rate_limit[axis])
+    }
+
+    /// The desired roll, pitch and yaw rates in rad/s.
+    pub fn desired_rates(&self, channels: &Channels) -> [f64; 3] {
+        [
+            self.setpoint_rate(RateAxis::Roll, channels.roll),
+            self.setpoint_rate(RateAxis::Pitch, channels.pitch),
+            self.setpoint_rate(RateAxis::Yaw, channels.yaw),
+        ]
+        .map(f64::to_radians)
+    }
+
+    /// Applies the throttle curve (`thr_mid` and `thr_expo`) to the throttle stick. The result is
+    /// between 0 and 1.
+    pub fn throttle(&self, throttle_stick: f64) -> f64 {
+        let throttle = (throttle_stick.clamp(-1., 1.) + 1.) / 2.;
+        let mid = self.thr_mid / 100.;
+        let expo = self.thr_expo / 100.;
+        let tmp = throttle - mid;
+        let y = if tmp > 0. {
+            1. - mid
+        } else if tmp < 0. {
+            mid
+        } else {
+            1.
+        };
+        let shaped = mid + tmp * (1. - expo + expo * (tmp * tmp) / (y * y));
+        shaped.clamp(0., 1.)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rates::{RateAxis, RateProfile, RatesType};
+
+    #[test]
+    fn full_stick_rates() {
+        let betaflight = RateProfile {
+            rates_type: RatesType::Betaflight,
+            rc_rates: [100.; 3],
+            rates: [70.; 3],
+            ..Default::default()
+        };
+        let actual = RateProfile::default();
+        let kiss = RateProfile {
+            rates_type: RatesType::Kiss,
+            rc_rates: [100.; 3],
+            rates: [70.; 3],
+            ..Default::default()
+        };
+
+        for (profile, max_rate) in [(betaflight, 666.7), (actual, 670.), (kiss, 666.7)] {
+            assert_eq!(profile.setpoint_rate(RateAxis::Roll, 0.), 0.);
+            let full_stick = profile.setpoint_rate(RateAxis::Roll, 1.);
+            assert!(
+                (full_stick - max_rate).abs() < 0.1,
+                "{:?}: {full_stick}",
+                profile.rates_type
+            );
+            assert_eq!(profile.setpoint_rate(RateAxis::Yaw, -1.), -full_stick);
+        }
+    }
+
+    #[test]
+    fn throttle_curve() {
+        let linear = RateProfile::default();
+        assert_eq!(linear.throttle(-1.), 0.);
+        assert_eq!(linear.throttle(0.), 0.5);
+        assert_eq!(linear.throttle(1.), 1.);
+
+        let expo = RateProfile {
+            thr_expo: 50.,
+            ..Default::default()
+        };
+        assert_eq!(expo.throttle(1.), 1.);
+        assert!(expo.throttle(0.5) < linear.throttle(0.5));
+    }
+}
@@ -1,26 +1,65 @@
-use crate::LoaderTrait;
+use crate::{LoaderError, LoaderTrait};
 use base64::{Engine, prelude::BASE64_STANDARD};
 use db_common::{
-    DBDroneModel, DBLowPassFilter, DBRotorState, DBSamplePoint, DBSimulationFrame, NewDBRcModel,
-    queries::TestingDB,
+    DBDroneModel, DBLowPassFilter, DBRateProfile, DBRotorState, DBSamplePoint, DBSimulationFrame,
+    NewDBRcModel, queries::TestingDB,
 };
 use drone::{
     BatteryModel, BatteryState, Drone, DroneFrameState, DroneModel, GyroModel, GyroState,
-    LowPassFilter, RotorModel, RotorState, RotorsState, SampleCurve, SamplePoint, SimulationFrame,
+    LowPassFilter, Noise, RotorModel, RotorState, RotorsState, SampleCurve, SamplePoint,
+    SimulationFrame, SimulationRates,
+};
+use flight_controller::{
+    Channels, MotorMap,
+    rates::{RateProfile, RatesType},
 };
-use flight_controller::Channels;
 use loggers::{FlightLog, SnapShot};
 use nalgebra::{DMatrix, Matrix3, Quaternion, Rotation3, UnitQuaternion, Vector3};
 use res::esn::Esn;
 use res::representation::{OutputRepr, Representation};
 use res_controller::DroneRc;
 use ridge::{RidgeRegression, RidgeRegressionSol};
+use serde::de::DeserializeOwned;
 use simulator::{BatteryUpdate, GyroUpdate, MotorInput};
 use std::{
     sync::{Arc, Mutex},
     time::Duration,
 };
 
+pub fn db_to_rate_profile(db_profile: DBRateProfile) -> Result<RateProfile, LoaderError> {
+    let rates_type = RatesType::from_bf_name(&db_profile.rates_type).ok_or_else(|| {
+        LoaderError::UnknownRatesType {
+            profile_id: db_profile.id.clone(),
+            rates_type: db_profile.rates_type.clone(),
+        }
+    })?;
+    Ok(RateProfile {
+        rates_type,
+        rc_rates: [
+            db_profile.roll_rc_rate,
+            db_profile.pitch_rc_rate,
+            db_profile.yaw_rc_rate,
+        ],
+        rc_expo: [
+            db_profile.roll_expo,
+            db_profile.pitch_expo,
+            db_profile.yaw_expo,
+        ],
+        rates: [
+            db_profile.roll_srate,
+            db_profile.pitch_srate,
+            db_profile.yaw_srate,
+        ],
+        rate_limit: [
+            db_profile.roll_rate_limit,
+            db_profile.pitch_rate_limit,
+            db_profile.yaw_rate_limit,
+        ],
+        thr_mid: db_profile.thr_mid,
+        thr_expo: db_profile.thr_expo,
+    })
+}
+
 pub fn db_to_rotor_state(db_rotor_state: DBRotorState, pwm_state: DBLowPassFilter) -> RotorState {
     RotorState {
         current: db_rotor_state.current,
@@ -136,6 +175,22 @@ impl DBParts {
 
 // pub fn drone_to_db_parts(drone: &Drone) {}
 
+// the json columns of a flight log, a malformed value is reported and treated as missing
+fn decode_column<T: DeserializeOwned>(
+    sim_id: &str,
+    column: &str,
+    value: Option<String>,
+) -> Option<T> {
+    let value = value?;
+    match serde_json::from_str(&value) {
+        Ok(decoded) => Some(decoded),
+        Err(err) => {
+            log::warn!("Malformed {column} in the flight log of {sim_id}: {err}");
+            None
+        }
+    }
+}
+
 impl LoaderTrait for DBLoader {
     fn load_drone(&mut self, config_id: &str) -> Drone {
         let mut db = self.db.lock().unwrap();
@@ -185,6 +240,8 @@ impl LoaderTrait for DBLoader {
                 frame.acceleration_y,
                 frame.acceleration_z,
             ),
+            external_force: Vector3::zeros(),
+            wind: Vector3::zeros(),
         };
         let gyro_state = GyroState {
             rotation: UnitQuaternion::new_normalize(Quaternion::new(
@@ -280,7 +337,7 @@ impl LoaderTrait for DBLoader {
             )),
         };
 
-        let gyro_model = GyroModel {};
+        let gyro_model = GyroModel::default();
         Drone {
             current_frame,
             next_frame,
@@ -288,6 +345,10 @@ impl LoaderTrait for DBLoader {
             rotor_model,
             drone_model,
             gyro_model,
+            // the motor order is not stored in the db yet
+            motor_map: MotorMap::default(),
+            rates: SimulationRates::default(),
+            noise: Noise::default(),
         }
     }
 
@@ -341,13 +402,32 @@ impl LoaderTrait for DBLoader {
                     yaw: fl.yaw,
                     roll: fl.roll,
                     pitch: fl.pitch,
+                    aux: decode_column(sim_id, "aux_channels", fl.aux_channels)
+                        .unwrap_or(Channels::default().aux),
                 },
+                shadow_motor_inputs: decode_column(
+                    sim_id,
+                    "shadow_motor_inputs",
+                    fl.shadow_motor_inputs,
+                )
+                .unwrap_or_default(),
+                arming_state: decode_column(sim_id, "arming_state", fl.arming_state)
+                    .unwrap_or_default(),
+                intervention: decode_column(sim_id, "intervention", fl.intervention).flatten(),
             })
             .collect();
-        FlightLog {
-            simulation_id: sim_id.to_owned(),
-            steps: snapshots,
+        let mut flight_log = FlightLog::new(sim_id.to_owned(), snapshots);
+        // older flight logs have no events
+        if let Some(events) = db.fetch_flight_log_events(sim_id) {
+            flight_log.collisions =
+                decode_column(sim_id, "collisions", events.collisions).unwrap_or_default();
+            flight_log.gate_passes =
+                decode_column(sim_id, "gate_passes", events.gate_passes).unwrap_or_default();
+            flight_log.laps = decode_column(sim_id, "laps", events.laps).unwrap_or_default();
+            flight_log.parameters =
+                decode_column(sim_id, "parameters", events.parameters).unwrap_or_default();
         }
+        flight_log
     }
 
     fn load_res_controller(&mut self, controller_id: &str) -> DroneRc {
@@ -416,12 +496,63 @@ impl LoaderTrait for DBLoader {
         };
         self.db.lock().unwrap().insert_reservoir(db_rc_model);
     }
+
+    fn load_rate_profile(&mut self, profile_id: &str) -> Result<RateProfile, LoaderError> {
+        let db_profile = self.db.lock().unwrap().fetch_rate_profile(profile_id);
+        db_to_rate_profile(db_profile)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::db_loader::DBParts;
+    use crate::{
+        LoaderError,
+        db_loader::{DBParts, db_to_rate_profile, decode_column},
+    };
+    use db_common::DBRateProfile;
     use drone::default_drone::default_7in_4s_drone;
+    use flight_controller::AUX_CHANNELS;
+
+    #[test]
+    fn unknown_rates_type() {
+        let db_profile = |rates_type: &str| DBRateProfile {
+            id: "race".into(),
+            rates_type: rates_type.into(),
+            roll_rc_rate: 7.,
+            pitch_rc_rate: 7.,
+            yaw_rc_rate: 7.,
+            roll_expo: 0.,
+            pitch_expo: 0.,
+            yaw_expo: 0.,
+            roll_srate: 67.,
+            pitch_srate: 67.,
+            yaw_srate: 67.,
+            roll_rate_limit: 1998.,
+            pitch_rate_limit: 1998.,
+            yaw_rate_limit: 1998.,
+            thr_mid: 0.5,
+            thr_expo: 0.,
+        };
+        assert!(db_to_rate_profile(db_profile("actual")).is_ok());
+        assert_eq!(
+            db_to_rate_profile(db_profile("quick")).unwrap_err(),
+            LoaderError::UnknownRatesType {
+                profile_id: "race".into(),
+                rates_type: "quick".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_columns() {
+        let aux: Option<[f64; AUX_CHANNELS]> =
+            decode_column("sim", "aux_channels", Some("[1.0, 2.0]".into()));
+        assert_eq!(aux, None);
+        let aux: Option<[f64; 2]> = decode_column("sim", "aux_channels", Some("[1.0, 2.0]".into()));
+        assert_eq!(aux, Some([1., 2.]));
+        let aux: Option<[f64; 2]> = decode_column("sim", "aux_channels", None);
+        assert_eq!(aux, None);
+    }
 
     #[test]
     fn save_default_config_to_db() {
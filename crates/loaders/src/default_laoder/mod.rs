@@ -1,9 +1,10 @@
 // NOTE: only loads the default drone config. This is for debugging and stuff
 
 use drone::default_drone::default_7in_4s_drone;
+use flight_controller::rates::RateProfile;
 use res_controller::DroneRc;
 
-use crate::LoaderTrait;
+use crate::{LoaderError, LoaderTrait};
 
 #[derive(Debug, Default)]
 pub struct DefaultLoader {}
@@ -32,4 +33,8 @@ impl LoaderTrait for DefaultLoader {
     fn insert_reservoir(&mut self, _controller_id: &str, _controller: DroneRc) {
         todo!()
     }
+
+    fn load_rate_profile(&mut self, _profile_id: &str) -> Result<RateProfile, LoaderError> {
+        Ok(RateProfile::default())
+    }
 }
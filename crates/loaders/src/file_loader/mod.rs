@@ -1,8 +1,9 @@
 use drone::Drone;
+use flight_controller::rates::RateProfile;
 use res_controller::DroneRc;
 use std::{fs, path::PathBuf};
 
-use crate::LoaderTrait;
+use crate::{LoaderError, LoaderTrait};
 
 // const LOADER_PATH: &str = "/home/gabor/.local/share/quad/";
 fn loader_path() -> PathBuf {
@@ -71,12 +72,22 @@ impl LoaderTrait for FileLoader {
         let serialized = serde_json::to_string(&controller).unwrap();
         fs::write(reservoir_dir, serialized).unwrap();
     }
+
+    fn load_rate_profile(&mut self, profile_id: &str) -> Result<RateProfile, LoaderError> {
+        let mut rates_path = loader_path();
+        rates_path.push("rates/");
+        fs::create_dir_all(&rates_path).unwrap();
+        rates_path.push(format!("{profile_id}.json"));
+        let content = fs::read_to_string(rates_path).unwrap();
+        Ok(serde_json::from_slice(content.as_bytes()).unwrap())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use crate::file_loader::loader_path;
     use drone::default_drone::default_7in_4s_drone;
+    use flight_controller::rates::RateProfile;
     use std::fs;
 
     #[test]
@@ -89,4 +100,14 @@ mod test {
         drone_path.push("7in_4s_drone.json");
         fs::write(drone_path, serialized).unwrap();
     }
+
+    #[test]
+    fn save_default_rate_profile_to_file() {
+        let serialized = serde_json::to_string(&RateProfile::default()).unwrap();
+        let mut rates_path = loader_path();
+        rates_path.push("rates");
+        fs::create_dir_all(&rates_path).unwrap();
+        rates_path.push("default.json");
+        fs::write(rates_path, serialized).unwrap();
+    }
 }
@@ -3,9 +3,36 @@ pub mod default_laoder;
 pub mod file_loader;
 
 use drone::Drone;
+use flight_controller::rates::RateProfile;
 // use flight_controller::controllers::res_controller::ResController;
 use loggers::FlightLog;
 use res_controller::DroneRc;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoaderError {
+    // the stored rate profile has a rates type this version does not know
+    UnknownRatesType {
+        profile_id: String,
+        rates_type: String,
+    },
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoaderError::UnknownRatesType {
+                profile_id,
+                rates_type,
+            } => write!(
+                f,
+                "rate profile {profile_id} has unknown rates type {rates_type:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoaderError {}
 
 pub trait LoaderTrait: Send + Sync {
     // load a drone
@@ -29,4 +56,7 @@ pub trait LoaderTrait: Send + Sync {
 
     // Insert a new reservoir
     fn insert_reservoir(&mut self, controller_id: &str, controller: DroneRc);
+
+    // Load a stick rate profile
+    fn load_rate_profile(&mut self, profile_id: &str) -> Result<RateProfile, LoaderError>;
 }
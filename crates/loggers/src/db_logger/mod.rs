@@ -1,14 +1,20 @@
-use crate::{Logger, SnapShot};
+use crate::{CollisionEvent, GatePass, LapTime, Logger, SnapShot};
 use db_common::DBNewFlightLog;
 use sqlx::Connection;
 use sqlx::SqliteConnection;
 use sqlx::query;
+use std::collections::BTreeMap;
 
 pub struct DBLogger {
     pub data: Vec<DBNewFlightLog>,
     pub simulation_id: String,
     pub last_time_step: f64,
     pub conn: SqliteConnection,
+    // written to flight_log_events with the steps
+    pub collisions: Vec<CollisionEvent>,
+    pub gate_passes: Vec<GatePass>,
+    pub laps: Vec<LapTime>,
+    pub parameters: BTreeMap<String, f64>,
 }
 
 impl DBLogger {
@@ -24,6 +30,10 @@ impl DBLogger {
             simulation_id,
             last_time_step: 0.,
             conn,
+            collisions: vec![],
+            gate_passes: vec![],
+            laps: vec![],
+            parameters: BTreeMap::new(),
         }
     }
 
@@ -56,6 +66,12 @@ impl DBLogger {
             roll: snapshot.channels.roll,
             pitch: snapshot.channels.pitch,
             yaw: snapshot.channels.yaw,
+            aux_channels: Some(serde_json::to_string(&snapshot.channels.aux).unwrap()),
+            shadow_motor_inputs: Some(
+                serde_json::to_string(&snapshot.shadow_motor_inputs).unwrap(),
+            ),
+            arming_state: Some(serde_json::to_string(&snapshot.arming_state).unwrap()),
+            intervention: Some(serde_json::to_string(&snapshot.intervention).unwrap()),
         });
     }
 
@@ -69,9 +85,10 @@ impl DBLogger {
                         motor_input_3, motor_input_4, battery_voltage_sag, battery_voltage, amperage,
                         mah_drawn, cell_count, rot_quat_x, rot_quat_y, rot_quat_z, rot_quat_w,
                         linear_acceleration_x, linear_acceleration_y, linear_acceleration_z,
-                        angular_velocity_x, angular_velocity_y, angular_velocity_z, throttle, roll, pitch, yaw
+                        angular_velocity_x, angular_velocity_y, angular_velocity_z, throttle, roll, pitch, yaw,
+                        aux_channels, shadow_motor_inputs, arming_state, intervention
                     ) VALUES (
-                        ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?
+                        ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?
                     )"#,
                 self.simulation_id,
                 flight_log.start_seconds,
@@ -99,6 +116,28 @@ impl DBLogger {
                 flight_log.roll,
                 flight_log.pitch,
                 flight_log.yaw,
+                flight_log.aux_channels,
+                flight_log.shadow_motor_inputs,
+                flight_log.arming_state,
+                flight_log.intervention,
+            );
+            query.execute(&mut *trx).await.unwrap();
+        }
+        if !self.data.is_empty() {
+            let collisions = serde_json::to_string(&self.collisions).unwrap();
+            let gate_passes = serde_json::to_string(&self.gate_passes).unwrap();
+            let laps = serde_json::to_string(&self.laps).unwrap();
+            let parameters = serde_json::to_string(&self.parameters).unwrap();
+            let query = query!(
+                r#"
+                    INSERT OR REPLACE INTO flight_log_events (
+                        simulation_id, collisions, gate_passes, laps, parameters
+                    ) VALUES (?, ?, ?, ?, ?)"#,
+                self.simulation_id,
+                collisions,
+                gate_passes,
+                laps,
+                parameters,
             );
             query.execute(&mut *trx).await.unwrap();
         }
@@ -113,6 +152,22 @@ impl Logger for DBLogger {
         self.last_time_step = new_time_step;
     }
 
+    fn log_collision(&mut self, collision: CollisionEvent) {
+        self.collisions.push(collision);
+    }
+
+    fn log_gate_pass(&mut self, gate_pass: GatePass) {
+        self.gate_passes.push(gate_pass);
+    }
+
+    fn log_lap(&mut self, lap: LapTime) {
+        self.laps.push(lap);
+    }
+
+    fn log_parameters(&mut self, parameters: BTreeMap<String, f64>) {
+        self.parameters = parameters;
+    }
+
     fn flush(&mut self) {
         smol::block_on(async { self.write_flight_logs_async().await })
     }
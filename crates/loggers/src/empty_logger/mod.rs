@@ -1,9 +1,14 @@
-use crate::{Logger, SnapShot};
+use crate::{CollisionEvent, GatePass, LapTime, Logger, SnapShot};
+use std::collections::BTreeMap;
 
 #[derive(Default)]
 pub struct EmptyLogger {}
 
 impl Logger for EmptyLogger {
     fn log_time_stamp(&mut self, _snapshot: SnapShot) {}
+    fn log_collision(&mut self, _collision: CollisionEvent) {}
+    fn log_gate_pass(&mut self, _gate_pass: GatePass) {}
+    fn log_lap(&mut self, _lap: LapTime) {}
+    fn log_parameters(&mut self, _parameters: BTreeMap<String, f64>) {}
     fn flush(&mut self) {}
 }
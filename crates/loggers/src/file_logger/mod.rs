@@ -1,11 +1,15 @@
-use crate::{FlightLog, Logger, SnapShot};
-use std::{fs, path::PathBuf};
+use crate::{CollisionEvent, FlightLog, GatePass, LapTime, Logger, SnapShot};
+use std::{collections::BTreeMap, fs, path::PathBuf};
 
 const LOG_PATH: &str = "/home/gabor/.local/share/quad/replays/";
 
 pub struct FileLogger {
     simulation_id: String,
     snapshots: Vec<SnapShot>,
+    collisions: Vec<CollisionEvent>,
+    gate_passes: Vec<GatePass>,
+    laps: Vec<LapTime>,
+    parameters: BTreeMap<String, f64>,
 }
 
 impl Logger for FileLogger {
@@ -13,10 +17,30 @@ impl Logger for FileLogger {
         self.snapshots.push(snapshot);
     }
 
+    fn log_collision(&mut self, collision: CollisionEvent) {
+        self.collisions.push(collision);
+    }
+
+    fn log_gate_pass(&mut self, gate_pass: GatePass) {
+        self.gate_passes.push(gate_pass);
+    }
+
+    fn log_lap(&mut self, lap: LapTime) {
+        self.laps.push(lap);
+    }
+
+    fn log_parameters(&mut self, parameters: BTreeMap<String, f64>) {
+        self.parameters = parameters;
+    }
+
     fn flush(&mut self) {
         let flight_log = FlightLog {
             simulation_id: self.simulation_id.clone(),
             steps: self.snapshots.clone(),
+            collisions: self.collisions.clone(),
+            gate_passes: self.gate_passes.clone(),
+            laps: self.laps.clone(),
+            parameters: self.parameters.clone(),
         };
         if self.snapshots.len() > 0 {
             let mut log_path = PathBuf::from(LOG_PATH);
@@ -32,6 +56,10 @@ impl FileLogger {
         Self {
             simulation_id,
             snapshots: vec![],
+            collisions: vec![],
+            gate_passes: vec![],
+            laps: vec![],
+            parameters: BTreeMap::new(),
         }
     }
 }
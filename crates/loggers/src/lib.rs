@@ -3,9 +3,12 @@ pub mod empty_logger;
 pub mod file_logger;
 pub mod rerun_logger;
 
-use flight_controller::{BatteryUpdate, Channels, GyroUpdate, MotorInput};
+use flight_controller::{
+    ArmingState, BatteryUpdate, Breach, Channels, GyroUpdate, MotorInput, ShadowOutput,
+    controllers::supervisor::Intervention,
+};
 use serde::{Deserialize, Serialize};
-use std::{any::Any, time::Duration};
+use std::{any::Any, collections::BTreeMap, time::Duration};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SnapShot {
@@ -14,6 +17,15 @@ pub struct SnapShot {
     pub battery_update: BatteryUpdate,
     pub gyro_update: GyroUpdate,
     pub channels: Channels,
+    // what the shadow controllers would have done, the drone was flown with `motor_input`
+    #[serde(default)]
+    pub shadow_motor_inputs: Vec<ShadowOutput>,
+    // older logs do not have an arming state
+    #[serde(default)]
+    pub arming_state: ArmingState,
+    // set while a safety supervisor flies the drone with its fallback controller
+    #[serde(default)]
+    pub intervention: Option<Breach>,
     // // TODO: this has a lot, I think this should be enough
     // pub current_frame: SimulationFrame,
 }
@@ -32,15 +44,71 @@ impl SnapShot {
             battery_update,
             gyro_update,
             channels,
+            shadow_motor_inputs: vec![],
+            arming_state: ArmingState::default(),
+            intervention: None,
         }
     }
 }
 
+/// The drone started to touch an obstacle of the scene
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CollisionEvent {
+    pub duration: Duration,
+    pub obstacle: String,
+    // the point of the obstacle that was hit, in the world frame
+    pub point: [f64; 3],
+    // the speed towards the obstacle in m/s
+    pub impact_speed: f64,
+    // the drone crashed and stays where it hit
+    pub crashed: bool,
+}
+
+/// The drone flew through a gate of the race course
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GatePass {
+    pub duration: Duration,
+    pub gate: usize,
+    // the lap the gate belongs to, the start gate is the first gate of a lap
+    pub lap: usize,
+    // since the start of the lap
+    pub split: Duration,
+    // the gates that were skipped to get here
+    pub missed: Vec<usize>,
+}
+
+/// A lap of the race course, from one pass of the start gate to the next
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct LapTime {
+    pub lap: usize,
+    pub time: Duration,
+    // the time from the start of the lap to every gate that was passed, and to the finish
+    pub splits: Vec<Duration>,
+    pub missed: Vec<usize>,
+}
+
+impl LapTime {
+    /// A lap only counts if no gate was missed
+    pub fn is_valid(&self) -> bool {
+        self.missed.is_empty()
+    }
+}
+
 // This is what we need to save
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FlightLog {
     pub simulation_id: String,
     pub steps: Vec<SnapShot>,
+    // older logs do not have collisions
+    #[serde(default)]
+    pub collisions: Vec<CollisionEvent>,
+    #[serde(default)]
+    pub gate_passes: Vec<GatePass>,
+    #[serde(default)]
+    pub laps: Vec<LapTime>,
+    // the physical parameters the drone was flown with, e.g. when they were randomized
+    #[serde(default)]
+    pub parameters: BTreeMap<String, f64>,
 }
 
 impl FlightLog {
@@ -48,9 +116,21 @@ impl FlightLog {
         Self {
             simulation_id,
             steps,
+            collisions: vec![],
+            gate_passes: vec![],
+            laps: vec![],
+            parameters: BTreeMap::new(),
         }
     }
 
+    /// The fastest valid lap
+    pub fn best_lap(&self) -> Option<&LapTime> {
+        self.laps
+            .iter()
+            .filter(|lap| lap.is_valid())
+            .min_by_key(|lap| lap.time)
+    }
+
     pub fn downsample(&mut self, target_dt: Duration) {
         let mut t = self.steps[0].duration;
         let mut new_steps = vec![self.steps[0].clone()];
@@ -62,10 +142,90 @@ impl FlightLog {
         }
         self.steps = new_steps
     }
+
+    /// The mean squared error between the motor inputs of a shadow controller and the ones that
+    /// flew the drone. None if the controller was not recorded.
+    pub fn shadow_error(&self, controller_id: &str) -> Option<f64> {
+        let mut squared_error_sum = 0.;
+        let mut samples = 0;
+        for step in self.steps.iter() {
+            let Some(shadow) = step
+                .shadow_motor_inputs
+                .iter()
+                .find(|shadow| shadow.controller_id == controller_id)
+            else {
+                continue;
+            };
+            for i in 0..4 {
+                squared_error_sum += (shadow.motor_input[i] - step.motor_input[i]).powi(2);
+            }
+            samples += 4;
+        }
+        (samples > 0).then(|| squared_error_sum / samples as f64)
+    }
+
+    /// The interventions of the safety supervisor, put together from the logged steps. The last
+    /// one has no end if the fallback was still in control when the log ended.
+    pub fn interventions(&self) -> Vec<Intervention> {
+        let mut interventions: Vec<Intervention> = vec![];
+        for step in self.steps.iter() {
+            let ongoing = interventions
+                .last_mut()
+                .filter(|intervention| intervention.end.is_none());
+            match (step.intervention, ongoing) {
+                (Some(breach), None) => interventions.push(Intervention {
+                    start: step.duration,
+                    end: None,
+                    breach,
+                }),
+                (None, Some(intervention)) => intervention.end = Some(step.duration),
+                _ => {}
+            }
+        }
+        interventions
+    }
 }
 
 pub trait Logger: Sync + Send + Any {
     fn log_time_stamp(&mut self, snapshot: SnapShot);
+    fn log_collision(&mut self, collision: CollisionEvent);
+    fn log_gate_pass(&mut self, gate_pass: GatePass);
+    fn log_lap(&mut self, lap: LapTime);
+    fn log_parameters(&mut self, parameters: BTreeMap<String, f64>);
     fn flush(&mut self);
     // fn set_simulation_id(&mut self, simulation_id: &str);
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{FlightLog, SnapShot};
+    use flight_controller::{Breach, MotorInput};
+    use std::time::Duration;
+
+    #[test]
+    fn logged_interventions() {
+        let breaches = [None, Some(Breach::Tilt(1.2)), Some(Breach::Rate(30.)), None];
+        let steps = breaches
+            .into_iter()
+            .chain([Some(Breach::NonFinite)])
+            .enumerate()
+            .map(|(i, intervention)| SnapShot {
+                intervention,
+                ..SnapShot::new(
+                    Duration::from_millis(i as u64),
+                    MotorInput::default(),
+                    Default::default(),
+                    Default::default(),
+                    Default::default(),
+                )
+            })
+            .collect();
+        let interventions = FlightLog::new("log".into(), steps).interventions();
+        assert_eq!(interventions.len(), 2);
+        // the breach that started it, the fallback stays in control until the drone recovered
+        assert_eq!(interventions[0].breach, Breach::Tilt(1.2));
+        assert_eq!(interventions[0].start, Duration::from_millis(1));
+        assert_eq!(interventions[0].end, Some(Duration::from_millis(3)));
+        assert_eq!(interventions[1].end, None);
+    }
+}
@@ -1,5 +1,6 @@
-use crate::{Logger, SnapShot};
-use rerun::RecordingStream;
+use crate::{CollisionEvent, GatePass, LapTime, Logger, SnapShot};
+use rerun::{RecordingStream, TextLog, TextLogLevel};
+use std::collections::BTreeMap;
 
 pub struct RerunLogger {
     counter: usize,
@@ -18,6 +19,44 @@ impl Logger for RerunLogger {
         //     .unwrap();
     }
 
+    fn log_collision(&mut self, collision: CollisionEvent) {
+        let level = if collision.crashed {
+            TextLogLevel::ERROR
+        } else {
+            TextLogLevel::WARN
+        };
+        let text = format!(
+            "hit {} at {:.2} m/s",
+            collision.obstacle, collision.impact_speed
+        );
+        self.log_event("events/collisions", text, level);
+    }
+
+    fn log_gate_pass(&mut self, gate_pass: GatePass) {
+        let text = format!(
+            "gate {} of lap {} after {:.3} s, missed {:?}",
+            gate_pass.gate,
+            gate_pass.lap,
+            gate_pass.split.as_secs_f64(),
+            gate_pass.missed
+        );
+        self.log_event("events/gates", text, TextLogLevel::INFO);
+    }
+
+    fn log_lap(&mut self, lap: LapTime) {
+        let text = format!(
+            "lap {} in {:.3} s, missed {:?}",
+            lap.lap,
+            lap.time.as_secs_f64(),
+            lap.missed
+        );
+        self.log_event("events/laps", text, TextLogLevel::INFO);
+    }
+
+    fn log_parameters(&mut self, parameters: BTreeMap<String, f64>) {
+        self.log_event("parameters", format!("{parameters:?}"), TextLogLevel::INFO);
+    }
+
     fn flush(&mut self) {
         self.rec.flush_blocking();
     }
@@ -30,6 +69,13 @@ impl Drop for RerunLogger {
 }
 
 impl RerunLogger {
+    // the events are logged at the time of the last snapshot, the step they happened in
+    fn log_event(&mut self, path: &str, text: String, level: &str) {
+        if let Err(err) = self.rec.log(path, &TextLog::new(text).with_level(level)) {
+            log::warn!("Could not log to rerun: {err}");
+        }
+    }
+
     pub fn new(simulation_id: String) -> Self {
         let rec = rerun::RecordingStreamBuilder::new(simulation_id)
             .spawn()
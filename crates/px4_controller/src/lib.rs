@@ -0,0 +1,512 @@
+//! Flies the drone with PX4 SITL through its MAVLink HIL interface. PX4 connects to the simulator
+//! over TCP (port 4560), the simulator sends `HIL_SENSOR`, `HIL_GPS` and `HIL_STATE_QUATERNION`
+//! and PX4 answers with `HIL_ACTUATOR_CONTROLS`. In lockstep mode PX4 only advances its clock with
+//! the timestamps of the sensor messages, so every flight controller update sends the sensors and
+//! waits for the actuator controls.
+//!
+//! PX4 is NED/FRD while the simulator is y up, see `flight_controller::frames`. The GPS position is
+//! computed from the position relative to the home location.
+
+pub mod mavlink;
+pub mod mock;
+
+use flight_controller::{
+    FlightController, FlightControllerUpdate, InitError, MotorInput,
+    frames::{GRAVITY, ned_to_frd, pressure, specific_force_frd, to_frd, to_ned_quaternion},
+};
+use mavlink::{
+    Frame, FrameReader, HilActuatorControls, HilGps, HilSensor, HilStateQuaternion, MavMessage,
+};
+use std::{
+    io::{ErrorKind, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tempfile::TempDir;
+
+const EARTH_RADIUS: f64 = 6_371_000.;
+// PX4 motor n drives this Betaflight motor (quad X: front right, rear left, front left, rear
+// right)
+const BF_MOTOR_OF_PX4_MOTOR: [usize; 4] = [1, 2, 3, 0];
+// the simulator's MAVLink ids
+const SYS_ID: u8 = 1;
+const COMP_ID: u8 = 51;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoPoint {
+    pub lat: f64, // deg
+    pub lon: f64,
+    pub alt: f64, // m above sea level
+}
+
+#[derive(Debug, Clone)]
+pub struct Px4Config {
+    // None if PX4 is started by hand
+    pub binary: Option<PathBuf>,
+    pub args: Vec<String>,
+    pub envs: Vec<(String, String)>,
+    pub bind: String,
+    // the rate the sensors are sent at, PX4 runs its controllers on the imu
+    pub scheduler_delta: Duration,
+    pub gps_interval: Duration,
+    // how long to wait for the actuator controls, the connection can take much longer
+    pub frame_timeout: Duration,
+    // how long the first update waits for PX4 to connect, later updates only check for it
+    pub startup_timeout: Duration,
+    // where the simulation origin is on the globe
+    pub home: GeoPoint,
+    // the earth's magnetic field at home in NED, in gauss
+    pub magnetic_field: [f64; 3],
+}
+
+impl Default for Px4Config {
+    fn default() -> Self {
+        Self {
+            binary: None,
+            args: vec![],
+            envs: vec![],
+            bind: "127.0.0.1:4560".into(),
+            scheduler_delta: Duration::from_millis(4),
+            gps_interval: Duration::from_millis(100),
+            frame_timeout: Duration::from_secs(1),
+            startup_timeout: Duration::from_secs(60),
+            // the default home of the PX4 SITL
+            home: GeoPoint {
+                lat: 47.397742,
+                lon: 8.545594,
+                alt: 488.,
+            },
+            magnetic_field: [0.21, 0.015, 0.43],
+        }
+    }
+}
+
+impl Px4Config {
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: Some(binary.into()),
+            ..Default::default()
+        }
+    }
+}
+
+/// A running PX4 SITL, it is killed when this is dropped.
+#[derive(Debug)]
+pub struct Px4Process {
+    child: Child,
+    // PX4 keeps its parameters and logs in the working directory
+    _workdir: TempDir,
+}
+
+impl Px4Process {
+    pub fn spawn(binary: &Path, config: &Px4Config) -> std::io::Result<Self> {
+        let workdir = TempDir::new()?;
+        let child = Command::new(binary)
+            .args(&config.args)
+            .envs(config.envs.iter().cloned())
+            .current_dir(workdir.path())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        log::info!("Started PX4 SITL with pid {}", child.id());
+        Ok(Self {
+            child,
+            _workdir: workdir,
+        })
+    }
+}
+
+impl Drop for Px4Process {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// The HIL messages for one update, the GPS is only sent every gps interval
+pub fn hil_messages(
+    config: &Px4Config,
+    time_usec: u64,
+    update: &FlightControllerUpdate,
+    velocity: [f64; 3],
+) -> (HilSensor, HilGps, HilStateQuaternion) {
+    let gyro = &update.gyro_update;
+    let [north, east, down] = to_frd(update.position);
+    let [vn, ve, vd] = to_frd(velocity);
+    let home = &config.home;
+    let lat = home.lat + f64::to_degrees(north / EARTH_RADIUS);
+    let lon = home.lon + f64::to_degrees(east / (EARTH_RADIUS * home.lat.to_radians().cos()));
+    let alt = home.alt - down;
+    let rotation = to_ned_quaternion(gyro.rotation);
+    let acc = specific_force_frd(gyro);
+    let f32s = |v: [f64; 3]| v.map(|x| x as f32);
+    // cm/s and mG
+    let cm = |x: f64| (x * 100.).round() as i16;
+    let milli_g = |x: f64| (x / GRAVITY * 1000.).round() as i16;
+
+    let sensor = HilSensor {
+        time_usec,
+        acc: f32s(acc),
+        gyro: f32s(to_frd(gyro.angular_velocity)),
+        mag: f32s(ned_to_frd(gyro.rotation, config.magnetic_field)),
+        abs_pressure: (pressure(alt) / 100.) as f32,
+        diff_pressure: 0.,
+        pressure_alt: alt as f32,
+        temperature: (15. - 0.0065 * alt) as f32,
+        fields_updated: HilSensor::ALL_FIELDS_UPDATED,
+        id: 0,
+    };
+    let ground_speed = f64::sqrt(vn * vn + ve * ve);
+    let gps = HilGps {
+        time_usec,
+        lat: (lat * 1e7).round() as i32,
+        lon: (lon * 1e7).round() as i32,
+        alt: (alt * 1000.).round() as i32,
+        eph: 100,
+        epv: 100,
+        vel: (ground_speed * 100.).round() as u16,
+        vn: cm(vn),
+        ve: cm(ve),
+        vd: cm(vd),
+        cog: if ground_speed > 0.1 {
+            (f64::atan2(ve, vn).to_degrees().rem_euclid(360.) * 100.) as u16
+        } else {
+            u16::MAX
+        },
+        fix_type: 3,
+        satellites_visible: 10,
+        id: 0,
+        yaw: 0,
+    };
+    let [roll_rate, pitch_rate, yaw_rate] = to_frd(gyro.angular_velocity);
+    let state = HilStateQuaternion {
+        time_usec,
+        attitude_quaternion: rotation.map(|x| x as f32),
+        rollspeed: roll_rate as f32,
+        pitchspeed: pitch_rate as f32,
+        yawspeed: yaw_rate as f32,
+        lat: gps.lat,
+        lon: gps.lon,
+        alt: gps.alt,
+        vx: cm(vn),
+        vy: cm(ve),
+        vz: cm(vd),
+        ind_airspeed: gps.vel,
+        true_airspeed: gps.vel,
+        xacc: milli_g(acc[0]),
+        yacc: milli_g(acc[1]),
+        zacc: milli_g(acc[2]),
+    };
+    (sensor, gps, state)
+}
+
+/// The first four actuator controls in Betaflight motor order
+pub fn motor_input(controls: &HilActuatorControls) -> MotorInput {
+    let mut input = [0.; 4];
+    for (px4_motor, bf_motor) in BF_MOTOR_OF_PX4_MOTOR.iter().enumerate() {
+        input[*bf_motor] = (controls.controls[px4_motor] as f64).clamp(0., 1.);
+    }
+    MotorInput { input }
+}
+
+#[derive(Debug, Default)]
+struct Px4State {
+    process: Option<Px4Process>,
+    listener: Option<TcpListener>,
+    stream: Option<TcpStream>,
+    reader: FrameReader,
+    seq: u8,
+    time_usec: u64,
+    last_gps: Option<u64>,
+    last_position: Option<[f64; 3]>,
+    motor_input: MotorInput,
+    armed: bool,
+    timeouts: usize,
+    // PX4 had the startup timeout to connect, a reconnect must not block the updates
+    started: bool,
+}
+
+impl Px4State {
+    fn send<M: MavMessage>(&mut self, message: &M) -> std::io::Result<()> {
+        let frame = Frame::new(message, self.seq, SYS_ID, COMP_ID);
+        self.seq = self.seq.wrapping_add(1);
+        match self.stream.as_mut() {
+            Some(stream) => stream.write_all(&frame.to_bytes()),
+            None => Err(ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Px4Controller {
+    pub config: Px4Config,
+    state: Mutex<Px4State>,
+}
+
+impl Px4Controller {
+    pub fn new(config: Px4Config) -> Self {
+        Self {
+            config,
+            state: Mutex::new(Px4State::default()),
+        }
+    }
+
+    /// The address PX4 has to connect to, None before init
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        let state = self.state.lock().unwrap();
+        state
+            .listener
+            .as_ref()
+            .and_then(|listener| listener.local_addr().ok())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().stream.is_some()
+    }
+
+    /// Whether PX4 reported to be armed with the last actuator controls
+    pub fn is_armed(&self) -> bool {
+        self.state.lock().unwrap().armed
+    }
+
+    /// How often the actuator controls did not arrive in time
+    pub fn timeouts(&self) -> usize {
+        self.state.lock().unwrap().timeouts
+    }
+
+    // blocking reads that give up after a frame, without delaying the small HIL messages
+    fn set_up(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(self.config.frame_timeout))
+    }
+
+    fn accept(&self, state: &mut Px4State) -> Option<TcpStream> {
+        let timeout = if state.started {
+            Duration::ZERO
+        } else {
+            self.config.startup_timeout
+        };
+        state.started = true;
+        let listener = state.listener.as_ref()?;
+        if let Err(err) = listener.set_nonblocking(true) {
+            log::warn!("Could not poll for the PX4 connection: {err}");
+            return None;
+        }
+        let start = Instant::now();
+        loop {
+            match listener.accept() {
+                // PX4 connects again if the connection is dropped
+                Ok((stream, _)) => match self.set_up(&stream) {
+                    Ok(()) => return Some(stream),
+                    Err(err) => {
+                        log::warn!("Dropped the PX4 connection: {err}");
+                        return None;
+                    }
+                },
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    if start.elapsed() >= timeout {
+                        return None;
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(err) => {
+                    log::warn!("Could not accept the PX4 connection: {err}");
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl FlightController for Px4Controller {
+    fn init(&self) -> Result<(), InitError> {
+        let mut state = self.state.lock().unwrap();
+        // the old process has to release the port first
+        *state = Px4State::default();
+        let listener = TcpListener::bind(&self.config.bind)
+            .map_err(|err| format!("could not bind the HIL port {}: {err}", self.config.bind))?;
+        state.listener = Some(listener);
+        if let Some(binary) = &self.config.binary {
+            let process = Px4Process::spawn(binary, &self.config).map_err(|err| {
+                format!("could not start the PX4 SITL {}: {err}", binary.display())
+            })?;
+            state.process = Some(process);
+        }
+        Ok(())
+    }
+
+    fn update(&self, delta_time: f64, update: FlightControllerUpdate) -> MotorInput {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        if state.stream.is_none() {
+            state.stream = self.accept(state);
+            if state.stream.is_none() {
+                state.timeouts += 1;
+                return MotorInput::default();
+            }
+            state.reader = FrameReader::default();
+        }
+
+        state.time_usec += (delta_time * 1e6).round() as u64;
+        let velocity = match state.last_position {
+            Some(last) if delta_time > 0. => {
+                std::array::from_fn(|i| (update.position[i] - last[i]) / delta_time)
+            }
+            _ => [0.; 3],
+        };
+        state.last_position = Some(update.position);
+        let (sensor, gps, ground_truth) =
+            hil_messages(&self.config, state.time_usec, &update, velocity);
+        let gps_due = state.last_gps.is_none_or(|last| {
+            state.time_usec - last >= self.config.gps_interval.as_micros() as u64
+        });
+
+        let mut sent = state.send(&sensor);
+        if gps_due {
+            state.last_gps = Some(state.time_usec);
+            sent = sent.and_then(|_| state.send(&gps));
+        }
+        sent = sent.and_then(|_| state.send(&ground_truth));
+        if let Err(err) = sent {
+            log::warn!("PX4 disconnected: {err}");
+            state.stream = None;
+            return MotorInput::default();
+        }
+
+        loop {
+            let stream = state.stream.as_mut().unwrap();
+            match state.reader.read_frame(stream) {
+                Ok(frame) => {
+                    if let Some(controls) = frame.message::<HilActuatorControls>() {
+                        state.armed =
+                            controls.mode & HilActuatorControls::MODE_FLAG_SAFETY_ARMED != 0;
+                        state.motor_input = motor_input(&controls);
+                        break;
+                    }
+                }
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    log::warn!(
+                        "No actuator controls from PX4 within {:?}",
+                        self.config.frame_timeout
+                    );
+                    state.timeouts += 1;
+                    break;
+                }
+                Err(err) => {
+                    log::warn!("PX4 disconnected: {err}");
+                    state.stream = None;
+                    state.motor_input = MotorInput::default();
+                    break;
+                }
+            }
+        }
+        state.motor_input
+    }
+
+    fn scheduler_delta(&self) -> Duration {
+        self.config.scheduler_delta
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Px4Config, Px4Controller, mock::MockPx4};
+    use flight_controller::{FlightController, FlightControllerUpdate};
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn missing_binary() {
+        let config = Px4Config {
+            bind: "127.0.0.1:0".into(),
+            ..Px4Config::new("/nonexistent/px4")
+        };
+        let err = Px4Controller::new(config).init().unwrap_err();
+        assert!(err.to_string().contains("could not start the PX4 SITL"));
+    }
+
+    #[test]
+    fn unusable_connection() {
+        // the socket rejects a zero read timeout, the connection is dropped instead
+        let config = Px4Config {
+            bind: "127.0.0.1:0".into(),
+            frame_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        let controller = Px4Controller::new(config);
+        controller.init().unwrap();
+        let _mock = MockPx4::connect(controller.local_addr().unwrap());
+        let mut update = FlightControllerUpdate::default();
+        update.gyro_update.rotation = [0., 0., 0., 1.];
+        assert_eq!(controller.update(0.004, update).input, [0.; 4]);
+        assert!(!controller.is_connected());
+    }
+
+    #[test]
+    fn lock_step_with_mock() {
+        let config = Px4Config {
+            bind: "127.0.0.1:0".into(),
+            frame_timeout: Duration::from_millis(100),
+            startup_timeout: Duration::from_secs(5),
+            gps_interval: Duration::from_millis(8),
+            ..Default::default()
+        };
+        let controller = Px4Controller::new(config);
+        controller.init().unwrap();
+        let address = controller.local_addr().unwrap();
+
+        let mock = thread::spawn(move || {
+            let mut mock = MockPx4::connect(address);
+            let mut sensors = vec![];
+            for i in 0..3 {
+                let sensor = mock.receive_sensor().unwrap();
+                // front right, rear left, front left, rear right
+                mock.send_controls([0.1, 0.2, 0.3, 0.4 + i as f32 / 10.], true);
+                sensors.push(sensor);
+            }
+            (sensors, mock)
+        });
+
+        let mut update = FlightControllerUpdate::default();
+        update.gyro_update.rotation = [0., 0., 0., 1.];
+        for i in 0..3 {
+            let input = controller.update(0.004, update);
+            let expected = [0.4 + i as f64 / 10., 0.1, 0.2, 0.3];
+            assert!(
+                input
+                    .input
+                    .iter()
+                    .zip(expected)
+                    .all(|(a, b)| (a - b).abs() < 1e-6)
+            );
+        }
+        assert!(controller.is_armed());
+
+        // the mock is only dropped now, closing it with unread data resets the connection
+        let (sensors, mock) = mock.join().unwrap();
+        let times: Vec<_> = sensors.iter().map(|sensor| sensor.time_usec).collect();
+        assert_eq!(times, [4000, 8000, 12000]);
+        assert_eq!(sensors[0].acc, [0., 0., -9.81]);
+        // at the home altitude
+        assert!((sensors[0].pressure_alt - 488.).abs() < 1e-3);
+        // the GPS went out with the first and third sensors, the last GPS and state are not read
+        assert_eq!((mock.gps_received, mock.states_received), (1, 2));
+
+        drop(mock);
+        let input = controller.update(0.004, update);
+        assert_eq!(input.input, [0.; 4]);
+        assert!(!controller.is_connected());
+        // the updates after the disconnect do not wait for the startup timeout
+        let start = Instant::now();
+        controller.update(0.004, update);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!controller.is_connected());
+    }
+}
@@ -0,0 +1,548 @@
+//! The small part of MAVLink the HIL interface of PX4 needs. Frames are sent as MAVLink 2, both
+//! versions are read. Payloads are laid out like the generated C code: the fields are sorted by
+//! size (largest first) and the extension fields come last.
+
+use std::io::{self, Read};
+
+const STX_V1: u8 = 0xFE;
+const STX_V2: u8 = 0xFD;
+const SIGNATURE_LEN: usize = 13;
+const INCOMPAT_FLAG_SIGNED: u8 = 0x01;
+
+/// CRC-16/MCRF4XX as used by MAVLink
+pub fn crc_accumulate(crc: u16, byte: u8) -> u16 {
+    let mut tmp = byte ^ (crc & 0xff) as u8;
+    tmp ^= tmp << 4;
+    let tmp = tmp as u16;
+    (crc >> 8) ^ (tmp << 8) ^ (tmp << 3) ^ (tmp >> 4)
+}
+
+pub fn crc_calculate(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .fold(0xffff, |crc, byte| crc_accumulate(crc, *byte))
+}
+
+/// The CRC extra of the messages we know, frames of other messages are skipped
+fn crc_extra(msg_id: u32) -> Option<u8> {
+    match msg_id {
+        Heartbeat::ID => Some(Heartbeat::CRC_EXTRA),
+        HilActuatorControls::ID => Some(HilActuatorControls::CRC_EXTRA),
+        HilSensor::ID => Some(HilSensor::CRC_EXTRA),
+        HilGps::ID => Some(HilGps::CRC_EXTRA),
+        HilStateQuaternion::ID => Some(HilStateQuaternion::CRC_EXTRA),
+        _ => None,
+    }
+}
+
+pub trait MavMessage: Sized {
+    const ID: u32;
+    const CRC_EXTRA: u8;
+    fn encode(&self, payload: &mut Vec<u8>);
+    /// The payload is zero extended to the full length before decoding
+    fn decode(payload: &[u8]) -> Self;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub seq: u8,
+    pub sys_id: u8,
+    pub comp_id: u8,
+    pub msg_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new<M: MavMessage>(message: &M, seq: u8, sys_id: u8, comp_id: u8) -> Self {
+        let mut payload = vec![];
+        message.encode(&mut payload);
+        Self {
+            seq,
+            sys_id,
+            comp_id,
+            msg_id: M::ID,
+            payload,
+        }
+    }
+
+    pub fn message<M: MavMessage>(&self) -> Option<M> {
+        if self.msg_id != M::ID {
+            return None;
+        }
+        let mut payload = self.payload.clone();
+        payload.resize(payload.len().max(255), 0);
+        Some(M::decode(&payload))
+    }
+
+    /// MAVLink 2, the trailing zeros of the payload are cut off
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let len = self
+            .payload
+            .iter()
+            .rposition(|byte| *byte != 0)
+            .map_or(1, |i| i + 1);
+        let mut bytes = vec![STX_V2, len as u8, 0, 0, self.seq, self.sys_id, self.comp_id];
+        bytes.extend(&self.msg_id.to_le_bytes()[..3]);
+        bytes.extend(&self.payload[..len]);
+        let mut crc = crc_calculate(&bytes[1..]);
+        crc = crc_accumulate(crc, crc_extra(self.msg_id).unwrap_or(0));
+        bytes.extend(crc.to_le_bytes());
+        bytes
+    }
+}
+
+/// Collects bytes from a stream and cuts them into frames. Bytes that are not part of a valid
+/// frame are dropped.
+#[derive(Debug, Default)]
+pub struct FrameReader {
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    /// Blocks until a frame of a known message arrived, a read timeout of the stream is passed on
+    /// and the partial frame is kept for the next call.
+    pub fn read_frame(&mut self, reader: &mut impl Read) -> io::Result<Frame> {
+        loop {
+            if let Some(frame) = self.parse() {
+                return Ok(frame);
+            }
+            let mut chunk = [0; 512];
+            let len = reader.read(&mut chunk)?;
+            if len == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buffer.extend(&chunk[..len]);
+        }
+    }
+
+    fn parse(&mut self) -> Option<Frame> {
+        loop {
+            let start = self
+                .buffer
+                .iter()
+                .position(|byte| *byte == STX_V1 || *byte == STX_V2);
+            let Some(start) = start else {
+                self.buffer.clear();
+                return None;
+            };
+            self.buffer.drain(..start);
+            match self.parse_frame() {
+                // not enough data yet
+                None => return None,
+                Some(Ok((frame, len))) => {
+                    self.buffer.drain(..len);
+                    return Some(frame);
+                }
+                // not a frame we can use, look for the next start
+                Some(Err(len)) => {
+                    self.buffer.drain(..len);
+                }
+            }
+        }
+    }
+
+    // the frame and its length, Err with the number of bytes to skip if the frame is unusable
+    fn parse_frame(&self) -> Option<Result<(Frame, usize), usize>> {
+        let buf = &self.buffer;
+        let v2 = buf[0] == STX_V2;
+        let header_len = if v2 { 10 } else { 6 };
+        if buf.len() < header_len {
+            return None;
+        }
+        let payload_len = buf[1] as usize;
+        let signature_len = if v2 && buf[2] & INCOMPAT_FLAG_SIGNED != 0 {
+            SIGNATURE_LEN
+        } else {
+            0
+        };
+        let frame_len = header_len + payload_len + 2 + signature_len;
+        if buf.len() < frame_len {
+            return None;
+        }
+        let (seq, sys_id, comp_id, msg_id) = if v2 {
+            let msg_id = u32::from_le_bytes([buf[7], buf[8], buf[9], 0]);
+            (buf[4], buf[5], buf[6], msg_id)
+        } else {
+            (buf[2], buf[3], buf[4], buf[5] as u32)
+        };
+        let Some(extra) = crc_extra(msg_id) else {
+            return Some(Err(frame_len));
+        };
+        let crc_end = header_len + payload_len;
+        let crc = crc_accumulate(crc_calculate(&buf[1..crc_end]), extra);
+        if crc.to_le_bytes() != buf[crc_end..crc_end + 2] {
+            // might have been a stray start byte
+            return Some(Err(1));
+        }
+        let frame = Frame {
+            seq,
+            sys_id,
+            comp_id,
+            msg_id,
+            payload: buf[header_len..crc_end].to_vec(),
+        };
+        Some(Ok((frame, frame_len)))
+    }
+}
+
+// little endian field access for the decoders
+struct Fields<'a> {
+    payload: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(payload: &'a [u8]) -> Self {
+        Self { payload, offset: 0 }
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let bytes = self.payload[self.offset..self.offset + N]
+            .try_into()
+            .unwrap();
+        self.offset += N;
+        bytes
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take())
+    }
+
+    fn f32(&mut self) -> f32 {
+        f32::from_le_bytes(self.take())
+    }
+
+    fn f32s<const N: usize>(&mut self) -> [f32; N] {
+        std::array::from_fn(|_| self.f32())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Heartbeat {
+    pub custom_mode: u32,
+    pub mav_type: u8,
+    pub autopilot: u8,
+    pub base_mode: u8,
+    pub system_status: u8,
+    pub mavlink_version: u8,
+}
+
+impl MavMessage for Heartbeat {
+    const ID: u32 = 0;
+    const CRC_EXTRA: u8 = 50;
+
+    fn encode(&self, payload: &mut Vec<u8>) {
+        payload.extend(self.custom_mode.to_le_bytes());
+        payload.extend([
+            self.mav_type,
+            self.autopilot,
+            self.base_mode,
+            self.system_status,
+            self.mavlink_version,
+        ]);
+    }
+
+    fn decode(payload: &[u8]) -> Self {
+        let mut fields = Fields::new(payload);
+        Self {
+            custom_mode: fields.u32(),
+            mav_type: fields.u8(),
+            autopilot: fields.u8(),
+            base_mode: fields.u8(),
+            system_status: fields.u8(),
+            mavlink_version: fields.u8(),
+        }
+    }
+}
+
+/// The outputs of PX4, the motors are between 0 and 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HilActuatorControls {
+    pub time_usec: u64,
+    pub controls: [f32; 16],
+    pub mode: u8,
+    pub flags: u64,
+}
+
+impl HilActuatorControls {
+    // set in the mode while PX4 is armed
+    pub const MODE_FLAG_SAFETY_ARMED: u8 = 128;
+}
+
+impl MavMessage for HilActuatorControls {
+    const ID: u32 = 93;
+    const CRC_EXTRA: u8 = 47;
+
+    fn encode(&self, payload: &mut Vec<u8>) {
+        payload.extend(self.time_usec.to_le_bytes());
+        payload.extend(self.flags.to_le_bytes());
+        for control in self.controls {
+            payload.extend(control.to_le_bytes());
+        }
+        payload.push(self.mode);
+    }
+
+    fn decode(payload: &[u8]) -> Self {
+        let mut fields = Fields::new(payload);
+        let time_usec = fields.u64();
+        let flags = fields.u64();
+        Self {
+            time_usec,
+            flags,
+            controls: fields.f32s(),
+            mode: fields.u8(),
+        }
+    }
+}
+
+/// The IMU, magnetometer and barometer. Vectors are FRD, the pressures are in hPa.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HilSensor {
+    pub time_usec: u64,
+    pub acc: [f32; 3],
+    pub gyro: [f32; 3],
+    pub mag: [f32; 3], // gauss
+    pub abs_pressure: f32,
+    pub diff_pressure: f32,
+    pub pressure_alt: f32,
+    pub temperature: f32,
+    // which of the fields above are new, bit 0 is xacc
+    pub fields_updated: u32,
+    pub id: u8,
+}
+
+impl HilSensor {
+    pub const ALL_FIELDS_UPDATED: u32 = 0x1fff;
+}
+
+impl MavMessage for HilSensor {
+    const ID: u32 = 107;
+    const CRC_EXTRA: u8 = 108;
+
+    fn encode(&self, payload: &mut Vec<u8>) {
+        payload.extend(self.time_usec.to_le_bytes());
+        let floats = self
+            .acc
+            .into_iter()
+            .chain(self.gyro)
+            .chain(self.mag)
+            .chain([
+                self.abs_pressure,
+                self.diff_pressure,
+                self.pressure_alt,
+                self.temperature,
+            ]);
+        for value in floats {
+            payload.extend(value.to_le_bytes());
+        }
+        payload.extend(self.fields_updated.to_le_bytes());
+        payload.push(self.id);
+    }
+
+    fn decode(payload: &[u8]) -> Self {
+        let mut fields = Fields::new(payload);
+        Self {
+            time_usec: fields.u64(),
+            acc: fields.f32s(),
+            gyro: fields.f32s(),
+            mag: fields.f32s(),
+            abs_pressure: fields.f32(),
+            diff_pressure: fields.f32(),
+            pressure_alt: fields.f32(),
+            temperature: fields.f32(),
+            fields_updated: fields.u32(),
+            id: fields.u8(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HilGps {
+    pub time_usec: u64,
+    pub lat: i32, // degE7
+    pub lon: i32,
+    pub alt: i32, // mm above sea level
+    pub eph: u16,
+    pub epv: u16,
+    pub vel: u16, // ground speed in cm/s
+    pub vn: i16,  // cm/s
+    pub ve: i16,
+    pub vd: i16,
+    pub cog: u16, // cdeg, u16::MAX if unknown
+    pub fix_type: u8,
+    pub satellites_visible: u8,
+    pub id: u8,
+    pub yaw: u16,
+}
+
+impl MavMessage for HilGps {
+    const ID: u32 = 113;
+    const CRC_EXTRA: u8 = 124;
+
+    fn encode(&self, payload: &mut Vec<u8>) {
+        payload.extend(self.time_usec.to_le_bytes());
+        for value in [self.lat, self.lon, self.alt] {
+            payload.extend(value.to_le_bytes());
+        }
+        for value in [self.eph, self.epv, self.vel] {
+            payload.extend(value.to_le_bytes());
+        }
+        for value in [self.vn, self.ve, self.vd] {
+            payload.extend(value.to_le_bytes());
+        }
+        payload.extend(self.cog.to_le_bytes());
+        payload.extend([self.fix_type, self.satellites_visible, self.id]);
+        payload.extend(self.yaw.to_le_bytes());
+    }
+
+    fn decode(payload: &[u8]) -> Self {
+        let mut fields = Fields::new(payload);
+        let i32 = |fields: &mut Fields| i32::from_le_bytes(fields.take());
+        let u16 = |fields: &mut Fields| u16::from_le_bytes(fields.take());
+        let i16 = |fields: &mut Fields| i16::from_le_bytes(fields.take());
+        Self {
+            time_usec: fields.u64(),
+            lat: i32(&mut fields),
+            lon: i32(&mut fields),
+            alt: i32(&mut fields),
+            eph: u16(&mut fields),
+            epv: u16(&mut fields),
+            vel: u16(&mut fields),
+            vn: i16(&mut fields),
+            ve: i16(&mut fields),
+            vd: i16(&mut fields),
+            cog: u16(&mut fields),
+            fix_type: fields.u8(),
+            satellites_visible: fields.u8(),
+            id: fields.u8(),
+            yaw: u16(&mut fields),
+        }
+    }
+}
+
+/// The ground truth state, PX4 only logs it.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HilStateQuaternion {
+    pub time_usec: u64,
+    pub attitude_quaternion: [f32; 4], // w, x, y, z
+    pub rollspeed: f32,
+    pub pitchspeed: f32,
+    pub yawspeed: f32,
+    pub lat: i32,
+    pub lon: i32,
+    pub alt: i32,
+    pub vx: i16, // cm/s
+    pub vy: i16,
+    pub vz: i16,
+    pub ind_airspeed: u16,
+    pub true_airspeed: u16,
+    pub xacc: i16, // mG
+    pub yacc: i16,
+    pub zacc: i16,
+}
+
+impl MavMessage for HilStateQuaternion {
+    const ID: u32 = 115;
+    const CRC_EXTRA: u8 = 4;
+
+    fn encode(&self, payload: &mut Vec<u8>) {
+        payload.extend(self.time_usec.to_le_bytes());
+        let floats = self.attitude_quaternion.into_iter().chain([
+            self.rollspeed,
+            self.pitchspeed,
+            self.yawspeed,
+        ]);
+        for value in floats {
+            payload.extend(value.to_le_bytes());
+        }
+        for value in [self.lat, self.lon, self.alt] {
+            payload.extend(value.to_le_bytes());
+        }
+        for value in [self.vx, self.vy, self.vz] {
+            payload.extend(value.to_le_bytes());
+        }
+        for value in [self.ind_airspeed, self.true_airspeed] {
+            payload.extend(value.to_le_bytes());
+        }
+        for value in [self.xacc, self.yacc, self.zacc] {
+            payload.extend(value.to_le_bytes());
+        }
+    }
+
+    fn decode(payload: &[u8]) -> Self {
+        let mut fields = Fields::new(payload);
+        let i32 = |fields: &mut Fields| i32::from_le_bytes(fields.take());
+        let u16 = |fields: &mut Fields| u16::from_le_bytes(fields.take());
+        let i16 = |fields: &mut Fields| i16::from_le_bytes(fields.take());
+        Self {
+            time_usec: fields.u64(),
+            attitude_quaternion: fields.f32s(),
+            rollspeed: fields.f32(),
+            pitchspeed: fields.f32(),
+            yawspeed: fields.f32(),
+            lat: i32(&mut fields),
+            lon: i32(&mut fields),
+            alt: i32(&mut fields),
+            vx: i16(&mut fields),
+            vy: i16(&mut fields),
+            vz: i16(&mut fields),
+            ind_airspeed: u16(&mut fields),
+            true_airspeed: u16(&mut fields),
+            xacc: i16(&mut fields),
+            yacc: i16(&mut fields),
+            zacc: i16(&mut fields),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mavlink::{
+        Frame, FrameReader, Heartbeat, HilActuatorControls, HilSensor, crc_calculate,
+    };
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc_calculate(b"123456789"), 0x6f91);
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let sensor = HilSensor {
+            time_usec: 4000,
+            acc: [0., 0., -9.81],
+            fields_updated: HilSensor::ALL_FIELDS_UPDATED,
+            ..Default::default()
+        };
+        let controls = HilActuatorControls {
+            time_usec: 4000,
+            controls: std::array::from_fn(|i| if i < 4 { 0.5 } else { 0. }),
+            mode: HilActuatorControls::MODE_FLAG_SAFETY_ARMED,
+            flags: 0,
+        };
+        let mut bytes = vec![0x12, 0x34]; // garbage before the first frame
+        bytes.extend(Frame::new(&sensor, 0, 1, 1).to_bytes());
+        bytes.extend(Frame::new(&Heartbeat::default(), 1, 1, 1).to_bytes());
+        bytes.extend(Frame::new(&controls, 2, 1, 1).to_bytes());
+
+        let mut reader = FrameReader::default();
+        let mut stream = bytes.as_slice();
+        let frame = reader.read_frame(&mut stream).unwrap();
+        assert_eq!(frame.message::<HilSensor>(), Some(sensor));
+        let frame = reader.read_frame(&mut stream).unwrap();
+        // the heartbeat is all zeros, only one byte of it is sent
+        assert_eq!(frame.payload.len(), 1);
+        assert_eq!(frame.message::<Heartbeat>(), Some(Heartbeat::default()));
+        let frame = reader.read_frame(&mut stream).unwrap();
+        assert_eq!(frame.message::<HilActuatorControls>(), Some(controls));
+        assert!(reader.read_frame(&mut stream).is_err());
+    }
+}
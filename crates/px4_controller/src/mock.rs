@@ -0,0 +1,66 @@
+use crate::mavlink::{
+    Frame, FrameReader, HilActuatorControls, HilGps, HilSensor, HilStateQuaternion,
+};
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpStream},
+    time::Duration,
+};
+
+/// Plays the PX4 side of the HIL interface with a fixed script, so the controller can be tested
+/// without a PX4 build.
+pub struct MockPx4 {
+    pub stream: TcpStream,
+    pub gps_received: usize,
+    pub states_received: usize,
+    reader: FrameReader,
+    seq: u8,
+}
+
+impl MockPx4 {
+    pub fn connect(simulator: SocketAddr) -> Self {
+        let stream = TcpStream::connect(simulator).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        Self {
+            stream,
+            gps_received: 0,
+            states_received: 0,
+            reader: FrameReader::default(),
+            seq: 0,
+        }
+    }
+
+    /// Waits for the next sensor message, the GPS and ground truth messages are counted
+    pub fn receive_sensor(&mut self) -> Option<HilSensor> {
+        loop {
+            let frame = self.reader.read_frame(&mut self.stream).ok()?;
+            if let Some(sensor) = frame.message::<HilSensor>() {
+                return Some(sensor);
+            }
+            if frame.message::<HilGps>().is_some() {
+                self.gps_received += 1;
+            }
+            if frame.message::<HilStateQuaternion>().is_some() {
+                self.states_received += 1;
+            }
+        }
+    }
+
+    /// Sends the motor outputs in PX4 order
+    pub fn send_controls(&mut self, motors: [f32; 4], armed: bool) {
+        let controls = HilActuatorControls {
+            controls: std::array::from_fn(|i| motors.get(i).copied().unwrap_or(0.)),
+            mode: if armed {
+                HilActuatorControls::MODE_FLAG_SAFETY_ARMED
+            } else {
+                0
+            },
+            ..Default::default()
+        };
+        let frame = Frame::new(&controls, self.seq, 1, 1);
+        self.seq = self.seq.wrapping_add(1);
+        self.stream.write_all(&frame.to_bytes()).unwrap();
+    }
+}
@@ -1,5 +1,7 @@
 use drone::Drone;
-use flight_controller::{Channels, FlightController, FlightControllerUpdate, MotorInput};
+use flight_controller::{
+    Channels, ControllerState, FlightController, FlightControllerUpdate, InitError, MotorInput,
+};
 use loggers::SnapShot;
 use nalgebra::{DMatrix, DVector};
 use res::{
@@ -104,6 +106,7 @@ pub fn flight_controller_update_to_reservoir_input(update: FlightControllerUpdat
         roll,
         pitch,
         yaw,
+        ..
     } = update.channels;
     let [rot_x, rot_y, rot_z, rot_w] = update.gyro_update.rotation;
     DVector::from_row_slice(&[
@@ -112,8 +115,14 @@ pub fn flight_controller_update_to_reservoir_input(update: FlightControllerUpdat
     ])
 }
 
+// the marker of the snapshots of a `DroneRc`, there is no state in it
+#[derive(Debug, Clone, Copy)]
+struct DroneRcState;
+
 impl FlightController for DroneRc {
-    fn init(&self) {}
+    fn init(&self) -> Result<(), InitError> {
+        Ok(())
+    }
 
     fn update(&self, _delta_time: f64, update: FlightControllerUpdate) -> MotorInput {
         let rc_input = flight_controller_update_to_reservoir_input(update);
@@ -131,4 +140,13 @@ impl FlightController for DroneRc {
     fn scheduler_delta(&self) -> Duration {
         Duration::from_millis(5)
     }
+
+    // every update starts from a fresh reservoir, so there is nothing to save
+    fn snapshot(&self) -> Option<ControllerState> {
+        Some(Box::new(DroneRcState))
+    }
+
+    fn restore(&self, state: &ControllerState) -> bool {
+        state.is::<DroneRcState>()
+    }
 }
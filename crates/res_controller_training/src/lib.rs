@@ -43,7 +43,9 @@ pub fn recreate_replay(
     flight_log_id: &str,
     new_fliht_log: &str,
 ) {
-    sim_context.set_controller(sim_context::ControllerType::Reservoir(controller_id.into()));
+    sim_context
+        .set_controller(sim_context::ControllerType::Reservoir(controller_id.into()))
+        .unwrap();
     sim_context.set_logger(sim_context::LoggerType::File(new_fliht_log.into()));
     let mut simulator = sim_context.try_load_simulator().unwrap();
     let mut current_time = Duration::ZERO;
@@ -122,6 +124,7 @@ pub fn evaluate_single_episode_training(
             battery_update: snapshot.battery_update,
             gyro_update: snapshot.gyro_update,
             channels: snapshot.channels,
+            ..Default::default()
         };
         let prediction = controller.update(duration.as_secs_f64(), update);
         predicted_motor_inputs.push(prediction);
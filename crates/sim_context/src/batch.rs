@@ -0,0 +1,458 @@
+//! Runs many episodes on a pool of threads, e.g. to generate the data sets the reservoir
+//! controllers are trained on. Every worker has its own `SimContext`, so its own controller and
+//! logger. A failing or panicking episode is recorded in the manifest and the rest of the batch
+//! goes on.
+
+use crate::{
+    headless::{self, InputSource, Summary},
+    input_gen, ControllerType, LoggerType, SimContext,
+};
+use bf_controller::MAX_INSTANCES;
+use drone::{randomization::RandomizationSpec, sub_seed, SeedStream};
+use flight_controller::controllers::null_controller::NullController;
+use rand::{rngs::StdRng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fs, io,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpisodeSpec {
+    pub config_id: String,
+    pub controller: ControllerType,
+    pub input: InputSource,
+    // None flies the whole input, generated inputs need one
+    pub duration: Option<Duration>,
+    // seeds the generated sticks and the noise of the simulation
+    pub seed: u64,
+    pub logger: LoggerType,
+    // perturbs the drone, the parameters are sampled from the seed
+    pub randomization: Option<RandomizationSpec>,
+}
+
+/// What happened to an episode, the entry of the manifest
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EpisodeRecord {
+    // None if the episode was not logged
+    pub log_id: Option<String>,
+    pub config_id: String,
+    pub controller: String,
+    pub input: String,
+    pub seed: u64,
+    pub simulation_time: Duration,
+    pub wall_time: Duration,
+    pub crashed: bool,
+    // why the episode failed, its log may be missing or cut short
+    pub error: Option<String>,
+    // the parameters of the randomized drone, empty without randomization
+    #[serde(default)]
+    pub parameters: BTreeMap<String, f64>,
+    // the numbers of the flight summary
+    #[serde(default)]
+    pub metrics: BTreeMap<String, f64>,
+}
+
+impl EpisodeRecord {
+    fn new(spec: &EpisodeSpec) -> Self {
+        let log_id = match &spec.logger {
+            LoggerType::File(id) | LoggerType::Db(id) | LoggerType::Rerun(id) => Some(id.clone()),
+            LoggerType::Empty => None,
+        };
+        Self {
+            log_id,
+            config_id: spec.config_id.clone(),
+            controller: format!("{:?}", spec.controller),
+            input: spec.input.to_string(),
+            seed: spec.seed,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// The episodes of a batch in the order of the specs
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub episodes: Vec<EpisodeRecord>,
+}
+
+impl Manifest {
+    /// The ids of the logs of the episodes that went through
+    pub fn logs(&self) -> impl Iterator<Item = &str> {
+        self.episodes
+            .iter()
+            .filter(|episode| episode.is_ok())
+            .filter_map(|episode| episode.log_id.as_deref())
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &EpisodeRecord> {
+        self.episodes.iter().filter(|episode| !episode.is_ok())
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// Handed to the progress callback after every episode
+#[derive(Debug)]
+pub struct BatchProgress<'a> {
+    pub done: usize,
+    pub failed: usize,
+    pub total: usize,
+    pub elapsed: Duration,
+    pub episode: &'a EpisodeRecord,
+}
+
+pub type ContextFn = Box<dyn Fn() -> SimContext + Send + Sync>;
+pub type ProgressFn = Box<dyn Fn(&BatchProgress) + Send + Sync>;
+
+pub struct BatchRunner {
+    pub workers: usize,
+    // builds the context of a worker, the controller, the logger and the config are replaced for
+    // every episode
+    pub context: ContextFn,
+    pub progress: ProgressFn,
+    // written once the batch is done
+    pub manifest: Option<PathBuf>,
+}
+
+impl Default for BatchRunner {
+    fn default() -> Self {
+        Self {
+            workers: thread::available_parallelism().map_or(1, |workers| workers.get()),
+            context: Box::new(SimContext::default),
+            progress: Box::new(|_| {}),
+            manifest: None,
+        }
+    }
+}
+
+// the virtual betaflights an episode with the controller keeps alive at the same time
+fn betaflight_instances(controller: &ControllerType) -> usize {
+    match controller {
+        ControllerType::Betafligt | ControllerType::BetaflightCli(_) => 1,
+        ControllerType::Supervised(primary, fallback) => {
+            betaflight_instances(primary) + betaflight_instances(fallback)
+        }
+        ControllerType::Shadowed(primary, shadows) => {
+            betaflight_instances(primary) + shadows.iter().map(betaflight_instances).sum::<usize>()
+        }
+        _ => 0,
+    }
+}
+
+// the cli diff is sent over the serial port of the virtual betaflight, only one instance of the
+// process can open it
+fn needs_serial_port(controller: &ControllerType) -> bool {
+    match controller {
+        ControllerType::BetaflightCli(_) => true,
+        ControllerType::Supervised(primary, fallback) => {
+            needs_serial_port(primary) || needs_serial_port(fallback)
+        }
+        ControllerType::Shadowed(primary, shadows) => {
+            needs_serial_port(primary) || shadows.iter().any(needs_serial_port)
+        }
+        _ => false,
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".into())
+}
+
+// the summary of the flight and the parameters of the randomized drone
+fn fly_episode(
+    context: &mut SimContext,
+    spec: &EpisodeSpec,
+) -> Result<(Summary, BTreeMap<String, f64>), String> {
+    context
+        .set_controller(spec.controller.clone())
+        .map_err(|err| format!("could not start the controller: {err}"))?;
+    let frames = spec.input.frames(context, spec.duration)?;
+    let mut simulator = context
+        .try_load_simulator()
+        .ok_or("no drone config selected")?;
+    simulator.seed(spec.seed);
+    let mut parameters = BTreeMap::new();
+    if let Some(randomization) = &spec.randomization {
+        let mut rng = StdRng::seed_from_u64(sub_seed(spec.seed, SeedStream::Randomization));
+        parameters = randomization.apply(&mut rng, &mut simulator.drone);
+        simulator
+            .logger
+            .lock()
+            .unwrap()
+            .log_parameters(parameters.clone());
+    }
+    simulator.init().map_err(|err| err.to_string())?;
+    Ok((headless::fly(&mut simulator, frames), parameters))
+}
+
+fn run_episode(context: &mut SimContext, spec: &EpisodeSpec, record: &mut EpisodeRecord) {
+    input_gen::rng_seed(spec.seed);
+    context.config_id = Some(spec.config_id.clone());
+    context.set_logger(spec.logger.clone());
+    match fly_episode(context, spec) {
+        Ok((summary, parameters)) => {
+            record.simulation_time = summary.simulation_time;
+            record.crashed = summary.crashed;
+            record.metrics = summary.metrics();
+            record.parameters = parameters;
+        }
+        Err(err) => record.error = Some(err),
+    }
+    // writes the log and frees the betaflight instances for the next episode
+    context.set_logger(LoggerType::Empty);
+    context.flight_controller = Arc::new(NullController::default());
+}
+
+impl BatchRunner {
+    pub fn with_workers(self, workers: usize) -> Self {
+        Self { workers, ..self }
+    }
+
+    pub fn with_context(self, context: impl Fn() -> SimContext + Send + Sync + 'static) -> Self {
+        Self {
+            context: Box::new(context),
+            ..self
+        }
+    }
+
+    pub fn with_progress(self, progress: impl Fn(&BatchProgress) + Send + Sync + 'static) -> Self {
+        Self {
+            progress: Box::new(progress),
+            ..self
+        }
+    }
+
+    pub fn with_manifest(self, manifest: impl Into<PathBuf>) -> Self {
+        Self {
+            manifest: Some(manifest.into()),
+            ..self
+        }
+    }
+
+    /// The workers actually started. Every virtual betaflight lives in its own link-map namespace
+    /// and only `MAX_INSTANCES` of them fit, so betaflight batches get fewer workers. Batches that
+    /// apply a cli diff run on a single worker, the instances share one serial port.
+    pub fn worker_count(&self, specs: &[EpisodeSpec]) -> usize {
+        if specs.iter().any(|spec| needs_serial_port(&spec.controller)) {
+            return 1;
+        }
+        let instances = specs
+            .iter()
+            .map(|spec| betaflight_instances(&spec.controller))
+            .max()
+            .unwrap_or(0);
+        let workers = match instances {
+            0 => self.workers,
+            instances => self.workers.min(MAX_INSTANCES / instances),
+        };
+        workers.clamp(1, specs.len().max(1))
+    }
+
+    /// Runs the episodes and writes the manifest. Errors writing the manifest are returned, the
+    /// episodes that failed are only recorded in it.
+    pub fn run(&self, specs: &[EpisodeSpec]) -> io::Result<Manifest> {
+        let start = Instant::now();
+        let next = AtomicUsize::new(0);
+        let (sender, receiver) = mpsc::channel();
+        let mut records: Vec<Option<EpisodeRecord>> = vec![None; specs.len()];
+        thread::scope(|scope| {
+            for _ in 0..self.worker_count(specs) {
+                let sender = sender.clone();
+                let next = &next;
+                scope.spawn(move || {
+                    let mut context = (self.context)();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(spec) = specs.get(index) else {
+                            break;
+                        };
+                        let mut record = EpisodeRecord::new(spec);
+                        let episode_start = Instant::now();
+                        let result = catch_unwind(AssertUnwindSafe(|| {
+                            run_episode(&mut context, spec, &mut record)
+                        }));
+                        record.wall_time = episode_start.elapsed();
+                        if let Err(payload) = result {
+                            record.error = Some(panic_message(payload));
+                            // the controller or the logger may be left in any state
+                            context = (self.context)();
+                        }
+                        let _ = sender.send((index, record));
+                    }
+                });
+            }
+            drop(sender);
+
+            let (mut done, mut failed) = (0, 0);
+            for (index, record) in receiver {
+                done += 1;
+                failed += usize::from(!record.is_ok());
+                (self.progress)(&BatchProgress {
+                    done,
+                    failed,
+                    total: specs.len(),
+                    elapsed: start.elapsed(),
+                    episode: &record,
+                });
+                records[index] = Some(record);
+            }
+        });
+
+        let manifest = Manifest {
+            episodes: records.into_iter().flatten().collect(),
+        };
+        if let Some(path) = &self.manifest {
+            manifest.save(path)?;
+        }
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        batch::{BatchRunner, EpisodeSpec, Manifest},
+        input_gen::{rng_seed, InputGenerationMethod, InputGenerator},
+        ControllerType, LoggerType,
+    };
+    use bf_controller::MAX_INSTANCES;
+    use flight_controller::Channels;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    fn spec(controller: ControllerType, input: &str, seed: u64) -> EpisodeSpec {
+        EpisodeSpec {
+            config_id: "7in_4s_drone".into(),
+            controller,
+            input: input.parse().unwrap(),
+            duration: Some(Duration::from_millis(500)),
+            seed,
+            logger: LoggerType::Empty,
+            randomization: None,
+        }
+    }
+
+    #[test]
+    fn isolated_episodes() {
+        let specs = vec![
+            spec(
+                ControllerType::Pid,
+                "gen:throttle=brownian,roll=brownian",
+                1,
+            ),
+            spec(ControllerType::Pid, "tape:/nonexistent/tape.csv", 2),
+            // the default loader has no replays and panics
+            spec(ControllerType::NullController, "replay:missing", 3),
+            spec(ControllerType::NullController, "gen:", 4),
+        ];
+        let progress = Arc::new(AtomicUsize::new(0));
+        let counter = progress.clone();
+        let manifest = BatchRunner::default()
+            .with_workers(2)
+            .with_progress(move |progress| {
+                counter.fetch_add(1, Ordering::Relaxed);
+                assert!(progress.done <= progress.total);
+            })
+            .run(&specs)
+            .unwrap();
+
+        assert_eq!(progress.load(Ordering::Relaxed), 4);
+        assert_eq!(manifest.episodes.len(), 4);
+        let seeds: Vec<_> = manifest.episodes.iter().map(|e| e.seed).collect();
+        assert_eq!(seeds, [1, 2, 3, 4]);
+        assert!(manifest.episodes[0].is_ok());
+        assert_eq!(
+            manifest.episodes[0].simulation_time,
+            Duration::from_millis(500)
+        );
+        assert!(manifest.episodes[1]
+            .error
+            .as_ref()
+            .is_some_and(|error| error.contains("could not read")));
+        assert!(!manifest.episodes[2].is_ok());
+        assert!(manifest.episodes[3].is_ok());
+        assert_eq!(manifest.failed().count(), 2);
+        // nothing was logged
+        assert_eq!(manifest.logs().count(), 0);
+    }
+
+    #[test]
+    fn betaflight_workers() {
+        let betaflight = ControllerType::Shadowed(
+            Box::new(ControllerType::Betafligt),
+            vec![ControllerType::Pid, ControllerType::Betafligt],
+        );
+        let specs = vec![spec(betaflight, "gen:", 0); 20];
+        let runner = BatchRunner::default().with_workers(64);
+        assert_eq!(runner.worker_count(&specs), MAX_INSTANCES / 2);
+        let specs = vec![spec(ControllerType::Pid, "gen:", 0); 3];
+        assert_eq!(runner.worker_count(&specs), 3);
+        let cli = ControllerType::Supervised(
+            Box::new(ControllerType::BetaflightCli("diff.txt".into())),
+            Box::new(ControllerType::Pid),
+        );
+        let mut specs = vec![spec(ControllerType::Pid, "gen:", 0); 3];
+        specs.push(spec(cli, "gen:", 0));
+        assert_eq!(runner.worker_count(&specs), 1);
+    }
+
+    #[test]
+    fn seeded_inputs() {
+        let generator = InputGenerator::default().set_roll(InputGenerationMethod::Brownian);
+        rng_seed(5);
+        let roll = |channels: Vec<Channels>| channels.iter().map(|c| c.roll).collect::<Vec<_>>();
+        let first = roll(generator.generate(Duration::from_millis(200)));
+        rng_seed(5);
+        assert_eq!(roll(generator.generate(Duration::from_millis(200))), first);
+    }
+
+    #[test]
+    fn manifest_file() {
+        let mut manifest = Manifest::default();
+        let mut record = super::EpisodeRecord::new(&EpisodeSpec {
+            logger: LoggerType::File("run_0".into()),
+            ..spec(ControllerType::Pid, "gen:roll=0.2", 7)
+        });
+        record.simulation_time = Duration::from_secs(1);
+        manifest.episodes.push(record);
+        let path = std::env::temp_dir().join(format!("manifest_{}.json", uuid::Uuid::new_v4()));
+        manifest.save(&path).unwrap();
+        let loaded = Manifest::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, manifest);
+        assert_eq!(loaded.logs().collect::<Vec<_>>(), ["run_0"]);
+        assert_eq!(loaded.episodes[0].input, "gen:roll=0.2");
+    }
+}
@@ -0,0 +1,317 @@
+//! Flies a drone without the visualizer and prints how the flight went, e.g.
+//!
+//! `quad-sim --controller pid --input gen:throttle=step:-1:0.2:1000,roll=brownian --duration 30`
+//!
+//! The flight log is written by the selected logger, by default to a file named after a new uuid.
+//! With `--task` the controller is benchmarked on the standard tasks instead, e.g.
+//!
+//! `quad-sim --controller pid --task all --runs 5`
+//!
+//! With `--randomize` the input is flown with a randomized drone per run and the flight metrics
+//! are aggregated per parameter, e.g.
+//!
+//! `quad-sim --controller pid --randomize randomization.json --runs 100`
+use drone::randomization::RandomizationSpec;
+use sim_context::{
+    batch::{BatchRunner, EpisodeSpec},
+    headless::{self, InputSource},
+    sweep::{sweep, SweepReport},
+    ControllerType, LoaderType, LoggerType, SimContext,
+};
+use simulator::{
+    course::Course,
+    scene::Scene,
+    tasks::{standard_tasks, Task},
+};
+use std::{fs, ops::Range, path::PathBuf, process::ExitCode, str::FromStr, time::Duration};
+
+// bins per parameter of a sweep
+const SWEEP_BINS: usize = 5;
+
+const USAGE: &str = "usage: quad-sim [options]
+
+options:
+  --config <id>          drone config, defaults to 7in_4s_drone
+  --loader <loader>      default, file or db
+  --controller <kind>    betaflight, betaflight-cli:<diff>, betaflight-sitl:<binary>,
+                         ardupilot:<binary>, px4:<binary>, reservoir:<id>, null, pid or
+                         socket:<tcp:host:port|unix:path>
+  --logger <logger>      empty, file:<id>, db:<id> or rerun:<id>, defaults to file:<uuid>
+  --input <source>       gen:<axis=method,...>, tape:<csv file> or replay:<id>
+  --duration <seconds>   defaults to 10 for generated inputs, to the whole input otherwise
+  --rates <profile id>   rate profile used by the pid controller
+  --seed <seed>          seed of the simulated noise and the generated sticks
+  --scene <json file>    obstacles to fly around, by default the world is empty
+  --course <json file>   race course to time the laps on
+  --task <name>          benchmarks the controller on hover, step, attitude, trajectory,
+                         thrown, racing or all of them instead of flying the input
+  --runs <count>         episodes per task, seeded from --seed on, defaults to 1
+  --randomize <json file> flies the input --runs times, every time with a drone randomized
+                         as specified, and reports the metrics per parameter
+  --help                 prints this
+
+generated inputs: throttle, yaw, pitch and roll are set to brownian, a constant or
+step:<from>:<to>:<ms>, arm=<ms> or arm=off moves the arm switch from 500ms";
+
+struct Arguments {
+    config: Option<String>,
+    loader: LoaderType,
+    controller: ControllerType,
+    logger: LoggerType,
+    input: InputSource,
+    duration: Option<Duration>,
+    rates: Option<String>,
+    seed: Option<u64>,
+    scene: Option<PathBuf>,
+    course: Option<PathBuf>,
+    task: Option<String>,
+    runs: u64,
+    randomize: Option<PathBuf>,
+}
+
+fn parse<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    let value = value.ok_or_else(|| format!("{flag} needs a value"))?;
+    value
+        .parse()
+        .map_err(|err| format!("invalid {flag} `{value}`: {err}"))
+}
+
+// None if the usage was asked for
+fn parse_arguments(mut args: impl Iterator<Item = String>) -> Result<Option<Arguments>, String> {
+    let mut arguments = Arguments {
+        config: None,
+        loader: LoaderType::DefaultLoader,
+        controller: ControllerType::Pid,
+        logger: LoggerType::File(uuid::Uuid::new_v4().to_string()),
+        input: InputSource::Generator(String::new()),
+        duration: None,
+        rates: None,
+        seed: None,
+        scene: None,
+        course: None,
+        task: None,
+        runs: 1,
+        randomize: None,
+    };
+    while let Some(flag) = args.next() {
+        let value = args.next();
+        match flag.as_str() {
+            "--help" | "-h" => return Ok(None),
+            "--config" => arguments.config = Some(parse(&flag, value)?),
+            "--loader" => arguments.loader = parse(&flag, value)?,
+            "--controller" => arguments.controller = parse(&flag, value)?,
+            "--logger" => arguments.logger = parse(&flag, value)?,
+            "--input" => arguments.input = parse(&flag, value)?,
+            "--duration" => {
+                let seconds: f64 = parse(&flag, value)?;
+                let duration = Duration::try_from_secs_f64(seconds)
+                    .map_err(|err| format!("invalid --duration `{seconds}`: {err}"))?;
+                arguments.duration = Some(duration);
+            }
+            "--rates" => arguments.rates = Some(parse(&flag, value)?),
+            "--seed" => arguments.seed = Some(parse(&flag, value)?),
+            "--scene" => arguments.scene = Some(parse(&flag, value)?),
+            "--course" => arguments.course = Some(parse(&flag, value)?),
+            "--task" => arguments.task = Some(parse(&flag, value)?),
+            "--runs" => arguments.runs = parse(&flag, value)?,
+            "--randomize" => arguments.randomize = Some(parse(&flag, value)?),
+            _ => return Err(format!("unknown option `{flag}`")),
+        }
+    }
+    if arguments.duration.is_none() && matches!(arguments.input, InputSource::Generator(_)) {
+        arguments.duration = Some(Duration::from_secs(10));
+    }
+    Ok(Some(arguments))
+}
+
+fn run_benchmark(context: &mut SimContext, task: &str, seed: Option<u64>, runs: u64) -> ExitCode {
+    let tasks = match task {
+        "all" => standard_tasks(),
+        name => match Task::from_name(name) {
+            Some(task) => vec![task],
+            None => {
+                eprintln!("unknown task `{name}`\n\n{USAGE}");
+                return ExitCode::from(2);
+            }
+        },
+    };
+    let first_seed = seed.unwrap_or(0);
+    let seeds: Vec<u64> = (first_seed..first_seed + runs).collect();
+    match headless::benchmark(context, tasks, &seeds) {
+        Ok(results) => {
+            for result in results.iter() {
+                println!("{result}");
+            }
+            let mean = results.iter().map(|result| result.score).sum::<f64>()
+                / results.len().max(1) as f64;
+            println!("mean score {mean:.3}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_sweep(
+    new_context: impl Fn() -> SimContext + Send + Sync + 'static,
+    template: EpisodeSpec,
+    randomization: &PathBuf,
+    seeds: Range<u64>,
+) -> ExitCode {
+    let randomization: RandomizationSpec = match fs::read_to_string(randomization)
+        .map_err(|err| err.to_string())
+        .and_then(|json| serde_json::from_str(&json).map_err(|err| err.to_string()))
+    {
+        Ok(randomization) => randomization,
+        Err(err) => {
+            eprintln!("invalid randomization {randomization:?}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let template = EpisodeSpec {
+        randomization: Some(randomization),
+        ..template
+    };
+    let runner = BatchRunner::default()
+        .with_context(new_context)
+        .with_progress(|progress| {
+            if let Some(error) = &progress.episode.error {
+                eprintln!("seed {} failed: {error}", progress.episode.seed);
+            }
+        });
+    match sweep(&runner, &template, seeds) {
+        Ok(manifest) => {
+            println!("{}", SweepReport::new(&manifest, SWEEP_BINS));
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let arguments = match parse_arguments(std::env::args().skip(1)) {
+        Ok(Some(arguments)) => arguments,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("{err}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+
+    if let Some(seed) = arguments.seed {
+        sim_context::input_gen::rng_seed(seed);
+    }
+    let scene = match arguments.scene.as_ref().map(Scene::load).transpose() {
+        Ok(scene) => scene,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let course = match arguments.course.as_ref().map(Course::load).transpose() {
+        Ok(course) => course,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // loaded once, the workers of a sweep all fly with the same rates
+    let rate_profile = match &arguments.rates {
+        Some(rates) => {
+            let mut context = SimContext::default();
+            context.set_loader(&arguments.loader);
+            if let Err(err) = context.set_rate_profile(rates) {
+                eprintln!("could not load the rates: {err}");
+                return ExitCode::FAILURE;
+            }
+            Some(context.rate_profile)
+        }
+        None => None,
+    };
+    // the workers of a sweep need contexts of their own
+    let loader = arguments.loader.clone();
+    let new_context = move || {
+        let mut context = SimContext::default();
+        context.set_loader(&loader);
+        if let Some(rate_profile) = &rate_profile {
+            context.rate_profile = rate_profile.clone();
+        }
+        if let Some(scene) = &scene {
+            context.set_scene(scene.clone());
+        }
+        if let Some(course) = &course {
+            context.set_course(course.clone());
+        }
+        context
+    };
+    let mut context = new_context();
+    if let Some(config) = arguments.config {
+        context.config_id = Some(config);
+    }
+    if let Some(randomize) = arguments.randomize {
+        let Some(config_id) = context.config_id.clone() else {
+            eprintln!("no drone config selected");
+            return ExitCode::FAILURE;
+        };
+        let template = EpisodeSpec {
+            config_id,
+            controller: arguments.controller,
+            input: arguments.input,
+            duration: arguments.duration,
+            seed: 0,
+            logger: arguments.logger,
+            randomization: None,
+        };
+        let seed = arguments.seed.unwrap_or(0);
+        return run_sweep(
+            new_context,
+            template,
+            &randomize,
+            seed..seed + arguments.runs,
+        );
+    }
+    if let Err(err) = context.set_controller(arguments.controller) {
+        eprintln!("could not start the controller: {err}");
+        return ExitCode::FAILURE;
+    }
+    if let Some(task) = arguments.task {
+        return run_benchmark(&mut context, &task, arguments.seed, arguments.runs);
+    }
+    let log_id = match &arguments.logger {
+        LoggerType::File(id) | LoggerType::Db(id) | LoggerType::Rerun(id) => Some(id.clone()),
+        LoggerType::Empty => None,
+    };
+    context.set_logger(arguments.logger);
+
+    match headless::run(
+        &mut context,
+        &arguments.input,
+        arguments.duration,
+        arguments.seed,
+    ) {
+        Ok(summary) => {
+            println!("{summary}");
+            if let Some(log_id) = log_id {
+                println!("flight log:           {log_id}");
+            }
+            // the logger writes the flight log when the context is dropped
+            drop(context);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,488 @@
+//! Runs a simulation without the visualizer, this is what the `quad-sim` binary does. The
+//! controller, logger and loader are picked with the usual `SimContext` setters, the string forms
+//! parsed here are the ones the command line takes.
+
+use crate::{
+    input_gen::{InputGenerationMethod, InputGenerator, ARM_DELAY},
+    ControllerType, LoaderType, LoggerType, SimContext,
+};
+use flight_controller::{ArmingState, ChannelMap, Channels, AUX_CHANNELS};
+use simulator::{
+    tasks::{self, Task, TaskResult},
+    Simulator,
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+// generated and taped inputs have a frame every millisecond
+const FRAME_TIME: Duration = Duration::from_millis(1);
+
+// kind:argument, the argument is everything after the first colon
+fn split_argument(s: &str) -> (&str, Option<&str>) {
+    match s.split_once(':') {
+        Some((kind, argument)) => (kind, Some(argument)),
+        None => (s, None),
+    }
+}
+
+fn required(kind: &str, argument: Option<&str>) -> Result<String, String> {
+    argument
+        .map(String::from)
+        .ok_or_else(|| format!("`{kind}` needs an argument: {kind}:<...>"))
+}
+
+impl FromStr for ControllerType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, argument) = split_argument(s);
+        match kind {
+            "betaflight" => Ok(Self::Betafligt),
+            "betaflight-cli" => Ok(Self::BetaflightCli(required(kind, argument)?)),
+            "betaflight-sitl" => Ok(Self::BetaflightSitl(required(kind, argument)?)),
+            "ardupilot" => Ok(Self::ArduPilotSitl(required(kind, argument)?)),
+            "px4" => Ok(Self::Px4Sitl(required(kind, argument)?)),
+            "reservoir" => Ok(Self::Reservoir(required(kind, argument)?)),
+            "null" => Ok(Self::NullController),
+            "pid" => Ok(Self::Pid),
+            "socket" => Ok(Self::Socket(required(kind, argument)?)),
+            _ => Err(format!("unknown controller `{kind}`")),
+        }
+    }
+}
+
+impl FromStr for LoggerType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, argument) = split_argument(s);
+        match kind {
+            "empty" => Ok(Self::Empty),
+            "file" => Ok(Self::File(required(kind, argument)?)),
+            "db" => Ok(Self::Db(required(kind, argument)?)),
+            "rerun" => Ok(Self::Rerun(required(kind, argument)?)),
+            _ => Err(format!("unknown logger `{kind}`")),
+        }
+    }
+}
+
+impl FromStr for LoaderType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::DefaultLoader),
+            "file" => Ok(Self::File),
+            "db" => Ok(Self::DB),
+            _ => Err(format!("unknown loader `{s}`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputSource {
+    // generated sticks, see `parse_generator`
+    Generator(String),
+    // a csv file, see `parse_tape`
+    Tape(PathBuf),
+    // the sticks of a logged flight
+    Replay(String),
+}
+
+impl FromStr for InputSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, argument) = split_argument(s);
+        match kind {
+            "gen" => Ok(Self::Generator(argument.unwrap_or_default().to_string())),
+            "tape" => Ok(Self::Tape(required(kind, argument)?.into())),
+            "replay" => Ok(Self::Replay(required(kind, argument)?)),
+            _ => Err(format!("unknown input `{kind}`")),
+        }
+    }
+}
+
+impl fmt::Display for InputSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Generator(spec) => write!(f, "gen:{spec}"),
+            Self::Tape(path) => write!(f, "tape:{}", path.display()),
+            Self::Replay(replay_id) => write!(f, "replay:{replay_id}"),
+        }
+    }
+}
+
+fn parse_method(method: &str) -> Result<InputGenerationMethod, String> {
+    if method == "brownian" {
+        return Ok(InputGenerationMethod::Brownian);
+    }
+    if let Some(step) = method.strip_prefix("step:") {
+        let parts: Vec<_> = step.split(':').collect();
+        let [from, to, at] = parts.as_slice() else {
+            return Err(format!("expected step:<from>:<to>:<ms>, got `{method}`"));
+        };
+        let number = |s: &str| s.parse().map_err(|_| format!("invalid number `{s}`"));
+        return Ok(InputGenerationMethod::Step {
+            from: number(from)?,
+            to: number(to)?,
+            at: Duration::from_millis(number(at)? as u64),
+        });
+    }
+    method
+        .parse()
+        .map(InputGenerationMethod::Uniform)
+        .map_err(|_| format!("expected brownian, step:<from>:<to>:<ms> or a value, got `{method}`"))
+}
+
+/// Comma separated `axis=method` pairs, e.g. `throttle=brownian,roll=0.2,pitch=step:0:0.5:2000`.
+/// The axes are throttle, yaw, pitch and roll, a method is `brownian`, a constant value or a step.
+/// The arm switch is flipped after 500ms, `arm=<ms>` moves that and `arm=off` never arms. Arming
+/// needs a low throttle, so a throttle that should fly is stepped up after the arm time.
+pub fn parse_generator(spec: &str) -> Result<InputGenerator, String> {
+    let mut generator = InputGenerator::default();
+    let mut arm_at = Some(ARM_DELAY);
+    for item in spec.split(',').filter(|item| !item.is_empty()) {
+        let Some((axis, method)) = item.split_once('=') else {
+            return Err(format!("expected <axis>=<method>, got `{item}`"));
+        };
+        generator = match axis {
+            "throttle" => generator.set_throttle(parse_method(method)?),
+            "yaw" => generator.set_yaw(parse_method(method)?),
+            "pitch" => generator.set_pitch(parse_method(method)?),
+            "roll" => generator.set_roll(parse_method(method)?),
+            "arm" if method == "off" => {
+                arm_at = None;
+                generator
+            }
+            "arm" => {
+                let ms = method
+                    .parse()
+                    .map_err(|_| format!("invalid arm time `{method}`"))?;
+                arm_at = Some(Duration::from_millis(ms));
+                generator
+            }
+            _ => return Err(format!("unknown axis `{axis}`")),
+        };
+    }
+    Ok(match arm_at {
+        Some(arm_at) => generator.arm_at(ChannelMap::default(), arm_at),
+        None => generator,
+    })
+}
+
+/// One row per millisecond: throttle, yaw, pitch, roll and optionally the aux channels, all
+/// between -1 and 1. Empty lines, `#` comments and a header row are skipped.
+pub fn parse_tape(tape: &str) -> Result<Vec<Channels>, String> {
+    let mut channels = vec![];
+    for (line_number, line) in tape.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values: Result<Vec<f64>, _> = line.split(',').map(|v| v.trim().parse()).collect();
+        let values = match values {
+            Ok(values) => values,
+            Err(_) if channels.is_empty() && line_number == 0 => continue,
+            Err(_) => return Err(format!("line {}: invalid value", line_number + 1)),
+        };
+        if values.len() < 4 || values.len() > 4 + AUX_CHANNELS {
+            return Err(format!(
+                "line {}: expected 4 to {} values",
+                line_number + 1,
+                4 + AUX_CHANNELS
+            ));
+        }
+        let mut frame = Channels {
+            throttle: values[0],
+            yaw: values[1],
+            pitch: values[2],
+            roll: values[3],
+            ..Default::default()
+        };
+        for (aux, value) in frame.aux.iter_mut().zip(&values[4..]) {
+            *aux = *value;
+        }
+        channels.push(frame);
+    }
+    Ok(channels)
+}
+
+impl InputSource {
+    /// The inputs as (delta, channels) pairs. With a duration the inputs are cut there, or the
+    /// last sticks are held until then if the inputs are shorter.
+    pub fn frames(
+        &self,
+        context: &mut SimContext,
+        duration: Option<Duration>,
+    ) -> Result<Vec<(Duration, Channels)>, String> {
+        let frames: Vec<_> = match self {
+            InputSource::Generator(spec) => {
+                let duration = duration.ok_or("generated inputs need a duration")?;
+                let generator = parse_generator(spec)?;
+                generator
+                    .generate(duration)
+                    .into_iter()
+                    .map(|channels| (FRAME_TIME, channels))
+                    .collect()
+            }
+            InputSource::Tape(path) => {
+                let tape = std::fs::read_to_string(path)
+                    .map_err(|err| format!("could not read {path:?}: {err}"))?;
+                parse_tape(&tape)?
+                    .into_iter()
+                    .map(|channels| (FRAME_TIME, channels))
+                    .collect()
+            }
+            InputSource::Replay(replay_id) => {
+                let flight_log = context.load_flight_log(replay_id);
+                let mut last = Duration::ZERO;
+                flight_log
+                    .steps
+                    .iter()
+                    .map(|step| {
+                        let delta = step.duration.saturating_sub(last);
+                        last = step.duration;
+                        (delta, step.channels)
+                    })
+                    .collect()
+            }
+        };
+        let Some(duration) = duration else {
+            return Ok(frames);
+        };
+
+        let mut elapsed = Duration::ZERO;
+        let mut fitted: Vec<_> = frames
+            .into_iter()
+            .take_while(|(delta, _)| {
+                elapsed += *delta;
+                elapsed <= duration
+            })
+            .collect();
+        let mut elapsed: Duration = fitted.iter().map(|(delta, _)| *delta).sum();
+        let last = fitted
+            .last()
+            .map(|(_, channels)| *channels)
+            .unwrap_or_default();
+        while elapsed + FRAME_TIME <= duration {
+            fitted.push((FRAME_TIME, last));
+            elapsed += FRAME_TIME;
+        }
+        Ok(fitted)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Summary {
+    pub simulation_time: Duration,
+    pub wall_time: Duration,
+    pub frames: usize,
+    pub armed_time: Duration,
+    // relative to the start position
+    pub max_altitude: f64,
+    pub max_distance: f64,
+    pub max_speed: f64,            // m/s
+    pub max_angular_velocity: f64, // deg/s
+    pub final_voltage: f64,
+    // obstacles of the scene that were hit
+    pub collisions: usize,
+    pub crashed: bool,
+    // laps of the race course
+    pub laps: usize,
+    pub best_lap: Option<Duration>,
+}
+
+impl Summary {
+    pub fn real_time_factor(&self) -> f64 {
+        self.simulation_time.as_secs_f64() / self.wall_time.as_secs_f64()
+    }
+
+    /// The numbers of the summary by name, the wall time is left out since it depends on the
+    /// machine and not on the flight
+    pub fn metrics(&self) -> BTreeMap<String, f64> {
+        [
+            ("simulation_time", self.simulation_time.as_secs_f64()),
+            ("armed_time", self.armed_time.as_secs_f64()),
+            ("max_altitude", self.max_altitude),
+            ("max_distance", self.max_distance),
+            ("max_speed", self.max_speed),
+            ("max_angular_velocity", self.max_angular_velocity),
+            ("final_voltage", self.final_voltage),
+            ("collisions", self.collisions as f64),
+            ("crashed", f64::from(u8::from(self.crashed))),
+            ("laps", self.laps as f64),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "simulated:            {:.3}s",
+            self.simulation_time.as_secs_f64()
+        )?;
+        writeln!(
+            f,
+            "wall time:            {:.3}s ({:.1}x real time)",
+            self.wall_time.as_secs_f64(),
+            self.real_time_factor()
+        )?;
+        writeln!(f, "input frames:         {}", self.frames)?;
+        writeln!(
+            f,
+            "armed:                {:.3}s",
+            self.armed_time.as_secs_f64()
+        )?;
+        writeln!(f, "max altitude:         {:.2}m", self.max_altitude)?;
+        writeln!(f, "max distance:         {:.2}m", self.max_distance)?;
+        writeln!(f, "max speed:            {:.2}m/s", self.max_speed)?;
+        writeln!(
+            f,
+            "max angular velocity: {:.1}deg/s",
+            self.max_angular_velocity
+        )?;
+        writeln!(f, "final voltage:        {:.2}V", self.final_voltage)?;
+        write!(f, "collisions:           {}", self.collisions)?;
+        if self.crashed {
+            write!(f, " (crashed)")?;
+        }
+        if self.laps > 0 {
+            write!(f, "\nlaps:                 {}", self.laps)?;
+            if let Some(best_lap) = self.best_lap {
+                write!(f, ", best {:.3}s", best_lap.as_secs_f64())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flies the inputs with the simulator of the context, the seed seeds the simulated noise. The
+/// flight log is written by the logger of the context once the simulator and the context are
+/// dropped.
+pub fn run(
+    context: &mut SimContext,
+    input: &InputSource,
+    duration: Option<Duration>,
+    seed: Option<u64>,
+) -> Result<Summary, String> {
+    let frames = input.frames(context, duration)?;
+    let mut simulator = context
+        .try_load_simulator()
+        .ok_or("no drone config selected")?;
+    if let Some(seed) = seed {
+        simulator.seed(seed);
+    }
+    simulator.init().map_err(|err| err.to_string())?;
+    Ok(fly(&mut simulator, frames))
+}
+
+/// Flies the inputs with an initialized simulator
+pub fn fly(simulator: &mut Simulator, frames: Vec<(Duration, Channels)>) -> Summary {
+    let start = simulator.simulation_info().position;
+    let wall_clock = Instant::now();
+    let mut summary = Summary::default();
+    for (delta, channels) in frames {
+        let observation = simulator.simulate_delta(delta, channels);
+        let offset = observation.position - start;
+        summary.frames += 1;
+        if observation.arming_state == ArmingState::Armed {
+            summary.armed_time += delta;
+        }
+        summary.max_altitude = summary.max_altitude.max(offset.y);
+        summary.max_distance = summary.max_distance.max(offset.norm());
+        summary.max_speed = summary.max_speed.max(observation.linear_velocity.norm());
+        summary.max_angular_velocity = summary
+            .max_angular_velocity
+            .max(observation.angular_velocity.norm().to_degrees());
+        summary.simulation_time = observation.simulation_time;
+        summary.final_voltage = observation.bat_voltage;
+        summary.collisions += observation.collisions.len();
+        if let Some(race) = observation.race {
+            summary.laps = race.completed_laps;
+            summary.best_lap = race.best_lap;
+        }
+        // nothing moves anymore
+        if observation.crashed {
+            summary.crashed = true;
+            break;
+        }
+    }
+    summary.wall_time = wall_clock.elapsed();
+    summary
+}
+
+/// Flies every task once per seed with the selected controller. The pilot of the tasks gets the
+/// rate profile of the context, so the sticks ask for the rates it means.
+pub fn benchmark(
+    context: &mut SimContext,
+    mut tasks: Vec<Task>,
+    seeds: &[u64],
+) -> Result<Vec<TaskResult>, String> {
+    let mut simulator = context
+        .try_load_simulator()
+        .ok_or("no drone config selected")?;
+    for task in tasks.iter_mut() {
+        task.pilot.rate_profile = context.rate_profile.clone();
+    }
+    tasks::benchmark(&mut simulator, &tasks, seeds).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        headless::{parse_generator, parse_tape, run, InputSource},
+        ControllerType, LoggerType, SimContext,
+    };
+    use std::time::Duration;
+
+    #[test]
+    fn parse_arguments() {
+        assert_eq!(
+            "socket:tcp:127.0.0.1:5000".parse::<ControllerType>(),
+            Ok(ControllerType::Socket("tcp:127.0.0.1:5000".into()))
+        );
+        assert_eq!("pid".parse::<ControllerType>(), Ok(ControllerType::Pid));
+        assert!("px4".parse::<ControllerType>().is_err());
+        assert_eq!(
+            "file:run_1".parse::<LoggerType>(),
+            Ok(LoggerType::File("run_1".into()))
+        );
+        assert_eq!(
+            "replay:abc".parse::<InputSource>(),
+            Ok(InputSource::Replay("abc".into()))
+        );
+        assert!(
+            parse_generator("throttle=brownian,roll=0.2,pitch=step:0:0.5:2000,arm=100").is_ok()
+        );
+        assert!(parse_generator("throttle=fast").is_err());
+
+        let tape = parse_tape("throttle,yaw,pitch,roll,arm\n-1,0,0,0\n0.5,0,0.1,0,1\n").unwrap();
+        assert_eq!(tape.len(), 2);
+        assert_eq!(
+            (tape[1].throttle, tape[1].pitch, tape[1].aux[0]),
+            (0.5, 0.1, 1.)
+        );
+        assert!(parse_tape("-1,0,0,0\nx,0,0,0").is_err());
+    }
+
+    #[test]
+    fn headless_run() {
+        let mut context = SimContext::default();
+        context.set_controller(ControllerType::Pid).unwrap();
+        let input = InputSource::Generator("throttle=step:-1:0.5:150,arm=100".into());
+        let summary = run(&mut context, &input, Some(Duration::from_millis(300)), None).unwrap();
+        assert_eq!(summary.frames, 300);
+        assert!(summary.armed_time > Duration::ZERO);
+        assert!(summary.max_altitude > 0.);
+    }
+}
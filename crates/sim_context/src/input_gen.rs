@@ -1,22 +1,37 @@
-use crate::SimContext;
-use flight_controller::Channels;
-use rand::{
-    distributions::Bernoulli, prelude::Distribution, rngs::StdRng, thread_rng, SeedableRng,
+use crate::{
+    batch::{BatchRunner, EpisodeSpec, Manifest},
+    headless::InputSource,
+    ControllerType, LoaderType, LoggerType, SimContext,
 };
-use std::{cell::RefCell, time::Duration};
+use drone::{sub_seed, SeedStream};
+use flight_controller::{AuxRole, ChannelMap, Channels, AUX_CHANNELS};
+use rand::{distributions::Bernoulli, prelude::Distribution, rngs::StdRng, SeedableRng};
+use std::{cell::RefCell, io, path::PathBuf, time::Duration};
 
-// set seed!
+// random unless seeded
 thread_local! {
-    static RNG: RefCell<StdRng> = RefCell::new(StdRng::seed_from_u64(0));
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
 }
 
+/// Reseeds the generated sticks of the current thread
+pub fn rng_seed(seed: u64) {
+    let seed = sub_seed(seed, SeedStream::Sticks);
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+// How long we wait before flipping the arm switch in generated inputs
+pub const ARM_DELAY: Duration = Duration::from_millis(500);
+
 // TODO: check if the data set is going to be rich enough
 fn generate_brownian(milisecs: u128) -> Vec<f64> {
     let bernoulli = Bernoulli::new(0.5).unwrap();
-    let mut rng = thread_rng();
+    RNG.with(|rng| generate_brownian_with(&bernoulli, &mut rng.borrow_mut(), milisecs))
+}
+
+fn generate_brownian_with(bernoulli: &Bernoulli, rng: &mut StdRng, milisecs: u128) -> Vec<f64> {
     let axis = (0..milisecs).fold((0., 0., vec![]), |acc, _| {
         let (mut pos, mut vel, mut all_pos) = acc;
-        vel += if bernoulli.sample(&mut rng) {
+        vel += if bernoulli.sample(rng) {
             0.0001
         } else {
             -0.0001
@@ -37,6 +52,8 @@ fn generate_brownian(milisecs: u128) -> Vec<f64> {
 pub enum InputGenerationMethod {
     Uniform(f64),
     Brownian,
+    // jumps from one value to the other at the given time, useful for switches
+    Step { from: f64, to: f64, at: Duration },
 }
 
 impl InputGenerationMethod {
@@ -44,15 +61,27 @@ impl InputGenerationMethod {
         match self {
             Self::Uniform(val) => vec![*val; milisecs as usize],
             Self::Brownian => generate_brownian(milisecs),
+            Self::Step { from, to, at } => (0..milisecs)
+                .map(|ms| if ms < at.as_millis() { *from } else { *to })
+                .collect(),
         }
     }
 }
 
+// Describes when the arm switch is flipped. Betaflight only arms if the throttle is low, so the
+// throttle is held low until the switch is flipped.
+struct ArmingSequence {
+    channel_map: ChannelMap,
+    arm_at: Duration,
+}
+
 pub struct InputGenerator {
     throttle: InputGenerationMethod,
     yaw: InputGenerationMethod,
     pitch: InputGenerationMethod,
     roll: InputGenerationMethod,
+    aux: [InputGenerationMethod; AUX_CHANNELS],
+    arming: Option<ArmingSequence>,
 }
 
 impl Default for InputGenerator {
@@ -62,108 +91,180 @@ impl Default for InputGenerator {
             yaw: InputGenerationMethod::Uniform(0.),
             pitch: InputGenerationMethod::Uniform(0.),
             roll: InputGenerationMethod::Uniform(0.),
+            aux: std::array::from_fn(|_| InputGenerationMethod::Uniform(-1.)),
+            arming: None,
         }
     }
 }
 
 impl InputGenerator {
-    fn set_throttle(self, throttle: InputGenerationMethod) -> Self {
+    pub fn set_throttle(self, throttle: InputGenerationMethod) -> Self {
         Self { throttle, ..self }
     }
 
-    fn set_yaw(self, yaw: InputGenerationMethod) -> Self {
+    pub fn set_yaw(self, yaw: InputGenerationMethod) -> Self {
         Self { yaw, ..self }
     }
 
-    fn set_pitch(self, pitch: InputGenerationMethod) -> Self {
+    pub fn set_pitch(self, pitch: InputGenerationMethod) -> Self {
         Self { pitch, ..self }
     }
 
-    fn set_roll(self, roll: InputGenerationMethod) -> Self {
+    pub fn set_roll(self, roll: InputGenerationMethod) -> Self {
         Self { roll, ..self }
     }
 
-    fn generate(&self, duration: Duration) -> Vec<Channels> {
+    /// None if there is no AUX channel with the index
+    pub fn set_aux(mut self, aux_index: usize, method: InputGenerationMethod) -> Option<Self> {
+        *self.aux.get_mut(aux_index)? = method;
+        Some(self)
+    }
+
+    /// Sets the switch assigned to the role to a constant position
+    pub fn set_switch(mut self, channel_map: &ChannelMap, role: AuxRole, on: bool) -> Self {
+        if let Some(aux) = channel_map
+            .aux_index(role)
+            .and_then(|aux_index| self.aux.get_mut(aux_index))
+        {
+            *aux = InputGenerationMethod::Uniform(if on { 1. } else { -1. });
+        }
+        self
+    }
+
+    /// Flips the arm switch at `arm_at`. Until then the throttle is held low.
+    pub fn arm_at(self, channel_map: ChannelMap, arm_at: Duration) -> Self {
+        Self {
+            arming: Some(ArmingSequence {
+                channel_map,
+                arm_at,
+            }),
+            ..self
+        }
+    }
+
+    pub fn generate(&self, duration: Duration) -> Vec<Channels> {
         let milisecs = duration.as_millis();
         let throttle = self.throttle.to_values(milisecs);
         let yaw = self.yaw.to_values(milisecs);
         let pitch = self.pitch.to_values(milisecs);
         let roll = self.roll.to_values(milisecs);
+        let aux = self
+            .aux
+            .iter()
+            .map(|method| method.to_values(milisecs))
+            .collect::<Vec<_>>();
 
         let mut channels = Vec::with_capacity(milisecs as usize);
         for ms in 0..milisecs {
             let idx = ms as usize;
-            channels.push(Channels {
+            let mut channel = Channels {
                 throttle: throttle[idx],
                 yaw: yaw[idx],
                 pitch: pitch[idx],
                 roll: roll[idx],
-            });
+                aux: std::array::from_fn(|i| aux[i][idx]),
+            };
+            if let Some(ArmingSequence {
+                channel_map,
+                arm_at,
+            }) = &self.arming
+            {
+                let armed = ms >= arm_at.as_millis();
+                if !armed {
+                    channel.throttle = -1.;
+                }
+                channel.set_switch(channel_map, AuxRole::Arm, armed);
+            }
+            channels.push(channel);
         }
         channels
     }
 }
 
+// next to the drone configs and the replays
+fn data_set_path(data_set_id: &str) -> PathBuf {
+    PathBuf::from(std::env::var("HOME").unwrap())
+        .join(".local/share/quad/data_sets")
+        .join(format!("{data_set_id}.json"))
+}
+
+/// Flies brownian sticks on every axis into the `<id>_training_<n>` and `<id>_testing_<n>` file
+/// logs, the episodes run on all cores and are seeded with their index. The manifest of the logs
+/// is written to the `data_sets` directory.
 pub fn build_data_set(
     data_set_id: String,
+    controller: ControllerType,
     training_duration: Duration,
     training_size: usize,
     test_size: usize,
-) {
-    let mut context = SimContext::default();
-    let axis_generator = InputGenerator::default()
-        .set_throttle(InputGenerationMethod::Brownian)
-        .set_yaw(InputGenerationMethod::Brownian)
-        .set_pitch(InputGenerationMethod::Brownian)
-        .set_roll(InputGenerationMethod::Brownian);
-    context.set_loader(&crate::LoaderType::File);
-    let training_inputs = (0..training_size)
-        .map(|_| axis_generator.generate(training_duration))
-        .collect::<Vec<_>>();
-    let test_inputs = (0..test_size)
-        .map(|_| axis_generator.generate(training_duration))
-        .collect::<Vec<_>>();
-
-    // TODO: do this on multiple cores
-    for (ep, inputs) in training_inputs.into_iter().enumerate() {
-        let simulation_id = format!("{data_set_id}_training_{ep}");
-        context.set_logger(crate::LoggerType::File(simulation_id));
-        let mut simulation = context.try_load_simulator().unwrap();
-        simulation.init();
-        for input in inputs {
-            simulation.simulate_delta(Duration::from_millis(1), input);
-        }
-    }
-
-    for (ep, inputs) in test_inputs.into_iter().enumerate() {
-        let simulation_id = format!("{data_set_id}_testing_{ep}");
-        context.set_logger(crate::LoggerType::File(simulation_id));
-        let mut simulation = context.try_load_simulator().unwrap();
-        simulation.init(); // tr_id.clone()
-        for input in inputs {
-            simulation.simulate_delta(Duration::from_millis(1), input);
-        }
-    }
+) -> io::Result<Manifest> {
+    let input = InputSource::Generator(
+        "throttle=brownian,yaw=brownian,pitch=brownian,roll=brownian".into(),
+    );
+    let training = (0..training_size).map(|ep| format!("{data_set_id}_training_{ep}"));
+    let testing = (0..test_size).map(|ep| format!("{data_set_id}_testing_{ep}"));
+    let specs: Vec<_> = training
+        .chain(testing)
+        .enumerate()
+        .map(|(seed, simulation_id)| EpisodeSpec {
+            config_id: "7in_4s_drone".into(),
+            controller: controller.clone(),
+            input: input.clone(),
+            duration: Some(training_duration),
+            seed: seed as u64,
+            logger: LoggerType::File(simulation_id),
+            randomization: None,
+        })
+        .collect();
+    BatchRunner::default()
+        .with_context(|| {
+            let mut context = SimContext::default();
+            context.set_loader(&LoaderType::File);
+            context
+        })
+        .with_manifest(data_set_path(&data_set_id))
+        .run(&specs)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{input_gen::InputGenerator, SimContext};
+    use crate::{
+        input_gen::{InputGenerator, ARM_DELAY},
+        SimContext,
+    };
+    use flight_controller::{ChannelMap, AUX_CHANNELS};
     use std::time::Duration;
 
+    #[test]
+    fn aux_index() {
+        let generator = InputGenerator::default();
+        assert!(generator
+            .set_aux(AUX_CHANNELS, super::InputGenerationMethod::Uniform(1.))
+            .is_none());
+        let channels = InputGenerator::default()
+            .set_aux(2, super::InputGenerationMethod::Uniform(1.))
+            .unwrap()
+            .generate(Duration::from_millis(2));
+        assert_eq!(channels[1].aux[2], 1.);
+        assert_eq!(channels[1].aux[3], -1.);
+    }
+
     #[test]
     fn up_only_ds() {
         let mut context = SimContext::default();
-        context.set_controller(crate::ControllerType::Betafligt);
+        context
+            .set_controller(crate::ControllerType::Betafligt)
+            .unwrap();
         context.set_loader(&crate::LoaderType::File);
         context.set_logger(crate::LoggerType::File("up_only".into()));
 
-        let input_generator =
-            InputGenerator::default().set_throttle(super::InputGenerationMethod::Brownian);
+        let input_generator = InputGenerator::default()
+            .set_throttle(super::InputGenerationMethod::Brownian)
+            .arm_at(ChannelMap::default(), ARM_DELAY);
         let inputs = input_generator.generate(Duration::from_secs(5));
 
         let mut simulation = context.try_load_simulator().unwrap();
-        simulation.init();
+        simulation.init().unwrap();
 
         for input in inputs {
             simulation.simulate_delta(Duration::from_millis(1), input);
@@ -173,16 +274,19 @@ mod test {
     #[test]
     fn yaw_only_ds() {
         let mut context = SimContext::default();
-        context.set_controller(crate::ControllerType::Betafligt);
+        context
+            .set_controller(crate::ControllerType::Betafligt)
+            .unwrap();
         context.set_loader(&crate::LoaderType::File);
         context.set_logger(crate::LoggerType::File("yaw_only".into()));
 
-        let input_generator =
-            InputGenerator::default().set_yaw(super::InputGenerationMethod::Brownian);
+        let input_generator = InputGenerator::default()
+            .set_yaw(super::InputGenerationMethod::Brownian)
+            .arm_at(ChannelMap::default(), ARM_DELAY);
         let inputs = input_generator.generate(Duration::from_secs(5));
 
         let mut simulation = context.try_load_simulator().unwrap();
-        simulation.init();
+        simulation.init().unwrap();
 
         for input in inputs {
             simulation.simulate_delta(Duration::from_millis(1), input);
@@ -212,7 +316,7 @@ mod test {
         ];
 
         for axes in axis_combinations {
-            let mut generator = InputGenerator::default();
+            let mut generator = InputGenerator::default().arm_at(ChannelMap::default(), ARM_DELAY);
             let mut log_suffix_parts: Vec<&str> = Vec::with_capacity(axes.len());
 
             for axis in &axes {
@@ -237,7 +341,9 @@ mod test {
             let test_logger_id = format!("{log_suffix}_combo_test");
 
             let mut context = SimContext::default();
-            context.set_controller(crate::ControllerType::Betafligt);
+            context
+                .set_controller(crate::ControllerType::Betafligt)
+                .unwrap();
             context.set_loader(&crate::LoaderType::File);
 
             // Training trajectory
@@ -245,7 +351,7 @@ mod test {
             let train_inputs = generator.generate(Duration::from_secs(5));
 
             let mut simulation = context.try_load_simulator().unwrap();
-            simulation.init();
+            simulation.init().unwrap();
 
             for input in train_inputs {
                 simulation.simulate_delta(Duration::from_millis(1), input);
@@ -256,7 +362,7 @@ mod test {
             let test_inputs = generator.generate(Duration::from_secs(5));
 
             let mut simulation = context.try_load_simulator().unwrap();
-            simulation.init();
+            simulation.init().unwrap();
 
             for input in test_inputs {
                 simulation.simulate_delta(Duration::from_millis(1), input);
@@ -1,22 +1,39 @@
+pub mod batch;
+pub mod headless;
 pub mod input_gen;
+pub mod sweep;
 
-use bf_controller::BFController;
-use drone::Drone;
-use flight_controller::{controllers::null_controller::NullController, FlightController};
-use loaders::{db_loader::DBLoader, LoaderTrait};
+use ardupilot_controller::{ArduPilotConfig, ArduPilotController};
+use bf_controller::{
+    sitl::{SitlConfig, SitlController},
+    BFConfig, BFController, BFError,
+};
+use drone::{Drone, SimulationRates};
+use flight_controller::{
+    controllers::{
+        null_controller::NullController, pid_controller::PidController, shadow::ShadowController,
+        supervisor::SafetySupervisor,
+    },
+    rates::RateProfile,
+    Arming, ArmingConfig, FlightController, Mixer, MixerError,
+};
+use loaders::{db_loader::DBLoader, LoaderError, LoaderTrait};
 use loaders::{default_laoder::DefaultLoader, file_loader::FileLoader};
 use loggers::{
     db_logger::DBLogger, empty_logger::EmptyLogger, file_logger::FileLogger,
     rerun_logger::RerunLogger, Logger as LoggerTrait,
 };
 use loggers::{FlightLog, Logger};
+use px4_controller::{Px4Config, Px4Controller};
 use res_controller::DroneRc;
+use simulator::course::{Course, CourseTracker};
+use simulator::latency::{Latency, LatencyConfig};
+use simulator::scene::{Collisions, Scene};
+use simulator::scheduler::Scheduler;
 use simulator::Replayer;
 use simulator::Simulator;
-use std::{
-    sync::{Arc, Mutex},
-    time::Duration,
-};
+use socket_controller::{SocketAddress, SocketConfig, SocketController};
+use std::sync::{Arc, Mutex};
 
 #[derive(Default, Eq, PartialEq, Hash, Debug, Clone)]
 pub enum LoggerType {
@@ -31,8 +48,19 @@ pub enum LoggerType {
 pub enum ControllerType {
     #[default]
     Betafligt, // no parameters
-    Reservoir(String), // reservoir controller id
-    NullController,    // no controller
+    BetaflightCli(String), // path to a cli diff applied to the virtual betaflight
+    BetaflightSitl(String), // path to a stock betaflight SITL executable
+    ArduPilotSitl(String), // path to an ArduPilot copter SITL executable
+    Px4Sitl(String),       // path to a PX4 SITL executable
+    Reservoir(String),     // reservoir controller id
+    NullController,        // no controller
+    Pid,                   // native rate controller, uses the rate profile of the context
+    // the first controller flies, the second one takes over when the drone leaves the envelope
+    Supervised(Box<ControllerType>, Box<ControllerType>),
+    // the first controller flies, the rest only have their outputs logged
+    Shadowed(Box<ControllerType>, Vec<ControllerType>),
+    // external controller, tcp:host:port or unix:/path/to/socket
+    Socket(String),
 }
 
 #[derive(Debug)]
@@ -66,6 +94,14 @@ impl Loader {
             Self::DefaultLoader(loader) => loader.load_flight_log(replay_id),
         }
     }
+
+    pub fn load_rate_profile(&mut self, profile_id: &str) -> Result<RateProfile, LoaderError> {
+        match self {
+            Self::DBLoader(loader) => loader.load_rate_profile(profile_id),
+            Self::FileLoader(loader) => loader.load_rate_profile(profile_id),
+            Self::DefaultLoader(loader) => loader.load_rate_profile(profile_id),
+        }
+    }
 }
 
 impl Default for Loader {
@@ -98,6 +134,21 @@ pub struct SimContext {
     pub replay_id: Option<String>,
     // Config id
     pub config_id: Option<String>,
+    // Stick rates used by controllers that need desired rates from the sticks
+    pub rate_profile: RateProfile,
+    // Sensor, motor and loop time latencies of the loaded simulators
+    pub latency: LatencyConfig,
+    // Pre-arm checks and RC failsafe of the loaded simulators
+    pub arming: ArmingConfig,
+    // Overrides the simulation rates of the drone config
+    pub rates: Option<SimulationRates>,
+    // Obstacles the loaded simulators check the drone against
+    pub scene: Arc<Scene>,
+    // Race course the loaded simulators time the laps on
+    pub course: Arc<Course>,
+    // Mixer of the native controllers, its props direction sets the rotor directions of the drone.
+    // Only set through set_mixer, so it is always a quad mixer
+    mixer: Mixer,
 }
 
 impl std::fmt::Debug for SimContext {
@@ -117,6 +168,13 @@ impl Default for SimContext {
             reservoir_controller_ids: Default::default(),
             replay_id: Default::default(),
             config_id: Some(format!("7in_4s_drone")),
+            rate_profile: RateProfile::default(),
+            latency: LatencyConfig::default(),
+            arming: ArmingConfig::default(),
+            rates: None,
+            scene: Arc::new(Scene::default()),
+            course: Arc::new(Course::default()),
+            mixer: Mixer::default(),
         };
         sim_context.refresh_cache();
         sim_context
@@ -134,6 +192,44 @@ impl SimContext {
         }
     }
 
+    /// Keeps the current rates if the profile can not be loaded
+    pub fn set_rate_profile(&mut self, profile_id: &str) -> Result<(), LoaderError> {
+        self.rate_profile = self.loader.lock().unwrap().load_rate_profile(profile_id)?;
+        Ok(())
+    }
+
+    pub fn set_latency(&mut self, latency: LatencyConfig) {
+        self.latency = latency;
+    }
+
+    pub fn set_arming(&mut self, arming: ArmingConfig) {
+        self.arming = arming;
+    }
+
+    /// The rates of the loaded simulators, None uses the rates of the drone config
+    pub fn set_rates(&mut self, rates: Option<SimulationRates>) {
+        self.rates = rates;
+    }
+
+    pub fn set_scene(&mut self, scene: Scene) {
+        self.scene = Arc::new(scene);
+    }
+
+    pub fn set_course(&mut self, course: Course) {
+        self.course = Arc::new(course);
+    }
+
+    pub fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+
+    /// Only quad mixers can drive the loaded drones
+    pub fn set_mixer(&mut self, mixer: Mixer) -> Result<(), MixerError> {
+        mixer.quad_rotor_dirs()?;
+        self.mixer = mixer;
+        Ok(())
+    }
+
     pub fn set_replay_id(&mut self, replay_id: String) {
         self.replay_id = Some(replay_id)
     }
@@ -148,28 +244,84 @@ impl SimContext {
         self.logger = logger;
     }
 
-    pub fn set_controller(&mut self, controller: ControllerType) {
-        let flight_controller: Arc<dyn FlightController> = match controller {
-            ControllerType::Betafligt => Arc::new(BFController::default()),
+    /// Keeps the current controller if the new one can not be started
+    pub fn set_controller(&mut self, controller: ControllerType) -> Result<(), BFError> {
+        self.flight_controller = self.build_controller(controller)?;
+        Ok(())
+    }
+
+    pub fn build_controller(
+        &mut self,
+        controller: ControllerType,
+    ) -> Result<Arc<dyn FlightController>, BFError> {
+        let controller: Arc<dyn FlightController> = match controller {
+            ControllerType::Betafligt => Arc::new(BFController::new(BFConfig::default())?),
+            ControllerType::BetaflightCli(cli) => {
+                let config = BFConfig::default().with_cli(cli);
+                Arc::new(BFController::new(config)?)
+            }
+            ControllerType::BetaflightSitl(binary) => {
+                Arc::new(SitlController::new(SitlConfig::new(binary)))
+            }
+            ControllerType::ArduPilotSitl(binary) => {
+                Arc::new(ArduPilotController::new(ArduPilotConfig::new(binary)))
+            }
+            ControllerType::Px4Sitl(binary) => Arc::new(Px4Controller::new(Px4Config::new(binary))),
             ControllerType::Reservoir(res_id) => {
                 let res_controller = self.loader.lock().unwrap().load_res_controller(&res_id);
                 Arc::new(res_controller)
             }
             ControllerType::NullController => Arc::new(NullController::default()),
+            ControllerType::Pid => Arc::new(
+                PidController::new(
+                    self.rate_profile.clone(),
+                    self.mixer.clone(),
+                    Default::default(),
+                )
+                .expect("set_mixer only takes quad mixers"),
+            ),
+            ControllerType::Supervised(primary, fallback) => {
+                let primary = self.build_controller(*primary)?;
+                let fallback = self.build_controller(*fallback)?;
+                Arc::new(SafetySupervisor::new(primary, fallback))
+            }
+            ControllerType::Shadowed(primary, shadows) => {
+                let primary = self.build_controller(*primary)?;
+                let mut shadow_controller = ShadowController::new(primary);
+                for shadow in shadows {
+                    let controller_id = format!("{shadow:?}");
+                    let controller = self.build_controller(shadow)?;
+                    shadow_controller = shadow_controller.with_shadow(controller_id, controller);
+                }
+                Arc::new(shadow_controller)
+            }
+            ControllerType::Socket(address) => Arc::new(SocketController::new(SocketConfig::new(
+                SocketAddress::parse(&address),
+            ))),
         };
-        self.flight_controller = flight_controller;
+        Ok(controller)
+    }
+
+    // the drone config with the rotor directions of the props
+    fn load_configured_drone(&self, config_id: &str) -> Drone {
+        let mut drone = self.loader.lock().unwrap().load_drone(config_id);
+        // set_mixer only takes quad mixers
+        if let Ok(rotor_dirs) = self.mixer.quad_rotor_dirs() {
+            drone.set_rotor_dirs(rotor_dirs);
+        }
+        drone
     }
 
     pub fn load_simulator(&self, config_id: &str) -> Simulator {
-        let drone = self.loader.lock().unwrap().load_drone(&config_id);
+        let drone = self.load_configured_drone(config_id);
+        let rates = self.rates.unwrap_or(drone.rates);
         Simulator {
-            drone: drone.clone(),
-            flight_controller: self.flight_controller.clone(),
-            time_accu: Duration::default(),
-            time: Duration::new(0, 0),
-            dt: Duration::from_nanos(5000), // TODO: update this maybe?
-            fc_time_accu: Duration::default(),
-            logger: self.logger.clone(),
+            scheduler: Scheduler::new(rates),
+            latency: Latency::new(self.latency),
+            arming: Arming::new(self.arming.clone()),
+            collisions: Collisions::new(self.scene.clone()),
+            course: CourseTracker::new(self.course.clone()),
+            ..Simulator::new(drone, self.flight_controller.clone(), self.logger.clone())
         }
     }
 
@@ -203,20 +355,14 @@ impl SimContext {
         let Some(config_id) = self.config_id.clone() else {
             return None;
         };
-        Some(self.loader.lock().unwrap().load_drone(&config_id))
+        Some(self.load_configured_drone(&config_id))
     }
 
     pub fn load_replayer(&mut self, config_id: &str, replay_id: &str) -> Replayer {
-        let drone = self.loader.lock().unwrap().load_drone(config_id);
+        let drone = self.load_configured_drone(config_id);
         let sim_logs = self.loader.lock().unwrap().load_flight_log(replay_id);
-        Replayer {
-            drone,
-            time: Duration::new(0, 0),
-            time_accu: Duration::new(0, 0),
-            time_steps: sim_logs,
-            replay_index: 0,
-            dt: Duration::from_nanos(5000), // TODO: update this
-        }
+        let rates = self.rates.unwrap_or(drone.rates);
+        Replayer::new(drone, sim_logs, rates)
     }
 
     pub fn try_load_replay(&mut self) -> Option<Replayer> {
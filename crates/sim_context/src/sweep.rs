@@ -0,0 +1,278 @@
+//! Monte-Carlo parameter sweeps. The same episode is flown with many randomized drones and the
+//! metrics of the flights are aggregated per parameter, to see which parameters a controller is
+//! sensitive to.
+
+use crate::{
+    batch::{BatchRunner, EpisodeRecord, EpisodeSpec, Manifest},
+    LoggerType,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, io,
+    ops::Range,
+};
+
+/// The episodes whose parameter fell into `min..max`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParameterBin {
+    pub min: f64,
+    pub max: f64,
+    pub episodes: usize,
+    // the mean of every metric over the episodes of the bin
+    pub metrics: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParameterReport {
+    pub name: String,
+    pub bins: Vec<ParameterBin>,
+    // the correlation of the parameter with every metric, metrics that never changed are left out
+    pub correlations: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SweepReport {
+    pub episodes: usize,
+    pub failed: usize,
+    pub parameters: Vec<ParameterReport>,
+}
+
+fn episode_logger(logger: &LoggerType, seed: u64) -> LoggerType {
+    match logger {
+        LoggerType::File(id) => LoggerType::File(format!("{id}_{seed}")),
+        LoggerType::Db(id) => LoggerType::Db(format!("{id}_{seed}")),
+        LoggerType::Rerun(id) => LoggerType::Rerun(format!("{id}_{seed}")),
+        LoggerType::Empty => LoggerType::Empty,
+    }
+}
+
+/// Flies the template once per seed, every episode with a drone randomized from its seed. The
+/// logs are named after the log of the template with the seed appended.
+pub fn sweep(
+    runner: &BatchRunner,
+    template: &EpisodeSpec,
+    seeds: Range<u64>,
+) -> io::Result<Manifest> {
+    let specs: Vec<_> = seeds
+        .map(|seed| EpisodeSpec {
+            seed,
+            logger: episode_logger(&template.logger, seed),
+            ..template.clone()
+        })
+        .collect();
+    runner.run(&specs)
+}
+
+// pearson correlation, None if either side never changed
+fn correlation(xs: &[f64], ys: &[f64]) -> Option<f64> {
+    let n = xs.len() as f64;
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = ys.iter().sum::<f64>() / n;
+    let (mut covariance, mut variance_x, mut variance_y) = (0., 0., 0.);
+    for (x, y) in xs.iter().zip(ys) {
+        covariance += (x - mean_x) * (y - mean_y);
+        variance_x += (x - mean_x).powi(2);
+        variance_y += (y - mean_y).powi(2);
+    }
+    (variance_x > 0. && variance_y > 0.).then(|| covariance / (variance_x * variance_y).sqrt())
+}
+
+fn mean_metrics(episodes: &[&EpisodeRecord]) -> BTreeMap<String, f64> {
+    let mut sums: BTreeMap<String, f64> = BTreeMap::new();
+    for episode in episodes {
+        for (name, value) in &episode.metrics {
+            *sums.entry(name.clone()).or_default() += value;
+        }
+    }
+    sums.values_mut()
+        .for_each(|sum| *sum /= episodes.len() as f64);
+    sums
+}
+
+fn parameter_report(
+    name: &str,
+    episodes: &[&EpisodeRecord],
+    bins: usize,
+) -> Option<ParameterReport> {
+    let samples: Vec<_> = episodes
+        .iter()
+        .filter_map(|episode| Some((*episode.parameters.get(name)?, *episode)))
+        .collect();
+    let min = samples
+        .iter()
+        .map(|(value, _)| *value)
+        .fold(f64::INFINITY, f64::min);
+    let max = samples
+        .iter()
+        .map(|(value, _)| *value)
+        .fold(f64::NEG_INFINITY, f64::max);
+    // the parameter was not randomized
+    if max <= min {
+        return None;
+    }
+
+    let width = (max - min) / bins as f64;
+    let mut binned = vec![Vec::new(); bins];
+    for (value, episode) in samples.iter() {
+        let bin = (((value - min) / width) as usize).min(bins - 1);
+        binned[bin].push(*episode);
+    }
+    let bins = binned
+        .iter()
+        .enumerate()
+        .map(|(i, episodes)| ParameterBin {
+            min: min + width * i as f64,
+            max: min + width * (i + 1) as f64,
+            episodes: episodes.len(),
+            metrics: mean_metrics(episodes),
+        })
+        .collect();
+
+    let values: Vec<_> = samples.iter().map(|(value, _)| *value).collect();
+    let metrics: BTreeSet<_> = samples
+        .iter()
+        .flat_map(|(_, episode)| episode.metrics.keys())
+        .collect();
+    let correlations = metrics
+        .into_iter()
+        .filter_map(|metric| {
+            let metric_values: Vec<_> = samples
+                .iter()
+                .map(|(_, episode)| episode.metrics.get(metric).copied().unwrap_or_default())
+                .collect();
+            Some((metric.clone(), correlation(&values, &metric_values)?))
+        })
+        .collect();
+    Some(ParameterReport {
+        name: name.to_string(),
+        bins,
+        correlations,
+    })
+}
+
+impl SweepReport {
+    /// Aggregates the episodes that went through into `bins` equally wide bins per parameter.
+    /// Parameters that are the same in every episode are left out.
+    pub fn new(manifest: &Manifest, bins: usize) -> Self {
+        let episodes: Vec<_> = manifest.episodes.iter().filter(|e| e.is_ok()).collect();
+        let names: BTreeSet<_> = episodes
+            .iter()
+            .flat_map(|episode| episode.parameters.keys())
+            .collect();
+        let parameters = names
+            .into_iter()
+            .filter_map(|name| parameter_report(name, &episodes, bins.max(1)))
+            .collect();
+        Self {
+            episodes: manifest.episodes.len(),
+            failed: manifest.failed().count(),
+            parameters,
+        }
+    }
+}
+
+impl fmt::Display for SweepReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "episodes: {} ({} failed)", self.episodes, self.failed)?;
+        for parameter in self.parameters.iter() {
+            write!(f, "\n\n{}", parameter.name)?;
+            for (metric, correlation) in parameter.correlations.iter() {
+                write!(f, "\n  {metric:<22}r={correlation:+.2}")?;
+            }
+            for bin in parameter.bins.iter() {
+                write!(
+                    f,
+                    "\n  {:>10.4} ..{:>10.4} {:>5} episodes",
+                    bin.min, bin.max, bin.episodes
+                )?;
+                if let Some(crashed) = bin.metrics.get("crashed") {
+                    write!(f, ", {:.0}% crashed", crashed * 100.)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        batch::{BatchRunner, EpisodeRecord, EpisodeSpec, Manifest},
+        sweep::{sweep, SweepReport},
+        ControllerType, LoggerType,
+    };
+    use drone::randomization::{ParameterDistribution, RandomizationSpec};
+    use std::{collections::BTreeMap, time::Duration};
+
+    #[test]
+    fn binned_parameters() {
+        let episode = |mass: f64, crashed: f64| EpisodeRecord {
+            parameters: BTreeMap::from([("mass".into(), mass), ("motor_kv".into(), 1300.)]),
+            metrics: BTreeMap::from([("crashed".into(), crashed), ("laps".into(), 0.)]),
+            ..Default::default()
+        };
+        let manifest = Manifest {
+            episodes: vec![
+                episode(1., 0.),
+                episode(1.5, 0.),
+                episode(2., 1.),
+                episode(3., 1.),
+                EpisodeRecord {
+                    error: Some("failed".into()),
+                    ..episode(10., 0.)
+                },
+            ],
+        };
+        let report = SweepReport::new(&manifest, 2);
+        assert_eq!((report.episodes, report.failed), (5, 1));
+        // the kv was the same every time
+        assert_eq!(report.parameters.len(), 1);
+        let mass = &report.parameters[0];
+        assert_eq!(mass.name, "mass");
+        let episodes: Vec<_> = mass.bins.iter().map(|bin| bin.episodes).collect();
+        assert_eq!(episodes, [2, 2]);
+        assert_eq!(mass.bins[0].metrics["crashed"], 0.);
+        assert_eq!(mass.bins[1].metrics["crashed"], 1.);
+        assert!(mass.correlations["crashed"] > 0.8);
+        assert!(!mass.correlations.contains_key("laps"));
+        assert!(report.to_string().contains("100% crashed"));
+    }
+
+    #[test]
+    fn randomized_episodes() {
+        let template = EpisodeSpec {
+            config_id: "7in_4s_drone".into(),
+            controller: ControllerType::NullController,
+            input: "gen:".parse().unwrap(),
+            duration: Some(Duration::from_millis(300)),
+            seed: 0,
+            logger: LoggerType::Empty,
+            randomization: Some(RandomizationSpec {
+                mass: Some(ParameterDistribution::Uniform { min: 0.8, max: 1.2 }),
+                wind_speed: Some(ParameterDistribution::Uniform { min: 0., max: 5. }),
+                ..Default::default()
+            }),
+        };
+        let runner = BatchRunner::default().with_workers(2);
+        let manifest = sweep(&runner, &template, 0..6).unwrap();
+        assert_eq!(manifest.failed().count(), 0);
+        let seeds: Vec<_> = manifest.episodes.iter().map(|e| e.seed).collect();
+        assert_eq!(seeds, [0, 1, 2, 3, 4, 5]);
+        // the same seed samples the same drone
+        let again = sweep(&runner, &template, 2..3).unwrap();
+        assert_eq!(
+            again.episodes[0].parameters,
+            manifest.episodes[2].parameters
+        );
+
+        let report = SweepReport::new(&manifest, 3);
+        let names: Vec<_> = report.parameters.iter().map(|p| p.name.as_str()).collect();
+        assert!(names.contains(&"mass"));
+        assert!(names.contains(&"wind_speed"));
+        assert!(!names.contains(&"motor_kv"));
+        for parameter in report.parameters.iter() {
+            let episodes: usize = parameter.bins.iter().map(|bin| bin.episodes).sum();
+            assert_eq!(episodes, 6);
+        }
+    }
+}
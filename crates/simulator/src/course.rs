@@ -0,0 +1,410 @@
+use loggers::{GatePass, LapTime};
+use nalgebra::{Rotation3, Vector3};
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, path::Path, sync::Arc, time::Duration};
+
+/// A rectangular gate. Without rotation it stands upright and is flown through towards -z, the
+/// forward direction of the drone. The yaw turns it around the vertical axis, a pitch of -90deg
+/// makes it a dive gate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Gate {
+    #[serde(default)]
+    pub name: String,
+    pub center: Vector3<f64>,
+    #[serde(default)]
+    pub yaw: f64,
+    #[serde(default)]
+    pub pitch: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Gate {
+    fn rotation(&self) -> Rotation3<f64> {
+        Rotation3::from_axis_angle(&Vector3::y_axis(), self.yaw)
+            * Rotation3::from_axis_angle(&Vector3::x_axis(), self.pitch)
+    }
+
+    /// The direction the gate is flown through
+    pub fn forward(&self) -> Vector3<f64> {
+        self.rotation() * -Vector3::z()
+    }
+
+    /// Where between `from` and `to` (0 to 1) the drone flew through the gate in the forward
+    /// direction, None if it did not
+    pub fn crossing(&self, from: Vector3<f64>, to: Vector3<f64>) -> Option<f64> {
+        let forward = self.forward();
+        let before = (from - self.center).dot(&forward);
+        let after = (to - self.center).dot(&forward);
+        if before >= 0. || after < 0. {
+            return None;
+        }
+        let fraction = before / (before - after);
+        let point = from + (to - from) * fraction;
+        let local = self.rotation().inverse() * (point - self.center);
+        (local.x.abs() <= self.width / 2. && local.y.abs() <= self.height / 2.).then_some(fraction)
+    }
+}
+
+/// The gates of a race track in the order they are flown, the first gate is the start and finish
+/// gate
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Course {
+    #[serde(default)]
+    pub name: String,
+    pub gates: Vec<Gate>,
+    // the race is over after this many laps, None keeps going
+    #[serde(default)]
+    pub laps: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum CourseError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    NoGates,
+}
+
+impl fmt::Display for CourseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CourseError::Io(err) => write!(f, "could not read the course: {err}"),
+            CourseError::Parse(err) => write!(f, "invalid course: {err}"),
+            CourseError::NoGates => write!(f, "the course has no gates"),
+        }
+    }
+}
+
+impl std::error::Error for CourseError {}
+
+impl Course {
+    /// Reads a course from a JSON file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CourseError> {
+        let contents = fs::read_to_string(path).map_err(CourseError::Io)?;
+        let course: Course = serde_json::from_str(&contents).map_err(CourseError::Parse)?;
+        if course.gates.is_empty() {
+            return Err(CourseError::NoGates);
+        }
+        Ok(course)
+    }
+}
+
+/// Where the drone is in the race
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RaceProgress {
+    pub next_gate: usize,
+    // the lap being flown, 0 until the start gate is passed
+    pub lap: usize,
+    pub completed_laps: usize,
+    pub last_lap: Option<Duration>,
+    // the fastest lap without missed gates
+    pub best_lap: Option<Duration>,
+    pub finished: bool,
+}
+
+/// Follows the drone around the course. A gate counts when the path of the drone during a
+/// physics step goes through it in the forward direction, the time is interpolated within the
+/// step. Flying through a later gate of the lap skips the gates before it, they are recorded as
+/// missed and the lap does not count for the best lap.
+#[derive(Debug, Clone, Default)]
+pub struct CourseTracker {
+    pub course: Arc<Course>,
+    progress: RaceProgress,
+    lap_start: Option<Duration>,
+    splits: Vec<Duration>,
+    missed: Vec<usize>,
+    laps: Vec<LapTime>,
+}
+
+impl CourseTracker {
+    /// The course can be shared between simulators
+    pub fn new(course: impl Into<Arc<Course>>) -> Self {
+        Self {
+            course: course.into(),
+            ..Default::default()
+        }
+    }
+
+    /// None if there is no course
+    pub fn progress(&self) -> Option<RaceProgress> {
+        (!self.course.gates.is_empty()).then_some(self.progress)
+    }
+
+    /// The completed laps
+    pub fn laps(&self) -> &[LapTime] {
+        &self.laps
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self {
+            course: self.course.clone(),
+            ..Default::default()
+        };
+    }
+
+    /// Checks the path of the drone during a physics step against the gates, returns the gates
+    /// that were passed and the laps that were completed
+    pub(crate) fn step(
+        &mut self,
+        start: Duration,
+        end: Duration,
+        from: Vector3<f64>,
+        to: Vector3<f64>,
+    ) -> (Vec<GatePass>, Vec<LapTime>) {
+        let mut gate_passes = vec![];
+        let mut laps = vec![];
+        if self.progress.finished {
+            return (gate_passes, laps);
+        }
+        let mut crossings: Vec<(f64, usize)> = self
+            .course
+            .gates
+            .iter()
+            .enumerate()
+            .filter_map(|(i, gate)| Some((gate.crossing(from, to)?, i)))
+            .collect();
+        crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        for (fraction, gate) in crossings {
+            let time = start + (end - start).mul_f64(fraction);
+            if let Some(gate_pass) = self.pass(time, gate, &mut laps) {
+                gate_passes.push(gate_pass);
+            }
+            if self.progress.finished {
+                break;
+            }
+        }
+        (gate_passes, laps)
+    }
+
+    fn pass(&mut self, time: Duration, gate: usize, laps: &mut Vec<LapTime>) -> Option<GatePass> {
+        let gate_count = self.course.gates.len();
+        let Some(lap_start) = self.lap_start else {
+            // the race starts at the start gate
+            if gate != 0 {
+                return None;
+            }
+            self.start_lap(time);
+            return Some(GatePass {
+                duration: time,
+                gate,
+                lap: self.progress.lap,
+                split: Duration::ZERO,
+                missed: vec![],
+            });
+        };
+
+        let next_gate = self.progress.next_gate;
+        // the gates between the next gate and this one were skipped, gates that were already
+        // passed in this lap do not count again
+        let missed: Vec<usize> = if gate == 0 {
+            (next_gate..gate_count).filter(|_| next_gate != 0).collect()
+        } else if next_gate != 0 && gate >= next_gate {
+            (next_gate..gate).collect()
+        } else {
+            return None;
+        };
+        let split = time - lap_start;
+        self.splits.push(split);
+        self.missed.extend(missed.iter().copied());
+
+        if gate != 0 {
+            self.progress.next_gate = (gate + 1) % gate_count;
+            return Some(GatePass {
+                duration: time,
+                gate,
+                lap: self.progress.lap,
+                split,
+                missed,
+            });
+        }
+
+        let lap = LapTime {
+            lap: self.progress.lap,
+            time: split,
+            splits: std::mem::take(&mut self.splits),
+            missed: std::mem::take(&mut self.missed),
+        };
+        self.progress.completed_laps += 1;
+        self.progress.last_lap = Some(lap.time);
+        if lap.is_valid() {
+            self.progress.best_lap = Some(
+                self.progress
+                    .best_lap
+                    .map_or(lap.time, |best| best.min(lap.time)),
+            );
+        }
+        laps.push(lap.clone());
+        self.laps.push(lap);
+        if self
+            .course
+            .laps
+            .is_some_and(|race_laps| self.progress.completed_laps >= race_laps)
+        {
+            self.progress.finished = true;
+        } else {
+            self.start_lap(time);
+        }
+        Some(GatePass {
+            duration: time,
+            gate,
+            lap: self.progress.lap,
+            split: Duration::ZERO,
+            missed,
+        })
+    }
+
+    fn start_lap(&mut self, time: Duration) {
+        self.lap_start = Some(time);
+        self.progress.lap += 1;
+        self.progress.next_gate = 1 % self.course.gates.len();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        course::{Course, CourseTracker, Gate},
+        Simulator,
+    };
+    use drone::default_drone::default_7in_4s_drone;
+    use flight_controller::{controllers::null_controller::NullController, Channels};
+    use loggers::empty_logger::EmptyLogger;
+    use nalgebra::Vector3;
+    use std::{
+        f64::consts::PI,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    fn gate(x: f64, z: f64, yaw: f64) -> Gate {
+        Gate {
+            name: String::new(),
+            center: Vector3::new(x, 1., z),
+            yaw,
+            pitch: 0.,
+            width: 1.5,
+            height: 1.5,
+        }
+    }
+
+    // two gates 10m apart, flown in a loop
+    fn course() -> Course {
+        Course {
+            name: "drag".into(),
+            gates: vec![gate(0., 0., 0.), gate(2., -10., PI)],
+            laps: Some(3),
+        }
+    }
+
+    #[test]
+    fn gate_crossing() {
+        let gate = gate(0., 0., 0.);
+        let fraction = gate.crossing(Vector3::new(0., 1., 1.), Vector3::new(0., 1., -3.));
+        assert_eq!(fraction, Some(0.25));
+        // backwards
+        assert!(gate
+            .crossing(Vector3::new(0., 1., -1.), Vector3::new(0., 1., 1.))
+            .is_none());
+        // next to the gate
+        assert!(gate
+            .crossing(Vector3::new(1., 1., 1.), Vector3::new(1., 1., -1.))
+            .is_none());
+        let dive = Gate {
+            pitch: -PI / 2.,
+            ..gate
+        };
+        assert!(dive
+            .crossing(Vector3::new(0., 2., 0.), Vector3::new(0., 0., 0.))
+            .is_some());
+    }
+
+    #[test]
+    fn laps() {
+        let mut tracker = CourseTracker::new(course());
+        let mut time = Duration::ZERO;
+        let mut fly = |tracker: &mut CourseTracker, x: f64, from: f64, to: f64, seconds: f64| {
+            let start = time;
+            time += Duration::from_secs_f64(seconds);
+            tracker.step(
+                start,
+                time,
+                Vector3::new(x, 1., from),
+                Vector3::new(x, 1., to),
+            )
+        };
+        // the lap starts halfway through the step
+        let (passes, _) = fly(&mut tracker, 0., 1., -1., 1.);
+        assert_eq!(passes[0].duration, Duration::from_millis(500));
+        assert_eq!(tracker.progress().unwrap().lap, 1);
+        // out through the second gate and back through the start gate
+        fly(&mut tracker, 2., -11., -9., 1.);
+        let (_, laps) = fly(&mut tracker, 0., 1., -1., 1.);
+        assert_eq!(laps[0].time, Duration::from_secs(2));
+        assert_eq!(laps[0].splits.len(), 2);
+        assert!(laps[0].is_valid());
+
+        // the second gate is skipped
+        let (passes, laps) = fly(&mut tracker, 0., 1., -1., 1.);
+        assert_eq!(passes[0].missed, vec![1]);
+        assert!(!laps[0].is_valid());
+        let progress = tracker.progress().unwrap();
+        assert_eq!(progress.last_lap, Some(Duration::from_secs(1)));
+        assert_eq!(progress.best_lap, Some(Duration::from_secs(2)));
+
+        fly(&mut tracker, 2., -11., -9., 1.);
+        fly(&mut tracker, 0., 1., -1., 1.);
+        let progress = tracker.progress().unwrap();
+        assert!(progress.finished);
+        assert_eq!(tracker.laps().len(), 3);
+        // nothing counts after the race
+        assert!(fly(&mut tracker, 0., 1., -1., 1.).0.is_empty());
+    }
+
+    #[test]
+    fn load_course() {
+        let path = std::env::temp_dir().join("course_test.json");
+        let course = r#"{
+            "name": "hairpin",
+            "gates": [
+                {"center": [0, 1, 0], "width": 1.5, "height": 1.5},
+                {"name": "dive", "center": [0, 3, -10], "pitch": -1.57, "width": 1.5, "height": 1.5}
+            ]
+        }"#;
+        std::fs::write(&path, course).unwrap();
+        let course = Course::load(&path).unwrap();
+        assert_eq!(course.gates.len(), 2);
+        assert_eq!(course.laps, None);
+        std::fs::write(&path, r#"{"gates": []}"#).unwrap();
+        assert!(Course::load(&path).is_err());
+    }
+
+    #[test]
+    fn observed_gate_passes() {
+        let mut drone = default_7in_4s_drone();
+        let state = &mut drone.current_frame.drone_frame_state;
+        state.position = Vector3::new(0., 1., 1.);
+        state.linear_velocity = Vector3::new(0., 0., -10.);
+        let mut simulator = Simulator {
+            course: CourseTracker::new(course()),
+            ..Simulator::new(
+                drone,
+                Arc::new(NullController::default()),
+                Arc::new(Mutex::new(EmptyLogger::default())),
+            )
+        };
+        simulator.init().unwrap();
+        let mut passes = vec![];
+        for _ in 0..20 {
+            let observation =
+                simulator.simulate_delta(Duration::from_millis(10), Channels::default());
+            passes.extend(observation.gate_passes);
+        }
+        assert_eq!(passes.len(), 1);
+        // 1m at 10m/s, a bit more with the drag
+        assert!(passes[0].duration.abs_diff(Duration::from_millis(100)) < Duration::from_millis(5));
+        let progress = simulator.simulation_info().race.unwrap();
+        assert_eq!(progress.lap, 1);
+        assert_eq!(progress.next_gate, 1);
+    }
+}
@@ -0,0 +1,534 @@
+use crate::{SimulationObservation, Simulator};
+use drone::SimulationFrame;
+use flight_controller::{
+    AuxRole, Channels, ControllerState, FlightController, FlightControllerUpdate, InitError,
+    MotorInput,
+};
+use nalgebra::{UnitQuaternion, Vector3};
+use rayon::prelude::*;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// The quantities an observation is made of, the observation is the concatenation of the items in
+/// the order of the observation space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObservationItem {
+    Rotation, // quaternion i, j, k, w
+    AngularVelocity,
+    LinearVelocity,
+    Acceleration,
+    Position,
+    // the motor inputs that currently drive the motors
+    MotorInput,
+    BatteryVoltage,
+}
+
+impl ObservationItem {
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Rotation | Self::MotorInput => 4,
+            Self::AngularVelocity | Self::LinearVelocity | Self::Acceleration | Self::Position => 3,
+            Self::BatteryVoltage => 1,
+        }
+    }
+
+    fn write(&self, simulator: &Simulator, info: &SimulationObservation, obs: &mut Vec<f64>) {
+        match self {
+            Self::Rotation => {
+                let q = UnitQuaternion::from_rotation_matrix(&info.rotation);
+                obs.extend([q.i, q.j, q.k, q.w]);
+            }
+            Self::AngularVelocity => obs.extend(info.angular_velocity.iter()),
+            Self::LinearVelocity => obs.extend(info.linear_velocity.iter()),
+            Self::Acceleration => obs.extend(info.acceleration.iter()),
+            Self::Position => obs.extend(info.position.iter()),
+            Self::MotorInput => obs.extend(simulator.drone.motor_input().input),
+            Self::BatteryVoltage => obs.push(info.bat_voltage_sag),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObservationSpace {
+    pub items: Vec<ObservationItem>,
+}
+
+impl Default for ObservationSpace {
+    fn default() -> Self {
+        Self {
+            items: vec![
+                ObservationItem::Rotation,
+                ObservationItem::AngularVelocity,
+                ObservationItem::LinearVelocity,
+                ObservationItem::Position,
+            ],
+        }
+    }
+}
+
+impl ObservationSpace {
+    pub fn len(&self) -> usize {
+        self.items.iter().map(|item| item.size()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn observe(&self, simulator: &Simulator) -> Vec<f64> {
+        let info = simulator.simulation_info();
+        let mut obs = Vec::with_capacity(self.len());
+        for item in self.items.iter() {
+            item.write(simulator, &info, &mut obs);
+        }
+        obs
+    }
+}
+
+#[derive(Clone, Default)]
+pub enum ActionSpace {
+    // the four motor commands between 0 and 1, in flight controller output order
+    #[default]
+    Motors,
+    // throttle, roll, pitch and yaw sticks between -1 and 1, flown by the controller (e.g. the
+    // PID controller in rate mode)
+    Sticks(Arc<dyn FlightController>),
+}
+
+impl ActionSpace {
+    pub fn len(&self) -> usize {
+        4
+    }
+
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// The lower and upper bound of every action, actions outside are clamped
+    pub fn bounds(&self) -> (Vec<f64>, Vec<f64>) {
+        match self {
+            Self::Motors => (vec![0.; 4], vec![1.; 4]),
+            Self::Sticks(_) => (vec![-1.; 4], vec![1.; 4]),
+        }
+    }
+}
+
+/// Everything the reward and termination functions get to see after a step.
+pub struct Transition<'a> {
+    pub observation: &'a SimulationObservation,
+    pub action: &'a [f64],
+    // the position the episode started at
+    pub initial_position: Vector3<f64>,
+    pub episode_steps: usize,
+}
+
+pub type RewardFn = Arc<dyn Fn(&Transition) -> f64 + Send + Sync>;
+pub type TerminationFn = Arc<dyn Fn(&Transition) -> bool + Send + Sync>;
+
+/// Rewards staying at the initial position with little rotation.
+pub fn hover_reward() -> RewardFn {
+    Arc::new(|transition| {
+        let observation = transition.observation;
+        let distance = (observation.position - transition.initial_position).norm();
+        1. - distance - 0.01 * observation.angular_velocity.norm()
+    })
+}
+
+/// Terminates when the drone is further than max_distance (m) away from the initial position or
+/// the simulation blew up.
+pub fn out_of_bounds(max_distance: f64) -> TerminationFn {
+    Arc::new(move |transition| {
+        let observation = transition.observation;
+        let distance = (observation.position - transition.initial_position).norm();
+        !distance.is_finite() || distance > max_distance
+    })
+}
+
+#[derive(Clone)]
+pub struct EnvConfig {
+    pub observation_space: ObservationSpace,
+    pub action_space: ActionSpace,
+    // the simulated time between two actions
+    pub step_time: Duration,
+    // episodes are truncated after this time
+    pub max_episode_time: Duration,
+    pub reward: RewardFn,
+    pub termination: TerminationFn,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        Self {
+            observation_space: ObservationSpace::default(),
+            action_space: ActionSpace::default(),
+            step_time: Duration::from_millis(10),
+            max_episode_time: Duration::from_secs(10),
+            reward: hover_reward(),
+            termination: out_of_bounds(5.),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ResetOptions {
+    // start from this frame instead of the environment's initial frame
+    pub initial_frame: Option<SimulationFrame>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct StepInfo {
+    pub simulation_time: Duration,
+    pub episode_steps: usize,
+    // the vectorised env resets finished episodes right away, this is the last observation of the
+    // finished episode
+    pub terminal_observation: Option<Vec<f64>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StepResult {
+    pub observation: Vec<f64>,
+    pub reward: f64,
+    pub terminated: bool,
+    pub truncated: bool,
+    pub info: StepInfo,
+}
+
+// hands the actions of the agent straight to the motors
+#[derive(Default)]
+struct ActionController {
+    motor_input: Mutex<MotorInput>,
+}
+
+impl FlightController for ActionController {
+    fn init(&self) -> Result<(), InitError> {
+        *self.motor_input.lock().unwrap() = MotorInput::default();
+        Ok(())
+    }
+
+    fn update(&self, _: f64, _: FlightControllerUpdate) -> MotorInput {
+        *self.motor_input.lock().unwrap()
+    }
+
+    fn scheduler_delta(&self) -> Duration {
+        Duration::from_micros(250)
+    }
+
+    fn snapshot(&self) -> Option<ControllerState> {
+        Some(Box::new(*self.motor_input.lock().unwrap()))
+    }
+
+    fn restore(&self, state: &ControllerState) -> bool {
+        let Some(motor_input) = state.downcast_ref::<MotorInput>() else {
+            return false;
+        };
+        *self.motor_input.lock().unwrap() = *motor_input;
+        true
+    }
+}
+
+/// A gym style environment on top of the simulator. The drone is armed on every reset, the agent
+/// either drives the motors directly or gives stick commands to a flight controller.
+pub struct Env {
+    pub simulator: Simulator,
+    pub config: EnvConfig,
+    pub initial_frame: SimulationFrame,
+    action_controller: Arc<ActionController>,
+    episode_steps: usize,
+    initial_position: Vector3<f64>,
+}
+
+impl Env {
+    /// The simulator's flight controller is replaced according to the action space, the current
+    /// frame of the drone is used as the initial frame.
+    pub fn new(mut simulator: Simulator, config: EnvConfig) -> Self {
+        let action_controller = Arc::new(ActionController::default());
+        simulator.flight_controller = match &config.action_space {
+            ActionSpace::Motors => action_controller.clone(),
+            ActionSpace::Sticks(controller) => controller.clone(),
+        };
+        let initial_frame = simulator.drone.current_frame.clone();
+        Self {
+            simulator,
+            config,
+            initial_frame,
+            action_controller,
+            episode_steps: 0,
+            initial_position: Vector3::zeros(),
+        }
+    }
+
+    /// Starts a new episode. The seed reseeds the noise of the simulator, without a seed the noise
+    /// carries on.
+    pub fn reset(
+        &mut self,
+        seed: Option<u64>,
+        options: ResetOptions,
+    ) -> Result<Vec<f64>, InitError> {
+        if let Some(seed) = seed {
+            self.simulator.seed(seed);
+        }
+        let initial_frame = options
+            .initial_frame
+            .unwrap_or_else(|| self.initial_frame.clone());
+        self.initial_position = initial_frame.drone_frame_state.position;
+        self.simulator.reset(initial_frame);
+        self.simulator.init()?;
+        self.episode_steps = 0;
+
+        // arm right away, the sticks are centered and the throttle is low
+        let rotation = self
+            .simulator
+            .drone
+            .current_frame
+            .gyro_state
+            .gyro_update()
+            .rotation;
+        let channels = self.channels(&[-1., 0., 0., 0.]);
+        self.simulator
+            .arming
+            .update(Duration::ZERO, Some(channels), rotation);
+
+        Ok(self.config.observation_space.observe(&self.simulator))
+    }
+
+    fn channels(&self, sticks: &[f64]) -> Channels {
+        let map = &self.simulator.arming.config.channel_map;
+        Channels {
+            throttle: sticks[0],
+            roll: sticks[1],
+            pitch: sticks[2],
+            yaw: sticks[3],
+            ..Default::default()
+        }
+        .with_switch(map, AuxRole::Arm, true)
+    }
+
+    pub fn step(&mut self, action: &[f64]) -> StepResult {
+        assert_eq!(
+            action.len(),
+            self.config.action_space.len(),
+            "wrong action size"
+        );
+        let (low, high) = self.config.action_space.bounds();
+        let action: Vec<f64> = (0..action.len())
+            .map(|i| action[i].clamp(low[i], high[i]))
+            .collect();
+        let channels = match self.config.action_space {
+            ActionSpace::Motors => {
+                *self.action_controller.motor_input.lock().unwrap() = MotorInput {
+                    input: [action[0], action[1], action[2], action[3]],
+                };
+                // the sticks do not matter, the arm switch does
+                self.channels(&[-1., 0., 0., 0.])
+            }
+            ActionSpace::Sticks(_) => self.channels(&action),
+        };
+
+        let simulation_observation = self
+            .simulator
+            .simulate_delta(self.config.step_time, channels);
+        self.episode_steps += 1;
+        let transition = Transition {
+            observation: &simulation_observation,
+            action: &action,
+            initial_position: self.initial_position,
+            episode_steps: self.episode_steps,
+        };
+        let reward = (self.config.reward)(&transition);
+        // a crashed drone does not move anymore
+        let terminated = simulation_observation.crashed || (self.config.termination)(&transition);
+        // counted in steps, the simulation time can lag behind by one dt
+        let episode_time = self.config.step_time * self.episode_steps as u32;
+        let truncated = !terminated && episode_time >= self.config.max_episode_time;
+        if terminated || truncated {
+            self.simulator.end_episode();
+        }
+
+        StepResult {
+            observation: self.config.observation_space.observe(&self.simulator),
+            reward,
+            terminated,
+            truncated,
+            info: StepInfo {
+                simulation_time: self.simulator.time,
+                episode_steps: self.episode_steps,
+                terminal_observation: None,
+            },
+        }
+    }
+}
+
+/// Steps several environments in parallel. Finished episodes are reset right away, like the
+/// vectorised environments of gymnasium and stable-baselines3. Every environment has its own
+/// noise, so a seeded run is reproducible however the environments are spread over the threads.
+pub struct VecEnv {
+    pub envs: Vec<Env>,
+}
+
+impl VecEnv {
+    pub fn new(envs: Vec<Env>) -> Self {
+        Self { envs }
+    }
+
+    pub fn len(&self) -> usize {
+        self.envs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+    /// Resets all environments, environment i is seeded with seed + i
+    pub fn reset(&mut self, seed: Option<u64>) -> Result<Vec<Vec<f64>>, InitError> {
+        self.envs
+            .par_iter_mut()
+            .enumerate()
+            .map(|(i, env)| env.reset(seed.map(|seed| seed + i as u64), ResetOptions::default()))
+            .collect()
+    }
+
+    /// Steps all environments, a finished environment is reset right away
+    pub fn step(&mut self, actions: &[Vec<f64>]) -> Result<Vec<StepResult>, InitError> {
+        assert_eq!(actions.len(), self.envs.len(), "one action per env");
+        self.envs
+            .par_iter_mut()
+            .zip(actions.par_iter())
+            .map(|(env, action)| {
+                let mut result = env.step(action);
+                if result.terminated || result.truncated {
+                    let observation = env.reset(None, ResetOptions::default())?;
+                    result.info.terminal_observation =
+                        Some(std::mem::replace(&mut result.observation, observation));
+                }
+                Ok(result)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        env::{
+            out_of_bounds, ActionSpace, Env, EnvConfig, ObservationItem, ObservationSpace,
+            ResetOptions, VecEnv,
+        },
+        Simulator,
+    };
+    use drone::default_drone::default_7in_4s_drone;
+    use flight_controller::controllers::{
+        null_controller::NullController, pid_controller::PidController,
+    };
+    use loggers::empty_logger::EmptyLogger;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    fn simulator() -> Simulator {
+        Simulator::new(
+            default_7in_4s_drone(),
+            Arc::new(NullController::default()),
+            Arc::new(Mutex::new(EmptyLogger::default())),
+        )
+    }
+
+    #[test]
+    fn motor_actions() {
+        let config = EnvConfig {
+            observation_space: ObservationSpace {
+                items: vec![ObservationItem::Position, ObservationItem::MotorInput],
+            },
+            max_episode_time: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let mut env = Env::new(simulator(), config);
+        let obs = env.reset(Some(1), ResetOptions::default()).unwrap();
+        assert_eq!(obs.len(), 7);
+
+        // full throttle climbs until the episode is truncated
+        let mut result = env.step(&[1.; 4]);
+        assert_eq!(result.observation[3..], [1.; 4]);
+        while !(result.terminated || result.truncated) {
+            result = env.step(&[2.; 4]);
+        }
+        assert!(result.truncated);
+        assert!(result.observation[1] > 0.);
+        assert_eq!(result.info.episode_steps, 20);
+
+        let obs = env.reset(None, ResetOptions::default()).unwrap();
+        assert_eq!(obs[..3], [0.; 3]);
+    }
+
+    #[test]
+    fn stick_actions_and_termination() {
+        let config = EnvConfig {
+            action_space: ActionSpace::Sticks(Arc::new(PidController::default())),
+            termination: out_of_bounds(0.5),
+            ..Default::default()
+        };
+        let mut env = Env::new(simulator(), config);
+        env.reset(None, ResetOptions::default()).unwrap();
+        assert!(env.simulator.arming.state().motors_enabled());
+        // without throttle the drone falls out of the bounds
+        let mut steps = 0;
+        while !env.step(&[-1., 0., 0., 0.]).terminated {
+            steps += 1;
+            assert!(steps < 100);
+        }
+    }
+
+    #[test]
+    fn vec_env_resets_finished_envs() {
+        let config = EnvConfig {
+            termination: out_of_bounds(0.5),
+            ..Default::default()
+        };
+        let envs = (0..3)
+            .map(|_| Env::new(simulator(), config.clone()))
+            .collect();
+        let mut vec_env = VecEnv::new(envs);
+        assert_eq!(vec_env.reset(Some(0)).unwrap().len(), 3);
+        let actions = vec![vec![0.; 4]; 3];
+        let mut results = vec_env.step(&actions).unwrap();
+        while !results[0].terminated {
+            results = vec_env.step(&actions).unwrap();
+        }
+        assert!(results.iter().all(|result| result.terminated));
+        let terminal = results[0].info.terminal_observation.as_ref().unwrap();
+        assert!(terminal[11] < -0.5);
+        // the new episode starts at the initial position
+        assert_eq!(results[0].observation[11], 0.);
+    }
+
+    #[test]
+    fn vec_env_seeded_noise() {
+        let config = EnvConfig {
+            observation_space: ObservationSpace {
+                items: vec![ObservationItem::BatteryVoltage],
+            },
+            ..Default::default()
+        };
+        let run = || {
+            let envs = (0..4)
+                .map(|_| Env::new(simulator(), config.clone()))
+                .collect();
+            let mut vec_env = VecEnv::new(envs);
+            vec_env.reset(Some(3)).unwrap();
+            let actions = vec![vec![0.5; 4]; 4];
+            (0..10)
+                .map(|_| {
+                    let results = vec_env.step(&actions).unwrap();
+                    results.into_iter().map(|r| r.observation[0]).collect()
+                })
+                .collect::<Vec<Vec<f64>>>()
+        };
+        let voltages = run();
+        // the threads the envs run on do not matter
+        assert_eq!(run(), voltages);
+        // every env has its own seed
+        assert_ne!(voltages[9][0], voltages[9][1]);
+    }
+}
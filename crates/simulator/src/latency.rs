@@ -0,0 +1,137 @@
+use drone::Noise;
+use flight_controller::{BatteryUpdate, GyroUpdate, MotorInput};
+use std::{collections::VecDeque, time::Duration};
+
+/// The latencies of the real system. The sensor delay covers the gyro sampling and the filter
+/// group delay, the motor delay the ESC and motor latency. The loop jitter is the largest
+/// deviation of the flight controller's loop time, it is drawn uniformly every loop.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LatencyConfig {
+    pub sensor_delay: Duration,
+    pub motor_delay: Duration,
+    pub loop_jitter: Duration,
+}
+
+/// Holds values for a fixed amount of simulation time before handing them out.
+#[derive(Debug, Clone)]
+pub struct DelayLine<T> {
+    delay: Duration,
+    samples: VecDeque<(Duration, T)>,
+    current: Option<T>,
+}
+
+impl<T: Clone> DelayLine<T> {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            samples: VecDeque::new(),
+            current: None,
+        }
+    }
+
+    pub fn push(&mut self, time: Duration, value: T) {
+        self.samples.push_back((time, value));
+    }
+
+    /// The last value that was pushed
+    pub fn newest(&self) -> Option<T> {
+        self.samples
+            .back()
+            .map(|(_, value)| value.clone())
+            .or_else(|| self.current.clone())
+    }
+
+    /// The newest value that is at least `delay` old, None if there is no such value yet.
+    pub fn sample(&mut self, now: Duration) -> Option<T> {
+        while let Some((time, _)) = self.samples.front() {
+            if *time + self.delay > now {
+                break;
+            }
+            self.current = self.samples.pop_front().map(|(_, value)| value);
+        }
+        self.current.clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SensorSample {
+    pub battery_update: BatteryUpdate,
+    pub gyro_update: GyroUpdate,
+}
+
+#[derive(Debug, Clone)]
+pub struct Latency {
+    pub config: LatencyConfig,
+    pub sensors: DelayLine<SensorSample>,
+    pub motors: DelayLine<MotorInput>,
+    // the jitter of the current loop in ns, can be negative
+    jitter: i64,
+    // draws the jitter
+    pub noise: Noise,
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Self::new(LatencyConfig::default())
+    }
+}
+
+impl Latency {
+    pub fn new(config: LatencyConfig) -> Self {
+        let mut latency = Self {
+            config,
+            sensors: DelayLine::new(config.sensor_delay),
+            motors: DelayLine::new(config.motor_delay),
+            jitter: 0,
+            noise: Noise::default(),
+        };
+        latency.next_loop();
+        latency
+    }
+
+    /// Empties the delay lines and draws a new jitter, the noise carries on
+    pub fn reset(&mut self) {
+        self.sensors = DelayLine::new(self.config.sensor_delay);
+        self.motors = DelayLine::new(self.config.motor_delay);
+        self.next_loop();
+    }
+
+    /// The jitter of the current loop in ns, can be negative
+    pub fn jitter(&self) -> i64 {
+        self.jitter
+    }
+
+    /// Draws the jitter for the next loop
+    pub fn next_loop(&mut self) {
+        let max_jitter = self.config.loop_jitter.as_nanos() as f64;
+        self.jitter = if max_jitter > 0. {
+            self.noise.gen_range(-max_jitter..max_jitter) as i64
+        } else {
+            0
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::latency::DelayLine;
+    use std::time::Duration;
+
+    #[test]
+    fn delay_line() {
+        let mut line = DelayLine::new(Duration::from_millis(2));
+        assert_eq!(line.sample(Duration::ZERO), None);
+        for ms in 0..5 {
+            line.push(Duration::from_millis(ms), ms);
+        }
+        assert_eq!(line.sample(Duration::from_millis(1)), None);
+        assert_eq!(line.sample(Duration::from_millis(3)), Some(1));
+        // holds the last value
+        assert_eq!(line.sample(Duration::from_millis(3)), Some(1));
+        assert_eq!(line.sample(Duration::from_millis(10)), Some(4));
+
+        let mut no_delay = DelayLine::new(Duration::ZERO);
+        no_delay.push(Duration::from_millis(1), 1);
+        assert_eq!(no_delay.sample(Duration::from_millis(1)), Some(1));
+    }
+}
@@ -1,12 +1,27 @@
-use drone::{Drone, SimulationFrame};
+pub mod course;
+pub mod env;
+pub mod latency;
+pub mod observer;
+pub mod rewind;
+pub mod scene;
+pub mod scheduler;
+pub mod tasks;
+pub mod world;
+
+use course::{CourseTracker, RaceProgress};
+use drone::{sub_seed, Drone, Noise, SeedStream, SimulationFrame, SimulationRates};
+use flight_controller::{
+    Arming, ArmingState, Channels, ControllerState, FlightController, FlightControllerUpdate,
+    InitError,
+};
 pub use flight_controller::{BatteryUpdate, GyroUpdate, MotorInput};
-use flight_controller::{Channels, FlightController, FlightControllerUpdate};
-use loggers::{FlightLog, Logger, SnapShot};
+use latency::{Latency, SensorSample};
+use loggers::{CollisionEvent, FlightLog, GatePass, LapTime, Logger, SnapShot};
 use nalgebra::{Rotation3, Vector3, Vector4};
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use observer::{ControllerStep, Observer, Observers, PhysicsStep};
+use scene::Collisions;
+use scheduler::Scheduler;
 use std::{
-    cell::RefCell,
-    ops::Range,
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -15,14 +30,6 @@ pub const MAX_EFFECT_SPEED: f64 = 18.0;
 pub const AIR_RHO: f64 = 1.225;
 pub const GRAVITY: f64 = 9.81;
 
-thread_local! {
-    static RNG: RefCell<StdRng> = RefCell::new(StdRng::seed_from_u64(0));
-}
-
-pub fn rng_gen_range(range: Range<f64>) -> f64 {
-    RNG.with(|rng| rng.borrow_mut().gen_range(range))
-}
-
 #[derive(Debug, Default)]
 pub struct SimulationObservation {
     pub simulation_time: Duration,
@@ -36,6 +43,30 @@ pub struct SimulationObservation {
     pub pwms: Vector4<f64>,
     pub bat_voltage: f64,
     pub bat_voltage_sag: f64,
+    pub arming_state: ArmingState,
+    // the drone hit an obstacle and the collision response is to crash
+    pub crashed: bool,
+    // the obstacles the drone hit since the last observation
+    pub collisions: Vec<CollisionEvent>,
+    // None if there is no race course
+    pub race: Option<RaceProgress>,
+    // the gates passed and the laps completed since the last observation
+    pub gate_passes: Vec<GatePass>,
+    pub laps: Vec<LapTime>,
+}
+
+/// The state of a simulator at one point in time, including the flight controller and the noise
+/// generators. The logger is not part of it, what was logged after the snapshot stays logged.
+#[derive(Debug)]
+pub struct SimulatorSnapshot {
+    pub drone: Drone,
+    pub time: Duration,
+    scheduler: Scheduler,
+    latency: Latency,
+    arming: Arming,
+    collisions: Collisions,
+    course: CourseTracker,
+    flight_controller: ControllerState,
 }
 
 // The simulator simulates the complete drone with a flight controller and all the neccessary aux
@@ -43,14 +74,41 @@ pub struct SimulationObservation {
 pub struct Simulator {
     pub drone: Drone,
     pub time: Duration,
-    pub time_accu: Duration, // the accumulated time between two steps + the correction from the
-    pub dt: Duration,
+    // decides when the physics, the sensors and the flight controller run
+    pub scheduler: Scheduler,
     pub flight_controller: Arc<dyn FlightController>,
-    pub fc_time_accu: Duration,
     pub logger: Arc<Mutex<dyn Logger>>, // needs to be mutable
+    pub latency: Latency,
+    pub arming: Arming,
+    pub observers: Observers,
+    // the static geometry of the world, empty by default
+    pub collisions: Collisions,
+    // times the laps of the race course, without gates it does nothing
+    pub course: CourseTracker,
 }
 
 impl Simulator {
+    /// A simulator at the rates of the drone, without latency, scene or race course and with the
+    /// default arming checks. The other parts can be set on the returned simulator.
+    pub fn new(
+        drone: Drone,
+        flight_controller: Arc<dyn FlightController>,
+        logger: Arc<Mutex<dyn Logger>>,
+    ) -> Self {
+        Self {
+            scheduler: Scheduler::new(drone.rates),
+            drone,
+            time: Duration::ZERO,
+            flight_controller,
+            logger,
+            latency: Latency::default(),
+            arming: Arming::default(),
+            observers: Observers::default(),
+            collisions: Collisions::default(),
+            course: CourseTracker::default(),
+        }
+    }
+
     // TODO: dont need this
     pub fn simulation_info(&self) -> SimulationObservation {
         let current_frame = &self.drone.current_frame;
@@ -82,60 +140,236 @@ impl Simulator {
             pwms,
             bat_voltage: battery_state.bat_voltage,
             bat_voltage_sag: battery_state.bat_voltage_sag,
+            arming_state: self.arming.state(),
+            crashed: self.collisions.crashed(),
+            collisions: vec![],
+            race: self.course.progress(),
+            gate_passes: vec![],
+            laps: vec![],
         }
     }
 
     /// Given a duration (typically 10ms between frames), runs the simulation until the time
     /// accumlator is less then the simulation's dt. It will also try to
     pub fn simulate_delta(&mut self, delta: Duration, channels: Channels) -> SimulationObservation {
-        self.time_accu += delta;
-        while self.time_accu > self.dt {
-            self.fc_time_accu += self.dt;
-            self.drone.update(self.dt.as_secs_f64());
+        self.simulate_delta_rc(delta, Some(channels))
+    }
 
-            let call_fc = self.fc_time_accu > self.flight_controller.scheduler_delta();
+    /// Same as `simulate_delta`, but the RC link can drop out. `rc_frame` is None if no RC frame
+    /// arrived during the delta, the failsafe kicks in if that lasts too long.
+    pub fn simulate_delta_rc(
+        &mut self,
+        delta: Duration,
+        rc_frame: Option<Channels>,
+    ) -> SimulationObservation {
+        self.scheduler.advance(delta);
+        let mut collisions = vec![];
+        let mut gate_passes = vec![];
+        let mut laps = vec![];
+        while let Some(step) = self.scheduler.next_step() {
+            let position = self.drone.position();
+            self.drone.update(self.scheduler.dt());
+            for collision in self.collisions.step(self.scheduler.time(), &mut self.drone) {
+                self.observers.collision(&collision);
+                self.logger.lock().unwrap().log_collision(collision.clone());
+                collisions.push(collision);
+            }
+            let (step_gate_passes, step_laps) = self.course.step(
+                self.time,
+                self.scheduler.time(),
+                position,
+                self.drone.position(),
+            );
+            for gate_pass in step_gate_passes {
+                self.logger.lock().unwrap().log_gate_pass(gate_pass.clone());
+                gate_passes.push(gate_pass);
+            }
+            for lap in step_laps {
+                self.logger.lock().unwrap().log_lap(lap.clone());
+                laps.push(lap);
+            }
+            let sensor_sample = SensorSample {
+                battery_update: self.drone.battery_update(),
+                gyro_update: self.drone.current_frame.gyro_state.gyro_update(),
+            };
+            // between two samples of a sensor its last reading is held
+            let rates = self.scheduler.rates;
+            let gyro_due = self.scheduler.is_due(step, rates.gyro);
+            let accel_due = self.scheduler.is_due(step, rates.accel);
+            if gyro_due || accel_due {
+                let held = self.latency.sensors.newest().unwrap_or(sensor_sample);
+                let mut sample = sensor_sample;
+                if !gyro_due {
+                    sample.gyro_update.rotation = held.gyro_update.rotation;
+                    sample.gyro_update.angular_velocity = held.gyro_update.angular_velocity;
+                }
+                if !accel_due {
+                    sample.gyro_update.linear_acc = held.gyro_update.linear_acc;
+                }
+                self.latency.sensors.push(self.time, sample);
+            }
+
+            let controller_loop = self.scheduler.controller_loop(
+                step,
+                self.flight_controller.scheduler_delta(),
+                self.latency.jitter(),
+            );
 
             // update the flight controller
-            if call_fc {
+            if let Some(controller_loop) = controller_loop {
+                // the flight controller only sees the delayed sensor data, right after the start
+                // there is nothing old enough so it gets the current one
+                let SensorSample {
+                    battery_update,
+                    gyro_update,
+                } = self
+                    .latency
+                    .sensors
+                    .sample(self.time)
+                    .unwrap_or(sensor_sample);
+                // the arming state decides what the flight controller gets to see
+                let rc_frame = rc_frame.filter(|_| controller_loop.rc_frame);
+                let channels = self
+                    .arming
+                    .update(self.time, rc_frame, gyro_update.rotation);
                 let motor_input = self.flight_controller.update(
-                    self.fc_time_accu.as_secs_f64(),
+                    controller_loop.elapsed.as_secs_f64(),
                     FlightControllerUpdate {
-                        battery_update: self.drone.battery_update(),
-                        gyro_update: self.drone.current_frame.gyro_state.gyro_update(),
+                        battery_update,
+                        gyro_update,
                         channels,
+                        position: self.drone.position().into(),
                     },
                 );
-                self.drone.set_motor_pwms(motor_input);
-                self.fc_time_accu -= self.flight_controller.scheduler_delta();
+                let motor_input = if self.arming.state().motors_enabled() {
+                    motor_input
+                } else {
+                    MotorInput::default()
+                };
+                self.latency.motors.push(self.time, motor_input);
+                self.latency.next_loop();
 
-                let mut logger = self.logger.lock().unwrap();
                 let snapshot = SnapShot {
                     duration: self.time,
                     motor_input: motor_input,
-                    battery_update: self.drone.battery_update(),
-                    gyro_update: self.drone.current_frame.gyro_state.gyro_update(),
+                    battery_update,
+                    gyro_update,
                     channels,
+                    shadow_motor_inputs: self.flight_controller.shadow_outputs(),
+                    arming_state: self.arming.state(),
+                    intervention: self.flight_controller.intervention(),
                 };
-                logger.log_time_stamp(snapshot);
+                self.observers.controller_step(&ControllerStep {
+                    time: self.time,
+                    frame: &self.drone.current_frame,
+                    snapshot: &snapshot,
+                });
+                if controller_loop.log {
+                    self.logger.lock().unwrap().log_time_stamp(snapshot);
+                }
             }
 
-            self.time_accu -= self.dt;
-            self.time += self.dt;
+            // the motor commands reach the motors after the motor delay, a crashed drone does
+            // not spin its motors anymore
+            if self.collisions.crashed() {
+                self.drone.set_motor_pwms(MotorInput::default());
+            } else if let Some(motor_input) = self.latency.motors.sample(self.time) {
+                self.drone.set_motor_pwms(motor_input);
+            }
+
+            let step_start = self.time;
+            self.time = self.scheduler.time();
+            self.observers.physics_step(&PhysicsStep {
+                time: self.time,
+                dt: self.time - step_start,
+                frame: &self.drone.current_frame,
+                drone: &self.drone,
+            });
         }
 
-        self.simulation_info()
+        SimulationObservation {
+            collisions,
+            gate_passes,
+            laps,
+            ..self.simulation_info()
+        }
+    }
+
+    /// Initializes the flight controller and starts a new episode
+    pub fn init(&mut self) -> Result<(), InitError> {
+        self.flight_controller.init()?;
+        self.observers
+            .start_episode(self.time, &self.drone.current_frame);
+        Ok(())
+    }
+
+    pub fn add_observer(&mut self, observer: Arc<Mutex<dyn Observer>>) {
+        self.observers.add(observer);
+    }
+
+    /// Lets the observers know the episode is over, e.g. because the drone crashed. Resetting the
+    /// simulator also ends the episode.
+    pub fn end_episode(&mut self) {
+        self.observers
+            .end_episode(self.time, &self.drone.current_frame);
+    }
+
+    /// None if the flight controller does not support snapshots
+    pub fn snapshot(&self) -> Option<SimulatorSnapshot> {
+        Some(SimulatorSnapshot {
+            drone: self.drone.clone(),
+            time: self.time,
+            scheduler: self.scheduler.clone(),
+            latency: self.latency.clone(),
+            arming: self.arming.clone(),
+            collisions: self.collisions.clone(),
+            course: self.course.clone(),
+            flight_controller: self.flight_controller.snapshot()?,
+        })
+    }
+
+    /// Continues from the snapshot, given the same inputs the simulation runs exactly as it did
+    /// after the snapshot was taken. Returns false if the flight controller rejected its state,
+    /// the simulator is left as it was then.
+    pub fn restore(&mut self, snapshot: &SimulatorSnapshot) -> bool {
+        if !self.flight_controller.restore(&snapshot.flight_controller) {
+            return false;
+        }
+        self.drone = snapshot.drone.clone();
+        self.time = snapshot.time;
+        self.scheduler = snapshot.scheduler.clone();
+        self.latency = snapshot.latency.clone();
+        self.arming = snapshot.arming.clone();
+        self.collisions = snapshot.collisions.clone();
+        self.course = snapshot.course.clone();
+        true
     }
 
-    pub fn init(&mut self) {
-        self.flight_controller.init();
+    /// Reseeds the noise of the drone and of the loop jitter, the same seed gives the same noise
+    /// on any thread. Both draw from their own stream of the seed.
+    pub fn seed(&mut self, seed: u64) {
+        self.drone.noise = Noise::new(sub_seed(seed, SeedStream::SensorNoise));
+        self.latency.noise = Noise::new(sub_seed(seed, SeedStream::LoopJitter));
+    }
+
+    /// Puts the drone back into the initial frame and restarts the clock, the flight controller
+    /// has to be initialized separately.
+    pub fn reset(&mut self, initial_frame: SimulationFrame) {
+        self.end_episode();
+        self.drone.reset(initial_frame);
+        self.time = Duration::ZERO;
+        self.scheduler = Scheduler::new(self.scheduler.rates);
+        self.latency.reset();
+        self.arming = Arming::new(self.arming.config.clone());
+        self.collisions.reset();
+        self.course.reset();
     }
 }
 
 pub struct Replayer {
     pub drone: Drone,
-    pub time: Duration, // TODO: remove this
-    pub time_accu: Duration,
-    pub dt: Duration,
+    // replays in whole physics steps like the simulator, so it does not drift from the recording
+    pub scheduler: Scheduler,
     // we assume that therer are not gaps in the input and the range of the input is always larger
     // than dt, since the simulation generarally runs at a higher frequency. Maybe in the future we
     // can eliviate these issues
@@ -144,15 +378,23 @@ pub struct Replayer {
 }
 
 impl Replayer {
+    pub fn new(drone: Drone, time_steps: FlightLog, rates: SimulationRates) -> Self {
+        Self {
+            drone,
+            scheduler: Scheduler::new(rates),
+            time_steps,
+            replay_index: 0,
+        }
+    }
+
     fn get_motor_input(&mut self) -> Option<MotorInput> {
         if self.replay_index < self.time_steps.steps.len() {
-            self.time += self.dt;
             let SnapShot {
                 duration,
                 motor_input,
                 ..
             } = self.time_steps.steps[self.replay_index];
-            if self.time.as_secs_f64() >= duration.as_secs_f64() {
+            if self.scheduler.time() >= duration {
                 self.replay_index += 1;
             }
             Some(MotorInput {
@@ -187,7 +429,7 @@ impl Replayer {
             Vector4::from_row_slice(&rotors_state.iter().map(|r| r.pwm).collect::<Vec<f64>>());
 
         SimulationObservation {
-            simulation_time: self.time,
+            simulation_time: self.scheduler.time(),
             rotation: drone_state.rotation,
             position: drone_state.position,
             linear_velocity: drone_state.linear_velocity,
@@ -198,20 +440,30 @@ impl Replayer {
             pwms,
             bat_voltage: battery_state.bat_voltage,
             bat_voltage_sag: battery_state.bat_voltage_sag,
+            arming_state: self
+                .time_steps
+                .steps
+                .get(self.replay_index)
+                .map(|step| step.arming_state)
+                .unwrap_or_default(),
+            crashed: false,
+            collisions: vec![],
+            race: None,
+            gate_passes: vec![],
+            laps: vec![],
         }
     }
 
     pub fn replay_delta(&mut self, delta: Duration) -> SimulationObservation {
-        self.time_accu += delta;
-        while self.time_accu > self.dt {
-            self.drone.update(self.dt.as_secs_f64());
+        self.scheduler.advance(delta);
+        while self.scheduler.next_step().is_some() {
+            self.drone.update(self.scheduler.dt());
             let motor_input = self.get_motor_input();
             if let Some(motor_input) = motor_input {
                 self.drone.set_motor_pwms(motor_input);
             } else {
                 self.drone.set_motor_pwms(MotorInput::default());
             }
-            self.time_accu -= self.dt;
         }
 
         self.simulation_info()
@@ -219,8 +471,101 @@ impl Replayer {
 
     pub fn reset(&mut self, initial_frame: SimulationFrame) {
         self.drone.reset(initial_frame);
-        self.time = Duration::new(0, 0);
-        self.time_accu = Duration::new(0, 0);
+        self.scheduler = Scheduler::new(self.scheduler.rates);
         self.replay_index = 0;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{Replayer, Simulator};
+    use drone::{default_drone::default_7in_4s_drone, SimulationRates};
+    use flight_controller::{
+        arming::{FailsafeConfig, FailsafeProcedure},
+        controllers::pid_controller::PidController,
+        Arming, ArmingConfig, ArmingState, AuxRole, ChannelMap, Channels, FailsafeStage,
+    };
+    use loggers::{empty_logger::EmptyLogger, FlightLog};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[test]
+    fn failsafe_landing() {
+        let arming = ArmingConfig {
+            failsafe: FailsafeConfig {
+                procedure: FailsafeProcedure::Land,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut simulator = Simulator {
+            arming: Arming::new(arming),
+            ..Simulator::new(
+                default_7in_4s_drone(),
+                Arc::new(PidController::default()),
+                Arc::new(Mutex::new(EmptyLogger::default())),
+            )
+        };
+        simulator.init().unwrap();
+
+        let map = ChannelMap::default();
+        let step = Duration::from_millis(10);
+        // the throttle is not low, so arming is refused
+        let channels = Channels::default().with_switch(&map, AuxRole::Arm, true);
+        let observation = simulator.simulate_delta(
+            step,
+            Channels {
+                throttle: 0.,
+                ..channels
+            },
+        );
+        assert_eq!(observation.arming_state, ArmingState::Disarmed);
+        simulator.simulate_delta(step, Channels::default());
+        let observation = simulator.simulate_delta(step, channels);
+        assert_eq!(observation.arming_state, ArmingState::Armed);
+
+        for _ in 0..50 {
+            simulator.simulate_delta(
+                step,
+                Channels {
+                    throttle: 0.,
+                    ..channels
+                },
+            );
+        }
+        assert!(simulator.drone.position().y > 0.);
+
+        let mut states = vec![];
+        for _ in 0..300 {
+            states.push(simulator.simulate_delta_rc(step, None).arming_state);
+        }
+        assert!(states.contains(&ArmingState::Failsafe(FailsafeStage::Hold)));
+        assert!(states.contains(&ArmingState::Failsafe(FailsafeStage::Land)));
+        assert_eq!(
+            states.last(),
+            Some(&ArmingState::Failsafe(FailsafeStage::Drop))
+        );
+        assert_eq!(simulator.drone.motor_input().input, [0.; 4]);
+    }
+
+    #[test]
+    fn replay_in_whole_steps() {
+        // a physics step of a third of a millisecond is not a whole number of nanoseconds
+        let rates = SimulationRates {
+            physics: 3000,
+            ..Default::default()
+        };
+        let mut replayer = Replayer::new(
+            default_7in_4s_drone(),
+            FlightLog::new("replay".into(), vec![]),
+            rates,
+        );
+        for _ in 0..100 {
+            replayer.replay_delta(Duration::from_millis(10));
+        }
+        assert_eq!(replayer.scheduler.steps(), 3000);
+        assert_eq!(replayer.scheduler.time(), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,192 @@
+use drone::{Drone, SimulationFrame};
+use loggers::{CollisionEvent, SnapShot};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// A step of the physics, the simulation time is at the end of the step
+pub struct PhysicsStep<'a> {
+    pub time: Duration,
+    pub dt: Duration,
+    pub frame: &'a SimulationFrame,
+    // the models of the drone
+    pub drone: &'a Drone,
+}
+
+/// A call to the flight controller
+pub struct ControllerStep<'a> {
+    pub time: Duration,
+    pub frame: &'a SimulationFrame,
+    // what the loggers get, the sensor data is what the flight controller saw
+    pub snapshot: &'a SnapShot,
+}
+
+/// An episode starts when the simulator is initialized and ends when it is reset or when
+/// `Simulator::end_episode` is called
+pub struct Episode<'a> {
+    pub start: Duration,
+    pub time: Duration,
+    pub frame: &'a SimulationFrame,
+}
+
+/// Gets called from the simulation loop, for metrics, custom loggers or checks that should not
+/// live in the simulator. The physics steps run every `dt`, so those should be cheap.
+pub trait Observer: Send {
+    fn on_physics_step(&mut self, _step: &PhysicsStep) {}
+    fn on_controller_step(&mut self, _step: &ControllerStep) {}
+    fn on_collision(&mut self, _collision: &CollisionEvent) {}
+    fn on_episode_start(&mut self, _episode: &Episode) {}
+    fn on_episode_end(&mut self, _episode: &Episode) {}
+}
+
+/// The observers of a simulator. They are shared like the logger, so whoever added one can read
+/// its results.
+#[derive(Default)]
+pub struct Observers {
+    observers: Vec<Arc<Mutex<dyn Observer>>>,
+    // the start of the running episode
+    episode_start: Option<Duration>,
+}
+
+impl Observers {
+    pub fn add(&mut self, observer: Arc<Mutex<dyn Observer>>) {
+        self.observers.push(observer);
+    }
+
+    pub fn len(&self) -> usize {
+        self.observers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.observers.clear();
+    }
+
+    pub fn episode_running(&self) -> bool {
+        self.episode_start.is_some()
+    }
+
+    pub(crate) fn physics_step(&self, step: &PhysicsStep) {
+        for observer in self.observers.iter() {
+            observer.lock().unwrap().on_physics_step(step);
+        }
+    }
+
+    pub(crate) fn controller_step(&self, step: &ControllerStep) {
+        for observer in self.observers.iter() {
+            observer.lock().unwrap().on_controller_step(step);
+        }
+    }
+
+    pub(crate) fn collision(&self, collision: &CollisionEvent) {
+        for observer in self.observers.iter() {
+            observer.lock().unwrap().on_collision(collision);
+        }
+    }
+
+    pub(crate) fn start_episode(&mut self, time: Duration, frame: &SimulationFrame) {
+        self.end_episode(time, frame);
+        self.episode_start = Some(time);
+        let episode = Episode {
+            start: time,
+            time,
+            frame,
+        };
+        for observer in self.observers.iter() {
+            observer.lock().unwrap().on_episode_start(&episode);
+        }
+    }
+
+    pub(crate) fn end_episode(&mut self, time: Duration, frame: &SimulationFrame) {
+        let Some(start) = self.episode_start.take() else {
+            return;
+        };
+        let episode = Episode { start, time, frame };
+        for observer in self.observers.iter() {
+            observer.lock().unwrap().on_episode_end(&episode);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        observer::{ControllerStep, Episode, Observer, PhysicsStep},
+        Simulator,
+    };
+    use drone::default_drone::default_7in_4s_drone;
+    use flight_controller::{
+        controllers::pid_controller::PidController, AuxRole, ChannelMap, Channels,
+    };
+    use loggers::empty_logger::EmptyLogger;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[derive(Default)]
+    struct Counter {
+        physics_steps: usize,
+        controller_steps: usize,
+        episodes: Vec<(Duration, Duration)>,
+        max_altitude: f64,
+    }
+
+    impl Observer for Counter {
+        fn on_physics_step(&mut self, step: &PhysicsStep) {
+            self.physics_steps += 1;
+            let altitude = step.frame.drone_frame_state.position.y;
+            self.max_altitude = self.max_altitude.max(altitude);
+        }
+
+        fn on_controller_step(&mut self, step: &ControllerStep) {
+            assert_eq!(step.snapshot.duration, step.time);
+            self.controller_steps += 1;
+        }
+
+        fn on_episode_end(&mut self, episode: &Episode) {
+            self.episodes.push((episode.start, episode.time));
+        }
+    }
+
+    #[test]
+    fn observes_the_simulation() {
+        let mut simulator = Simulator::new(
+            default_7in_4s_drone(),
+            Arc::new(PidController::default()),
+            Arc::new(Mutex::new(EmptyLogger::default())),
+        );
+        let counter = Arc::new(Mutex::new(Counter::default()));
+        simulator.add_observer(counter.clone());
+        simulator.init().unwrap();
+
+        let channels = Channels::default().with_switch(&ChannelMap::default(), AuxRole::Arm, true);
+        simulator.simulate_delta(Duration::from_millis(10), channels);
+        for _ in 0..100 {
+            simulator.simulate_delta(
+                Duration::from_millis(10),
+                Channels {
+                    throttle: 0.2,
+                    ..channels
+                },
+            );
+        }
+        let end = simulator.time;
+        let initial_frame = default_7in_4s_drone().current_frame;
+        simulator.reset(initial_frame);
+        // not running, so there is nothing to end
+        simulator.end_episode();
+
+        let counter = counter.lock().unwrap();
+        // the simulator runs a step less if the time does not divide evenly
+        assert!(counter.physics_steps.abs_diff(202_000) <= 101);
+        // the pid controller runs every 250us
+        assert!(counter.controller_steps.abs_diff(4040) <= 101);
+        assert!(counter.max_altitude > 0.);
+        assert_eq!(counter.episodes, [(Duration::ZERO, end)]);
+    }
+}
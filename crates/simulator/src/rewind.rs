@@ -0,0 +1,176 @@
+use crate::{Simulator, SimulatorSnapshot};
+use std::{collections::VecDeque, time::Duration};
+
+/// Keeps snapshots of the last seconds of a flight, so the simulation can be taken back a few
+/// seconds and flown differently from there. A snapshot is taken every `interval`, the ones older
+/// than `horizon` are dropped.
+#[derive(Debug)]
+pub struct RewindBuffer {
+    pub horizon: Duration,
+    pub interval: Duration,
+    snapshots: VecDeque<SimulatorSnapshot>,
+}
+
+impl RewindBuffer {
+    pub fn new(horizon: Duration, interval: Duration) -> Self {
+        Self {
+            horizon,
+            interval,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    /// Takes a snapshot if the last one is at least `interval` old, meant to be called after every
+    /// step. Returns false if the flight controller does not support snapshots.
+    pub fn record(&mut self, simulator: &Simulator) -> bool {
+        match self.snapshots.back() {
+            // the simulator was reset, the old snapshots are from another flight
+            Some(last) if simulator.time < last.time => self.snapshots.clear(),
+            Some(last) if simulator.time < last.time + self.interval => return true,
+            _ => {}
+        }
+        let Some(snapshot) = simulator.snapshot() else {
+            return false;
+        };
+        self.snapshots.push_back(snapshot);
+        // the oldest snapshot is kept as long as it is needed to go back the whole horizon
+        while self.snapshots.len() > 1 && self.snapshots[1].time + self.horizon <= simulator.time {
+            self.snapshots.pop_front();
+        }
+        true
+    }
+
+    /// Takes the simulator back at least `by`, or as far as the buffer goes. The snapshots after
+    /// the restored one are dropped. Returns the simulation time it went back to, None if there is
+    /// no snapshot or the flight controller rejected it.
+    pub fn rewind(&mut self, simulator: &mut Simulator, by: Duration) -> Option<Duration> {
+        let target = simulator.time.saturating_sub(by);
+        let index = self
+            .snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.time <= target)
+            .unwrap_or(0);
+        self.snapshots.truncate(index + 1);
+        let snapshot = self.snapshots.back()?;
+        simulator.restore(snapshot).then_some(snapshot.time)
+    }
+
+    /// The simulation time of the oldest snapshot
+    pub fn oldest(&self) -> Option<Duration> {
+        self.snapshots.front().map(|snapshot| snapshot.time)
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        latency::{Latency, LatencyConfig},
+        rewind::RewindBuffer,
+        Simulator,
+    };
+    use drone::default_drone::default_7in_4s_drone;
+    use flight_controller::{
+        controllers::{null_controller::NullController, pid_controller::PidController},
+        AuxRole, ChannelMap, Channels,
+    };
+    use loggers::empty_logger::EmptyLogger;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    fn simulator() -> Simulator {
+        Simulator {
+            latency: Latency::new(LatencyConfig {
+                loop_jitter: Duration::from_micros(20),
+                ..Default::default()
+            }),
+            ..Simulator::new(
+                default_7in_4s_drone(),
+                Arc::new(PidController::default()),
+                Arc::new(Mutex::new(EmptyLogger::default())),
+            )
+        }
+    }
+
+    // arms and then climbs while rolling
+    fn channels(step: usize) -> Channels {
+        let channels = Channels::default().with_switch(&ChannelMap::default(), AuxRole::Arm, true);
+        if step == 0 {
+            return channels;
+        }
+        Channels {
+            throttle: 0.2,
+            roll: 0.1,
+            ..channels
+        }
+    }
+
+    #[test]
+    fn restore_replays_the_flight() {
+        let step = Duration::from_millis(10);
+        let mut simulator = simulator();
+        simulator.init().unwrap();
+        for i in 0..50 {
+            simulator.simulate_delta(step, channels(i));
+        }
+        let snapshot = simulator.snapshot().unwrap();
+        let flown: Vec<_> = (50..100)
+            .map(|i| simulator.simulate_delta(step, channels(i)).position)
+            .collect();
+
+        assert!(simulator.restore(&snapshot));
+        assert_eq!(simulator.time, snapshot.time);
+        let replayed: Vec<_> = (50..100)
+            .map(|i| simulator.simulate_delta(step, channels(i)).position)
+            .collect();
+        assert_eq!(flown, replayed);
+
+        // the state of one controller means nothing to another one
+        simulator.flight_controller = Arc::new(NullController::default());
+        assert!(!simulator.restore(&snapshot));
+    }
+
+    #[test]
+    fn rewind() {
+        let step = Duration::from_millis(10);
+        let mut simulator = simulator();
+        simulator.init().unwrap();
+        let mut buffer = RewindBuffer::new(Duration::from_secs(1), Duration::from_millis(100));
+        for i in 0..300 {
+            simulator.simulate_delta(step, channels(i));
+            assert!(buffer.record(&simulator));
+        }
+        // one snapshot every 100ms for the last second, plus the one that reaches past it
+        assert_eq!(buffer.len(), 11);
+        assert!(buffer.oldest().unwrap() <= simulator.time - buffer.horizon);
+
+        let now = simulator.time;
+        let time = buffer
+            .rewind(&mut simulator, Duration::from_millis(500))
+            .unwrap();
+        assert!(time <= now - Duration::from_millis(500));
+        assert!(time > now - Duration::from_millis(600));
+        assert_eq!(simulator.time, time);
+
+        // further back than the buffer goes
+        let oldest = buffer.oldest().unwrap();
+        assert_eq!(
+            buffer.rewind(&mut simulator, Duration::from_secs(10)),
+            Some(oldest)
+        );
+        assert_eq!(buffer.len(), 1);
+    }
+}
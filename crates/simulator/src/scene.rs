@@ -0,0 +1,621 @@
+use drone::Drone;
+use loggers::CollisionEvent;
+use nalgebra::{Rotation3, Vector3};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+/// The shape of an obstacle, in the world frame with y up
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Shape {
+    // rotated by yaw (rad) around the vertical axis through its center
+    Box {
+        center: Vector3<f64>,
+        size: Vector3<f64>,
+        #[serde(default)]
+        yaw: f64,
+    },
+    // upright, standing on `base`
+    Cylinder {
+        base: Vector3<f64>,
+        radius: f64,
+        height: f64,
+    },
+    // everything behind the plane is solid, the normal points out of it
+    Plane {
+        point: Vector3<f64>,
+        normal: Vector3<f64>,
+    },
+    // the surface of a Wavefront OBJ file, the path is relative to the scene file
+    Mesh {
+        path: PathBuf,
+        #[serde(default)]
+        offset: Vector3<f64>,
+        #[serde(skip)]
+        triangles: Vec<[Vector3<f64>; 3]>,
+    },
+}
+
+/// Where a sphere touches a shape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contact {
+    pub point: Vector3<f64>,
+    // points from the shape towards the center of the sphere
+    pub normal: Vector3<f64>,
+    // how far the sphere has to move along the normal to not touch anymore
+    pub depth: f64,
+}
+
+impl Shape {
+    /// Where a sphere touches the shape, None if it does not
+    pub fn contact(&self, center: Vector3<f64>, radius: f64) -> Option<Contact> {
+        match self {
+            Shape::Box {
+                center: box_center,
+                size,
+                yaw,
+            } => {
+                let rotation = Rotation3::from_axis_angle(&Vector3::y_axis(), *yaw);
+                let local = rotation.inverse() * (center - box_center);
+                let half = size / 2.;
+                let closest = local.zip_map(&half, |x, h| x.clamp(-h, h));
+                let contact = if closest == local {
+                    // the center is inside, leave through the closest face
+                    let (axis, distance) = (0..3)
+                        .map(|i| (i, half[i] - local[i].abs()))
+                        .min_by(|a, b| a.1.total_cmp(&b.1))
+                        .unwrap();
+                    let mut normal = Vector3::zeros();
+                    normal[axis] = if local[axis] < 0. { -1. } else { 1. };
+                    let mut point = local;
+                    point[axis] = normal[axis] * half[axis];
+                    Contact {
+                        point,
+                        normal,
+                        depth: radius + distance,
+                    }
+                } else {
+                    sphere_contact(local, closest, radius)?
+                };
+                Some(Contact {
+                    point: rotation * contact.point + box_center,
+                    normal: rotation * contact.normal,
+                    depth: contact.depth,
+                })
+            }
+            Shape::Cylinder {
+                base,
+                radius: cylinder_radius,
+                height,
+            } => {
+                let local = center - base;
+                let radial = Vector3::new(local.x, 0., local.z);
+                let distance = radial.norm();
+                let inside_radius = distance <= *cylinder_radius;
+                let inside_height = (0. ..=*height).contains(&local.y);
+                if inside_radius && inside_height {
+                    // the center is inside, leave through the side, the top or the bottom
+                    let side = cylinder_radius - distance;
+                    let top = height - local.y;
+                    let bottom = local.y;
+                    let (normal, point, distance) = if side <= top && side <= bottom {
+                        let normal = if distance > 0. {
+                            radial / distance
+                        } else {
+                            Vector3::x()
+                        };
+                        (normal, normal * *cylinder_radius, side)
+                    } else if top <= bottom {
+                        (Vector3::y(), Vector3::new(local.x, *height, local.z), top)
+                    } else {
+                        (-Vector3::y(), Vector3::new(local.x, 0., local.z), bottom)
+                    };
+                    return Some(Contact {
+                        point: point + base,
+                        normal,
+                        depth: radius + distance,
+                    });
+                }
+                let mut closest = if inside_radius {
+                    radial
+                } else {
+                    radial * (cylinder_radius / distance)
+                };
+                closest.y = local.y.clamp(0., *height);
+                let contact = sphere_contact(local, closest, radius)?;
+                Some(Contact {
+                    point: contact.point + base,
+                    ..contact
+                })
+            }
+            Shape::Plane { point, normal } => {
+                let normal = normal.normalize();
+                let distance = (center - point).dot(&normal);
+                (distance < radius).then(|| Contact {
+                    point: center - distance * normal,
+                    normal,
+                    depth: radius - distance,
+                })
+            }
+            Shape::Mesh {
+                offset, triangles, ..
+            } => triangles
+                .iter()
+                .filter_map(|triangle| {
+                    let [a, b, c] = triangle.map(|vertex| vertex + offset);
+                    let closest = closest_on_triangle(center, a, b, c);
+                    if closest == center {
+                        // the center is on the surface, push it out of the front side
+                        let normal = (b - a).cross(&(c - a)).try_normalize(0.)?;
+                        return Some(Contact {
+                            point: closest,
+                            normal,
+                            depth: radius,
+                        });
+                    }
+                    sphere_contact(center, closest, radius)
+                })
+                .max_by(|a, b| a.depth.total_cmp(&b.depth)),
+        }
+    }
+}
+
+// the contact of a sphere whose center is outside the shape, `closest` is the closest point of
+// the shape
+fn sphere_contact(center: Vector3<f64>, closest: Vector3<f64>, radius: f64) -> Option<Contact> {
+    let offset = center - closest;
+    let distance = offset.norm();
+    (distance < radius && distance > 0.).then(|| Contact {
+        point: closest,
+        normal: offset / distance,
+        depth: radius - distance,
+    })
+}
+
+// from Real-Time Collision Detection (Ericson), 5.1.5
+fn closest_on_triangle(
+    p: Vector3<f64>,
+    a: Vector3<f64>,
+    b: Vector3<f64>,
+    c: Vector3<f64>,
+) -> Vector3<f64> {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = ab.dot(&ap);
+    let d2 = ac.dot(&ap);
+    if d1 <= 0. && d2 <= 0. {
+        return a;
+    }
+    let bp = p - b;
+    let d3 = ab.dot(&bp);
+    let d4 = ac.dot(&bp);
+    if d3 >= 0. && d4 <= d3 {
+        return b;
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0. && d1 >= 0. && d3 <= 0. {
+        return a + ab * (d1 / (d1 - d3));
+    }
+    let cp = p - c;
+    let d5 = ab.dot(&cp);
+    let d6 = ac.dot(&cp);
+    if d6 >= 0. && d5 <= d6 {
+        return c;
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0. && d2 >= 0. && d6 <= 0. {
+        return a + ac * (d2 / (d2 - d6));
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0. && d4 - d3 >= 0. && d5 - d6 >= 0. {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+    let denom = 1. / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Obstacle {
+    #[serde(default)]
+    pub name: String,
+    #[serde(flatten)]
+    pub shape: Shape,
+}
+
+/// What happens to the drone when it hits an obstacle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CollisionResponse {
+    // the drone stays where it hit with its motors off, the episode is over
+    #[default]
+    Crash,
+    // the velocity into the obstacle is reflected and scaled by the restitution, the velocity
+    // along the surface is scaled by 1 - friction
+    Bounce {
+        restitution: f64,
+        friction: f64,
+    },
+}
+
+fn default_drone_radius() -> f64 {
+    // a 7in quad with props
+    0.2
+}
+
+/// The static geometry of the world. The drone is a sphere of `drone_radius` around its center
+/// of mass, it is checked against every obstacle on every physics step.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Scene {
+    #[serde(default)]
+    pub obstacles: Vec<Obstacle>,
+    #[serde(default)]
+    pub response: CollisionResponse,
+    #[serde(default = "default_drone_radius")]
+    pub drone_radius: f64,
+}
+
+impl Default for Scene {
+    // the empty infinite space
+    fn default() -> Self {
+        Self {
+            obstacles: vec![],
+            response: CollisionResponse::default(),
+            drone_radius: default_drone_radius(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(PathBuf, std::io::Error),
+    Parse(serde_json::Error),
+    // the line of the OBJ file that could not be read
+    Mesh(PathBuf, usize),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io(path, err) => write!(f, "could not read {}: {err}", path.display()),
+            SceneError::Parse(err) => write!(f, "invalid scene: {err}"),
+            SceneError::Mesh(path, line) => {
+                write!(f, "invalid mesh {} in line {line}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl Scene {
+    /// Reads a scene from a JSON file, the meshes it refers to are loaded with it
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SceneError> {
+        let path = path.as_ref();
+        let contents =
+            fs::read_to_string(path).map_err(|err| SceneError::Io(path.to_owned(), err))?;
+        let mut scene: Scene = serde_json::from_str(&contents).map_err(SceneError::Parse)?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for obstacle in scene.obstacles.iter_mut() {
+            if let Shape::Mesh {
+                path, triangles, ..
+            } = &mut obstacle.shape
+            {
+                let mesh_path = directory.join(&*path);
+                let obj = fs::read_to_string(&mesh_path)
+                    .map_err(|err| SceneError::Io(mesh_path.clone(), err))?;
+                *triangles =
+                    parse_obj(&obj).map_err(|line| SceneError::Mesh(mesh_path.clone(), line))?;
+            }
+        }
+        Ok(scene)
+    }
+
+    pub fn with_obstacle(mut self, name: impl Into<String>, shape: Shape) -> Self {
+        self.obstacles.push(Obstacle {
+            name: name.into(),
+            shape,
+        });
+        self
+    }
+
+    pub fn with_response(mut self, response: CollisionResponse) -> Self {
+        self.response = response;
+        self
+    }
+
+    /// The obstacles a sphere touches, with their index
+    pub fn contacts(
+        &self,
+        center: Vector3<f64>,
+        radius: f64,
+    ) -> impl Iterator<Item = (usize, Contact)> + '_ {
+        self.obstacles
+            .iter()
+            .enumerate()
+            .filter_map(move |(i, obstacle)| Some((i, obstacle.shape.contact(center, radius)?)))
+    }
+}
+
+/// The triangles of a Wavefront OBJ file, polygons are split into fans. Only the vertices and the
+/// faces are read, the error is the line that could not be parsed.
+pub fn parse_obj(obj: &str) -> Result<Vec<[Vector3<f64>; 3]>, usize> {
+    let mut vertices = vec![];
+    let mut triangles = vec![];
+    for (i, line) in obj.lines().enumerate() {
+        let line_number = i + 1;
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => {
+                let coordinates = tokens
+                    .take(3)
+                    .map(|token| token.parse::<f64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| line_number)?;
+                if coordinates.len() != 3 {
+                    return Err(line_number);
+                }
+                vertices.push(Vector3::from_column_slice(&coordinates));
+            }
+            Some("f") => {
+                let face = tokens
+                    .map(|token| {
+                        // v, v/vt, v//vn or v/vt/vn, negative indices count from the end
+                        let index: i64 = token.split('/').next()?.parse().ok()?;
+                        let index = if index < 0 {
+                            vertices.len().checked_sub(index.unsigned_abs() as usize)?
+                        } else {
+                            (index as usize).checked_sub(1)?
+                        };
+                        vertices.get(index).copied()
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(line_number)?;
+                if face.len() < 3 {
+                    return Err(line_number);
+                }
+                for j in 1..face.len() - 1 {
+                    triangles.push([face[0], face[j], face[j + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(triangles)
+}
+
+/// Checks the drone against the scene after every physics step and applies the collision
+/// response
+#[derive(Debug, Clone, Default)]
+pub struct Collisions {
+    pub scene: Arc<Scene>,
+    // the obstacles the drone touched after the last step, a collision is only reported when the
+    // drone starts to touch an obstacle
+    touching: Vec<usize>,
+    // where the drone crashed
+    crash: Option<(Vector3<f64>, Rotation3<f64>)>,
+}
+
+impl Collisions {
+    /// The scene can be shared between simulators
+    pub fn new(scene: impl Into<Arc<Scene>>) -> Self {
+        Self {
+            scene: scene.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn crashed(&self) -> bool {
+        self.crash.is_some()
+    }
+
+    pub fn reset(&mut self) {
+        self.touching.clear();
+        self.crash = None;
+    }
+
+    /// Moves the drone out of the obstacles it touches, returns the collisions that started in
+    /// this step
+    pub(crate) fn step(&mut self, time: Duration, drone: &mut Drone) -> Vec<CollisionEvent> {
+        let state = &mut drone.current_frame.drone_frame_state;
+        if let Some((position, rotation)) = self.crash {
+            state.position = position;
+            state.rotation = rotation;
+            state.linear_velocity = Vector3::zeros();
+            state.angular_velocity = Vector3::zeros();
+            return vec![];
+        }
+
+        let contacts: Vec<_> = self
+            .scene
+            .contacts(state.position, self.scene.drone_radius)
+            .collect();
+        let mut events = vec![];
+        for (i, contact) in contacts.iter() {
+            state.position += contact.normal * contact.depth;
+            let normal_speed = state.linear_velocity.dot(&contact.normal);
+            if normal_speed < 0. {
+                match self.scene.response {
+                    CollisionResponse::Crash => {
+                        state.linear_velocity = Vector3::zeros();
+                        state.angular_velocity = Vector3::zeros();
+                        self.crash = Some((state.position, state.rotation));
+                    }
+                    CollisionResponse::Bounce {
+                        restitution,
+                        friction,
+                    } => {
+                        let normal = contact.normal * normal_speed;
+                        let tangential = state.linear_velocity - normal;
+                        state.linear_velocity = tangential * (1. - friction) - normal * restitution;
+                    }
+                }
+            }
+            if !self.touching.contains(i) {
+                events.push(CollisionEvent {
+                    duration: time,
+                    obstacle: self.scene.obstacles[*i].name.clone(),
+                    point: contact.point.into(),
+                    impact_speed: (-normal_speed).max(0.),
+                    crashed: self.crash.is_some(),
+                });
+            }
+        }
+        self.touching = contacts.into_iter().map(|(i, _)| i).collect();
+        events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        scene::{parse_obj, CollisionResponse, Collisions, Scene, Shape},
+        Simulator,
+    };
+    use drone::default_drone::default_7in_4s_drone;
+    use flight_controller::{controllers::null_controller::NullController, Channels};
+    use loggers::empty_logger::EmptyLogger;
+    use nalgebra::Vector3;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[test]
+    fn shape_contacts() {
+        let cube = Shape::Box {
+            center: Vector3::zeros(),
+            size: Vector3::new(2., 2., 2.),
+            yaw: std::f64::consts::FRAC_PI_4,
+        };
+        let contact = cube.contact(Vector3::new(0., 1.1, 0.), 0.2).unwrap();
+        assert!((contact.normal - Vector3::y()).norm() < 1e-9);
+        assert!((contact.depth - 0.1).abs() < 1e-9);
+        // the corner is turned away
+        assert!(cube.contact(Vector3::new(1.1, 0., 1.1), 0.2).is_none());
+
+        let pole = Shape::Cylinder {
+            base: Vector3::zeros(),
+            radius: 0.5,
+            height: 3.,
+        };
+        let contact = pole.contact(Vector3::new(0., 1., 0.6), 0.2).unwrap();
+        assert!((contact.normal - Vector3::z()).norm() < 1e-9);
+        assert!(pole.contact(Vector3::new(0., 3.3, 0.), 0.2).is_none());
+
+        let floor = Shape::Plane {
+            point: Vector3::zeros(),
+            normal: Vector3::y(),
+        };
+        assert!((floor.contact(Vector3::new(5., -1., 3.), 0.2).unwrap().depth - 1.2).abs() < 1e-9);
+
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 0 1\nv 0 0 1\nf 1/1 2/2 3/3 4/4\n";
+        let quad = Shape::Mesh {
+            path: "floor.obj".into(),
+            offset: Vector3::zeros(),
+            triangles: parse_obj(obj).unwrap(),
+        };
+        assert!(quad.contact(Vector3::new(0.5, 0.1, 0.5), 0.2).is_some());
+        assert!(quad.contact(Vector3::new(1.5, 0.1, 0.5), 0.2).is_none());
+        assert_eq!(parse_obj("v 0 0\n"), Err(1));
+        assert_eq!(parse_obj("v 0 0 0\nf 1 2 3\n"), Err(2));
+    }
+
+    #[test]
+    fn load_scene() {
+        let directory = std::env::temp_dir().join("scene_test");
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("gate.obj"),
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n",
+        )
+        .unwrap();
+        let scene = r#"{
+            "obstacles": [
+                {"name": "floor", "type": "plane", "point": [0, 0, 0], "normal": [0, 1, 0]},
+                {"type": "box", "center": [0, 1, 0], "size": [1, 1, 1]},
+                {"name": "gate", "type": "mesh", "path": "gate.obj", "offset": [0, 0, 5]}
+            ],
+            "response": {"type": "bounce", "restitution": 0.5, "friction": 0.1}
+        }"#;
+        std::fs::write(directory.join("scene.json"), scene).unwrap();
+        let scene = Scene::load(directory.join("scene.json")).unwrap();
+        assert_eq!(scene.obstacles.len(), 3);
+        assert_eq!(scene.drone_radius, 0.2);
+        assert!(matches!(
+            scene.response,
+            CollisionResponse::Bounce { restitution, .. } if restitution == 0.5
+        ));
+        let Shape::Mesh { triangles, .. } = &scene.obstacles[2].shape else {
+            panic!("not a mesh");
+        };
+        assert_eq!(triangles.len(), 1);
+        assert!(Scene::load(directory.join("missing.json")).is_err());
+    }
+
+    fn falling_drone(response: CollisionResponse) -> Simulator {
+        let floor = Shape::Plane {
+            point: Vector3::zeros(),
+            normal: Vector3::y(),
+        };
+        let mut drone = default_7in_4s_drone();
+        drone.current_frame.drone_frame_state.position = Vector3::new(0., 1., 0.);
+        Simulator {
+            collisions: Collisions::new(
+                Scene::default()
+                    .with_obstacle("floor", floor)
+                    .with_response(response),
+            ),
+            ..Simulator::new(
+                drone,
+                Arc::new(NullController::default()),
+                Arc::new(Mutex::new(EmptyLogger::default())),
+            )
+        }
+    }
+
+    #[test]
+    fn crash() {
+        let mut simulator = falling_drone(CollisionResponse::Crash);
+        simulator.init().unwrap();
+        let mut collisions = vec![];
+        for _ in 0..100 {
+            let observation =
+                simulator.simulate_delta(Duration::from_millis(10), Channels::default());
+            collisions.extend(observation.collisions);
+        }
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].obstacle, "floor");
+        assert!(collisions[0].crashed);
+        // fell 0.8m
+        assert!((collisions[0].impact_speed - (2. * 9.81 * 0.8f64).sqrt()).abs() < 0.1);
+        let observation = simulator.simulation_info();
+        assert!(observation.crashed);
+        assert!((observation.position.y - 0.2).abs() < 1e-6);
+        assert_eq!(observation.linear_velocity, Vector3::zeros());
+    }
+
+    #[test]
+    fn bounce() {
+        let mut simulator = falling_drone(CollisionResponse::Bounce {
+            restitution: 0.5,
+            friction: 0.,
+        });
+        simulator.init().unwrap();
+        let mut max_upwards: f64 = 0.;
+        for _ in 0..60 {
+            let observation =
+                simulator.simulate_delta(Duration::from_millis(10), Channels::default());
+            max_upwards = max_upwards.max(observation.linear_velocity.y);
+            assert!(observation.position.y >= 0.2 - 1e-6);
+            assert!(!observation.crashed);
+        }
+        // half the impact speed
+        assert!((max_upwards - 0.5 * (2. * 9.81 * 0.8f64).sqrt()).abs() < 0.1);
+    }
+}
@@ -0,0 +1,209 @@
+use drone::SimulationRates;
+use std::time::Duration;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// What happens in a flight controller loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerLoop {
+    // the simulated time since the last loop
+    pub elapsed: Duration,
+    // an RC frame arrived since the last loop
+    pub rc_frame: bool,
+    // the loop goes into the flight log
+    pub log: bool,
+}
+
+/// Runs the simulation in whole physics steps. The other tasks run every so many steps and the
+/// simulation time is computed from the step count, so the tasks stay in step with each other
+/// however long the simulation runs.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    pub rates: SimulationRates,
+    // the physics steps taken so far
+    steps: u64,
+    // the simulated time that was asked for so far
+    target: Duration,
+    // the nominal step of the next controller loop, the jitter does not move it
+    next_controller: Option<u64>,
+    last_controller: u64,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(SimulationRates::default())
+    }
+}
+
+impl Scheduler {
+    pub fn new(rates: SimulationRates) -> Self {
+        assert!(rates.physics > 0, "the physics rate has to be positive");
+        Self {
+            rates,
+            steps: 0,
+            target: Duration::ZERO,
+            next_controller: None,
+            last_controller: 0,
+        }
+    }
+
+    /// The simulation time after `steps` physics steps
+    pub fn time_at(&self, steps: u64) -> Duration {
+        let nanos = steps as u128 * NANOS_PER_SEC / self.rates.physics as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+
+    pub fn time(&self) -> Duration {
+        self.time_at(self.steps)
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// The length of a physics step in s
+    pub fn dt(&self) -> f64 {
+        1. / self.rates.physics as f64
+    }
+
+    /// The physics steps between two runs of a task at the rate, at least one
+    pub fn period(&self, rate: u64) -> u64 {
+        (self.rates.physics + rate / 2)
+            .checked_div(rate)
+            .unwrap_or(u64::MAX)
+            .max(1)
+    }
+
+    /// The physics steps closest to the duration, at least one
+    pub fn period_of(&self, duration: Duration) -> u64 {
+        let steps =
+            (duration.as_nanos() * self.rates.physics as u128 + NANOS_PER_SEC / 2) / NANOS_PER_SEC;
+        (steps as u64).max(1)
+    }
+
+    /// Adds simulated time to run
+    pub fn advance(&mut self, delta: Duration) {
+        self.target += delta;
+    }
+
+    /// The index of the next physics step, None once the simulation caught up with the time that
+    /// was asked for
+    pub fn next_step(&mut self) -> Option<u64> {
+        if self.time_at(self.steps + 1) > self.target {
+            return None;
+        }
+        self.steps += 1;
+        Some(self.steps - 1)
+    }
+
+    /// Whether a task at the rate runs in the step, None runs it every step
+    pub fn is_due(&self, step: u64, rate: Option<u64>) -> bool {
+        rate.is_none_or(|rate| step.is_multiple_of(self.period(rate)))
+    }
+
+    // a task at the rate ran in one of the steps in (from, to]
+    fn ran_between(&self, from: u64, to: u64, rate: Option<u64>) -> bool {
+        rate.is_none_or(|rate| {
+            let period = self.period(rate);
+            to / period > from / period
+        })
+    }
+
+    /// Whether the flight controller runs in the step. The controller runs at the controller rate,
+    /// or every `scheduler_delta` if that is not set. The jitter (in ns) delays or advances only
+    /// this loop, the loops after it are still on the nominal schedule.
+    pub fn controller_loop(
+        &mut self,
+        step: u64,
+        scheduler_delta: Duration,
+        jitter: i64,
+    ) -> Option<ControllerLoop> {
+        let period = match self.rates.controller {
+            Some(rate) => self.period(rate),
+            None => self.period_of(scheduler_delta),
+        };
+        // the loop runs at the end of the step
+        let now = step + 1;
+        let next = *self.next_controller.get_or_insert(period);
+        let jitter = jitter as i128 * self.rates.physics as i128 / NANOS_PER_SEC as i128;
+        if (now as i128) < next as i128 + jitter {
+            return None;
+        }
+
+        let last = self.last_controller;
+        let controller_loop = ControllerLoop {
+            elapsed: self.time_at(now) - self.time_at(last),
+            rc_frame: self.ran_between(last, now, self.rates.rc),
+            log: self.ran_between(last, now, self.rates.logging),
+        };
+        self.last_controller = now;
+        let mut next = next + period;
+        // only if the controller got slower, the loops that were missed are skipped
+        while next <= now {
+            next += period;
+        }
+        self.next_controller = Some(next);
+        Some(controller_loop)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::scheduler::Scheduler;
+    use drone::SimulationRates;
+    use std::time::Duration;
+
+    #[test]
+    fn drift_free() {
+        // 3kHz does not divide a second into whole ns
+        let mut scheduler = Scheduler::new(SimulationRates {
+            physics: 3000,
+            rc: Some(150),
+            logging: Some(1000),
+            ..Default::default()
+        });
+        let mut controller_loops = vec![];
+        let mut rc_frames = 0;
+        let mut logs = 0;
+        for _ in 0..1000 {
+            scheduler.advance(Duration::from_millis(10));
+            while let Some(step) = scheduler.next_step() {
+                if let Some(controller_loop) =
+                    scheduler.controller_loop(step, Duration::from_micros(2000), 0)
+                {
+                    controller_loops.push(controller_loop.elapsed);
+                    rc_frames += controller_loop.rc_frame as usize;
+                    logs += controller_loop.log as usize;
+                }
+            }
+        }
+        assert_eq!(scheduler.steps(), 30_000);
+        assert_eq!(scheduler.time(), Duration::from_secs(10));
+        // 2ms are 6 steps, so the controller runs at 500Hz
+        assert_eq!(controller_loops.len(), 5000);
+        assert_eq!(rc_frames, 1500);
+        // the log rate is higher than the controller rate, every loop is logged
+        assert_eq!(logs, 5000);
+        assert!(controller_loops
+            .iter()
+            .all(|elapsed| elapsed.abs_diff(Duration::from_millis(2)) < Duration::from_nanos(2)));
+    }
+
+    #[test]
+    fn jitter_does_not_accumulate() {
+        let mut scheduler = Scheduler::new(SimulationRates::default());
+        scheduler.advance(Duration::from_millis(100));
+        let mut loops = 0;
+        while let Some(step) = scheduler.next_step() {
+            // always late by 20us
+            if scheduler
+                .controller_loop(step, Duration::from_micros(250), 20_000)
+                .is_some()
+            {
+                loops += 1;
+            }
+        }
+        // the first loop is at 270us, the last one at 99.77ms
+        assert_eq!(loops, 399);
+    }
+}
@@ -0,0 +1,722 @@
+use crate::{
+    course::{Course, CourseTracker, Gate, RaceProgress},
+    env::{EnvConfig, RewardFn, TerminationFn, Transition},
+    SimulationObservation, Simulator, GRAVITY,
+};
+use drone::{sub_seed, SeedStream, SimulationFrame};
+use flight_controller::{
+    controllers::pid_controller::body_rates,
+    rates::{RateAxis, RateProfile},
+    AuxRole, ChannelMap, Channels, InitError,
+};
+use nalgebra::{Rotation3, Vector3};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{f64::consts::PI, fmt, sync::Arc, time::Duration};
+
+/// What the pilot is asked to do at a point of the episode. With an attitude the pilot holds the
+/// altitude and tracks the attitude, otherwise it flies to the position with the velocity as feed
+/// forward. Without a position it only follows the velocity.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Reference {
+    pub position: Option<Vector3<f64>>,
+    pub velocity: Vector3<f64>,
+    pub attitude: Option<Rotation3<f64>>,
+}
+
+/// Everything the reward and termination functions of a task get to see after a step
+pub struct TaskStep<'a> {
+    pub observation: &'a SimulationObservation,
+    pub reference: &'a Reference,
+    pub initial_position: Vector3<f64>,
+    // since the start of the episode
+    pub time: Duration,
+}
+
+/// Draws the initial frame of an episode, the argument is the frame of the drone config
+pub type InitialStateFn =
+    Arc<dyn Fn(&mut StdRng, &SimulationFrame) -> SimulationFrame + Send + Sync>;
+/// The reference given the time since the start of the episode and the last observation
+pub type ReferenceFn = Arc<dyn Fn(Duration, &SimulationObservation) -> Reference + Send + Sync>;
+pub type TaskRewardFn = Arc<dyn Fn(&TaskStep) -> f64 + Send + Sync>;
+pub type TaskTerminationFn = Arc<dyn Fn(&TaskStep) -> bool + Send + Sync>;
+pub type ScoreFn = Arc<dyn Fn(&TaskResult) -> f64 + Send + Sync>;
+
+/// Turns the reference into stick commands, the same way for every controller. It is a cascaded
+/// position, velocity and attitude controller on the true state of the drone. The body rates it
+/// wants are turned into sticks with the rate profile, which should be the one of the flight
+/// controller. The throttle needed to hover is learned while flying.
+#[derive(Debug, Clone)]
+pub struct Pilot {
+    pub rate_profile: RateProfile,
+    pub channel_map: ChannelMap,
+    // m/s per m of position error
+    pub position_gain: f64,
+    pub max_speed: f64,
+    // m/s² per m/s of velocity error
+    pub velocity_gain: f64,
+    // rad
+    pub max_tilt: f64,
+    // rad/s per rad of attitude error
+    pub attitude_gain: f64,
+    // the collective throttle (0 to 1) that keeps the drone in the air
+    pub hover_throttle: f64,
+    // throttle per m of vertical velocity error
+    pub hover_learning_rate: f64,
+    last_time: Option<Duration>,
+}
+
+impl Default for Pilot {
+    // flies the default 7in drone gently, it hovers at about 10% throttle
+    fn default() -> Self {
+        Self {
+            rate_profile: RateProfile::default(),
+            channel_map: ChannelMap::default(),
+            position_gain: 1.,
+            max_speed: 5.,
+            velocity_gain: 2.,
+            max_tilt: 0.6,
+            attitude_gain: 8.,
+            hover_throttle: 0.1,
+            hover_learning_rate: 0.1,
+            last_time: None,
+        }
+    }
+}
+
+// the input between -1 and 1 that gives the output, `f` has to be increasing
+fn invert(f: impl Fn(f64) -> f64, output: f64) -> f64 {
+    let (mut low, mut high) = (-1., 1.);
+    for _ in 0..40 {
+        let mid = (low + high) / 2.;
+        if f(mid) < output {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    (low + high) / 2.
+}
+
+impl Pilot {
+    pub fn sticks(
+        &mut self,
+        observation: &SimulationObservation,
+        reference: &Reference,
+    ) -> Channels {
+        let dt = self.last_time.map_or(0., |last| {
+            (observation.simulation_time - last).as_secs_f64()
+        });
+        self.last_time = Some(observation.simulation_time);
+
+        let mut velocity = reference.velocity;
+        if let Some(position) = reference.position {
+            velocity += (position - observation.position) * self.position_gain;
+            velocity = velocity.cap_magnitude(self.max_speed);
+        }
+        if reference.attitude.is_some() {
+            // only the altitude is held
+            velocity.x = 0.;
+            velocity.z = 0.;
+        }
+        let acceleration = (velocity - observation.linear_velocity) * self.velocity_gain;
+        let horizontal = Vector3::new(acceleration.x, 0., acceleration.z)
+            .cap_magnitude(GRAVITY * self.max_tilt.tan());
+        let vertical = (GRAVITY + acceleration.y).max(0.2 * GRAVITY);
+        let thrust = horizontal + Vector3::y() * vertical;
+
+        let vertical_error = velocity.y - observation.linear_velocity.y;
+        self.hover_throttle =
+            (self.hover_throttle + self.hover_learning_rate * vertical_error * dt).clamp(0.05, 0.9);
+
+        let up = observation.rotation * Vector3::y();
+        let (error, collective) = match reference.attitude {
+            Some(attitude) => {
+                let error = (observation.rotation.transpose() * attitude).scaled_axis();
+                let collective = self.hover_throttle * vertical / (GRAVITY * up.y.max(0.5));
+                (error, collective)
+            }
+            None => {
+                // the shortest rotation that points the thrust where it is needed
+                let target = thrust.normalize();
+                let angle = up.dot(&target).clamp(-1., 1.).acos();
+                let axis = up
+                    .cross(&target)
+                    .try_normalize(1e-9)
+                    .unwrap_or_else(|| observation.rotation * Vector3::x());
+                let error = observation.rotation.transpose() * axis * angle;
+                let collective = self.hover_throttle * thrust.dot(&up).max(0.) / GRAVITY;
+                (error, collective)
+            }
+        };
+        let rates = body_rates((error * self.attitude_gain).into()).map(f64::to_degrees);
+        let profile = &self.rate_profile;
+        let stick = |axis, rate| invert(|stick| profile.setpoint_rate(axis, stick), rate);
+        Channels {
+            throttle: invert(|stick| profile.throttle(stick), collective.clamp(0., 1.)),
+            roll: stick(RateAxis::Roll, rates[0]),
+            pitch: stick(RateAxis::Pitch, rates[1]),
+            yaw: stick(RateAxis::Yaw, rates[2]),
+            ..Default::default()
+        }
+        .with_switch(&self.channel_map, AuxRole::Arm, true)
+    }
+}
+
+/// How an episode of a task went
+#[derive(Debug, Clone, Default)]
+pub struct TaskResult {
+    pub task: String,
+    pub seed: u64,
+    pub steps: usize,
+    // the simulated time of the episode, shorter than the task if it was terminated
+    pub time: Duration,
+    pub total_reward: f64,
+    pub terminated: bool,
+    pub crashed: bool,
+    pub race: Option<RaceProgress>,
+    pub score: f64,
+}
+
+impl fmt::Display for TaskResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<20} seed {:<4} score {:.3} ({:.2}s",
+            self.task,
+            self.seed,
+            self.score,
+            self.time.as_secs_f64()
+        )?;
+        if self.crashed {
+            write!(f, ", crashed")?;
+        } else if self.race.is_some_and(|race| race.finished) {
+            write!(f, ", finished")?;
+        } else if self.terminated {
+            write!(f, ", terminated")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// A standard task a controller is benchmarked on. The pilot flies the reference with the
+/// controller under test, the score summarizes the episode, higher is better.
+#[derive(Clone)]
+pub struct Task {
+    pub name: String,
+    pub duration: Duration,
+    // the time between two stick commands
+    pub step_time: Duration,
+    pub initial_state: InitialStateFn,
+    pub reference: ReferenceFn,
+    pub pilot: Pilot,
+    pub reward: TaskRewardFn,
+    pub termination: TaskTerminationFn,
+    pub score: ScoreFn,
+    pub course: Option<Arc<Course>>,
+}
+
+/// The mean reward per step over the whole task, the steps after a termination count as zero
+pub fn mean_reward(step_time: Duration, duration: Duration) -> ScoreFn {
+    let steps = (duration.as_secs_f64() / step_time.as_secs_f64())
+        .round()
+        .max(1.);
+    Arc::new(move |result| result.total_reward / steps)
+}
+
+/// 1 at the reference position, falls off with the distance in m
+pub fn position_reward() -> TaskRewardFn {
+    Arc::new(|step| {
+        step.reference.position.map_or(0., |position| {
+            (-(step.observation.position - position).norm()).exp()
+        })
+    })
+}
+
+/// 1 at the reference attitude, falls off with the angle in rad
+pub fn attitude_reward() -> TaskRewardFn {
+    Arc::new(|step| {
+        step.reference.attitude.map_or(0., |attitude| {
+            let error = step.observation.rotation.transpose() * attitude;
+            (-error.angle()).exp()
+        })
+    })
+}
+
+/// Terminates when the drone is further than `max_distance` (m) away from the initial position
+/// or the simulation blew up
+pub fn too_far(max_distance: f64) -> TaskTerminationFn {
+    Arc::new(move |step| {
+        let distance = (step.observation.position - step.initial_position).norm();
+        !distance.is_finite() || distance > max_distance
+    })
+}
+
+fn random_direction(rng: &mut StdRng) -> Vector3<f64> {
+    Vector3::from_fn(|_, _| rng.gen_range(-1.0..=1.0))
+        .try_normalize(1e-9)
+        .unwrap_or_else(Vector3::y)
+}
+
+// the frame of the drone config, moving and turned by up to the given speed (m/s) and angle (rad)
+fn disturbed(speed: f64, angle: f64) -> InitialStateFn {
+    Arc::new(move |rng, frame| {
+        let mut frame = frame.clone();
+        let state = &mut frame.drone_frame_state;
+        state.linear_velocity = random_direction(rng) * rng.gen_range(0.0..=speed);
+        let rotation = Rotation3::new(random_direction(rng) * rng.gen_range(0.0..=angle));
+        state.rotation = rotation * state.rotation;
+        frame
+    })
+}
+
+impl Task {
+    /// Holds the position it starts at, starting slightly tilted and drifting
+    pub fn hover() -> Self {
+        let step_time = Duration::from_millis(10);
+        let duration = Duration::from_secs(5);
+        Self {
+            name: "hover".into(),
+            duration,
+            step_time,
+            initial_state: disturbed(0.5, 0.2),
+            reference: Arc::new(|_, _| Reference {
+                position: Some(Vector3::zeros()),
+                ..Default::default()
+            }),
+            pilot: Pilot::default(),
+            reward: position_reward(),
+            termination: too_far(10.),
+            score: mean_reward(step_time, duration),
+            course: None,
+        }
+    }
+
+    /// Hovers for a second, then flies to a setpoint 2m to the front, 1m up and 1m to the right
+    pub fn step_to_setpoint() -> Self {
+        let step_time = Duration::from_millis(10);
+        let duration = Duration::from_secs(6);
+        Self {
+            name: "step".into(),
+            reference: Arc::new(|time, _| Reference {
+                position: Some(if time < Duration::from_secs(1) {
+                    Vector3::zeros()
+                } else {
+                    Vector3::new(1., 1., -2.)
+                }),
+                ..Default::default()
+            }),
+            duration,
+            score: mean_reward(step_time, duration),
+            ..Self::hover()
+        }
+    }
+
+    /// Holds the altitude and follows a sequence of roll and pitch angles up to 30deg
+    pub fn attitude_tracking() -> Self {
+        let step_time = Duration::from_millis(10);
+        let duration = Duration::from_secs(6);
+        let angle = 30f64.to_radians();
+        Self {
+            name: "attitude".into(),
+            reference: Arc::new(move |time, _| {
+                let (roll, pitch) = match time.as_secs() {
+                    0 => (0., 0.),
+                    1 => (angle, 0.),
+                    2 => (0., angle),
+                    3 => (-angle, 0.),
+                    4 => (0., -angle),
+                    _ => (0., 0.),
+                };
+                // roll around the forward axis (-z), pitch around the right axis (x)
+                let attitude = Rotation3::from_axis_angle(&-Vector3::z_axis(), roll)
+                    * Rotation3::from_axis_angle(&Vector3::x_axis(), pitch);
+                Reference {
+                    attitude: Some(attitude),
+                    ..Default::default()
+                }
+            }),
+            reward: attitude_reward(),
+            termination: too_far(30.),
+            duration,
+            score: mean_reward(step_time, duration),
+            ..Self::hover()
+        }
+    }
+
+    /// Flies a horizontal circle with a radius of 2m in 8s
+    pub fn trajectory_tracking() -> Self {
+        let step_time = Duration::from_millis(10);
+        let duration = Duration::from_secs(10);
+        let radius = 2.;
+        let omega = 2. * PI / 8.;
+        Self {
+            name: "trajectory".into(),
+            reference: Arc::new(move |time, _| {
+                // starts at the center with the speed ramping up over the first second
+                let t = time.as_secs_f64();
+                let ramp = t.min(1.);
+                let phase = omega * t;
+                Reference {
+                    position: Some(Vector3::new(phase.sin(), 0., 1. - phase.cos()) * radius * ramp),
+                    velocity: Vector3::new(phase.cos(), 0., phase.sin()) * radius * omega * ramp,
+                    ..Default::default()
+                }
+            }),
+            duration,
+            score: mean_reward(step_time, duration),
+            ..Self::hover()
+        }
+    }
+
+    /// The drone is thrown with up to 6m/s, turned by up to 120deg and spinning with up to 5rad/s.
+    /// It has to level out and stop, the reward is for being upright and slow.
+    pub fn thrown_start() -> Self {
+        let step_time = Duration::from_millis(10);
+        let duration = Duration::from_secs(4);
+        Self {
+            name: "thrown".into(),
+            initial_state: Arc::new(|rng, frame| {
+                let mut frame = disturbed(0., 120f64.to_radians())(rng, frame);
+                let state = &mut frame.drone_frame_state;
+                state.linear_velocity = random_direction(rng) * rng.gen_range(2.0..=6.);
+                state.angular_velocity = Vector3::from_fn(|_, _| rng.gen_range(-5.0..=5.));
+                frame
+            }),
+            reference: Arc::new(|_, _| Reference::default()),
+            reward: Arc::new(|step| {
+                let observation = step.observation;
+                let upright = (observation.rotation * Vector3::y()).y.max(0.);
+                upright * (-observation.linear_velocity.norm()).exp()
+            }),
+            // it would have hit the ground
+            termination: Arc::new(|step| {
+                let drop = step.initial_position.y - step.observation.position.y;
+                !drop.is_finite() || drop > 10.
+            }),
+            duration,
+            score: mean_reward(step_time, duration),
+            ..Self::hover()
+        }
+    }
+
+    /// Two laps through four gates around a 10m square, the drone starts 3m in front of the start
+    /// gate. The reward is a point per gate, the score is the best lap in laps per second.
+    pub fn gate_racing() -> Self {
+        let gate = |x: f64, z: f64, yaw: f64| Gate {
+            name: String::new(),
+            center: Vector3::new(x, 0., z),
+            yaw,
+            pitch: 0.,
+            width: 2.,
+            height: 2.,
+        };
+        let course = Arc::new(Course {
+            name: "square".into(),
+            // the middles of the sides, flown to the left around the square
+            gates: vec![
+                gate(3., 0., -PI / 2.),
+                gate(8., -5., 0.),
+                gate(3., -10., PI / 2.),
+                gate(-2., -5., PI),
+            ],
+            laps: Some(2),
+        });
+        let gates = course.gates.clone();
+        Self {
+            name: "racing".into(),
+            duration: Duration::from_secs(30),
+            reference: Arc::new(move |_, observation| {
+                // chases a point on the line through the next gate, 2m ahead of the drone and at
+                // most 1.5m behind the gate, so the drone lines up before it flies through
+                let next_gate = observation.race.map_or(0, |race| race.next_gate);
+                let gate = &gates[next_gate];
+                let along = (observation.position - gate.center).dot(&gate.forward());
+                Reference {
+                    position: Some(gate.center + gate.forward() * (along + 2.).min(1.5)),
+                    ..Default::default()
+                }
+            }),
+            reward: Arc::new(|step| step.observation.gate_passes.len() as f64),
+            termination: Arc::new(|step| {
+                let finished = step.observation.race.is_some_and(|race| race.finished);
+                finished || too_far(30.)(step)
+            }),
+            score: Arc::new(|result| {
+                result
+                    .race
+                    .and_then(|race| race.best_lap)
+                    .map_or(0., |best_lap| 1. / best_lap.as_secs_f64())
+            }),
+            // the course is laid out around the origin
+            initial_state: Arc::new(|_, frame| {
+                let mut frame = frame.clone();
+                frame.drone_frame_state.position = Vector3::zeros();
+                frame
+            }),
+            course: Some(course),
+            ..Self::hover()
+        }
+    }
+
+    /// One of the standard tasks
+    pub fn from_name(name: &str) -> Option<Self> {
+        standard_tasks().into_iter().find(|task| task.name == name)
+    }
+
+    /// The initial frame of the episode with the seed
+    pub fn initial_frame(&self, frame: &SimulationFrame, seed: u64) -> SimulationFrame {
+        let seed = sub_seed(seed, SeedStream::InitialState);
+        (self.initial_state)(&mut StdRng::seed_from_u64(seed), frame)
+    }
+
+    /// Flies an episode starting from the drone config frame. The seed draws the initial state
+    /// and reseeds the noise of the simulator, so the same seed gives the same episode. The
+    /// course of the simulator is only replaced while the episode runs.
+    pub fn run(
+        &self,
+        simulator: &mut Simulator,
+        frame: &SimulationFrame,
+        seed: u64,
+    ) -> Result<TaskResult, InitError> {
+        let course = match &self.course {
+            Some(course) => CourseTracker::new(course.clone()),
+            None => CourseTracker::default(),
+        };
+        let course = std::mem::replace(&mut simulator.course, course);
+        let result = self.fly(simulator, frame, seed);
+        simulator.course = course;
+        result
+    }
+
+    fn fly(
+        &self,
+        simulator: &mut Simulator,
+        frame: &SimulationFrame,
+        seed: u64,
+    ) -> Result<TaskResult, InitError> {
+        simulator.seed(seed);
+        let initial_frame = self.initial_frame(frame, seed);
+        let initial_position = initial_frame.drone_frame_state.position;
+        simulator.reset(initial_frame);
+        simulator.init()?;
+        // armed with the attitude the gyro reports, it has not seen the throw of a thrown start
+        // yet
+        let rotation = simulator
+            .drone
+            .current_frame
+            .gyro_state
+            .gyro_update()
+            .rotation;
+        let map = &simulator.arming.config.channel_map;
+        let mut pilot = Pilot {
+            channel_map: map.clone(),
+            ..self.pilot.clone()
+        };
+        let channels = Channels {
+            throttle: -1.,
+            ..Default::default()
+        }
+        .with_switch(map, AuxRole::Arm, true);
+        simulator
+            .arming
+            .update(Duration::ZERO, Some(channels), rotation);
+
+        let mut result = TaskResult {
+            task: self.name.clone(),
+            seed,
+            ..Default::default()
+        };
+        let mut observation = simulator.simulation_info();
+        while result.time < self.duration {
+            let reference = (self.reference)(result.time, &observation);
+            // the reference is relative to the initial position
+            let reference = Reference {
+                position: reference
+                    .position
+                    .map(|position| position + initial_position),
+                ..reference
+            };
+            let channels = pilot.sticks(&observation, &reference);
+            observation = simulator.simulate_delta(self.step_time, channels);
+            result.time += self.step_time;
+            result.steps += 1;
+            let step = TaskStep {
+                observation: &observation,
+                reference: &reference,
+                initial_position,
+                time: result.time,
+            };
+            result.total_reward += (self.reward)(&step);
+            if observation.crashed || (self.termination)(&step) {
+                result.terminated = true;
+                result.crashed = observation.crashed;
+                break;
+            }
+        }
+        simulator.end_episode();
+        result.race = observation.race;
+        result.score = (self.score)(&result);
+        Ok(result)
+    }
+
+    /// The reward and the termination of the task for the gym style environment, the agent takes
+    /// the place of the pilot. The episodes should be reset with `initial_frame`, and for gate
+    /// racing the simulator needs a `CourseTracker` of the course.
+    pub fn env_config(&self, config: EnvConfig) -> EnvConfig {
+        let (reference, reward, termination) = (
+            self.reference.clone(),
+            self.reward.clone(),
+            self.termination.clone(),
+        );
+        let step_time = config.step_time;
+        let task_step = move |transition: &Transition| {
+            let time = step_time * transition.episode_steps as u32;
+            let reference = reference(time, transition.observation);
+            let reference = Reference {
+                position: reference
+                    .position
+                    .map(|position| position + transition.initial_position),
+                ..reference
+            };
+            (time, reference)
+        };
+        let task_step = Arc::new(task_step);
+        let reward_step = task_step.clone();
+        let reward: RewardFn = Arc::new(move |transition| {
+            let (time, reference) = reward_step(transition);
+            reward(&TaskStep {
+                observation: transition.observation,
+                reference: &reference,
+                initial_position: transition.initial_position,
+                time,
+            })
+        });
+        let termination: TerminationFn = Arc::new(move |transition| {
+            let (time, reference) = task_step(transition);
+            termination(&TaskStep {
+                observation: transition.observation,
+                reference: &reference,
+                initial_position: transition.initial_position,
+                time,
+            })
+        });
+        EnvConfig {
+            max_episode_time: self.duration,
+            reward,
+            termination,
+            ..config
+        }
+    }
+}
+
+/// Hover, step to setpoint, attitude tracking, trajectory tracking, thrown start and gate racing
+pub fn standard_tasks() -> Vec<Task> {
+    vec![
+        Task::hover(),
+        Task::step_to_setpoint(),
+        Task::attitude_tracking(),
+        Task::trajectory_tracking(),
+        Task::thrown_start(),
+        Task::gate_racing(),
+    ]
+}
+
+/// Runs every task once per seed. The simulator is reset for every episode, it starts from the
+/// frame the drone is in now.
+pub fn benchmark(
+    simulator: &mut Simulator,
+    tasks: &[Task],
+    seeds: &[u64],
+) -> Result<Vec<TaskResult>, InitError> {
+    let frame = simulator.drone.current_frame.clone();
+    tasks
+        .iter()
+        .flat_map(|task| seeds.iter().map(move |seed| (task, *seed)))
+        .map(|(task, seed)| task.run(simulator, &frame, seed))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        course::{Course, CourseTracker},
+        tasks::{benchmark, standard_tasks, Task},
+        Simulator,
+    };
+    use drone::default_drone::default_7in_4s_drone;
+    use flight_controller::{
+        controllers::{null_controller::NullController, pid_controller::PidController},
+        FlightController,
+    };
+    use loggers::empty_logger::EmptyLogger;
+    use std::sync::{Arc, Mutex};
+
+    fn simulator(flight_controller: Arc<dyn FlightController>) -> Simulator {
+        Simulator::new(
+            default_7in_4s_drone(),
+            flight_controller,
+            Arc::new(Mutex::new(EmptyLogger::default())),
+        )
+    }
+
+    #[test]
+    fn benchmark_controllers() {
+        let tasks = [
+            Task::hover(),
+            Task::attitude_tracking(),
+            Task::gate_racing(),
+        ];
+        let results = benchmark(
+            &mut simulator(Arc::new(PidController::default())),
+            &tasks,
+            &[1],
+        )
+        .unwrap();
+        assert!(results[0].score > 0.8);
+        assert!(results[1].score > 0.8);
+        let race = results[2].race.unwrap();
+        assert!(race.finished);
+        assert_eq!(race.completed_laps, 2);
+        assert!(results[2].score > 0.);
+
+        // nothing flies the drone, it falls out of the box
+        let results = benchmark(
+            &mut simulator(Arc::new(NullController::default())),
+            &tasks,
+            &[1],
+        )
+        .unwrap();
+        assert!(results.iter().all(|result| result.terminated));
+        assert!(results[0].score < 0.2);
+        assert_eq!(results[2].score, 0.);
+    }
+
+    #[test]
+    fn seeded_episodes() {
+        let mut simulator = simulator(Arc::new(PidController::default()));
+        let frame = simulator.drone.current_frame.clone();
+        let task = Task::from_name("thrown").unwrap();
+        let first = task.run(&mut simulator, &frame, 3).unwrap();
+        let again = task.run(&mut simulator, &frame, 3).unwrap();
+        let other = task.run(&mut simulator, &frame, 4).unwrap();
+        assert_eq!(first.steps, 400);
+        assert_eq!(first.total_reward, again.total_reward);
+        assert_ne!(first.total_reward, other.total_reward);
+        assert_eq!(standard_tasks().len(), 6);
+    }
+
+    #[test]
+    fn course_and_arming() {
+        let mut simulator = simulator(Arc::new(PidController::default()));
+        let course = Arc::new(Course::default());
+        simulator.course = CourseTracker::new(course.clone());
+        let frame = simulator.drone.current_frame.clone();
+        let race = Task::gate_racing().run(&mut simulator, &frame, 1).unwrap();
+        assert!(race.race.is_some());
+        assert!(Arc::ptr_eq(&simulator.course.course, &course));
+        // armed with the attitude of the gyro, the drone of a thrown start is not level
+        let thrown = Task::thrown_start().run(&mut simulator, &frame, 1).unwrap();
+        assert!(simulator.arming.state().motors_enabled());
+        assert!(!thrown.terminated);
+    }
+}
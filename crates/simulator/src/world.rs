@@ -0,0 +1,305 @@
+use crate::{SimulationObservation, Simulator};
+use flight_controller::{Channels, InitError};
+use nalgebra::Vector3;
+use std::time::Duration;
+
+/// Gives the sticks of a drone from its last observation, None if no RC frame arrives
+pub type InputSource = Box<dyn FnMut(&SimulationObservation) -> Option<Channels> + Send>;
+
+/// Sticks that never move
+pub fn constant_input(channels: Channels) -> InputSource {
+    Box::new(move |_| Some(channels))
+}
+
+/// Plays the frames back one after the other, every frame is held for `frame_time`. The last
+/// frame is held once the tape runs out.
+pub fn tape_input(frames: Vec<Channels>, frame_time: Duration) -> InputSource {
+    Box::new(move |observation| {
+        let index = observation.simulation_time.as_nanos() / frame_time.as_nanos().max(1);
+        let index = usize::try_from(index).unwrap_or(usize::MAX);
+        frames.get(index).or(frames.last()).copied()
+    })
+}
+
+/// The air pushed down by the props. A drone within the column below another drone is pushed
+/// along the thrust axis of the upper drone, with `strength` times the thrust of the upper drone
+/// right below it, fading out linearly over the length of the column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PropWash {
+    pub radius: f64,
+    pub length: f64,
+    pub strength: f64,
+}
+
+impl Default for PropWash {
+    // a 7in quad
+    fn default() -> Self {
+        Self {
+            radius: 0.2,
+            length: 2.,
+            strength: 0.3,
+        }
+    }
+}
+
+/// Two drones got closer than twice the collision radius
+#[derive(Debug, Clone, PartialEq)]
+pub struct Collision {
+    pub time: Duration,
+    pub drones: (String, String),
+    pub distance: f64,
+}
+
+pub struct WorldDrone {
+    pub id: String,
+    pub simulator: Simulator,
+    pub input: InputSource,
+    pub observation: SimulationObservation,
+    // the part of the external force that is the prop wash of the other drones
+    pub prop_wash_force: Vector3<f64>,
+}
+
+/// Several drones on a shared clock, each with its own simulator, so its own controller, logger
+/// and rates. The simulators of a `SimContext` share its controller and logger, so the context
+/// has to be set up again before each drone is loaded.
+///
+/// The drones only see each other between two world steps, the collisions and the prop wash are
+/// updated after every step. The drones are stepped one after the other on the calling thread,
+/// so the noise stays the same from run to run.
+#[derive(Default)]
+pub struct World {
+    pub drones: Vec<WorldDrone>,
+    pub time: Duration,
+    // the drones are spheres of this radius, None turns collision detection off
+    pub collision_radius: Option<f64>,
+    // None turns the prop wash off
+    pub prop_wash: Option<PropWash>,
+    collisions: Vec<Collision>,
+    // the pairs of drones that are touching, a collision is only recorded when they start to
+    touching: Vec<(usize, usize)>,
+}
+
+impl World {
+    pub fn with_collisions(mut self, radius: f64) -> Self {
+        self.collision_radius = Some(radius);
+        self
+    }
+
+    pub fn with_prop_wash(mut self, prop_wash: PropWash) -> Self {
+        self.prop_wash = Some(prop_wash);
+        self
+    }
+
+    /// Adds a drone, the drone starts from the current frame of its simulator
+    pub fn add_drone(&mut self, id: impl Into<String>, simulator: Simulator, input: InputSource) {
+        let observation = simulator.simulation_info();
+        self.drones.push(WorldDrone {
+            id: id.into(),
+            simulator,
+            input,
+            observation,
+            prop_wash_force: Vector3::zeros(),
+        });
+    }
+
+    pub fn drone(&self, id: &str) -> Option<&WorldDrone> {
+        self.drones.iter().find(|drone| drone.id == id)
+    }
+
+    pub fn collisions(&self) -> &[Collision] {
+        &self.collisions
+    }
+
+    pub fn init(&mut self) -> Result<(), InitError> {
+        for drone in self.drones.iter_mut() {
+            drone.simulator.init()?;
+        }
+        Ok(())
+    }
+
+    /// Runs every drone for the delta and then lets them interact
+    pub fn step(&mut self, delta: Duration) {
+        for drone in self.drones.iter_mut() {
+            let channels = (drone.input)(&drone.observation);
+            drone.observation = drone.simulator.simulate_delta_rc(delta, channels);
+        }
+        self.time += delta;
+        if let Some(radius) = self.collision_radius {
+            self.detect_collisions(radius);
+        }
+        if let Some(prop_wash) = self.prop_wash {
+            self.apply_prop_wash(prop_wash);
+        }
+    }
+
+    fn detect_collisions(&mut self, radius: f64) {
+        let mut touching = vec![];
+        for i in 0..self.drones.len() {
+            for j in i + 1..self.drones.len() {
+                let distance = (self.drones[i].simulator.drone.position()
+                    - self.drones[j].simulator.drone.position())
+                .norm();
+                if distance >= 2. * radius {
+                    continue;
+                }
+                if !self.touching.contains(&(i, j)) {
+                    self.collisions.push(Collision {
+                        time: self.time,
+                        drones: (self.drones[i].id.clone(), self.drones[j].id.clone()),
+                        distance,
+                    });
+                }
+                touching.push((i, j));
+            }
+        }
+        self.touching = touching;
+    }
+
+    fn apply_prop_wash(&mut self, prop_wash: PropWash) {
+        let forces: Vec<Vector3<f64>> = self
+            .drones
+            .iter()
+            .map(|lower| {
+                let position = lower.simulator.drone.position();
+                self.drones
+                    .iter()
+                    .filter(|upper| upper.id != lower.id)
+                    .map(|upper| prop_wash.force(&upper.simulator, position))
+                    .sum()
+            })
+            .collect();
+        for (drone, force) in self.drones.iter_mut().zip(forces) {
+            let frame_state = &mut drone.simulator.drone.current_frame.drone_frame_state;
+            // only the prop wash of the last step is replaced, e.g. the wind stays
+            frame_state.external_force += force - drone.prop_wash_force;
+            drone.prop_wash_force = force;
+        }
+    }
+}
+
+impl PropWash {
+    /// The force the prop wash of the simulated drone puts on something at the position
+    pub fn force(&self, simulator: &Simulator, position: Vector3<f64>) -> Vector3<f64> {
+        let frame = &simulator.drone.current_frame;
+        let thrust: f64 = frame
+            .rotors_state
+            .iter()
+            .map(|rotor| rotor.effective_thrust)
+            .sum();
+        // the wash goes down the thrust axis, y is up
+        let down = -(frame.drone_frame_state.rotation * Vector3::y());
+        let offset = position - frame.drone_frame_state.position;
+        let along = offset.dot(&down);
+        let radial = (offset - along * down).norm();
+        if along <= 0. || along >= self.length || radial >= self.radius || thrust <= 0. {
+            return Vector3::zeros();
+        }
+        down * self.strength * thrust * (1. - along / self.length)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        world::{constant_input, PropWash, World},
+        Simulator,
+    };
+    use drone::default_drone::default_7in_4s_drone;
+    use flight_controller::{
+        controllers::{null_controller::NullController, pid_controller::PidController},
+        AuxRole, ChannelMap, Channels, FlightController,
+    };
+    use loggers::empty_logger::EmptyLogger;
+    use nalgebra::Vector3;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    fn simulator(
+        flight_controller: Arc<dyn FlightController>,
+        position: Vector3<f64>,
+    ) -> Simulator {
+        let mut drone = default_7in_4s_drone();
+        drone.current_frame.drone_frame_state.position = position;
+        Simulator::new(
+            drone,
+            flight_controller,
+            Arc::new(Mutex::new(EmptyLogger::default())),
+        )
+    }
+
+    #[test]
+    fn collisions() {
+        let mut world = World::default().with_collisions(0.15);
+        for (id, x) in [("a", 0.), ("b", 0.2), ("c", 5.)] {
+            let simulator = simulator(Arc::new(NullController::default()), Vector3::new(x, 0., 0.));
+            world.add_drone(id, simulator, constant_input(Channels::default()));
+        }
+        world.init().unwrap();
+        for _ in 0..10 {
+            world.step(Duration::from_millis(10));
+        }
+        // the drones fall side by side, so they only collide once
+        assert_eq!(world.collisions().len(), 1);
+        let collision = &world.collisions()[0];
+        assert_eq!(collision.drones, ("a".into(), "b".into()));
+        assert_eq!(collision.time, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn prop_wash() {
+        let mut world = World::default().with_prop_wash(PropWash::default());
+        let armed = Channels::default().with_switch(&ChannelMap::default(), AuxRole::Arm, true);
+        let mut frames = 0;
+        let climbing = Box::new(move |_: &_| {
+            frames += 1;
+            // the throttle has to be low while arming
+            let mut channels = armed;
+            channels.throttle = if frames > 1 { 0. } else { -1. };
+            Some(channels)
+        });
+        world.add_drone(
+            "upper",
+            simulator(Arc::new(PidController::default()), Vector3::new(0., 1., 0.)),
+            climbing,
+        );
+        for (id, x) in [("lower", 0.), ("aside", 1.)] {
+            let simulator = simulator(Arc::new(NullController::default()), Vector3::new(x, 0., 0.));
+            world.add_drone(id, simulator, constant_input(Channels::default()));
+        }
+        // the prop wash adds to the other external forces
+        let wind = Vector3::new(0.1, 0., 0.);
+        for drone in world.drones.iter_mut() {
+            drone
+                .simulator
+                .drone
+                .current_frame
+                .drone_frame_state
+                .external_force = wind;
+        }
+        world.init().unwrap();
+        for _ in 0..20 {
+            world.step(Duration::from_millis(10));
+        }
+
+        let force = |id| {
+            world
+                .drone(id)
+                .unwrap()
+                .simulator
+                .drone
+                .current_frame
+                .drone_frame_state
+                .external_force
+        };
+        assert!(force("lower").y < 0.);
+        let prop_wash_force = world.drone("lower").unwrap().prop_wash_force;
+        assert!((force("lower") - prop_wash_force - wind).norm() < 1e-12);
+        assert_eq!(force("aside"), wind);
+        assert_eq!(force("upper"), wind);
+        // pushed down faster than the drone next to it
+        let altitude = |id| world.drone(id).unwrap().observation.position.y;
+        assert!(altitude("lower") < altitude("aside"));
+    }
+}
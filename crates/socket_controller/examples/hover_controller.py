@@ -0,0 +1,56 @@
+"""A minimal external controller, see the crate docs for the protocol.
+
+Run it, then fly ControllerType::Socket("tcp:127.0.0.1:5760"). It only holds the
+altitude with the throttle and keeps the drone level-ish with a proportional rate
+damper, it is meant as a starting point.
+"""
+
+import json
+import socket
+
+PROTOCOL_VERSION = 1
+TARGET_ALTITUDE = 1.0
+HOVER = 0.3
+
+
+def motor_input(update):
+    altitude = update["position"][1]
+    throttle = min(max(HOVER + 0.2 * (TARGET_ALTITUDE - altitude), 0.0), 1.0)
+    # mixer-sense body rates, see PidController::body_rates
+    w = update["gyro_update"]["angular_velocity"]
+    roll, pitch, yaw = -w[2], -w[0], w[1]
+    damping = [-0.02 * roll, -0.02 * pitch, -0.05 * yaw]
+    # Betaflight quad X: rear right, front right, rear left, front left
+    mix = [(-1, 1, -1), (-1, -1, 1), (1, 1, 1), (1, -1, -1)]
+    return [
+        min(max(throttle + r * damping[0] + p * damping[1] + y * damping[2], 0.0), 1.0)
+        for r, p, y in mix
+    ]
+
+
+def serve(connection):
+    reader = connection.makefile("r")
+    for line in reader:
+        request = json.loads(line)
+        kind = request["type"]
+        if kind == "hello":
+            response = {"type": "hello", "version": PROTOCOL_VERSION}
+        elif kind == "init":
+            response = {"type": "ready"}
+        elif kind == "update":
+            response = {
+                "type": "motor_input",
+                "seq": request["seq"],
+                "input": motor_input(request["update"]),
+            }
+        else:
+            return
+        connection.sendall((json.dumps(response) + "\n").encode())
+
+
+if __name__ == "__main__":
+    with socket.create_server(("127.0.0.1", 5760)) as server:
+        while True:
+            connection, _ = server.accept()
+            with connection:
+                serve(connection)
@@ -0,0 +1,406 @@
+//! A flight controller that runs in another process, so controllers can be written in any
+//! language. The simulator connects to the controller over TCP or a Unix socket.
+//!
+//! # Protocol (version 1)
+//!
+//! Every message is a single line of json with a `type` field, terminated by `\n`. The simulator
+//! sends requests and waits for the response to each one, so the controller runs in lock-step
+//! with the simulation.
+//!
+//! | request                                                 | response                                        |
+//! |---------------------------------------------------------|-------------------------------------------------|
+//! | `{"type":"hello","version":1,"scheduler_delta":0.005}`  | `{"type":"hello","version":1}`                  |
+//! | `{"type":"init"}`                                       | `{"type":"ready"}`                              |
+//! | `{"type":"update","seq":0,"delta_time":0.005,"update":{..}}` | `{"type":"motor_input","seq":0,"input":[..]}` |
+//! | `{"type":"bye"}`                                        | none, the connection is closed                  |
+//!
+//! - `hello` is the first message of every connection. The controller answers with its own
+//!   version, the connection is dropped if the versions differ. `scheduler_delta` is the period
+//!   (in s) at which the controller is called.
+//! - `init` is sent after the handshake and whenever the simulator (re)initializes the controller.
+//! - `update` carries the `FlightControllerUpdate` with the same field names as the Rust struct:
+//!   `battery_update`, `gyro_update` (the rotation is i, j, k, w), `channels` and `position`
+//!   (ground truth, y is up). The answer holds the four motor inputs in 0..1 in the order of the
+//!   Betaflight quad X mixer and has to repeat the `seq` of the update.
+//! - Any request can be answered with `{"type":"error","message":".."}`.
+//!
+//! If the controller does not answer within the timeout, answers with an error or disconnects,
+//! the motors are stopped for that update and the simulator reconnects on the next update.
+
+pub mod protocol;
+pub mod stand_in;
+
+use flight_controller::{FlightController, FlightControllerUpdate, InitError, MotorInput};
+use protocol::{PROTOCOL_VERSION, ProtocolError, Request, Response, read_message, write_message};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::Mutex,
+    time::Duration,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SocketAddress {
+    Tcp(String), // host:port
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl SocketAddress {
+    /// Parses `tcp:host:port` or `unix:/path/to/socket`, a plain `host:port` is a TCP address.
+    pub fn parse(address: &str) -> Self {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            return Self::Unix(PathBuf::from(path));
+        }
+        Self::Tcp(address.strip_prefix("tcp:").unwrap_or(address).to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SocketConfig {
+    pub address: SocketAddress,
+    pub scheduler_delta: Duration,
+    // how long to wait for the connection and for each response
+    pub timeout: Duration,
+}
+
+impl SocketConfig {
+    pub fn new(address: SocketAddress) -> Self {
+        Self {
+            address,
+            scheduler_delta: Duration::from_millis(5),
+            timeout: Duration::from_millis(500),
+        }
+    }
+
+    pub fn with_scheduler_delta(self, scheduler_delta: Duration) -> Self {
+        Self {
+            scheduler_delta,
+            ..self
+        }
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+}
+
+struct Connection {
+    reader: Box<dyn BufRead + Send>,
+    writer: Box<dyn Write + Send>,
+}
+
+impl Connection {
+    fn open(config: &SocketConfig) -> Result<Self, ProtocolError> {
+        let timeout = Some(config.timeout);
+        let mut connection = match &config.address {
+            SocketAddress::Tcp(address) => {
+                let address = address.to_socket_addrs()?.next().ok_or_else(|| {
+                    ProtocolError::Unexpected(format!("could not resolve {address}"))
+                })?;
+                let stream = TcpStream::connect_timeout(&address, config.timeout)?;
+                // the messages are small, do not wait for more data
+                stream.set_nodelay(true)?;
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)?;
+                Self {
+                    reader: Box::new(BufReader::new(stream.try_clone()?)),
+                    writer: Box::new(stream),
+                }
+            }
+            #[cfg(unix)]
+            SocketAddress::Unix(path) => {
+                let stream = std::os::unix::net::UnixStream::connect(path)?;
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)?;
+                Self {
+                    reader: Box::new(BufReader::new(stream.try_clone()?)),
+                    writer: Box::new(stream),
+                }
+            }
+        };
+
+        let hello = Request::Hello {
+            version: PROTOCOL_VERSION,
+            scheduler_delta: config.scheduler_delta.as_secs_f64(),
+        };
+        match connection.request(&hello)? {
+            Response::Hello { version } if version == PROTOCOL_VERSION => Ok(connection),
+            Response::Hello { version } => Err(ProtocolError::Version {
+                expected: PROTOCOL_VERSION,
+                received: version,
+            }),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response, ProtocolError> {
+        write_message(&mut self.writer, request)?;
+        match read_message(&mut self.reader)? {
+            Response::Error { message } => Err(ProtocolError::Remote(message)),
+            response => Ok(response),
+        }
+    }
+
+    fn init(&mut self) -> Result<(), ProtocolError> {
+        match self.request(&Request::Init)? {
+            Response::Ready => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn update(
+        &mut self,
+        seq: u64,
+        delta_time: f64,
+        update: FlightControllerUpdate,
+    ) -> Result<MotorInput, ProtocolError> {
+        let request = Request::Update {
+            seq,
+            delta_time,
+            update: Box::new(update),
+        };
+        match self.request(&request)? {
+            Response::MotorInput { seq: answer, input } if answer == seq => {
+                Ok(MotorInput { input })
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> ProtocolError {
+    ProtocolError::Unexpected(format!("{response:?}"))
+}
+
+#[derive(Default)]
+struct SocketState {
+    connection: Option<Connection>,
+    seq: u64,
+    // the controller has to be initialized on the next connection
+    init_pending: bool,
+    failures: usize,
+    last_error: Option<String>,
+}
+
+/// Forwards every update to an external controller and waits for its motor inputs. Failed updates
+/// stop the motors, the connection is reopened on the next update.
+pub struct SocketController {
+    pub config: SocketConfig,
+    state: Mutex<SocketState>,
+}
+
+impl SocketController {
+    /// Does not connect yet, the connection is opened by the first init or update.
+    pub fn new(config: SocketConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(SocketState::default()),
+        }
+    }
+
+    /// Connects right away, so a missing controller is reported immediately.
+    pub fn connect(config: SocketConfig) -> Result<Self, ProtocolError> {
+        let connection = Connection::open(&config)?;
+        Ok(Self {
+            config,
+            state: Mutex::new(SocketState {
+                connection: Some(connection),
+                ..Default::default()
+            }),
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connection.is_some()
+    }
+
+    /// The number of requests that failed so far
+    pub fn failures(&self) -> usize {
+        self.state.lock().unwrap().failures
+    }
+
+    pub fn last_error(&self) -> Option<String> {
+        self.state.lock().unwrap().last_error.clone()
+    }
+
+    fn fail(&self, state: &mut SocketState, err: ProtocolError) {
+        log::warn!("socket controller {:?}: {err}", self.config.address);
+        state.connection = None;
+        state.failures += 1;
+        state.last_error = Some(err.to_string());
+    }
+
+    fn connection<'a>(
+        &self,
+        state: &'a mut SocketState,
+    ) -> Result<&'a mut Connection, ProtocolError> {
+        if state.connection.is_none() {
+            let mut connection = Connection::open(&self.config)?;
+            if state.init_pending {
+                connection.init()?;
+            }
+            state.connection = Some(connection);
+        }
+        Ok(state.connection.as_mut().unwrap())
+    }
+}
+
+impl FlightController for SocketController {
+    fn init(&self) -> Result<(), InitError> {
+        let mut state = self.state.lock().unwrap();
+        state.init_pending = true;
+        state.seq = 0;
+        let result = match state.connection.as_mut() {
+            Some(connection) => connection.init(),
+            // a new connection is initialized when it is opened
+            None => self.connection(&mut state).map(|_| ()),
+        };
+        if let Err(err) = result {
+            let message = err.to_string();
+            self.fail(&mut state, err);
+            return Err(message.into());
+        }
+        Ok(())
+    }
+
+    fn update(&self, delta_time: f64, update: FlightControllerUpdate) -> MotorInput {
+        let mut state = self.state.lock().unwrap();
+        let seq = state.seq;
+        state.seq += 1;
+        let result = self
+            .connection(&mut state)
+            .and_then(|connection| connection.update(seq, delta_time, update));
+        match result {
+            Ok(motor_input) => motor_input,
+            Err(err) => {
+                self.fail(&mut state, err);
+                MotorInput::default()
+            }
+        }
+    }
+
+    fn scheduler_delta(&self) -> Duration {
+        self.config.scheduler_delta
+    }
+}
+
+impl Drop for SocketController {
+    fn drop(&mut self) {
+        if let Some(connection) = self.state.get_mut().unwrap().connection.as_mut() {
+            let _ = write_message(&mut connection.writer, &Request::Bye);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{SocketAddress, SocketConfig, SocketController, stand_in::StandInServer};
+    use flight_controller::{
+        FlightController, FlightControllerUpdate, InitError, MotorInput,
+        controllers::null_controller::NullController,
+    };
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    // echoes the throttle to all motors and counts the inits
+    #[derive(Default)]
+    struct ThrottleController {
+        inits: Mutex<usize>,
+    }
+
+    impl FlightController for ThrottleController {
+        fn init(&self) -> Result<(), InitError> {
+            *self.inits.lock().unwrap() += 1;
+            Ok(())
+        }
+        fn update(&self, _: f64, update: FlightControllerUpdate) -> MotorInput {
+            MotorInput {
+                input: [update.channels.throttle; 4],
+            }
+        }
+        fn scheduler_delta(&self) -> Duration {
+            Duration::from_millis(5)
+        }
+    }
+
+    fn update_with_throttle(throttle: f64) -> FlightControllerUpdate {
+        let mut update = FlightControllerUpdate::default();
+        update.channels.throttle = throttle;
+        update
+    }
+
+    fn check_lock_step(config: SocketConfig, remote: Arc<ThrottleController>) {
+        let controller = SocketController::connect(config).unwrap();
+        controller.init().unwrap();
+        for i in 0..100 {
+            let throttle = i as f64 / 100.;
+            let motor_input = controller.update(0.005, update_with_throttle(throttle));
+            assert_eq!(motor_input.input, [throttle; 4]);
+        }
+        assert_eq!(*remote.inits.lock().unwrap(), 1);
+        assert_eq!(controller.failures(), 0);
+    }
+
+    #[test]
+    fn tcp_lock_step() {
+        let remote = Arc::new(ThrottleController::default());
+        let server = StandInServer::tcp(remote.clone());
+        check_lock_step(SocketConfig::new(server.address.clone()), remote);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_lock_step() {
+        let path = std::env::temp_dir().join(format!("socket_controller_{}", std::process::id()));
+        let remote = Arc::new(ThrottleController::default());
+        let server = StandInServer::unix(&path, remote.clone());
+        assert_eq!(server.address, SocketAddress::Unix(path.clone()));
+        check_lock_step(SocketConfig::new(server.address.clone()), remote);
+        drop(server);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn timeout_and_reconnect() {
+        let remote = Arc::new(ThrottleController::default());
+        let server = StandInServer::tcp(remote.clone());
+        let config =
+            SocketConfig::new(server.address.clone()).with_timeout(Duration::from_millis(50));
+        let controller = SocketController::connect(config).unwrap();
+        controller.init().unwrap();
+
+        // a slow answer stops the motors and drops the connection
+        server.set_response_delay(Duration::from_millis(200));
+        let motor_input = controller.update(0.005, update_with_throttle(0.5));
+        assert_eq!(motor_input.input, [0.; 4]);
+        assert!(!controller.is_connected());
+        assert_eq!(controller.failures(), 1);
+
+        // the next update reconnects and initializes the controller again
+        server.set_response_delay(Duration::ZERO);
+        let motor_input = controller.update(0.005, update_with_throttle(0.5));
+        assert_eq!(motor_input.input, [0.5; 4]);
+        assert_eq!(*remote.inits.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn disconnect() {
+        let server = StandInServer::tcp(Arc::new(NullController::default()));
+        let address = server.address.clone();
+        let controller = SocketController::connect(SocketConfig::new(address)).unwrap();
+        drop(server);
+
+        let motor_input = controller.update(0.005, update_with_throttle(0.5));
+        assert_eq!(motor_input.input, [0.; 4]);
+        assert!(controller.last_error().is_some());
+        assert!(
+            SocketController::connect(SocketConfig::new(SocketAddress::parse("tcp:127.0.0.1:1")))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use flight_controller::{FlightControllerUpdate, MotorInput};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::io::{self, BufRead, Write};
+
+/// Bumped on every incompatible change of the messages below.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Messages sent by the simulator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    // first message of every connection, scheduler_delta is in s
+    Hello {
+        version: u32,
+        scheduler_delta: f64,
+    },
+    // the controller should reset its state
+    Init,
+    // delta_time is the time since the last update in s
+    Update {
+        seq: u64,
+        delta_time: f64,
+        update: Box<FlightControllerUpdate>,
+    },
+    // the simulator is done, the connection is closed afterwards
+    Bye,
+}
+
+/// Messages sent by the external controller.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Hello { version: u32 },
+    Ready,
+    // answers the update with the same seq
+    MotorInput { seq: u64, input: [f64; 4] },
+    Error { message: String },
+}
+
+impl Response {
+    pub fn motor_input(seq: u64, motor_input: MotorInput) -> Self {
+        Self::MotorInput {
+            seq,
+            input: motor_input.input,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    // the other side closed the connection
+    Closed,
+    Version { expected: u32, received: u32 },
+    Unexpected(String),
+    Remote(String),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "io error: {err}"),
+            Self::Json(err) => write!(f, "invalid message: {err}"),
+            Self::Closed => write!(f, "connection closed"),
+            Self::Version { expected, received } => {
+                write!(f, "protocol version {received}, expected {expected}")
+            }
+            Self::Unexpected(message) => write!(f, "unexpected message: {message}"),
+            Self::Remote(message) => write!(f, "controller error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ProtocolError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+/// Writes one message as a single line of json.
+pub fn write_message<T: Serialize>(
+    writer: &mut impl Write,
+    message: &T,
+) -> Result<(), ProtocolError> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the next line and parses it, empty lines are skipped.
+pub fn read_message<T: DeserializeOwned>(reader: &mut impl BufRead) -> Result<T, ProtocolError> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(ProtocolError::Closed);
+        }
+        if !line.trim().is_empty() {
+            return Ok(serde_json::from_str(&line)?);
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{
+    SocketAddress,
+    protocol::{PROTOCOL_VERSION, ProtocolError, Request, Response, write_message},
+};
+use flight_controller::FlightController;
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// how often the threads check if the server was stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+type Streams = (Box<dyn BufRead + Send>, Box<dyn Write + Send>);
+
+impl Listener {
+    // non blocking, None if there is no pending connection
+    fn accept(&self) -> io::Result<Option<Streams>> {
+        let accepted = match self {
+            Self::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                let reader: Box<dyn BufRead + Send> = Box::new(BufReader::new(stream.try_clone()?));
+                Ok((reader, Box::new(stream) as Box<dyn Write + Send>))
+            }),
+            #[cfg(unix)]
+            Self::Unix(listener) => listener.accept().and_then(|(stream, _)| {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                let reader: Box<dyn BufRead + Send> = Box::new(BufReader::new(stream.try_clone()?));
+                Ok((reader, Box::new(stream) as Box<dyn Write + Send>))
+            }),
+        };
+        match accepted {
+            Ok(streams) => Ok(Some(streams)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Serves a Rust flight controller over the socket protocol, it stands in for an external
+/// controller in tests and shows what a controller has to implement. Every connection is served by
+/// its own thread, dropping the server closes all connections.
+pub struct StandInServer {
+    pub address: SocketAddress,
+    response_delay: Arc<Mutex<Duration>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StandInServer {
+    /// Listens on a free port on localhost
+    pub fn tcp(controller: Arc<dyn FlightController>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = SocketAddress::Tcp(listener.local_addr().unwrap().to_string());
+        listener.set_nonblocking(true).unwrap();
+        Self::spawn(address, Listener::Tcp(listener), controller)
+    }
+
+    /// Listens on the given path, an existing socket file is replaced
+    #[cfg(unix)]
+    pub fn unix(path: &std::path::Path, controller: Arc<dyn FlightController>) -> Self {
+        let _ = std::fs::remove_file(path);
+        let listener = std::os::unix::net::UnixListener::bind(path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = SocketAddress::Unix(path.to_path_buf());
+        Self::spawn(address, Listener::Unix(listener), controller)
+    }
+
+    fn spawn(
+        address: SocketAddress,
+        listener: Listener,
+        controller: Arc<dyn FlightController>,
+    ) -> Self {
+        let response_delay = Arc::new(Mutex::new(Duration::ZERO));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let response_delay = response_delay.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut connections = vec![];
+                while !stop.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok(Some(streams)) => {
+                            let controller = controller.clone();
+                            let response_delay = response_delay.clone();
+                            let stop = stop.clone();
+                            connections.push(thread::spawn(move || {
+                                serve(streams, controller, response_delay, stop)
+                            }));
+                        }
+                        Ok(None) => thread::sleep(POLL_INTERVAL),
+                        Err(err) => {
+                            log::error!("stand-in server: {err}");
+                            break;
+                        }
+                    }
+                }
+                for connection in connections {
+                    let _ = connection.join();
+                }
+            })
+        };
+        Self {
+            address,
+            response_delay,
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Delays the answers to updates, to simulate a slow controller
+    pub fn set_response_delay(&self, delay: Duration) {
+        *self.response_delay.lock().unwrap() = delay;
+    }
+}
+
+impl Drop for StandInServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn serve(
+    (mut reader, mut writer): Streams,
+    controller: Arc<dyn FlightController>,
+    response_delay: Arc<Mutex<Duration>>,
+    stop: Arc<AtomicBool>,
+) {
+    // kept across timeouts, a read can time out in the middle of a line
+    let mut line = String::new();
+    while !stop.load(Ordering::Relaxed) {
+        match reader.read_line(&mut line) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(_) => return,
+        }
+        let request = serde_json::from_str::<Request>(&line);
+        line.clear();
+        let response = match request {
+            Ok(Request::Hello { version, .. }) => {
+                if version != PROTOCOL_VERSION {
+                    let _ = write_message(
+                        &mut writer,
+                        &Response::Error {
+                            message: ProtocolError::Version {
+                                expected: PROTOCOL_VERSION,
+                                received: version,
+                            }
+                            .to_string(),
+                        },
+                    );
+                    return;
+                }
+                Response::Hello {
+                    version: PROTOCOL_VERSION,
+                }
+            }
+            Ok(Request::Init) => match controller.init() {
+                Ok(()) => Response::Ready,
+                Err(err) => Response::Error {
+                    message: err.to_string(),
+                },
+            },
+            Ok(Request::Update {
+                seq,
+                delta_time,
+                update,
+            }) => {
+                let delay = *response_delay.lock().unwrap();
+                thread::sleep(delay);
+                Response::motor_input(seq, controller.update(delta_time, *update))
+            }
+            Ok(Request::Bye) => return,
+            Err(err) => Response::Error {
+                message: err.to_string(),
+            },
+        };
+        if write_message(&mut writer, &response).is_err() {
+            return;
+        }
+    }
+}
@@ -15,7 +15,7 @@ use bevy::{
     time::Time,
 };
 use bevy_panorbit_camera::PanOrbitCamera;
-use flight_controller::Channels;
+use flight_controller::{AuxRole, ChannelMap, Channels};
 use nalgebra::Vector3;
 use simulator::SimulationObservation;
 use simulator::Simulator;
@@ -29,6 +29,7 @@ pub struct Simulation(Simulator);
 #[derive(Resource, Default, Debug)]
 pub struct SimulationData {
     pub channels: Channels,
+    pub channel_map: ChannelMap,
     pub sim_info: SimulationObservation,
 }
 
@@ -72,6 +73,24 @@ pub fn handle_input(
         if let (KeyCode::ArrowRight, ButtonState::Pressed) = (ev.key_code, ev.state) {
             sim_data.channels.yaw += 0.01;
         }
+
+        // switches are toggled on key press
+        let role = match (ev.key_code, ev.state) {
+            (KeyCode::KeyA, ButtonState::Pressed) => Some(AuxRole::Arm),
+            (KeyCode::KeyM, ButtonState::Pressed) => Some(AuxRole::Angle),
+            (KeyCode::KeyB, ButtonState::Pressed) => Some(AuxRole::Beeper),
+            (KeyCode::KeyT, ButtonState::Pressed) => Some(AuxRole::Turtle),
+            _ => None,
+        };
+        if let Some(role) = role {
+            let SimulationData {
+                channels,
+                channel_map,
+                ..
+            } = &mut *sim_data;
+            let on = channels.switch_on(channel_map, role);
+            channels.set_switch(channel_map, role, !on);
+        }
     }
 }
 
@@ -113,7 +132,7 @@ pub fn sim_loop(
 // TODO: set it up according to the menu
 pub fn enter_simulation(mut commands: Commands, mut context: ResMut<Context>) {
     let mut simulation = context.try_load_simulator().unwrap();
-    simulation.init();
+    simulation.init().unwrap();
     commands.insert_resource(Simulation(simulation));
     commands.insert_resource(SimulationData::default());
 }
@@ -1,6 +1,7 @@
 use crate::VisualizerState;
 use bevy::{
     ecs::{resource::Resource, system::ResMut},
+    log::error,
     state::state::NextState,
 };
 use bevy_egui::{
@@ -145,7 +146,16 @@ pub fn main_menu_toggle(
                                 ControllerType::Reservoir(res_id.into()),
                                 format!("Resrevoid controller {}", res_id),
                             );
+                            ui.selectable_value(
+                                controller,
+                                ControllerType::Supervised(
+                                    Box::new(ControllerType::Reservoir(res_id.into())),
+                                    Box::new(ControllerType::Pid),
+                                ),
+                                format!("Supervised reservoir controller {}", res_id),
+                            );
                         }
+                        ui.selectable_value(controller, ControllerType::Pid, "PID");
                         ui.selectable_value(controller, ControllerType::NullController, "Null");
                     });
             }
@@ -255,8 +265,10 @@ pub fn main_menu_toggle(
                 // needs to be created
                 context.set_logger(logger.to_logger_type(simulation_name.to_owned()));
                 // needs to be created
-                context.set_controller(controller.clone());
-                next_visualizer_state.set(VisualizerState::Simulation);
+                match context.set_controller(controller.clone()) {
+                    Ok(()) => next_visualizer_state.set(VisualizerState::Simulation),
+                    Err(err) => error!("Could not start the controller: {err}"),
+                }
             }
             UIState::Replay {
                 replay_id: Some(replay_id),
@@ -2,6 +2,7 @@ use crate::{sim::SimulationData, VisualizerState};
 use bevy::prelude::{NextState, ResMut};
 use bevy_egui::{egui, EguiContexts};
 use egui_extras::{Column, TableBuilder};
+use flight_controller::AuxRole;
 
 pub fn simulation_ui(
     mut ctx: EguiContexts,
@@ -68,6 +69,15 @@ pub fn simulation_ui(
                     display_debug_data!("Yaw", sim_data.channels.yaw);
                     display_debug_data!("Pitch", sim_data.channels.pitch);
                     display_debug_data!("Roll", sim_data.channels.roll);
+                    display_debug_data!(
+                        "Arm switch",
+                        sim_data.channels.switch_on(&sim_data.channel_map, AuxRole::Arm)
+                    );
+                    display_debug_data!(
+                        "Angle switch",
+                        sim_data.channels.switch_on(&sim_data.channel_map, AuxRole::Angle)
+                    );
+                    display_debug_data!("Arming state", sim_data.sim_info.arming_state);
             });
         });
 }
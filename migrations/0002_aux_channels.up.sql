@@ -0,0 +1,2 @@
+-- Stores the AUX channels of a flight log step as a json array
+ALTER TABLE flight_log ADD COLUMN aux_channels TEXT;
@@ -0,0 +1,19 @@
+-- Betaflight style rate profiles, same units as the Betaflight CLI
+CREATE TABLE IF NOT EXISTS rate_profile (
+    id TEXT NOT NULL PRIMARY KEY,
+    rates_type TEXT NOT NULL,
+    roll_rc_rate DOUBLE NOT NULL,
+    pitch_rc_rate DOUBLE NOT NULL,
+    yaw_rc_rate DOUBLE NOT NULL,
+    roll_expo DOUBLE NOT NULL,
+    pitch_expo DOUBLE NOT NULL,
+    yaw_expo DOUBLE NOT NULL,
+    roll_srate DOUBLE NOT NULL,
+    pitch_srate DOUBLE NOT NULL,
+    yaw_srate DOUBLE NOT NULL,
+    roll_rate_limit DOUBLE NOT NULL,
+    pitch_rate_limit DOUBLE NOT NULL,
+    yaw_rate_limit DOUBLE NOT NULL,
+    thr_mid DOUBLE NOT NULL,
+    thr_expo DOUBLE NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Stores the motor inputs of the shadow controllers of a flight log step as a json array
+ALTER TABLE flight_log ADD COLUMN shadow_motor_inputs TEXT;
@@ -0,0 +1 @@
+ALTER TABLE flight_log DROP COLUMN arming_state;
@@ -0,0 +1,2 @@
+-- Stores the arming state of a flight log step as json
+ALTER TABLE flight_log ADD COLUMN arming_state TEXT;
@@ -0,0 +1,2 @@
+-- Stores the breach of the safety envelope while the fallback controller flew, as json
+ALTER TABLE flight_log ADD COLUMN intervention TEXT;
@@ -0,0 +1,6 @@
+-- Stores what happened during a whole flight as json, one row per flight log: the collisions with
+-- the scene
+CREATE TABLE IF NOT EXISTS flight_log_events (
+    simulation_id TEXT PRIMARY KEY NOT NULL,
+    collisions TEXT
+);
@@ -0,0 +1,2 @@
+ALTER TABLE flight_log_events DROP COLUMN laps;
+ALTER TABLE flight_log_events DROP COLUMN gate_passes;
@@ -0,0 +1,3 @@
+-- Stores the gate passes and laps of the race course as json
+ALTER TABLE flight_log_events ADD COLUMN gate_passes TEXT;
+ALTER TABLE flight_log_events ADD COLUMN laps TEXT;
@@ -0,0 +1 @@
+ALTER TABLE flight_log_events DROP COLUMN parameters;
@@ -0,0 +1,2 @@
+-- Stores the parameters of a randomized drone as json
+ALTER TABLE flight_log_events ADD COLUMN parameters TEXT;
@@ -28,5 +28,18 @@ CREATE TABLE IF NOT EXISTS flight_log (
     throttle REAL,
     roll REAL,
     pitch REAL,
-    yaw REAL
+    yaw REAL,
+    aux_channels TEXT,
+    shadow_motor_inputs TEXT,
+    arming_state TEXT,
+    intervention TEXT
+);
+
+-- collisions, race results and parameters of a flight log
+CREATE TABLE IF NOT EXISTS flight_log_events (
+    simulation_id TEXT PRIMARY KEY NOT NULL,
+    collisions TEXT,
+    gate_passes TEXT,
+    laps TEXT,
+    parameters TEXT
 );